@@ -0,0 +1,185 @@
+//! Allocations and time per packet through `Observer::capture_packets`, with a reader
+//! that allocates every packet afresh and one that reuses the buffers handed back by
+//! the capture loop.
+//!
+//! Run with `cargo bench --bench capture`. Criterion reports the time per packet, and
+//! the allocations per packet are printed once each bench is done.
+
+use anyhow::Result;
+use aragorn::plugin::{Metrics, Plugin};
+use aragorn::post_processor::ProcessedResult;
+use aragorn::tun::{ObsConfig, Observer, PacketReader, ReadResult};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::MutableIpv4Packet;
+use pnet::packet::tcp::{MutableTcpPacket, TcpFlags};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const PORT: u16 = 6379;
+
+/// Counts every allocation made by the process.
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// An Ethernet/IPv4/TCP frame from a client to `PORT` carrying a GET.
+fn frame() -> Vec<u8> {
+    let payload = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n";
+    let mut buf = vec![0u8; 14 + 20 + 20 + payload.len()];
+    let mut eth = MutableEthernetPacket::new(&mut buf).unwrap();
+    eth.set_ethertype(EtherTypes::Ipv4);
+    let mut ip = MutableIpv4Packet::new(&mut buf[14..]).unwrap();
+    ip.set_version(4);
+    ip.set_header_length(5);
+    ip.set_total_length((20 + 20 + payload.len()) as u16);
+    ip.set_ttl(64);
+    ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+    ip.set_source([10, 0, 0, 2].into());
+    ip.set_destination([10, 0, 0, 1].into());
+    let mut tcp = MutableTcpPacket::new(&mut buf[34..]).unwrap();
+    tcp.set_source(50000);
+    tcp.set_destination(PORT);
+    tcp.set_data_offset(5);
+    tcp.set_flags(TcpFlags::ACK | TcpFlags::PSH);
+    tcp.set_payload(payload);
+    buf
+}
+
+/// Hands out the same frame `packets` times, no further ahead of the plugin than
+/// half the capture queue, so none are dropped.
+struct Reader {
+    frame: Vec<u8>,
+    packets: usize,
+    read: usize,
+    processed: Arc<AtomicUsize>,
+    reuse: bool,
+    queue_depth: usize,
+}
+
+impl Reader {
+    fn next(&mut self) -> bool {
+        if self.read == self.packets {
+            return false;
+        }
+        while self.read - self.processed.load(Ordering::Relaxed) > self.queue_depth / 2 {
+            std::thread::yield_now();
+        }
+        self.read += 1;
+        true
+    }
+}
+
+impl PacketReader for Reader {
+    fn read_packet(&mut self) -> ReadResult {
+        if !self.next() {
+            return ReadResult::Eof;
+        }
+        ReadResult::Packet(self.frame.clone())
+    }
+
+    fn read_packet_into(&mut self, mut buf: Vec<u8>) -> ReadResult {
+        if !self.reuse {
+            return self.read_packet();
+        }
+        if !self.next() {
+            return ReadResult::Eof;
+        }
+        buf.clear();
+        buf.extend_from_slice(&self.frame);
+        ReadResult::Packet(buf)
+    }
+}
+
+struct NullPlugin(Arc<AtomicUsize>);
+
+impl Plugin<ProcessedResult> for NullPlugin {
+    async fn port(&self) -> u16 {
+        PORT
+    }
+
+    async fn process(
+        &self,
+        _input: Vec<u8>,
+        _metrics: Option<Metrics>,
+    ) -> Result<Option<ProcessedResult>> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+}
+
+/// Capture `packets` packets, returning how long that took and how many allocations
+/// it made.
+fn capture(packets: usize, reuse: bool) -> (Duration, u64) {
+    let cfg = ObsConfig::default();
+    let processed = Arc::new(AtomicUsize::new(0));
+    let reader = Reader {
+        frame: frame(),
+        packets,
+        read: 0,
+        processed: processed.clone(),
+        reuse,
+        queue_depth: cfg.queue_depth,
+    };
+    let plugin = Arc::new(NullPlugin(processed.clone()));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let obs = Arc::new(Observer::new(cfg));
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    rt.block_on(obs.capture_packets(reader, plugin)).unwrap();
+    let elapsed = start.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+
+    assert_eq!(processed.load(Ordering::Relaxed), packets);
+    (elapsed, allocations)
+}
+
+fn bench(c: &mut Criterion) {
+    let mut group = c.benchmark_group("capture");
+    group.throughput(Throughput::Elements(1));
+    for (name, reuse) in [("allocate", false), ("reuse", true)] {
+        let (mut packets, mut allocations) = (0, 0);
+        group.bench_function(name, |b| {
+            b.iter_custom(|iters| {
+                let (elapsed, allocs) = capture(iters as usize, reuse);
+                packets += iters;
+                allocations += allocs;
+                elapsed
+            })
+        });
+        if packets > 0 {
+            println!(
+                "capture/{}: {:.2} allocs/packet",
+                name,
+                allocations as f64 / packets as f64
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench);
+criterion_main!(benches);
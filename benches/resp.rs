@@ -0,0 +1,86 @@
+//! Throughput of the RESP parser and of the Redis handler's per-packet path.
+//!
+//! Run with `cargo bench --bench resp`. Pass a name to only run the benches
+//! containing it, e.g. `cargo bench --bench resp -- handler`, and
+//! `--save-baseline <name>` / `--baseline <name>` to compare against an earlier run.
+
+use aragorn::plugin::redis::handler::{RespConfig, RespHandler};
+use aragorn::plugin::redis::resp_parser::{parse_resp, parse_resp_ref, ParserConfig};
+use aragorn::plugin::{Metrics, Plugin, RequestId};
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::hint::black_box;
+use std::time::Duration;
+
+fn metrics(latency: Option<Duration>) -> Option<Metrics> {
+    Some(Metrics {
+        identifier: RequestId::Connection(1),
+        latency,
+        src_addr: None,
+        dst_addr: None,
+    })
+}
+
+fn get() -> Vec<u8> {
+    b"*2\r\n$3\r\nGET\r\n$17\r\nuser:1234:profile\r\n".to_vec()
+}
+
+fn set_1k() -> Vec<u8> {
+    [
+        b"*3\r\n$3\r\nSET\r\n$17\r\nuser:1234:profile\r\n$1024\r\n".as_slice(),
+        &[b'x'; 1024],
+        b"\r\n",
+    ]
+    .concat()
+}
+
+fn reply_1k() -> Vec<u8> {
+    [b"$1024\r\n".as_slice(), &[b'x'; 1024], b"\r\n"].concat()
+}
+
+fn parser(c: &mut Criterion) {
+    let cfg = ParserConfig::default();
+    for (name, input) in [
+        ("get", get()),
+        ("set_1k", set_1k()),
+        ("reply_1k", reply_1k()),
+    ] {
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function("parse_resp", |b| {
+            b.iter(|| parse_resp(black_box(&input), &cfg).unwrap().1)
+        });
+        group.bench_function("parse_resp_ref", |b| {
+            b.iter(|| parse_resp_ref(black_box(&input), &cfg).unwrap().1)
+        });
+        group.finish();
+    }
+}
+
+fn handler(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let handler = RespHandler::new(6379, RespConfig::default());
+    let latency = Some(Duration::from_millis(1));
+    let mut group = c.benchmark_group("handler");
+    for (name, request, response) in [
+        ("get", get(), reply_1k()),
+        ("set_1k", set_1k(), b"+OK\r\n".to_vec()),
+    ] {
+        group.throughput(Throughput::Bytes((request.len() + response.len()) as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let pending = handler.process(request.clone(), metrics(None)).await;
+                    black_box(pending.unwrap());
+                    let res = handler.process(response.clone(), metrics(latency)).await;
+                    black_box(res.unwrap())
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parser, handler);
+criterion_main!(benches);
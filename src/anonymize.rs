@@ -0,0 +1,128 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::post_processor::ProcessedResult;
+
+/// Masks keeping a /24 of IPv4 and a /48 of IPv6 addresses.
+const V4_NETWORK: u32 = !0 << 8;
+const V6_NETWORK: u128 = !0 << 80;
+
+/// How client IPs are anonymized, as chosen on the command line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AnonymizeMode {
+    /// Keep only the network: a /24 for IPv4, a /48 for IPv6.
+    Truncate,
+    /// Replace the address with one derived from its HMAC under a secret key.
+    Hash,
+}
+
+/// Anonymizer rewrites the client addresses of results before they reach any sink.
+///
+/// Hashed addresses stay addresses of the same family, taken from the leading bytes of
+/// an HMAC-SHA256 of the original, so sinks keep their formats and a client keeps the
+/// same stand-in for as long as the key is unchanged. Ports are kept, as they only
+/// tell a client's connections apart.
+#[derive(Clone)]
+pub enum Anonymizer {
+    Truncate,
+    Hash(PKey<Private>),
+}
+
+impl std::fmt::Debug for Anonymizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Anonymizer::Truncate => write!(f, "Truncate"),
+            Anonymizer::Hash(_) => write!(f, "Hash"),
+        }
+    }
+}
+
+impl Anonymizer {
+    /// An anonymizer for `mode`. Hashing needs a non-empty key.
+    pub fn new(mode: AnonymizeMode, key: Option<&[u8]>) -> Result<Self> {
+        match mode {
+            AnonymizeMode::Truncate => Ok(Anonymizer::Truncate),
+            AnonymizeMode::Hash => match key {
+                Some(key) if !key.is_empty() => Ok(Anonymizer::Hash(PKey::hmac(key)?)),
+                _ => anyhow::bail!("Hashing IPs needs a key"),
+            },
+        }
+    }
+
+    pub fn ip(&self, ip: IpAddr) -> IpAddr {
+        match self {
+            Anonymizer::Truncate => match ip {
+                IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & V4_NETWORK)),
+                IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & V6_NETWORK)),
+            },
+            Anonymizer::Hash(key) => {
+                let octets = match ip {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                let mac = Signer::new(MessageDigest::sha256(), key)
+                    .and_then(|mut signer| {
+                        signer.update(&octets)?;
+                        signer.sign_to_vec()
+                    })
+                    // Signing with an HMAC key in memory doesn't fail, but an address
+                    // must never leak through if it did
+                    .unwrap_or_else(|_| vec![0; 32]);
+                match ip {
+                    IpAddr::V4(_) => IpAddr::from(<[u8; 4]>::try_from(&mac[..4]).unwrap()),
+                    IpAddr::V6(_) => IpAddr::from(<[u8; 16]>::try_from(&mac[..16]).unwrap()),
+                }
+            }
+        }
+    }
+
+    pub fn addr(&self, addr: SocketAddr) -> SocketAddr {
+        SocketAddr::new(self.ip(addr.ip()), addr.port())
+    }
+
+    /// Anonymize the client address a result carries, if any.
+    pub fn result(&self, mut res: ProcessedResult) -> ProcessedResult {
+        if let ProcessedResult::Redis(res) = &mut res {
+            res.client_addr = res.client_addr.map(|a| self.addr(a));
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate() {
+        let anon = Anonymizer::new(AnonymizeMode::Truncate, None).unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        assert_eq!(anon.ip(ip("192.168.1.55")), ip("192.168.1.0"));
+        assert_eq!(anon.ip(ip("2001:db8:1234:5678::1")), ip("2001:db8:1234::"));
+        assert_eq!(
+            anon.addr("192.168.1.55:50000".parse().unwrap()),
+            "192.168.1.0:50000".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        assert!(Anonymizer::new(AnonymizeMode::Hash, None).is_err());
+        let anon = Anonymizer::new(AnonymizeMode::Hash, Some(b"secret")).unwrap();
+        let other = Anonymizer::new(AnonymizeMode::Hash, Some(b"another")).unwrap();
+        let ip: IpAddr = "192.168.1.55".parse().unwrap();
+
+        let hashed = anon.ip(ip);
+        assert!(hashed.is_ipv4());
+        assert_ne!(hashed, ip);
+        // Stable for a key, and different under another
+        assert_eq!(anon.ip(ip), hashed);
+        assert_ne!(other.ip(ip), hashed);
+        assert_ne!(anon.ip("192.168.1.56".parse().unwrap()), hashed);
+        assert!(anon.ip("2001:db8::1".parse().unwrap()).is_ipv6());
+    }
+}
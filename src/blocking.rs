@@ -0,0 +1,127 @@
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::runtime::{Builder, Runtime};
+
+use crate::plugin::Plugin;
+use crate::post_processor::ProcessedResult;
+use crate::tun::{Observer, PacketReader};
+
+/// BlockingObserver drives an `Observer` on a runtime of its own, for consumers that
+/// don't run tokio themselves.
+///
+/// `run_blocking` parks the calling thread until `stop` is called from another thread
+/// (share the observer through an `Arc`). The runtime, and any tasks the observer
+/// spawned on it, is shut down when the BlockingObserver is dropped, which must
+/// happen outside of an async context.
+pub struct BlockingObserver {
+    runtime: Runtime,
+    observer: Arc<Observer>,
+}
+
+impl BlockingObserver {
+    pub fn new(observer: Observer) -> Result<Self> {
+        let runtime = Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("aragorn-observer")
+            .enable_all()
+            .build()?;
+        {
+            let _guard = runtime.enter();
+            observer.start_cleanup();
+        }
+        Ok(BlockingObserver {
+            runtime,
+            observer: Arc::new(observer),
+        })
+    }
+
+    /// Capture packets from the reader until stopped.
+    pub fn run_blocking<H, R>(
+        &self,
+        reader: impl PacketReader + Send + 'static,
+        handler: H,
+    ) -> Result<()>
+    where
+        R: Send + 'static + Into<ProcessedResult>,
+        H: Plugin<R> + 'static,
+    {
+        let handler = Arc::new(handler);
+        self.runtime
+            .block_on(self.observer.capture_packets(reader, handler))
+    }
+
+    pub fn stop(&self) {
+        self.observer.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::Metrics;
+    use crate::post_processor::PubSubResult;
+    use crate::tun::{ObsConfig, ReadResult};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    /// Yields a frame every few milliseconds for ever, like a quiet live interface.
+    struct MockPacketReader;
+
+    impl PacketReader for MockPacketReader {
+        fn read_packet(&mut self) -> ReadResult {
+            thread::sleep(Duration::from_millis(5));
+            ReadResult::Packet(vec![0; 14])
+        }
+    }
+
+    struct NoopPlugin;
+
+    impl Plugin<NoopResult> for NoopPlugin {
+        async fn port(&self) -> u16 {
+            6379
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<NoopResult>> {
+            Ok(None)
+        }
+    }
+
+    struct NoopResult;
+
+    impl From<NoopResult> for ProcessedResult {
+        fn from(_res: NoopResult) -> ProcessedResult {
+            ProcessedResult::PubSub(PubSubResult {
+                channel: String::new(),
+                kind: String::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_run_blocking_stops() {
+        let observer =
+            Arc::new(BlockingObserver::new(Observer::new(ObsConfig::default())).unwrap());
+        let done = Arc::new(AtomicUsize::new(0));
+
+        let capture = {
+            let observer = observer.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let res = observer.run_blocking(MockPacketReader, NoopPlugin);
+                done.fetch_add(1, Ordering::SeqCst);
+                res
+            })
+        };
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(done.load(Ordering::SeqCst), 0);
+        observer.stop();
+        capture.join().unwrap().unwrap();
+        assert_eq!(done.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,147 @@
+//! Framing of HTTP/1.1 bodies sent with `Transfer-Encoding: chunked` (RFC 9112 section
+//! 7.1). Such a response has no `Content-Length`, so where it ends, and with it when
+//! its latency is measured, is only known from the chunks themselves.
+
+use anyhow::{anyhow, Result};
+
+/// Longest chunk size or trailer line kept while waiting for the rest of it.
+const MAX_LINE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    /// Reading a chunk size line, e.g. `1a;name=value`.
+    #[default]
+    Size,
+    /// Skipping this many more bytes of chunk data.
+    Data(u64),
+    /// Expecting the CRLF that ends a chunk's data.
+    DataEnd,
+    /// Reading trailer fields after the last chunk, up to an empty line.
+    Trailer,
+    Done,
+}
+
+/// ChunkedBody follows a chunked body fed to it a segment at a time and finds where
+/// it ends: after the zero length chunk and the (usually empty) trailer section. Chunk
+/// data is skipped rather than kept, and a line split across segments is held until
+/// the rest of it arrives.
+#[derive(Debug, Default)]
+pub struct ChunkedBody {
+    state: State,
+    /// The part of a line seen so far.
+    line: Vec<u8>,
+}
+
+impl ChunkedBody {
+    /// Feed the next bytes of the body. Returns how many of them belong to the body
+    /// once its end is among them, so whatever follows (a pipelined response) can be
+    /// told apart, or None if more are needed.
+    pub fn feed(&mut self, input: &[u8]) -> Result<Option<usize>> {
+        if self.state == State::Done {
+            return Ok(Some(0));
+        }
+        let mut at = 0;
+        while at < input.len() {
+            if let State::Data(remaining) = self.state {
+                let n = remaining.min((input.len() - at) as u64);
+                at += n as usize;
+                self.state = match remaining - n {
+                    0 => State::DataEnd,
+                    left => State::Data(left),
+                };
+                continue;
+            }
+
+            let rest = &input[at..];
+            let Some(end) = rest.iter().position(|&b| b == b'\n') else {
+                self.push_line(rest)?;
+                break;
+            };
+            self.push_line(&rest[..end])?;
+            at += end + 1;
+            let mut line = std::mem::take(&mut self.line);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+
+            self.state = match self.state {
+                State::Size => match chunk_size(&line)? {
+                    0 => State::Trailer,
+                    size => State::Data(size),
+                },
+                State::DataEnd if line.is_empty() => State::Size,
+                State::DataEnd => return Err(anyhow!("Chunk longer than its declared size")),
+                State::Trailer if line.is_empty() => State::Done,
+                state => state,
+            };
+            if self.state == State::Done {
+                return Ok(Some(at));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether the end of the body has been seen.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    fn push_line(&mut self, part: &[u8]) -> Result<()> {
+        if self.line.len() + part.len() > MAX_LINE {
+            return Err(anyhow!("Chunk size or trailer line too long"));
+        }
+        self.line.extend_from_slice(part);
+        Ok(())
+    }
+}
+
+/// The size of a chunk from its size line: hex digits, optionally followed by
+/// extensions after a `;`.
+fn chunk_size(line: &[u8]) -> Result<u64> {
+    let digits = line.split(|&b| b == b';').next().unwrap_or_default();
+    let digits = std::str::from_utf8(digits)?.trim_end_matches([' ', '\t']);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(anyhow!("Invalid chunk size {:?}", digits));
+    }
+    u64::from_str_radix(digits, 16).map_err(|_| anyhow!("Chunk size {} too large", digits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_end_across_reads() {
+        let body = b"5\r\nhello\r\n1;ext=1\r\n \r\n0\r\n\r\n";
+        let next = b"HTTP/1.1 200 OK\r\n";
+        // Split within the first chunk's data and again within the terminating chunk
+        let (first, second) = body.split_at(6);
+        let (second, third) = second.split_at(second.len() - 3);
+        let mut third = third.to_vec();
+        third.extend_from_slice(next);
+
+        let mut chunked = ChunkedBody::default();
+        assert_eq!(chunked.feed(first).unwrap(), None);
+        assert_eq!(chunked.feed(second).unwrap(), None);
+        assert!(!chunked.is_done());
+        assert_eq!(chunked.feed(&third).unwrap(), Some(3));
+        assert!(chunked.is_done());
+
+        // A byte at a time, through a size line split in two and a trailer field
+        let body = b"1A\r\nabcdefghijklmnopqrstuvwxyz\r\n0\r\nExpires: never\r\n\r\n";
+        let mut chunked = ChunkedBody::default();
+        let ends: Vec<_> = body.iter().map(|b| chunked.feed(&[*b]).unwrap()).collect();
+        assert!(ends[..body.len() - 1].iter().all(Option::is_none));
+        assert_eq!(ends[body.len() - 1], Some(1));
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(ChunkedBody::default().feed(b"zz\r\n").is_err());
+        assert!(ChunkedBody::default().feed(b"2\r\nabc\r\n").is_err());
+        assert!(ChunkedBody::default()
+            .feed(b"10000000000000000\r\n")
+            .is_err());
+        assert!(ChunkedBody::default().feed(&[b'1'; MAX_LINE + 1]).is_err());
+    }
+}
@@ -0,0 +1,107 @@
+//! The settings file given with `--config`, read at startup and again on SIGHUP so
+//! sampling and TTLs can change without restarting the capture.
+//!
+//! Each line is `key = value`, and `#` starts a comment:
+//!
+//! ```text
+//! sample_rate = 0.25
+//! request_ttl = 5        # seconds
+//! connection_ttl = 60    # seconds
+//! ```
+
+use crate::tun::Settings;
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::time::Duration;
+
+/// Keys of settings fixed when the capture starts. They are recognised so a file
+/// setting one is reported rather than rejected as a typo.
+const IMMUTABLE: &[&str] = &[
+    "interface",
+    "protocol",
+    "port",
+    "redis_port",
+    "grpc_port",
+    "websocket_port",
+    "cql_port",
+    "queue_depth",
+    "max_queue_depth",
+    "workers",
+    "direction",
+    "anonymize_ips",
+    "anonymize_key_file",
+    "max_parse_bytes",
+];
+
+fn seconds(value: &str) -> Result<Duration> {
+    let secs: f64 = value.parse()?;
+    Duration::try_from_secs_f64(secs).map_err(Into::into)
+}
+
+/// Apply the settings in `text` over `base`. Returns the result along with the keys
+/// that were ignored because they can't change while running.
+pub fn parse(text: &str, base: Settings) -> Result<(Settings, Vec<String>)> {
+    let mut settings = base;
+    let mut ignored = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .map(|(k, v)| (k.trim(), v.trim()))
+            .with_context(|| format!("Line {}: expected `key = value`", i + 1))?;
+        let invalid = || format!("Line {}: invalid {} {:?}", i + 1, key, value);
+        match key {
+            "sample_rate" => {
+                settings.sample_rate = value
+                    .parse()
+                    .ok()
+                    .filter(|r| (0.0..=1.0).contains(r))
+                    .with_context(invalid)?;
+            }
+            "request_ttl" => settings.ttl = seconds(value).with_context(invalid)?,
+            "connection_ttl" => settings.connection_ttl = seconds(value).with_context(invalid)?,
+            key if IMMUTABLE.contains(&key) => ignored.push(key.to_string()),
+            key => anyhow::bail!("Line {}: unknown setting {:?}", i + 1, key),
+        }
+    }
+    Ok((settings, ignored))
+}
+
+pub fn load(path: &Path, base: Settings) -> Result<(Settings, Vec<String>)> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    parse(&text, base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let base = Settings {
+            ttl: Duration::from_secs(5),
+            connection_ttl: Duration::from_secs(60),
+            sample_rate: 1.0,
+        };
+        let text = "# Reloaded on SIGHUP\nsample_rate = 0.25\n\nconnection_ttl=1.5 # seconds\ninterface = eth1\n";
+        let (settings, ignored) = parse(text, base).unwrap();
+        assert_eq!(
+            settings,
+            Settings {
+                ttl: Duration::from_secs(5),
+                connection_ttl: Duration::from_millis(1500),
+                sample_rate: 0.25,
+            }
+        );
+        assert_eq!(ignored, vec!["interface"]);
+
+        assert!(parse("sample_rate = 2", base).is_err());
+        assert!(parse("request_ttl = -1", base).is_err());
+        assert!(parse("sample_rte = 0.5", base).is_err());
+        assert!(parse("sample_rate", base).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use tracing::error;
+
+use crate::tun::{PacketReader, ReadResult};
+
+/// Largest frame accepted, to bound the allocation for a corrupt length prefix.
+const MAX_FRAME_LEN: usize = 65535;
+
+/// Width of the big-endian length that precedes every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+}
+
+impl LengthPrefix {
+    fn width(self) -> usize {
+        match self {
+            LengthPrefix::U16 => 2,
+            LengthPrefix::U32 => 4,
+        }
+    }
+}
+
+/// FramedReader reads raw Ethernet frames, each preceded by its length, from any byte
+/// stream. It lets an external tool (a remote tap, a FIFO, stdin) feed packets in
+/// place of a local interface. Reading stops at the end of the stream.
+pub struct FramedReader<R> {
+    reader: R,
+    prefix: LengthPrefix,
+}
+
+impl FramedReader<BufReader<File>> {
+    /// Read frames from the file or FIFO at `path`.
+    pub fn open(path: impl AsRef<Path>, prefix: LengthPrefix) -> Result<Self> {
+        Ok(Self::new(BufReader::new(File::open(path)?), prefix))
+    }
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(reader: R, prefix: LengthPrefix) -> Self {
+        FramedReader { reader, prefix }
+    }
+
+    fn next_frame(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut len = [0u8; 4];
+        let len = &mut len[4 - self.prefix.width()..];
+        // read_exact keeps reading when the prefix is split across reads
+        match self.reader.read_exact(len) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let len = len.iter().fold(0usize, |n, &b| n << 8 | b as usize);
+        if len > MAX_FRAME_LEN {
+            return Err(anyhow::anyhow!("Frame of {} bytes is too large", len));
+        }
+        let mut frame = vec![0u8; len];
+        self.reader.read_exact(&mut frame)?;
+        Ok(Some(frame))
+    }
+}
+
+impl<R: Read> PacketReader for FramedReader<R> {
+    fn read_packet(&mut self) -> ReadResult {
+        match self.next_frame() {
+            Ok(Some(frame)) => ReadResult::Packet(frame),
+            Ok(None) => ReadResult::Eof,
+            Err(e) => {
+                error!("Failed to read frame: {:?}", e);
+                ReadResult::Eof
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Hands out at most one byte per read, like a slow pipe.
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(1);
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let mut input = vec![0, 0, 0, 3, 1, 2, 3];
+        input.extend_from_slice(&[0, 0, 0, 2, 4, 5]);
+        let mut reader = FramedReader::new(Trickle(Cursor::new(input)), LengthPrefix::U32);
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![1, 2, 3]));
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![4, 5]));
+        assert_eq!(reader.read_packet(), ReadResult::Eof);
+
+        let input = vec![0, 1, 9, 0, 0];
+        let mut reader = FramedReader::new(Cursor::new(input), LengthPrefix::U16);
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![9]));
+        // An empty frame is still a frame
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![]));
+        assert_eq!(reader.read_packet(), ReadResult::Eof);
+    }
+
+    #[test]
+    fn test_truncated_and_oversized_frames() {
+        let mut reader = FramedReader::new(Cursor::new(vec![0, 5, 1, 2]), LengthPrefix::U16);
+        assert_eq!(reader.read_packet(), ReadResult::Eof);
+
+        let mut reader = FramedReader::new(Cursor::new(vec![0, 1, 0, 0]), LengthPrefix::U32);
+        assert_eq!(reader.read_packet(), ReadResult::Eof);
+    }
+}
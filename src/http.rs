@@ -0,0 +1,234 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use prometheus::{gather, Encoder, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+use tracing::error;
+
+use crate::gzip;
+use crate::openmetrics;
+use crate::post_processor::ewma::EwmaPostProcessor;
+use crate::post_processor::recent::RecentRequestsPostProcessor;
+
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// How long a client gets to send its request headers before it is dropped.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The parts of an HTTP request the server cares about.
+struct Request {
+    path: String,
+    gzip: bool,
+    openmetrics: bool,
+}
+
+/// Whether a comma separated header value like `Accept-Encoding` lists a value
+/// matching `wanted`, without refusing it with `q=0`.
+fn accepts<'a>(
+    headers: &[(&'a str, &'a str)],
+    header: &str,
+    wanted: impl Fn(&str) -> bool,
+) -> bool {
+    headers
+        .iter()
+        .filter(|(name, _)| name.trim().eq_ignore_ascii_case(header))
+        .flat_map(|(_, value)| value.split(','))
+        .any(|item| {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            // `gzip;q=0` explicitly refuses it
+            let refused = params.any(|p| {
+                p.trim()
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    == Some(0.0)
+            });
+            wanted(name) && !refused
+        })
+}
+
+/// Read the request line and headers, up to the blank line ending them.
+async fn read_request(socket: &mut TcpStream) -> Result<Request> {
+    let mut buf = vec![];
+    let mut chunk = [0; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = socket.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_LEN {
+            break;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    let text = String::from_utf8_lossy(&buf);
+    let mut lines = text.split("\r\n");
+    let path = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .unwrap_or("/")
+        .to_string();
+    let headers: Vec<(&str, &str)> = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .collect();
+    let gzip = accepts(&headers, "accept-encoding", |coding| {
+        coding.eq_ignore_ascii_case("gzip") || coding == "*"
+    });
+    // Only on request, as Prometheus asks for it while older scrapers don't
+    let openmetrics = accepts(&headers, "accept", |media| {
+        media.eq_ignore_ascii_case("application/openmetrics-text")
+    });
+    Ok(Request {
+        path,
+        gzip,
+        openmetrics,
+    })
+}
+
+async fn respond(
+    socket: &mut TcpStream,
+    recent: &RecentRequestsPostProcessor,
+    ewma: &EwmaPostProcessor,
+) -> Result<()> {
+    let request = timeout(READ_TIMEOUT, read_request(socket)).await??;
+    let (content_type, body) = if request.path == "/recent" {
+        ("application/json", recent.to_json().await.into_bytes())
+    } else if request.path == "/ewma" {
+        ("application/json", ewma.to_json().await.into_bytes())
+    } else if request.openmetrics {
+        let body = openmetrics::encode(&gather()).into_bytes();
+        (openmetrics::CONTENT_TYPE, body)
+    } else {
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&gather(), &mut buffer)?;
+        ("text/plain; version=0.0.4", buffer)
+    };
+
+    let (encoding, body) = if request.gzip {
+        ("Content-Encoding: gzip\r\n", gzip::compress(&body))
+    } else {
+        ("", body)
+    };
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        encoding,
+        body.len()
+    );
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(&body).await?;
+    Ok(())
+}
+
+/// Serve the recent results as JSON on /recent, the latency averages on /ewma and
+/// Prometheus metrics on any other path, gzip compressed when the client accepts it.
+/// Metrics are in the OpenMetrics format, with exemplars, when the client accepts
+/// `application/openmetrics-text`.
+pub async fn serve(
+    listener: TcpListener,
+    recent: RecentRequestsPostProcessor,
+    ewma: EwmaPostProcessor,
+) -> Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        // One task per connection so a slow client can't hold up the others
+        let (recent, ewma) = (recent.clone(), ewma.clone());
+        tokio::spawn(async move {
+            if let Err(e) = respond(&mut socket, &recent, &ewma).await {
+                error!("Failed to serve metrics: {:?}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gzip::testing::decompress;
+
+    async fn get(addr: std::net::SocketAddr, headers: &str) -> (String, Vec<u8>) {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\n{}\r\n",
+            headers
+        );
+        socket.write_all(request.as_bytes()).await.unwrap();
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await.unwrap();
+        let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..split].to_vec()).unwrap();
+        (head, response[split + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_gzip_metrics() {
+        // Make sure there is something in the default registry
+        prometheus::register_int_counter!("aragorn_http_test_total", "Test counter")
+            .unwrap()
+            .inc();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            RecentRequestsPostProcessor::new(1),
+            EwmaPostProcessor::new(Default::default()).unwrap(),
+        ));
+
+        let (head, plain) = get(addr, "").await;
+        assert!(!head.contains("Content-Encoding"));
+        let plain = String::from_utf8(plain).unwrap();
+        assert!(plain.contains("aragorn_http_test_total 1"));
+
+        let (head, body) = get(addr, "Accept-Encoding: deflate, gzip\r\n").await;
+        assert!(head.contains("Content-Encoding: gzip\r\n"));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        let text = String::from_utf8(decompress(&body)).unwrap();
+        assert!(text.contains("aragorn_http_test_total 1"));
+
+        let (head, _) = get(addr, "accept-encoding: gzip;q=0\r\n").await;
+        assert!(!head.contains("Content-Encoding"));
+    }
+
+    #[tokio::test]
+    async fn test_openmetrics_negotiated() {
+        prometheus::register_int_counter!("aragorn_http_om_test_total", "Test counter")
+            .unwrap()
+            .inc();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            RecentRequestsPostProcessor::new(1),
+            EwmaPostProcessor::new(Default::default()).unwrap(),
+        ));
+
+        let accept = "Accept: application/openmetrics-text;version=1.0.0,text/plain;q=0.5\r\n";
+        let (head, body) = get(addr, accept).await;
+        assert!(head.contains(&format!("Content-Type: {}\r\n", openmetrics::CONTENT_TYPE)));
+        let text = String::from_utf8(body).unwrap();
+        assert!(text.contains("aragorn_http_om_test_total 1\n"));
+        assert!(text.ends_with("# EOF\n"));
+
+        let (head, _) = get(addr, "Accept: text/plain\r\n").await;
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_does_not_block() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(
+            listener,
+            RecentRequestsPostProcessor::new(1),
+            EwmaPostProcessor::new(Default::default()).unwrap(),
+        ));
+
+        // Connects but never sends a request
+        let _idle = TcpStream::connect(addr).await.unwrap();
+        let (head, _) = timeout(Duration::from_secs(1), get(addr, ""))
+            .await
+            .expect("served while another client is idle");
+        assert!(head.starts_with("HTTP/1.1 200 OK"));
+    }
+}
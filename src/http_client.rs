@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// An http URL that sinks post to, with just enough of a client for one request per
+/// connection. Only plain http is supported.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    /// `host:port` to connect to.
+    addr: String,
+    host: String,
+    /// The path of the URL, without a trailing slash.
+    path: String,
+}
+
+impl Endpoint {
+    pub fn new(url: &str) -> Result<Self> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("URL must start with http://: {}", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("URL has no host: {}", url));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Endpoint {
+            addr,
+            host: host.to_string(),
+            path: path.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// The endpoint at `suffix` under this one's path, e.g. `/v1/logs`.
+    pub fn join(mut self, suffix: &str) -> Self {
+        self.path.push_str(suffix);
+        self
+    }
+
+    /// POST `body`, failing unless the response status is 2xx.
+    pub async fn post(&self, content_type: &str, body: &[u8]) -> Result<()> {
+        let mut socket = TcpStream::connect(&self.addr).await?;
+        let head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            if self.path.is_empty() { "/" } else { &self.path },
+            self.host,
+            content_type,
+            body.len()
+        );
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(body).await?;
+
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Malformed response from {}", self.host))?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("{} responded with status {}", self.host, status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_url() {
+        let endpoint = Endpoint::new("http://collector:4318/otlp/").unwrap();
+        assert_eq!(endpoint.addr, "collector:4318");
+        assert_eq!(endpoint.path, "/otlp");
+        assert_eq!(endpoint.join("/v1/logs").path, "/otlp/v1/logs");
+
+        let endpoint = Endpoint::new("http://collector").unwrap();
+        assert_eq!(endpoint.addr, "collector:80");
+        assert_eq!(endpoint.path, "");
+        assert!(Endpoint::new("https://collector").is_err());
+        assert!(Endpoint::new("http:///path").is_err());
+    }
+}
@@ -0,0 +1,244 @@
+use pnet::packet::ipv4::{Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::Packet;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Largest datagram an IPv4 total length can describe.
+const MAX_DATAGRAM_LEN: usize = 65535;
+
+/// Fragments of one datagram are matched on (source, destination, identification, protocol).
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u16, u8);
+
+struct PendingDatagram {
+    first_seen: Instant,
+    /// Header of the fragment at offset 0, reused for the reassembled datagram.
+    header: Option<Vec<u8>>,
+    data: Vec<u8>,
+    /// Byte ranges of `data` filled so far.
+    ranges: Vec<(usize, usize)>,
+    /// Payload length, known once the last fragment (MF unset) arrives.
+    len: Option<usize>,
+}
+
+impl PendingDatagram {
+    fn is_complete(&self) -> bool {
+        let Some(len) = self.len else {
+            return false;
+        };
+        let mut ranges = self.ranges.clone();
+        ranges.sort_unstable();
+        let mut end = 0;
+        for (start, stop) in ranges {
+            if start > end {
+                return false;
+            }
+            end = end.max(stop);
+        }
+        self.header.is_some() && end == len
+    }
+}
+
+/// Reassembles fragmented IPv4 datagrams.
+///
+/// The number of datagrams held at once is bounded, as is how long their fragments
+/// wait for the rest. A fragment overlapping one already received discards the whole
+/// datagram, as overlaps only show up in evasion attempts.
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, PendingDatagram>,
+    timeout: Duration,
+    max_pending: usize,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        // Linux's ipfrag_time default
+        Reassembler::new(Duration::from_secs(30), 1024)
+    }
+}
+
+/// Whether the packet is one fragment of a larger datagram.
+pub fn is_fragment(packet: &Ipv4Packet) -> bool {
+    packet.get_flags() & Ipv4Flags::MoreFragments != 0 || packet.get_fragment_offset() != 0
+}
+
+impl Reassembler {
+    pub fn new(timeout: Duration, max_pending: usize) -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+            timeout,
+            max_pending,
+        }
+    }
+
+    /// Add a fragment, returning the complete datagram once every fragment of it arrived.
+    pub fn push(&mut self, fragment: &Ipv4Packet, now: Instant) -> Option<Vec<u8>> {
+        self.pending
+            .retain(|_, p| now.saturating_duration_since(p.first_seen) < self.timeout);
+
+        let key = (
+            fragment.get_source(),
+            fragment.get_destination(),
+            fragment.get_identification(),
+            fragment.get_next_level_protocol().0,
+        );
+        if !self.pending.contains_key(&key) && self.pending.len() >= self.max_pending {
+            debug!("Dropping IPv4 fragment, too many datagrams pending reassembly");
+            return None;
+        }
+
+        let payload = fragment.payload();
+        let start = fragment.get_fragment_offset() as usize * 8;
+        let end = start + payload.len();
+        let last = fragment.get_flags() & Ipv4Flags::MoreFragments == 0;
+        let header_len = fragment.get_header_length() as usize * 4;
+        if end + header_len > MAX_DATAGRAM_LEN || (!last && !payload.len().is_multiple_of(8)) {
+            self.pending.remove(&key);
+            return None;
+        }
+
+        let pending = self.pending.entry(key).or_insert_with(|| PendingDatagram {
+            first_seen: now,
+            header: None,
+            data: vec![],
+            ranges: vec![],
+            len: None,
+        });
+        let overlaps = pending.ranges.iter().any(|&(s, e)| start < e && s < end);
+        let beyond_last = pending.len.is_some_and(|len| end > len);
+        let conflicting_last = last && (pending.len.is_some() || pending.data.len() > end);
+        if overlaps || beyond_last || conflicting_last {
+            debug!(?key, "Dropping IPv4 datagram with inconsistent fragments");
+            self.pending.remove(&key);
+            return None;
+        }
+
+        if pending.data.len() < end {
+            pending.data.resize(end, 0);
+        }
+        pending.data[start..end].copy_from_slice(payload);
+        pending.ranges.push((start, end));
+        if last {
+            pending.len = Some(end);
+        }
+        if start == 0 {
+            pending.header = Some(fragment.packet()[..header_len].to_vec());
+        }
+        if !pending.is_complete() {
+            return None;
+        }
+
+        let pending = self.pending.remove(&key)?;
+        let mut datagram = pending.header?;
+        datagram.extend_from_slice(&pending.data);
+        let mut packet = MutableIpv4Packet::new(&mut datagram)?;
+        packet.set_total_length((header_len + pending.data.len()) as u16);
+        packet.set_flags(0);
+        packet.set_fragment_offset(0);
+        Some(datagram)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use pnet::packet::ipv4::{Ipv4Flags, MutableIpv4Packet};
+
+    /// Split an IPv4 packet into fragments carrying at most `size` payload bytes each.
+    /// `size` must be a multiple of 8.
+    pub fn fragment(packet: &[u8], size: usize) -> Vec<Vec<u8>> {
+        let header = &packet[..20];
+        packet[20..]
+            .chunks(size)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut buf = header.to_vec();
+                buf.extend_from_slice(chunk);
+                let more = (i + 1) * size < packet.len() - 20;
+                let mut ip = MutableIpv4Packet::new(&mut buf).unwrap();
+                ip.set_total_length((20 + chunk.len()) as u16);
+                ip.set_flags(if more { Ipv4Flags::MoreFragments } else { 0 });
+                ip.set_fragment_offset((i * size / 8) as u16);
+                buf
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::fragment;
+    use super::*;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+
+    fn datagram(id: u16, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 20 + payload.len()];
+        let mut ip = MutableIpv4Packet::new(&mut buf).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((20 + payload.len()) as u16);
+        ip.set_identification(id);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip.set_source([10, 0, 0, 1].into());
+        ip.set_destination([10, 0, 0, 2].into());
+        ip.set_payload(payload);
+        buf
+    }
+
+    fn push(r: &mut Reassembler, fragment: &[u8], now: Instant) -> Option<Vec<u8>> {
+        r.push(&Ipv4Packet::new(fragment).unwrap(), now)
+    }
+
+    #[test]
+    fn test_reassemble_out_of_order() {
+        let payload: Vec<u8> = (0..100).collect();
+        let original = datagram(7, &payload);
+        let fragments = fragment(&original, 32);
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments
+            .iter()
+            .all(|f| is_fragment(&Ipv4Packet::new(f).unwrap())));
+
+        let mut r = Reassembler::default();
+        let now = Instant::now();
+        for f in fragments.iter().rev().take(3) {
+            assert_eq!(push(&mut r, f, now), None);
+        }
+        let datagram = push(&mut r, &fragments[0], now).unwrap();
+        assert_eq!(datagram, original);
+        assert!(!is_fragment(&Ipv4Packet::new(&datagram).unwrap()));
+        assert!(r.pending.is_empty());
+    }
+
+    #[test]
+    fn test_overlap_discards_datagram() {
+        let payload = [1u8; 48];
+        let fragments = fragment(&datagram(1, &payload), 24);
+        let mut r = Reassembler::default();
+        let now = Instant::now();
+        assert_eq!(push(&mut r, &fragments[0], now), None);
+        // The same bytes again, e.g. rewritten by an attacker
+        assert_eq!(push(&mut r, &fragments[0], now), None);
+        assert!(r.pending.is_empty());
+        assert_eq!(push(&mut r, &fragments[1], now), None);
+    }
+
+    #[test]
+    fn test_timeout_and_bound() {
+        let fragments = fragment(&datagram(1, &[0u8; 16]), 8);
+        let mut r = Reassembler::new(Duration::from_secs(1), 1);
+        let now = Instant::now();
+        assert_eq!(push(&mut r, &fragments[0], now), None);
+
+        // A second datagram doesn't fit while the first is pending
+        let other = fragment(&datagram(2, &[0u8; 16]), 8);
+        assert_eq!(push(&mut r, &other[0], now), None);
+        assert!(push(&mut r, &other[1], now).is_none());
+
+        // Once the first expires its late fragment starts over
+        let later = now + Duration::from_secs(2);
+        assert_eq!(push(&mut r, &fragments[1], later), None);
+        assert_eq!(r.pending.len(), 1);
+    }
+}
@@ -0,0 +1,23 @@
+pub mod anonymize;
+pub mod blocking;
+pub mod chunked;
+pub mod config;
+pub mod framed_reader;
+pub mod gzip;
+pub mod http;
+pub mod http_client;
+pub mod ip_fragment;
+pub mod json;
+pub mod live_packet_reader;
+pub mod logging;
+pub mod openmetrics;
+pub mod pcap;
+pub mod plugin;
+pub mod post_processor;
+pub mod pushgateway;
+pub mod queue_tuner;
+pub mod ring_packet_reader;
+pub mod tls;
+pub mod tun;
+pub mod tun_device;
+pub mod unix_proxy;
@@ -1,34 +1,155 @@
 use anyhow::Result;
-use pnet::datalink::{self, Channel::Ethernet};
+use pnet::datalink::{self, Channel::Ethernet, DataLinkReceiver};
+use std::io;
+use std::thread;
+use std::time::Duration;
+use tracing::{error, warn};
 
-use crate::tun::PacketReader;
+use crate::tun::{PacketReader, ReadResult};
+
+type Opener<'a> = Box<dyn FnMut() -> Result<Box<dyn DataLinkReceiver + 'a>> + Send + 'a>;
+
+#[derive(Debug, Clone)]
+pub struct RetryConfig {
+    /// How long to wait before reading again after a transient error.
+    pub transient_backoff: Duration,
+    /// Backoff before the first attempt to reopen a failed interface, doubled
+    /// on every further attempt up to `max_reopen_backoff`.
+    pub reopen_backoff: Duration,
+    pub max_reopen_backoff: Duration,
+    /// Give up and end the stream after this many failed reopen attempts.
+    pub max_reopen_attempts: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            transient_backoff: Duration::from_millis(10),
+            reopen_backoff: Duration::from_millis(100),
+            max_reopen_backoff: Duration::from_secs(10),
+            max_reopen_attempts: 10,
+        }
+    }
+}
 
 pub struct LivePacketReader<'a> {
-    rx: Box<dyn pnet::datalink::DataLinkReceiver + 'a>,
+    rx: Box<dyn DataLinkReceiver + 'a>,
+    open: Opener<'a>,
+    cfg: RetryConfig,
 }
 
 impl<'a> LivePacketReader<'a> {
     pub fn new(interface_name: &str) -> Result<Self> {
-        let interfaces = datalink::interfaces();
-        let interface = interfaces
-            .into_iter()
-            .find(|iface| iface.name == interface_name)
-            .ok_or_else(|| anyhow::anyhow!("Device not found"))?;
+        let name = interface_name.to_string();
+        let mut open: Opener<'a> = Box::new(move || open_channel(&name));
+        let rx = open()?;
+        Ok(Self {
+            rx,
+            open,
+            cfg: RetryConfig::default(),
+        })
+    }
+
+    /// Reopen the channel with exponential backoff. Returns false once the
+    /// attempts are exhausted.
+    fn reopen(&mut self) -> bool {
+        let mut backoff = self.cfg.reopen_backoff;
+        for attempt in 1..=self.cfg.max_reopen_attempts {
+            thread::sleep(backoff);
+            match (self.open)() {
+                Ok(rx) => {
+                    self.rx = rx;
+                    return true;
+                }
+                Err(e) => warn!(attempt, "Failed to reopen capture interface: {}", e),
+            }
+            backoff = (backoff * 2).min(self.cfg.max_reopen_backoff);
+        }
+        false
+    }
+}
+
+fn open_channel<'a>(interface_name: &str) -> Result<Box<dyn DataLinkReceiver + 'a>> {
+    let interfaces = datalink::interfaces();
+    let interface = interfaces
+        .into_iter()
+        .find(|iface| iface.name == interface_name)
+        .ok_or_else(|| anyhow::anyhow!("Device not found"))?;
+
+    match datalink::channel(&interface, Default::default())? {
+        Ethernet(_, rx) => Ok(rx),
+        _ => Err(anyhow::anyhow!("Unhandled channel type")),
+    }
+}
 
-        let (_, rx) = match datalink::channel(&interface, Default::default())? {
-            Ethernet(_, rx) => ((), rx),
-            _ => return Err(anyhow::anyhow!("Unhandled channel type")),
-        };
+/// Describe each interface on the host with its addresses, one per line.
+pub fn list_interfaces() -> Vec<String> {
+    datalink::interfaces()
+        .into_iter()
+        .map(|iface| {
+            let ips: Vec<String> = iface.ips.iter().map(|ip| ip.to_string()).collect();
+            format!("{}\t{}", iface.name, ips.join(", "))
+        })
+        .collect()
+}
 
-        Ok(Self { rx })
+/// Check that the interface can be opened for capture, explaining the likely
+/// cause when it can't.
+pub fn check(interface_name: &str) -> Result<()> {
+    if !datalink::interfaces()
+        .iter()
+        .any(|iface| iface.name == interface_name)
+    {
+        return Err(anyhow::anyhow!(
+            "Interface {} not found, see --list-interfaces",
+            interface_name
+        ));
     }
+    match open_channel(interface_name) {
+        Ok(_) => Ok(()),
+        Err(e) => match e.downcast_ref::<io::Error>().map(|e| e.kind()) {
+            Some(io::ErrorKind::PermissionDenied) => Err(anyhow::anyhow!(
+                "Permission denied opening {}: run as root or grant CAP_NET_RAW",
+                interface_name
+            )),
+            _ => Err(e.context(format!("Failed to open {}", interface_name))),
+        },
+    }
+}
+
+/// Errors after which the channel is still usable.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
 }
 
 impl<'a> PacketReader for LivePacketReader<'a> {
-    fn read_packet(&mut self) -> Option<Vec<u8>> {
-        match self.rx.next() {
-            Ok(packet) => Some(packet.to_vec()),
-            Err(_) => None,
+    fn read_packet(&mut self) -> ReadResult {
+        self.read_packet_into(Vec::new())
+    }
+
+    fn read_packet_into(&mut self, mut buf: Vec<u8>) -> ReadResult {
+        loop {
+            match self.rx.next() {
+                Ok(packet) => {
+                    buf.clear();
+                    buf.extend_from_slice(packet);
+                    return ReadResult::Packet(buf);
+                }
+                Err(e) if is_transient(&e) => {
+                    thread::sleep(self.cfg.transient_backoff);
+                    return ReadResult::WouldBlock;
+                }
+                Err(e) => {
+                    warn!("Capture interface failed, reopening: {}", e);
+                    if !self.reopen() {
+                        error!("Giving up on capture interface: {}", e);
+                        return ReadResult::Eof;
+                    }
+                }
+            }
         }
     }
 }
@@ -36,45 +157,120 @@ impl<'a> PacketReader for LivePacketReader<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io;
+    use std::collections::VecDeque;
 
-    // Mock the pnet::datalink::DataLinkReceiver trait
+    // Mock the pnet::datalink::DataLinkReceiver trait, replaying a script of
+    // reads and failing fatally once it runs out.
     struct MockDataLinkReceiver {
-        packets: Vec<Vec<u8>>,
+        script: VecDeque<io::Result<Vec<u8>>>,
         current_packet: Option<Vec<u8>>,
     }
 
+    impl MockDataLinkReceiver {
+        fn boxed(script: Vec<io::Result<Vec<u8>>>) -> Box<dyn DataLinkReceiver> {
+            Box::new(MockDataLinkReceiver {
+                script: script.into(),
+                current_packet: None,
+            })
+        }
+    }
+
     impl pnet::datalink::DataLinkReceiver for MockDataLinkReceiver {
         fn next(&mut self) -> io::Result<&[u8]> {
-            if let Some(packet) = self.packets.pop() {
-                self.current_packet = Some(packet);
-                Ok(self.current_packet.as_deref().unwrap())
-            } else {
-                Err(io::Error::new(io::ErrorKind::WouldBlock, "No more packets"))
+            match self.script.pop_front() {
+                Some(Ok(packet)) => {
+                    self.current_packet = Some(packet);
+                    Ok(self.current_packet.as_deref().unwrap())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(io::Error::new(io::ErrorKind::NotFound, "Interface gone")),
             }
         }
     }
 
+    fn reader<'a>(rx: Box<dyn DataLinkReceiver>, open: Opener<'a>) -> LivePacketReader<'a> {
+        LivePacketReader {
+            rx,
+            open,
+            cfg: RetryConfig {
+                transient_backoff: Duration::from_millis(1),
+                reopen_backoff: Duration::from_millis(1),
+                max_reopen_backoff: Duration::from_millis(4),
+                max_reopen_attempts: 3,
+            },
+        }
+    }
+
+    fn never_reopens<'a>() -> Opener<'a> {
+        Box::new(|| Err(anyhow::anyhow!("Device not found")))
+    }
+
+    #[test]
+    fn test_list_interfaces() {
+        let interfaces = list_interfaces();
+        assert!(!interfaces.is_empty());
+        assert!(interfaces.iter().any(|line| line.contains("127.0.0.1")));
+    }
+
+    #[test]
+    fn test_check_unknown_interface() {
+        let err = check("aragorn-does-not-exist").unwrap_err();
+        assert!(err.to_string().contains("not found"));
+    }
+
     #[test]
     fn test_read_packet() {
-        // Set up the mock data link receiver
-        let mock_packets = vec![
-            vec![0x01, 0x02, 0x03],
-            vec![0x04, 0x05, 0x06],
-            vec![0x07, 0x08, 0x09],
-        ];
-        let mock_receiver = MockDataLinkReceiver {
-            packets: mock_packets,
-            current_packet: None,
-        };
-
-        let mut packet_reader = LivePacketReader {
-            rx: Box::new(mock_receiver),
-        };
-
-        assert_eq!(packet_reader.read_packet(), Some(vec![0x07, 0x08, 0x09]));
-        assert_eq!(packet_reader.read_packet(), Some(vec![0x04, 0x05, 0x06]));
-        assert_eq!(packet_reader.read_packet(), Some(vec![0x01, 0x02, 0x03]));
-        assert_eq!(packet_reader.read_packet(), None);
+        let rx = MockDataLinkReceiver::boxed(vec![
+            Ok(vec![0x07, 0x08, 0x09]),
+            Ok(vec![0x04, 0x05, 0x06]),
+            Ok(vec![0x01, 0x02, 0x03]),
+        ]);
+        let mut packet_reader = reader(rx, never_reopens());
+
+        assert_eq!(
+            packet_reader.read_packet(),
+            ReadResult::Packet(vec![0x07, 0x08, 0x09])
+        );
+        assert_eq!(
+            packet_reader.read_packet(),
+            ReadResult::Packet(vec![0x04, 0x05, 0x06])
+        );
+        assert_eq!(
+            packet_reader.read_packet(),
+            ReadResult::Packet(vec![0x01, 0x02, 0x03])
+        );
+        assert_eq!(packet_reader.read_packet(), ReadResult::Eof);
+    }
+
+    #[test]
+    fn test_transient_error_would_block() {
+        let rx = MockDataLinkReceiver::boxed(vec![
+            Err(io::Error::new(io::ErrorKind::WouldBlock, "Try again")),
+            Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out")),
+            Ok(vec![0x01]),
+        ]);
+        let mut packet_reader = reader(rx, never_reopens());
+        assert_eq!(packet_reader.read_packet(), ReadResult::WouldBlock);
+        assert_eq!(packet_reader.read_packet(), ReadResult::WouldBlock);
+        assert_eq!(packet_reader.read_packet(), ReadResult::Packet(vec![0x01]));
+    }
+
+    #[test]
+    fn test_fatal_error_reopens() {
+        let mut attempts = 0;
+        let open: Opener = Box::new(move || {
+            // The interface comes back on the second attempt, then disappears for good
+            attempts += 1;
+            if attempts != 2 {
+                return Err(anyhow::anyhow!("Device not found"));
+            }
+            Ok(MockDataLinkReceiver::boxed(vec![Ok(vec![0x02])]))
+        });
+        let rx = MockDataLinkReceiver::boxed(vec![Ok(vec![0x01])]);
+        let mut packet_reader = reader(rx, open);
+
+        assert_eq!(packet_reader.read_packet(), ReadResult::Packet(vec![0x01]));
+        assert_eq!(packet_reader.read_packet(), ReadResult::Packet(vec![0x02]));
+        assert_eq!(packet_reader.read_packet(), ReadResult::Eof);
     }
 }
@@ -0,0 +1,299 @@
+use crate::json;
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::{self, FormatEvent, FormatFields};
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, MakeWriter};
+use tracing_subscriber::registry::LookupSpan;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Build the subscriber for the given level and format without installing it.
+pub fn subscriber<W>(
+    level: Level,
+    format: LogFormat,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(writer);
+    match format {
+        LogFormat::Text => Box::new(builder.finish()),
+        LogFormat::Json => Box::new(builder.event_format(JsonFormatter).finish()),
+    }
+}
+
+/// Install the global subscriber writing to stdout.
+pub fn init(level: Level, format: LogFormat) -> anyhow::Result<()> {
+    tracing::subscriber::set_global_default(subscriber(level, format, std::io::stdout))?;
+    Ok(())
+}
+
+/// Formats each event as a single line JSON object.
+/// Mirrors the layout of tracing-subscriber's own json formatter.
+struct JsonFormatter;
+
+impl<S, N> FormatEvent<S, N> for JsonFormatter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: format::Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut format::Writer::new(&mut timestamp))?;
+
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+
+        let meta = event.metadata();
+        write!(writer, "{{\"timestamp\":")?;
+        json::write_str(&mut writer, &timestamp)?;
+        write!(writer, ",\"level\":\"{}\",\"target\":", meta.level())?;
+        json::write_str(&mut writer, meta.target())?;
+        write!(writer, ",\"fields\":{{{}}}}}", visitor.fields.join(","))?;
+        writeln!(writer)
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor {
+    fields: Vec<String>,
+}
+
+impl JsonVisitor {
+    fn push(&mut self, field: &Field, value: String) {
+        let mut entry = json::quote(field.name());
+        entry.push(':');
+        entry.push_str(&value);
+        self.fields.push(entry);
+    }
+}
+
+impl Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, json::quote(&format!("{:?}", value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, json::quote(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if value.is_finite() {
+            self.push(field, value.to_string());
+        } else {
+            self.push(field, "null".to_string());
+        }
+    }
+}
+
+/// Format `data` like `hexdump -C`: offset, 16 bytes in hex and their printable ASCII.
+/// At most `limit` bytes are dumped; a final line notes how many were left out.
+pub fn hex_dump(data: &[u8], limit: usize) -> String {
+    let mut out = String::new();
+    for (i, line) in data[..data.len().min(limit)].chunks(16).enumerate() {
+        let mut hex = String::with_capacity(49);
+        for j in 0..16 {
+            if j == 8 {
+                hex.push(' ');
+            }
+            match line.get(j) {
+                Some(b) => hex.push_str(&format!("{:02x} ", b)),
+                None => hex.push_str("   "),
+            }
+        }
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!("{:08x}  {} |{}|\n", i * 16, hex, ascii));
+    }
+    if data.len() > limit {
+        out.push_str(&format!("... {} more bytes\n", data.len() - limit));
+    }
+    out
+}
+
+/// Most distinct kinds tracked before the oldest are forgotten.
+const MAX_TRACKED_KINDS: usize = 1024;
+
+/// Deduplicates repeated log events: each kind is let through at most once per
+/// interval, and the next event let through reports how many were held back.
+pub struct RateLimiter {
+    interval: Duration,
+    /// When each kind was last let through, and how many were suppressed since.
+    kinds: HashMap<String, (Instant, u64)>,
+}
+
+impl RateLimiter {
+    pub fn new(interval: Duration) -> Self {
+        RateLimiter {
+            interval,
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Whether an event of `kind` should be logged now. If so, returns the number of
+    /// events of that kind suppressed since it was last logged.
+    pub fn check(&mut self, kind: &str, now: Instant) -> Option<u64> {
+        if let Some((last, suppressed)) = self.kinds.get_mut(kind) {
+            if now.saturating_duration_since(*last) < self.interval {
+                *suppressed += 1;
+                return None;
+            }
+            let count = *suppressed;
+            *last = now;
+            *suppressed = 0;
+            return Some(count);
+        }
+        if self.kinds.len() >= MAX_TRACKED_KINDS {
+            let interval = self.interval;
+            self.kinds
+                .retain(|_, (last, _)| now.saturating_duration_since(*last) < interval);
+            if self.kinds.len() >= MAX_TRACKED_KINDS {
+                self.kinds.clear();
+            }
+        }
+        self.kinds.insert(kind.to_string(), (now, 0));
+        Some(0)
+    }
+}
+
+#[cfg(test)]
+pub mod testing {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::MakeWriter;
+
+    /// In-memory writer for asserting on emitted log lines.
+    #[derive(Clone, Default)]
+    pub struct BufWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl BufWriter {
+        pub fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    impl io::Write for BufWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for BufWriter {
+        type Writer = BufWriter;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::BufWriter;
+    use super::*;
+
+    fn capture(level: Level, format: LogFormat) -> String {
+        let buf = BufWriter::default();
+        let sub = subscriber(level, format, buf.clone());
+        tracing::subscriber::with_default(sub, || {
+            tracing::debug!("hidden");
+            tracing::info!(key = "abc", latency_ms = 3u64, "request \"done\"");
+        });
+        buf.contents()
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let data = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        assert_eq!(
+            hex_dump(data, 64),
+            "00000000  2a 32 0d 0a 24 33 0d 0a  47 45 54 0d 0a 24 31 0d  |*2..$3..GET..$1.|\n\
+             00000010  0a 6b 0d 0a                                       |.k..|\n"
+        );
+        assert_eq!(
+            hex_dump(data, 4),
+            "00000000  2a 32 0d 0a                                       |*2..|\n\
+             ... 16 more bytes\n"
+        );
+        assert_eq!(hex_dump(b"", 16), "");
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(limiter.check("parse", now), Some(0));
+        for i in 1..=5 {
+            assert_eq!(limiter.check("parse", now + Duration::from_secs(i)), None);
+        }
+        // Other kinds are limited separately
+        assert_eq!(limiter.check("io", now), Some(0));
+        assert_eq!(
+            limiter.check("parse", now + Duration::from_secs(10)),
+            Some(5)
+        );
+        assert_eq!(limiter.check("parse", now + Duration::from_secs(11)), None);
+    }
+
+    #[test]
+    fn test_text_subscriber() {
+        let out = capture(Level::INFO, LogFormat::Text);
+        assert!(out.contains("request \"done\""));
+        assert!(!out.contains("hidden"));
+    }
+
+    #[test]
+    fn test_json_subscriber() {
+        let out = capture(Level::INFO, LogFormat::Json);
+        let line = out.lines().next().unwrap();
+        assert_eq!(out.lines().count(), 1);
+        assert!(line.starts_with("{\"timestamp\":\""));
+        assert!(line.contains("\"level\":\"INFO\""));
+        assert!(line.contains(
+            "\"fields\":{\"message\":\"request \\\"done\\\"\",\"key\":\"abc\",\"latency_ms\":3}"
+        ));
+        assert!(line.ends_with('}'));
+    }
+}
@@ -1,56 +1,733 @@
-mod live_packet_reader;
-mod plugin;
-mod post_processor;
-mod tun;
-
+use anonymize::{AnonymizeMode, Anonymizer};
 use anyhow::Result;
-use clap::Parser;
+use aragorn::{
+    anonymize, config, framed_reader, http, json, live_packet_reader, logging, pcap, plugin,
+    post_processor, pushgateway, ring_packet_reader, tls, tun, tun_device, unix_proxy,
+};
+use clap::{Parser, Subcommand, ValueEnum};
+use framed_reader::{FramedReader, LengthPrefix};
 use live_packet_reader::LivePacketReader;
-use plugin::redis::handler::RespHandler;
-use post_processor::prometheus::PrometheusPostProcessor;
-use prometheus::{gather, Encoder, TextEncoder};
+use logging::LogFormat;
+use pcap::{PacketRecorder, PcapReader};
+use plugin::auto::AutoHandler;
+use plugin::cql::{CqlHandler, CqlResult};
+use plugin::grpc::{GrpcHandler, GrpcResult};
+use plugin::redis::handler::{RedactConfig, RedisResult, RespConfig, RespHandler};
+use plugin::redis::resp_parser::{parse_resp, ParserConfig};
+use plugin::replication::ReplicationHandler;
+use plugin::websocket::{WsHandler, WsResult};
+use plugin::Plugin;
+use post_processor::audit::{AuditConfig, AuditPostProcessor};
+use post_processor::ewma::{EwmaConfig, EwmaPostProcessor};
+use post_processor::grpc_stream::{self, GrpcStreamConfig, GrpcStreamPostProcessor};
+use post_processor::jsonl::JsonlPostProcessor;
+#[cfg(feature = "kafka")]
+use post_processor::kafka::{KafkaConfig, KafkaPostProcessor};
+use post_processor::otlp::{OtlpConfig, OtlpPostProcessor};
+use post_processor::prometheus::{LatencyBuckets, PrometheusConfig, PrometheusPostProcessor};
+use post_processor::recent::RecentRequestsPostProcessor;
+use post_processor::statsd::{StatsdConfig, StatsdPostProcessor};
+use post_processor::summary::{SummaryConfig, SummaryPostProcessor};
+use post_processor::webhook::{WebhookConfig, WebhookPostProcessor};
+use post_processor::{PostProcessor, ProcessedResult, ReplicationResult};
+use pushgateway::{PushConfig, Pusher};
+use ring_packet_reader::{RingConfig, RingPacketReader};
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use std::{io, net::SocketAddr};
-use tokio::io::AsyncWriteExt;
+use tls::{DecryptingPacketReader, KeyLog};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tokio::sync::Mutex;
-use tracing::{error, info, Level};
-use tun::Observer;
+use tracing::{error, info, warn, Level};
+use tun::{BoxedPacketReader, DirectionFilter, Observer, Settings};
+use tun_device::TunDeviceReader;
+use unix_proxy::UnixSocketProxy;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Protocol {
+    Redis,
+    Grpc,
+    Websocket,
+    Cql,
+    /// Writes a Redis master replicates, seen on --redis-port
+    Replication,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
-    /// The name of the TUN/TAP interface
-    #[arg(short, long, default_value = "lo0")]
-    interface: String,
+struct Cli {
+    /// Maximum level of log events to emit
+    #[arg(long, default_value = "info", global = true)]
+    log_level: Level,
+
+    /// Format of emitted log events
+    #[arg(long, value_enum, default_value = "text", global = true)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Observe live traffic on an interface
+    Capture(CaptureArgs),
+    /// Observe the traffic recorded in a pcap file
+    Replay(ReplayArgs),
+    /// Parse a dump of RESP bytes and print the values, without capturing
+    Parse(ParseArgs),
+}
+
+/// Flags shared by the subcommands that run the observer.
+#[derive(clap::Args, Debug)]
+struct ObserveArgs {
+    /// The protocol to observe. When omitted it is detected per connection
+    #[arg(long, value_enum)]
+    protocol: Option<Protocol>,
+
+    /// The port to observe when the protocol is detected. Defaults to --redis-port
+    #[arg(long, conflicts_with = "protocol")]
+    port: Option<u16>,
 
     /// The port to listen for redis handler
     #[arg(short, long, default_value = "6379")]
     redis_port: u16,
+
+    /// The port to listen for grpc handler
+    #[arg(long, default_value = "50051")]
+    grpc_port: u16,
+
+    /// The port to listen for websocket handler
+    #[arg(long, default_value = "8080")]
+    websocket_port: u16,
+
+    /// The port to listen for cql handler
+    #[arg(long, default_value = "9042")]
+    cql_port: u16,
+
+    /// Where results are sent: prometheus, jsonl:<path>, statsd:<host:port>,
+    /// otlp:<url> or kafka:<brokers>/<topic>. Repeatable. Defaults to prometheus
+    #[arg(long = "output", value_parser = parse_output)]
+    outputs: Vec<Output>,
+
+    /// Record the size of each redis response as a metric
+    #[arg(long)]
+    record_response_size: bool,
+
+    /// Mask keys matching this glob pattern (and their values) in results. Repeatable
+    #[arg(long = "redact-key")]
+    redact_keys: Vec<String>,
+
+    /// Number of captured packets that may wait for processing before new ones are dropped
+    #[arg(long, default_value = "4096")]
+    queue_depth: usize,
+
+    /// Let the queue grow up to this many packets while packets are being dropped. It
+    /// shrinks back to --queue-depth once traffic calms down
+    #[arg(long)]
+    max_queue_depth: Option<usize>,
+
+    /// Number of connections whose packets are handled concurrently. Defaults to the
+    /// number of CPUs
+    #[arg(long)]
+    workers: Option<usize>,
+
+    /// Log a hex dump of Redis payloads that fail to parse, up to this many bytes (256
+    /// when given without a value). Requires --log-level debug
+    #[arg(long, num_args = 0..=1, default_missing_value = "256")]
+    dump_unparsed: Option<usize>,
+
+    /// Parse only the first this many bytes of each payload. Large values are skipped
+    /// while the command and key are still read
+    #[arg(long)]
+    max_parse_bytes: Option<usize>,
+
+    /// Stop capturing after this many seconds and print a summary of the results: the
+    /// busiest keys with their error rate and p50/p99 latency
+    #[arg(long)]
+    duration: Option<u64>,
+
+    /// Observe only the requests clients send, or only the responses of the server.
+    /// Latency needs both, so one direction only yields results that don't pair, like
+    /// pub/sub messages
+    #[arg(long, value_enum, default_value = "both")]
+    direction: DirectionFilter,
+
+    /// Fraction of client IPs to observe, from 0.0 to 1.0. Connections are sampled whole
+    #[arg(long, default_value = "1.0", value_parser = parse_fraction)]
+    sample_rate: f64,
+
+    /// Bucket latencies exponentially like a native histogram of this schema (-4 to 8),
+    /// 2^(2^-schema) apart, instead of the fixed default buckets
+    #[arg(long, allow_hyphen_values = true)]
+    latency_schema: Option<i32>,
+
+    /// Decrypt TLS 1.2 traffic with the secrets in this key log, as written by clients
+    /// run with SSLKEYLOGFILE. Defaults to $SSLKEYLOGFILE when it is set
+    #[arg(long)]
+    tls_keylog: Option<PathBuf>,
+
+    /// Also push metrics to the Prometheus Pushgateway at this URL, e.g. http://localhost:9091
+    #[arg(long)]
+    pushgateway: Option<String>,
+
+    /// Seconds between pushes to the Pushgateway
+    #[arg(long, default_value = "15", requires = "pushgateway")]
+    push_interval: u64,
+
+    /// Job name metrics are pushed to the Pushgateway under
+    #[arg(long, default_value = "aragorn", requires = "pushgateway")]
+    push_job: String,
+
+    /// Number of recent results served on /recent
+    #[arg(long, default_value = "100")]
+    recent_requests: usize,
+
+    /// Weight of each new latency in the per-label averages served on /ewma, above 0
+    /// and up to 1. Higher values follow changes faster
+    #[arg(long, default_value = "0.1", value_parser = parse_fraction)]
+    ewma_alpha: f64,
+
+    /// Number of labels averaged on /ewma. The least recently seen are dropped beyond this
+    #[arg(long, default_value = "1000")]
+    ewma_keys: usize,
+
+    /// Append an audit record of every Redis request to this file
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Rotate the audit log once it reaches this many megabytes
+    #[arg(long, default_value = "100")]
+    audit_max_size: u64,
+
+    /// Include the client IP in audit records
+    #[arg(long)]
+    audit_client_addr: bool,
+
+    /// Anonymize client IPs before they reach any output: `truncate` keeps only the
+    /// /24 (IPv4) or /48 (IPv6), `hash` replaces them with a keyed hash
+    #[arg(long, value_enum)]
+    anonymize_ips: Option<AnonymizeMode>,
+
+    /// File holding the secret key for --anonymize-ips hash
+    #[arg(long, required_if_eq("anonymize_ips", "hash"))]
+    anonymize_key_file: Option<PathBuf>,
+
+    /// Stream every result to gRPC subscribers on this address, e.g. 0.0.0.0:9091.
+    /// See proto/export.proto
+    #[arg(long)]
+    grpc_export: Option<SocketAddr>,
+
+    /// Post a JSON alert to this URL when the p99 latency of a key goes above
+    /// --alert-p99, e.g. http://localhost:8080/alerts
+    #[arg(long)]
+    alert_webhook: Option<String>,
+
+    /// Milliseconds the p99 latency of a key may reach before it alerts
+    #[arg(long, default_value = "100", requires = "alert_webhook")]
+    alert_p99: u64,
+
+    /// Seconds of requests the p99 is taken over
+    #[arg(long, default_value = "60", requires = "alert_webhook")]
+    alert_window: u64,
+
+    /// Seconds a key stays quiet after alerting
+    #[arg(long, default_value = "300", requires = "alert_webhook")]
+    alert_cooldown: u64,
+
+    /// Read the sample rate and TTLs from this file, overriding the flags, and read it
+    /// again on SIGHUP to change them without restarting
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Check that the packet source can be opened, then exit
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CaptureArgs {
+    /// The name of the TUN/TAP interface
+    #[arg(short, long, default_value = "lo0")]
+    interface: String,
+
+    /// Read the interface through a memory mapped TPACKET_V3 ring, which keeps up with
+    /// more traffic. Linux only; elsewhere the default reader is used
+    #[arg(long)]
+    ring_buffer: bool,
+
+    /// Create a TUN interface with this name and capture the traffic routed through it
+    #[arg(long, conflicts_with = "unix_listen")]
+    create_tun: Option<String>,
+
+    /// Read length-prefixed raw frames from this file or FIFO (`-` for stdin) instead
+    /// of an interface
+    #[arg(long, conflicts_with_all = ["create_tun", "unix_listen"])]
+    frames: Option<PathBuf>,
+
+    /// Width in bytes of the big-endian length preceding each frame read with --frames
+    #[arg(
+        long,
+        default_value = "4",
+        requires = "frames",
+        value_parser = clap::builder::PossibleValuesParser::new(["2", "4"])
+    )]
+    frame_prefix: String,
+
+    /// Observe a unix socket server by proxying clients connecting to this path
+    #[arg(long, requires = "unix_upstream")]
+    unix_listen: Option<PathBuf>,
+
+    /// The unix socket path of the server that proxied connections are forwarded to
+    #[arg(long, requires = "unix_listen")]
+    unix_upstream: Option<PathBuf>,
+
+    /// Record captured packets to a pcap file at this path
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Print the available interfaces and their addresses, then exit
+    #[arg(long)]
+    list_interfaces: bool,
+
+    #[command(flatten)]
+    observe: ObserveArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReplayArgs {
+    /// The pcap file to replay
+    pcap: PathBuf,
+
+    /// Replay pacing relative to the recorded timestamps (1.0 = real time, 0 = as fast as possible)
+    #[arg(long, default_value = "0")]
+    replay_speed: f64,
+
+    #[command(flatten)]
+    observe: ObserveArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+#[derive(clap::Args, Debug)]
+struct ParseArgs {
+    /// The file holding the raw RESP bytes, or `-` to read them from stdin
+    #[arg(default_value = "-")]
+    file: PathBuf,
+
+    /// Format of the printed values
+    #[arg(long, value_enum, default_value = "text")]
+    format: OutputFormat,
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(v),
+        _ => Err(format!("{} is not a number between 0.0 and 1.0", s)),
+    }
+}
+
+/// A sink results are sent to, given with --output.
+#[derive(Debug, Clone, PartialEq)]
+enum Output {
+    /// Metrics served on /metrics and pushed to the Pushgateway.
+    Prometheus,
+    /// A JSON line per result appended to a file.
+    Jsonl(PathBuf),
+    /// StatsD metrics sent over UDP to `host:port`.
+    Statsd(String),
+    /// Log records exported to an OTLP/HTTP collector.
+    Otlp(String),
+    /// A JSON record per result produced to a topic.
+    #[cfg(feature = "kafka")]
+    Kafka { brokers: String, topic: String },
+}
+
+fn parse_output(s: &str) -> Result<Output, String> {
+    match s.split_once(':').unwrap_or((s, "")) {
+        ("prometheus", "") => Ok(Output::Prometheus),
+        ("jsonl", path) if !path.is_empty() => Ok(Output::Jsonl(PathBuf::from(path))),
+        ("statsd", addr) if !addr.is_empty() => Ok(Output::Statsd(addr.to_string())),
+        ("otlp", url) if !url.is_empty() => Ok(Output::Otlp(url.to_string())),
+        ("kafka", target) => match target.rsplit_once('/') {
+            #[cfg(feature = "kafka")]
+            Some((brokers, topic)) if !brokers.is_empty() && !topic.is_empty() => {
+                Ok(Output::Kafka {
+                    brokers: brokers.to_string(),
+                    topic: topic.to_string(),
+                })
+            }
+            #[cfg(not(feature = "kafka"))]
+            Some((brokers, topic)) if !brokers.is_empty() && !topic.is_empty() => {
+                Err("kafka needs aragorn built with the kafka feature".to_string())
+            }
+            _ => Err(format!("{} is not kafka:<brokers>/<topic>", s)),
+        },
+        _ => Err(format!(
+            "{} is not one of prometheus, jsonl:<path>, statsd:<host:port>, otlp:<url> or kafka:<brokers>/<topic>",
+            s
+        )),
+    }
+}
+
+/// Create the post-processor of each output.
+fn build_outputs(
+    outputs: &[Output],
+    prometheus: &PrometheusConfig,
+) -> Result<Vec<Arc<Mutex<dyn PostProcessor>>>> {
+    outputs
+        .iter()
+        .map(|output| -> Result<Arc<Mutex<dyn PostProcessor>>> {
+            Ok(match output {
+                Output::Prometheus => Arc::new(Mutex::new(PrometheusPostProcessor::new(
+                    prometheus.clone(),
+                )?)),
+                Output::Jsonl(path) => Arc::new(Mutex::new(JsonlPostProcessor::new(path)?)),
+                Output::Statsd(addr) => {
+                    Arc::new(Mutex::new(StatsdPostProcessor::new(StatsdConfig {
+                        addr: addr.clone(),
+                        ..Default::default()
+                    })?))
+                }
+                Output::Otlp(url) => Arc::new(Mutex::new(OtlpPostProcessor::new(OtlpConfig {
+                    url: url.clone(),
+                    ..Default::default()
+                })?)),
+                #[cfg(feature = "kafka")]
+                Output::Kafka { brokers, topic } => {
+                    Arc::new(Mutex::new(KafkaPostProcessor::connect(KafkaConfig {
+                        brokers: brokers.clone(),
+                        topic: topic.clone(),
+                        ..Default::default()
+                    })?))
+                }
+            })
+        })
+        .collect()
+}
+
+/// Where the observer reads traffic from.
+#[derive(Debug)]
+enum Source {
+    Live(String),
+    /// An interface read through a TPACKET_V3 ring.
+    Ring(String),
+    Tun(String),
+    Unix {
+        listen: PathBuf,
+        upstream: PathBuf,
+    },
+    Pcap {
+        path: PathBuf,
+        speed: f64,
+    },
+    Framed {
+        path: PathBuf,
+        prefix: LengthPrefix,
+    },
 }
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    tracing_subscriber::fmt()
-        .with_max_level(Level::DEBUG)
-        .init();
-    let args = Args::parse();
-
-    let redis_handler = Arc::new(Mutex::new(RespHandler::new(args.redis_port)));
-    let active_packet_reader =
-        LivePacketReader::new(&args.interface).expect("Failed to create packet reader");
-    let mut observer = Observer::new(tun::ObsConfig {
-        ..Default::default()
-    });
+    let cli = Cli::parse();
+    logging::init(cli.log_level, cli.log_format).expect("Failed to initialize logging");
+
+    match cli.command {
+        Command::Capture(args) => {
+            if args.list_interfaces {
+                for iface in live_packet_reader::list_interfaces() {
+                    println!("{}", iface);
+                }
+                return Ok(());
+            }
+            let prefix = match args.frame_prefix.as_str() {
+                "2" => LengthPrefix::U16,
+                _ => LengthPrefix::U32,
+            };
+            let source = match (
+                args.frames,
+                args.create_tun,
+                args.unix_listen,
+                args.unix_upstream,
+            ) {
+                (Some(path), _, _, _) => Source::Framed { path, prefix },
+                (_, Some(name), _, _) => Source::Tun(name),
+                (_, _, Some(listen), Some(upstream)) => Source::Unix { listen, upstream },
+                _ if args.ring_buffer => Source::Ring(args.interface),
+                _ => Source::Live(args.interface),
+            };
+            observe(&args.observe, source, args.record.as_deref()).await
+        }
+        Command::Replay(args) => {
+            let source = Source::Pcap {
+                path: args.pcap,
+                speed: args.replay_speed,
+            };
+            observe(&args.observe, source, None).await
+        }
+        Command::Parse(args) => {
+            let input = if args.file.as_os_str() == "-" {
+                let mut input = vec![];
+                io::stdin().lock().read_to_end(&mut input)?;
+                input
+            } else {
+                std::fs::read(&args.file)?
+            };
+            parse_dump(&input, args.format, &mut io::stdout().lock())
+        }
+    }
+}
+
+/// Print every RESP value in `input`, then report any bytes that don't form one.
+fn parse_dump(mut input: &[u8], format: OutputFormat, out: &mut impl io::Write) -> io::Result<()> {
+    let cfg = ParserConfig::default();
+    while !input.is_empty() {
+        match parse_resp(input, &cfg) {
+            Ok((rest, value)) => {
+                match format {
+                    OutputFormat::Text => writeln!(out, "{}", value)?,
+                    OutputFormat::Json => writeln!(out, "{}", value.to_json())?,
+                }
+                input = rest;
+            }
+            Err(_) => {
+                let trailing = input.escape_ascii().to_string();
+                match format {
+                    OutputFormat::Text => writeln!(
+                        out,
+                        "{} unparsed trailing bytes: \"{}\"",
+                        input.len(),
+                        trailing
+                    )?,
+                    OutputFormat::Json => writeln!(
+                        out,
+                        "{{\"unparsed\":{},\"bytes\":{}}}",
+                        input.len(),
+                        json::quote(&trailing)
+                    )?,
+                }
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Run the observer on `source` until capture ends, serving metrics meanwhile.
+async fn observe(args: &ObserveArgs, source: Source, record: Option<&Path>) -> io::Result<()> {
+    if args.check {
+        match check(&source) {
+            Ok(()) => {
+                println!("OK");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{:#}", e);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    observer.add_post_processor(Arc::new(Mutex::new(PrometheusPostProcessor::new())));
+    let anonymizer = args
+        .anonymize_ips
+        .map(|mode| {
+            let key = args
+                .anonymize_key_file
+                .as_ref()
+                .map(std::fs::read)
+                .transpose()?;
+            Anonymizer::new(mode, key.as_deref())
+        })
+        .transpose()
+        .expect("Invalid IP anonymization settings");
+    let defaults = tun::ObsConfig::default();
+    let obs_config = tun::ObsConfig {
+        queue_depth: args.queue_depth,
+        max_queue_depth: args.max_queue_depth,
+        workers: args.workers.unwrap_or(defaults.workers),
+        sample_rate: args.sample_rate,
+        max_parse_bytes: args.max_parse_bytes,
+        direction: args.direction,
+        anonymizer,
+        ..defaults
+    };
+    let base_settings = obs_config.settings();
+    let mut observer = Observer::new(obs_config);
+
+    let latency_buckets = match args.latency_schema {
+        Some(schema) => LatencyBuckets::Exponential {
+            schema,
+            min: 1e-4,
+            max: 100.0,
+        },
+        None => LatencyBuckets::Default,
+    };
+    // A client run with SSLKEYLOGFILE set logs its secrets where the variable points
+    let keylog = match args
+        .tls_keylog
+        .clone()
+        .or_else(|| std::env::var_os("SSLKEYLOGFILE").map(PathBuf::from))
+    {
+        Some(path) => {
+            info!("Decrypting TLS with the secrets logged to {:?}", path);
+            Some(KeyLog::open(&path).expect("Failed to read TLS key log"))
+        }
+        None => None,
+    };
+    let prometheus = PrometheusConfig {
+        latency_buckets,
+        server_name_label: keylog.is_some(),
+        tls_labels: keylog.is_some(),
+    };
+    let outputs = match args.outputs.as_slice() {
+        [] => &[Output::Prometheus][..],
+        outputs => outputs,
+    };
+    for output in build_outputs(outputs, &prometheus).expect("Failed to create outputs") {
+        observer.add_post_processor(output);
+    }
+    let recent = RecentRequestsPostProcessor::new(args.recent_requests);
+    observer.add_post_processor(Arc::new(Mutex::new(recent.clone())));
+    let ewma = EwmaPostProcessor::new(EwmaConfig {
+        alpha: args.ewma_alpha,
+        max_keys: args.ewma_keys,
+    })
+    .expect("Invalid EWMA settings");
+    observer.add_post_processor(Arc::new(Mutex::new(ewma.clone())));
+    if let Some(path) = &args.audit_log {
+        let audit = AuditPostProcessor::new(AuditConfig {
+            path: path.clone(),
+            max_bytes: args.audit_max_size * 1024 * 1024,
+            include_client_addr: args.audit_client_addr,
+            ..Default::default()
+        })
+        .expect("Failed to open audit log");
+        observer.add_post_processor(Arc::new(Mutex::new(audit)));
+    }
+    if let Some(addr) = args.grpc_export {
+        let export = GrpcStreamPostProcessor::new(GrpcStreamConfig::default());
+        observer.add_post_processor(Arc::new(Mutex::new(export.clone())));
+        let listener = TcpListener::bind(&addr).await?;
+        info!("gRPC export listening on: {}", addr);
+        tokio::spawn(grpc_stream::serve(listener, export));
+    }
+    if let Some(url) = &args.alert_webhook {
+        let webhook = WebhookPostProcessor::new(WebhookConfig {
+            url: url.clone(),
+            threshold: Duration::from_millis(args.alert_p99),
+            window: Duration::from_secs(args.alert_window),
+            cooldown: Duration::from_secs(args.alert_cooldown),
+            ..Default::default()
+        })
+        .expect("Invalid alert settings");
+        observer.add_post_processor(Arc::new(Mutex::new(webhook)));
+    }
+    let summary = args.duration.map(|_| {
+        let summary = SummaryPostProcessor::new(SummaryConfig::default());
+        observer.add_post_processor(Arc::new(Mutex::new(summary.clone())));
+        summary
+    });
+    if let Some(path) = record {
+        let recorder = PacketRecorder::create(path).expect("Failed to create pcap recorder");
+        observer.set_recorder(recorder);
+    }
+    if let Some(path) = &args.config {
+        let settings = load_config(path, base_settings).expect("Failed to load config");
+        observer.settings().send_replace(settings);
+        tokio::spawn(reload_on_hangup(
+            path.clone(),
+            base_settings,
+            observer.settings(),
+        ));
+    }
     observer.start_cleanup();
+    // Shared with the capture workers from here on
+    let observer = Arc::new(observer);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], 9090));
+    let listener = TcpListener::bind(&addr).await?;
+    info!("Prometheus server listening on: {}", addr);
+    tokio::spawn(http::serve(listener, recent, ewma));
 
-    tokio::spawn(run_prometheus_server());
+    let pusher = match &args.pushgateway {
+        Some(url) => {
+            let pusher = Pusher::new(PushConfig {
+                url: url.clone(),
+                job: args.push_job.clone(),
+                interval: Duration::from_secs(args.push_interval),
+            })
+            .expect("Invalid Pushgateway URL");
+            info!("Pushing metrics to {} every {}s", url, args.push_interval);
+            tokio::spawn(pusher.clone().run());
+            Some(pusher)
+        }
+        None => None,
+    };
 
-    let res = observer
-        .capture_packets(active_packet_reader, redis_handler)
-        .await;
+    let resp_config = RespConfig {
+        record_response_size: args.record_response_size,
+        dump_unparsed: args.dump_unparsed,
+        redact: RedactConfig {
+            key_patterns: args.redact_keys.clone(),
+            ..Default::default()
+        },
+        parser: ParserConfig {
+            allow_truncated: args.max_parse_bytes.is_some(),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let capture = async {
+        match args.protocol {
+            None => {
+                let port = args.port.unwrap_or(args.redis_port);
+                info!("Detecting the protocol of connections on port {}", port);
+                let handler = AutoHandler::new(port, resp_config);
+                capture::<_, ProcessedResult>(&source, &observer, handler, keylog).await
+            }
+            Some(Protocol::Redis) => {
+                let handler = RespHandler::new(args.redis_port, resp_config);
+                capture::<_, RedisResult>(&source, &observer, handler, keylog).await
+            }
+            Some(Protocol::Grpc) => {
+                let handler = GrpcHandler::new(args.grpc_port);
+                capture::<_, GrpcResult>(&source, &observer, handler, keylog).await
+            }
+            Some(Protocol::Websocket) => {
+                let handler = WsHandler::new(args.websocket_port);
+                capture::<_, WsResult>(&source, &observer, handler, keylog).await
+            }
+            Some(Protocol::Cql) => {
+                let handler = CqlHandler::new(args.cql_port);
+                capture::<_, CqlResult>(&source, &observer, handler, keylog).await
+            }
+            Some(Protocol::Replication) => {
+                let handler = ReplicationHandler::new(args.redis_port, resp_config.parser);
+                capture::<_, ReplicationResult>(&source, &observer, handler, keylog).await
+            }
+        }
+    };
+    let stop_after = async {
+        if let Some(secs) = args.duration {
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+            info!("Stopping after {}s", secs);
+            observer.stop();
+        }
+        std::future::pending::<()>().await
+    };
+    // Capture notices the stop and finishes what it was handling before returning
+    let res = tokio::select! {
+        res = capture => res,
+        _ = stop_after => unreachable!(),
+    };
 
     match res {
         Ok(_) => info!("Observer stopped successfully"),
@@ -59,28 +736,359 @@ async fn main() -> io::Result<()> {
 
     observer.stop();
 
+    if let Some(summary) = summary {
+        print!("{}", summary.summary().await);
+    }
+
+    // Capture may end between pushes, so push the final values
+    if let Some(pusher) = pusher {
+        if let Err(e) = pusher.push().await {
+            error!("Failed to push metrics: {:?}", e);
+        }
+    }
+
     Ok(())
 }
 
-async fn run_prometheus_server() -> Result<()> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], 9090));
-    let listener = TcpListener::bind(&addr).await?;
+/// Read the config file over the settings given by flags.
+fn load_config(path: &Path, base: Settings) -> Result<Settings> {
+    let (settings, ignored) = config::load(path, base)?;
+    for key in ignored {
+        warn!(
+            "{} can't change while capturing and is ignored, set it with --{}",
+            key,
+            key.replace('_', "-")
+        );
+    }
+    Ok(settings)
+}
 
-    info!("Prometheus server listening on: {}", addr);
+/// Re-read the config file on every SIGHUP. If it's invalid the current settings stay.
+async fn reload_on_hangup(path: PathBuf, base: Settings, settings: Arc<watch::Sender<Settings>>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!(
+                "Failed to listen for SIGHUP, {:?} won't be reloaded: {}",
+                path, e
+            );
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        match load_config(&path, base) {
+            Ok(new) => {
+                info!("Reloaded {:?}: {:?}", path, new);
+                settings.send_replace(new);
+            }
+            Err(e) => error!(
+                "Failed to reload {:?}, keeping the current settings: {:#}",
+                path, e
+            ),
+        }
+    }
+}
+
+/// Validate the packet source without capturing.
+fn check(source: &Source) -> Result<()> {
+    match source {
+        Source::Unix { upstream, .. } => {
+            std::os::unix::net::UnixStream::connect(upstream)
+                .map_err(|e| anyhow::anyhow!("Failed to connect to {:?}: {}", upstream, e))?;
+            Ok(())
+        }
+        Source::Pcap { path, speed } => PcapReader::open(path, *speed).map(|_| ()),
+        Source::Tun(name) => TunDeviceReader::create(name).map(|_| ()),
+        Source::Framed { path, .. } if path.as_os_str() == "-" => Ok(()),
+        Source::Framed { path, prefix } => FramedReader::open(path, *prefix).map(|_| ()),
+        Source::Live(interface) | Source::Ring(interface) => live_packet_reader::check(interface),
+    }
+}
+
+/// Run the observer with the packet source selected by the arguments.
+async fn capture<H, R>(
+    source: &Source,
+    observer: &Arc<Observer>,
+    handler: H,
+    keylog: Option<KeyLog>,
+) -> Result<()>
+where
+    R: Send + 'static + Into<ProcessedResult>,
+    H: Plugin<R> + 'static,
+{
+    let handler = Arc::new(handler);
+    let reader: BoxedPacketReader = match source {
+        Source::Tun(name) => {
+            let reader = TunDeviceReader::create(name).expect("Failed to create TUN interface");
+            info!("Capturing on TUN interface {}", reader.name());
+            tun::report_interface(reader.name());
+            // The interface goes away when the reader is dropped at the end of capture
+            Box::new(reader)
+        }
+        Source::Unix { listen, upstream } => {
+            let proxy =
+                UnixSocketProxy::bind(listen, upstream).expect("Failed to bind unix socket proxy");
+            return observer.capture_payloads(proxy, handler).await;
+        }
+        Source::Pcap { path, speed } => {
+            Box::new(PcapReader::open(path, *speed).expect("Failed to open pcap file"))
+        }
+        Source::Framed { path, prefix } if path.as_os_str() == "-" => {
+            Box::new(FramedReader::new(io::BufReader::new(io::stdin()), *prefix))
+        }
+        Source::Framed { path, prefix } => {
+            Box::new(FramedReader::open(path, *prefix).expect("Failed to open frame source"))
+        }
+        Source::Live(interface) => {
+            let reader = LivePacketReader::new(interface).expect("Failed to create packet reader");
+            tun::report_interface(interface);
+            Box::new(reader)
+        }
+        Source::Ring(interface) => {
+            tun::report_interface(interface);
+            match RingPacketReader::new(interface, RingConfig::default()) {
+                Ok(reader) => Box::new(reader),
+                Err(e) => {
+                    warn!("Falling back to the default reader: {:?}", e);
+                    Box::new(
+                        LivePacketReader::new(interface).expect("Failed to create packet reader"),
+                    )
+                }
+            }
+        }
+    };
+    // Decrypt TLS first when there is a key log
+    let reader: BoxedPacketReader = match keylog {
+        Some(keylog) => Box::new(DecryptingPacketReader::new(reader, keylog)),
+        None => reader,
+    };
+    observer.capture_packets(reader, handler).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_flags() {
+        let cli = Cli::try_parse_from([
+            "aragorn",
+            "capture",
+            "-i",
+            "eth0",
+            "--record",
+            "out.pcap",
+            "--protocol",
+            "grpc",
+            "--grpc-port",
+            "9000",
+            "--log-level",
+            "debug",
+        ])
+        .unwrap();
+        assert_eq!(cli.log_level, Level::DEBUG);
+        let Command::Capture(args) = cli.command else {
+            panic!("expected capture, got {:?}", cli.command);
+        };
+        assert_eq!(args.interface, "eth0");
+        assert_eq!(args.record, Some(PathBuf::from("out.pcap")));
+        assert_eq!(args.observe.protocol, Some(Protocol::Grpc));
+        assert_eq!(args.observe.grpc_port, 9000);
+        assert_eq!(args.observe.cql_port, 9042);
+        assert_eq!(args.observe.audit_log, None);
+        assert_eq!(args.observe.audit_max_size, 100);
+        assert_eq!(args.observe.latency_schema, None);
+        assert_eq!(args.observe.duration, None);
+        assert_eq!(args.observe.direction, DirectionFilter::Both);
+        assert_eq!(args.observe.alert_webhook, None);
+
+        let cli =
+            Cli::try_parse_from(["aragorn", "capture", "--frames", "-", "--frame-prefix", "2"])
+                .unwrap();
+        let Command::Capture(args) = cli.command else {
+            panic!("expected capture, got {:?}", cli.command);
+        };
+        assert_eq!(args.frames, Some(PathBuf::from("-")));
+        assert_eq!(args.frame_prefix, "2");
+        assert!(Cli::try_parse_from([
+            "aragorn",
+            "capture",
+            "--frames",
+            "-",
+            "--frame-prefix",
+            "3"
+        ])
+        .is_err());
+
+        // A unix proxy needs both ends
+        assert!(Cli::try_parse_from(["aragorn", "capture", "--unix-listen", "a.sock"]).is_err());
+    }
+
+    #[test]
+    fn test_replay_flags() {
+        let cli = Cli::try_parse_from([
+            "aragorn",
+            "replay",
+            "dump.pcap",
+            "--replay-speed",
+            "2",
+            "--redact-key",
+            "session:*",
+        ])
+        .unwrap();
+        let Command::Replay(args) = cli.command else {
+            panic!("expected replay, got {:?}", cli.command);
+        };
+        assert_eq!(args.pcap, PathBuf::from("dump.pcap"));
+        assert_eq!(args.replay_speed, 2.0);
+        assert_eq!(args.observe.redact_keys, vec!["session:*"]);
+        assert_eq!(args.observe.sample_rate, 1.0);
+        assert_eq!(args.observe.pushgateway, None);
+        assert_eq!(args.observe.protocol, None);
+        assert_eq!(args.observe.dump_unparsed, None);
+        assert_eq!(args.observe.max_parse_bytes, None);
 
-    loop {
-        let (mut socket, _) = listener.accept().await?;
-        let encoder = TextEncoder::new();
-        let metric_families = gather();
-        let mut buffer = vec![];
-        encoder.encode(&metric_families, &mut buffer)?;
-
-        let response = format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            buffer.len(),
-            String::from_utf8(buffer).unwrap()
+        let cli = Cli::try_parse_from([
+            "aragorn",
+            "replay",
+            "x.pcap",
+            "--max-parse-bytes",
+            "512",
+            "--dump-unparsed",
+        ])
+        .unwrap();
+        let Command::Replay(args) = cli.command else {
+            panic!("expected replay, got {:?}", cli.command);
+        };
+        assert_eq!(args.observe.dump_unparsed, Some(256));
+        assert_eq!(args.observe.max_parse_bytes, Some(512));
+
+        let cli = Cli::try_parse_from([
+            "aragorn",
+            "replay",
+            "x.pcap",
+            "--latency-schema",
+            "-1",
+            "--duration",
+            "30",
+            "--direction",
+            "response",
+        ])
+        .unwrap();
+        let Command::Replay(args) = cli.command else {
+            panic!("expected replay, got {:?}", cli.command);
+        };
+        assert_eq!(args.observe.latency_schema, Some(-1));
+        assert_eq!(args.observe.duration, Some(30));
+        assert_eq!(args.observe.direction, DirectionFilter::Response);
+        assert!(
+            Cli::try_parse_from(["aragorn", "replay", "dump.pcap", "--push-interval", "5"])
+                .is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["aragorn", "replay", "dump.pcap", "--alert-p99", "5"]).is_err()
+        );
+        assert!(
+            Cli::try_parse_from(["aragorn", "replay", "dump.pcap", "--sample-rate", "1.5"])
+                .is_err()
+        );
+
+        // Recording only makes sense for live capture
+        assert!(Cli::try_parse_from(["aragorn", "replay", "dump.pcap", "--record", "x"]).is_err());
+        assert!(Cli::try_parse_from(["aragorn", "replay"]).is_err());
+    }
+
+    #[test]
+    fn test_output_flags() {
+        let path =
+            std::env::temp_dir().join(format!("aragorn-output-{}.jsonl", std::process::id()));
+        let jsonl = format!("jsonl:{}", path.display());
+        let cli = Cli::try_parse_from([
+            "aragorn",
+            "replay",
+            "x.pcap",
+            "--output",
+            "prometheus",
+            "--output",
+            &jsonl,
+        ])
+        .unwrap();
+        let Command::Replay(args) = cli.command else {
+            panic!("expected replay, got {:?}", cli.command);
+        };
+        assert_eq!(
+            args.observe.outputs,
+            [Output::Prometheus, Output::Jsonl(path.clone())]
+        );
+        let processors = build_outputs(&args.observe.outputs, &PrometheusConfig::default());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(processors.unwrap().len(), 2);
+
+        assert_eq!(
+            parse_output("statsd:localhost:8125"),
+            Ok(Output::Statsd("localhost:8125".to_string()))
         );
+        assert_eq!(
+            parse_output("otlp:http://collector:4318"),
+            Ok(Output::Otlp("http://collector:4318".to_string()))
+        );
+        assert!(parse_output("jsonl").is_err());
+        assert!(parse_output("prometheus:9090").is_err());
+        assert!(parse_output("kafka:topic").is_err());
+        assert!(parse_output("kafka:localhost:9092/").is_err());
+        let kafka = parse_output("kafka:b1:9092,b2:9092/requests");
+        #[cfg(feature = "kafka")]
+        assert_eq!(
+            kafka,
+            Ok(Output::Kafka {
+                brokers: "b1:9092,b2:9092".to_string(),
+                topic: "requests".to_string(),
+            })
+        );
+        #[cfg(not(feature = "kafka"))]
+        assert!(kafka.is_err());
+    }
+
+    #[test]
+    fn test_parse_flags() {
+        let cli = Cli::try_parse_from(["aragorn", "parse", "dump.resp"]).unwrap();
+        let Command::Parse(args) = cli.command else {
+            panic!("expected parse, got {:?}", cli.command);
+        };
+        assert_eq!(args.file, PathBuf::from("dump.resp"));
+        assert_eq!(args.format, OutputFormat::Text);
+
+        let cli = Cli::try_parse_from(["aragorn", "parse", "--format", "json"]).unwrap();
+        let Command::Parse(args) = cli.command else {
+            panic!("expected parse, got {:?}", cli.command);
+        };
+        assert_eq!(args.file, PathBuf::from("-"));
+        assert_eq!(args.format, OutputFormat::Json);
 
-        socket.write_all(response.as_bytes()).await?;
+        assert!(Cli::try_parse_from(["aragorn", "parse", "dump.resp", "-i", "eth0"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_dump() {
+        let mut out = vec![];
+        parse_dump(
+            b"+OK\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n$3\r\nab",
+            OutputFormat::Text,
+            &mut out,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains("command: Some(\"GET\")"));
+        assert_eq!(lines[2], "6 unparsed trailing bytes: \"$3\\r\\nab\"");
+
+        let mut out = vec![];
+        parse_dump(b":1\r\n$3\r\nab", OutputFormat::Json, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(
+            out,
+            "{\"command\":null,\"key\":null,\"field\":null,\"value\":\"1\"}\n{\"unparsed\":6,\"bytes\":\"$3\\\\r\\\\nab\"}\n"
+        );
     }
 }
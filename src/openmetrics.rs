@@ -0,0 +1,306 @@
+//! OpenMetrics text exposition (https://openmetrics.io) with histogram exemplars.
+//!
+//! The prometheus crate only writes the classic text format, which has no exemplars,
+//! so gathered metric families are encoded here instead. Exemplars are kept in a
+//! process wide store next to the default registry and attached to histogram buckets
+//! while encoding.
+
+use lazy_static::lazy_static;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// A sample linked to the histogram bucket it fell in, e.g. the client that made
+/// a slow request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exemplar {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: f64,
+}
+
+lazy_static! {
+    /// The latest exemplar per series and bucket upper bound.
+    static ref EXEMPLARS: Mutex<HashMap<String, HashMap<u64, Exemplar>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Identify a series by its metric name and labels, sorted by name as gathered.
+fn series_key<'a>(name: &str, labels: impl Iterator<Item = (&'a str, &'a str)>) -> String {
+    let mut labels: Vec<_> = labels.collect();
+    labels.sort();
+    let mut key = name.to_string();
+    for (name, value) in labels {
+        let _ = write!(key, ",{}={:?}", name, value);
+    }
+    key
+}
+
+/// Record `value`, just observed in the histogram `name` with `labels` and bucket
+/// boundaries `buckets`, as the exemplar of the bucket it fell in. Each bucket keeps
+/// the most recent one.
+pub fn record_exemplar(
+    name: &str,
+    labels: &[(&str, &str)],
+    buckets: &[f64],
+    value: f64,
+    exemplar_labels: Vec<(String, String)>,
+) {
+    let bound = buckets
+        .iter()
+        .copied()
+        .find(|&b| value <= b)
+        .unwrap_or(f64::INFINITY);
+    let exemplar = Exemplar {
+        labels: exemplar_labels,
+        value,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+    };
+    EXEMPLARS
+        .lock()
+        .unwrap()
+        .entry(series_key(name, labels.iter().copied()))
+        .or_default()
+        .insert(bound.to_bits(), exemplar);
+}
+
+fn write_float(out: &mut String, v: f64) {
+    let _ = if v.is_nan() {
+        write!(out, "NaN")
+    } else if v.is_infinite() {
+        write!(out, "{}Inf", if v > 0.0 { "+" } else { "-" })
+    } else {
+        write!(out, "{}", v)
+    };
+}
+
+fn write_escaped(out: &mut String, s: &str) {
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Write `{name="value",...}` with an optional trailing label, omitting empty braces.
+fn write_labels(out: &mut String, labels: &[LabelPair], extra: Option<(&str, &str)>) {
+    let mut pairs = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(extra)
+        .peekable();
+    if pairs.peek().is_none() {
+        return;
+    }
+    out.push('{');
+    for (i, (name, value)) in pairs.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        out.push_str(name);
+        out.push_str("=\"");
+        write_escaped(out, value);
+        out.push('"');
+    }
+    out.push('}');
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra: Option<(&str, &str)>,
+    value: f64,
+) {
+    out.push_str(name);
+    write_labels(out, labels, extra);
+    out.push(' ');
+    write_float(out, value);
+}
+
+fn write_exemplar(out: &mut String, exemplar: &Exemplar) {
+    out.push_str(" # {");
+    for (i, (name, value)) in exemplar.labels.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(out, "{}=\"", name);
+        write_escaped(out, value);
+        out.push('"');
+    }
+    out.push_str("} ");
+    write_float(out, exemplar.value);
+    let _ = write!(out, " {:.3}", exemplar.timestamp);
+}
+
+/// Encode metric families in the OpenMetrics text format, ending with `# EOF`.
+pub fn encode(families: &[MetricFamily]) -> String {
+    let exemplars = EXEMPLARS.lock().unwrap();
+    let mut out = String::new();
+    for family in families {
+        let kind = family.get_field_type();
+        // Counter samples carry a `_total` suffix the family name doesn't
+        let name = match kind {
+            MetricType::COUNTER => {
+                let name = family.get_name();
+                name.strip_suffix("_total").unwrap_or(name)
+            }
+            _ => family.get_name(),
+        };
+        let type_name = match kind {
+            MetricType::COUNTER => "counter",
+            MetricType::GAUGE => "gauge",
+            MetricType::HISTOGRAM => "histogram",
+            MetricType::SUMMARY => "summary",
+            MetricType::UNTYPED => "unknown",
+        };
+        let _ = writeln!(out, "# TYPE {} {}", name, type_name);
+        if !family.get_help().is_empty() {
+            let _ = write!(out, "# HELP {} ", name);
+            write_escaped(&mut out, family.get_help());
+            out.push('\n');
+        }
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match kind {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", name);
+                    write_sample(
+                        &mut out,
+                        &sample,
+                        labels,
+                        None,
+                        metric.get_counter().get_value(),
+                    );
+                    out.push('\n');
+                }
+                MetricType::GAUGE => {
+                    write_sample(&mut out, name, labels, None, metric.get_gauge().get_value());
+                    out.push('\n');
+                }
+                MetricType::UNTYPED => {
+                    write_sample(
+                        &mut out,
+                        name,
+                        labels,
+                        None,
+                        metric.get_untyped().get_value(),
+                    );
+                    out.push('\n');
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for q in summary.get_quantile() {
+                        let quantile = q.get_quantile().to_string();
+                        let extra = Some(("quantile", quantile.as_str()));
+                        write_sample(&mut out, name, labels, extra, q.get_value());
+                        out.push('\n');
+                    }
+                    let sum = summary.get_sample_sum();
+                    write_sample(&mut out, &format!("{}_sum", name), labels, None, sum);
+                    out.push('\n');
+                    let count = summary.get_sample_count() as f64;
+                    write_sample(&mut out, &format!("{}_count", name), labels, None, count);
+                    out.push('\n');
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let series = exemplars.get(&series_key(
+                        name,
+                        labels.iter().map(|l| (l.get_name(), l.get_value())),
+                    ));
+                    let bucket = format!("{}_bucket", name);
+                    let buckets = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|b| (b.get_upper_bound(), b.get_cumulative_count()))
+                        .filter(|(bound, _)| bound.is_finite())
+                        .chain([(f64::INFINITY, histogram.get_sample_count())]);
+                    for (bound, count) in buckets {
+                        let mut le = String::new();
+                        write_float(&mut le, bound);
+                        let extra = Some(("le", le.as_str()));
+                        write_sample(&mut out, &bucket, labels, extra, count as f64);
+                        if let Some(exemplar) = series.and_then(|s| s.get(&bound.to_bits())) {
+                            write_exemplar(&mut out, exemplar);
+                        }
+                        out.push('\n');
+                    }
+                    let sum = histogram.get_sample_sum();
+                    write_sample(&mut out, &format!("{}_sum", name), labels, None, sum);
+                    out.push('\n');
+                    let count = histogram.get_sample_count() as f64;
+                    write_sample(&mut out, &format!("{}_count", name), labels, None, count);
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::new();
+        let counter =
+            CounterVec::new(Opts::new("om_requests_total", "Requests"), &["key"]).unwrap();
+        let histogram = HistogramVec::new(
+            HistogramOpts::new("om_latency_seconds", "Latency").buckets(vec![1.0, 2.0]),
+            &["key"],
+        )
+        .unwrap();
+        registry.register(Box::new(counter.clone())).unwrap();
+        registry.register(Box::new(histogram.clone())).unwrap();
+
+        counter.with_label_values(&["a\"b"]).inc();
+        histogram.with_label_values(&["k"]).observe(1.5);
+        record_exemplar(
+            "om_latency_seconds",
+            &[("key", "k")],
+            &[1.0, 2.0],
+            1.5,
+            vec![("client".to_string(), "10.0.0.2:50000".to_string())],
+        );
+
+        let out = encode(&registry.gather());
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines[..3],
+            [
+                "# TYPE om_latency_seconds histogram",
+                "# HELP om_latency_seconds Latency",
+                "om_latency_seconds_bucket{key=\"k\",le=\"1\"} 0",
+            ]
+        );
+        assert!(lines[3].starts_with(
+            "om_latency_seconds_bucket{key=\"k\",le=\"2\"} 1 # {client=\"10.0.0.2:50000\"} 1.5 "
+        ));
+        assert_eq!(
+            lines[4],
+            "om_latency_seconds_bucket{key=\"k\",le=\"+Inf\"} 1"
+        );
+        assert_eq!(lines[5], "om_latency_seconds_sum{key=\"k\"} 1.5");
+        assert_eq!(lines[6], "om_latency_seconds_count{key=\"k\"} 1");
+        assert_eq!(lines[7], "# TYPE om_requests counter");
+        assert_eq!(lines[9], "om_requests_total{key=\"a\\\"b\"} 1");
+        assert_eq!(lines.last(), Some(&"# EOF"));
+    }
+}
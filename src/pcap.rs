@@ -0,0 +1,308 @@
+use anyhow::Result;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::error;
+
+use crate::tun::{PacketReader, ReadResult};
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const PCAP_SNAPLEN: u32 = 65535;
+const LINKTYPE_ETHERNET: u32 = 1;
+
+/// PacketRecorder writes raw frames into a pcap file so a capture session
+/// can be inspected later with tcpdump/wireshark.
+pub struct PacketRecorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl PacketRecorder {
+    /// Create a recorder writing to the file at `path`, truncating it if it exists.
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let file = File::create(path)?;
+        Self::new(Box::new(BufWriter::new(file)))
+    }
+
+    /// Create a recorder over any writer. The pcap global header is written immediately.
+    pub fn new(mut writer: Box<dyn Write + Send>) -> Result<Self> {
+        writer.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MAJOR.to_le_bytes())?;
+        writer.write_all(&PCAP_VERSION_MINOR.to_le_bytes())?;
+        // thiszone and sigfigs
+        writer.write_all(&0i32.to_le_bytes())?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+        Ok(PacketRecorder { writer })
+    }
+
+    /// Append a packet record stamped with the current wall clock time.
+    pub fn record(&mut self, packet: &[u8]) -> Result<()> {
+        let ts = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        self.record_at(packet, ts)
+    }
+
+    /// Append a packet record stamped with `ts`, the time since the unix epoch.
+    pub fn record_at(&mut self, packet: &[u8], ts: Duration) -> Result<()> {
+        let incl_len = packet.len().min(PCAP_SNAPLEN as usize);
+        self.writer
+            .write_all(&(ts.as_secs() as u32).to_le_bytes())?;
+        self.writer.write_all(&ts.subsec_micros().to_le_bytes())?;
+        self.writer.write_all(&(incl_len as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&packet[..incl_len])?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// PcapReader plays back a pcap file as a PacketReader.
+pub struct PcapReader<R: Read> {
+    reader: R,
+    swapped: bool,
+    nanos: bool,
+    replay_speed: f64,
+    last: Option<(Duration, Instant)>,
+}
+
+impl PcapReader<BufReader<File>> {
+    pub fn open(path: impl AsRef<Path>, replay_speed: f64) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::new(BufReader::new(file), replay_speed)
+    }
+}
+
+impl<R: Read> PcapReader<R> {
+    /// Create a reader over a pcap stream, validating its global header.
+    /// `replay_speed` paces packets relative to their recorded timestamps:
+    /// 1.0 is real time, 2.0 twice as fast, and 0 replays as fast as possible.
+    pub fn new(mut reader: R, replay_speed: f64) -> Result<Self> {
+        let mut header = [0u8; 24];
+        reader.read_exact(&mut header)?;
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let (swapped, nanos) = match magic {
+            PCAP_MAGIC => (false, false),
+            PCAP_MAGIC_NANOS => (false, true),
+            m if m.swap_bytes() == PCAP_MAGIC => (true, false),
+            m if m.swap_bytes() == PCAP_MAGIC_NANOS => (true, true),
+            _ => return Err(anyhow::anyhow!("Not a pcap file")),
+        };
+        if !(replay_speed >= 0.0 && replay_speed.is_finite()) {
+            return Err(anyhow::anyhow!(
+                "Replay speed must be a non-negative number"
+            ));
+        }
+
+        Ok(PcapReader {
+            reader,
+            swapped,
+            nanos,
+            replay_speed,
+            last: None,
+        })
+    }
+
+    fn u32_at(&self, buf: &[u8], offset: usize) -> u32 {
+        let v = u32::from_le_bytes([
+            buf[offset],
+            buf[offset + 1],
+            buf[offset + 2],
+            buf[offset + 3],
+        ]);
+        if self.swapped {
+            v.swap_bytes()
+        } else {
+            v
+        }
+    }
+
+    fn next_record(&mut self) -> Result<Option<(Duration, Vec<u8>)>> {
+        let mut header = [0u8; 16];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let secs = self.u32_at(&header, 0) as u64;
+        let frac = self.u32_at(&header, 4);
+        let incl_len = self.u32_at(&header, 8) as usize;
+        if incl_len > PCAP_SNAPLEN as usize {
+            return Err(anyhow::anyhow!(
+                "Packet record of {} bytes is too large",
+                incl_len
+            ));
+        }
+        let ts = if self.nanos {
+            Duration::new(secs, frac)
+        } else {
+            Duration::new(secs, frac.saturating_mul(1000))
+        };
+
+        let mut packet = vec![0u8; incl_len];
+        self.reader.read_exact(&mut packet)?;
+        Ok(Some((ts, packet)))
+    }
+
+    /// Sleep so that the gap since the previous packet matches the recorded gap
+    /// scaled by the replay speed. Out of order timestamps don't sleep at all.
+    fn pace(&mut self, ts: Duration) {
+        if self.replay_speed > 0.0 {
+            if let Some((last_ts, last_emit)) = self.last {
+                let gap = ts.saturating_sub(last_ts).div_f64(self.replay_speed);
+                if let Some(remaining) = gap.checked_sub(last_emit.elapsed()) {
+                    std::thread::sleep(remaining);
+                }
+            }
+        }
+        self.last = Some((ts, Instant::now()));
+    }
+}
+
+impl<R: Read> PacketReader for PcapReader<R> {
+    fn read_packet(&mut self) -> ReadResult {
+        match self.next_record() {
+            Ok(Some((ts, packet))) => {
+                self.pace(ts);
+                ReadResult::Packet(packet)
+            }
+            Ok(None) => ReadResult::Eof,
+            Err(e) => {
+                error!("Failed to read pcap record: {:?}", e);
+                ReadResult::Eof
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pcap_with(records: &[(Duration, &[u8])]) -> Vec<u8> {
+        let buf = SharedBuf::default();
+        let mut recorder = PacketRecorder::new(Box::new(buf.clone())).unwrap();
+        for (ts, packet) in records {
+            recorder.record_at(packet, *ts).unwrap();
+        }
+        let out = buf.0.lock().unwrap().clone();
+        out
+    }
+
+    fn read_u32(buf: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_record_packets() {
+        let path = std::env::temp_dir().join(format!("aragorn-{}.pcap", std::process::id()));
+        let mut recorder = PacketRecorder::create(&path).unwrap();
+        recorder.record(&[0x01, 0x02, 0x03]).unwrap();
+        recorder.record(&[0x04, 0x05]).unwrap();
+        recorder.flush().unwrap();
+
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Global header
+        assert_eq!(read_u32(&buf, 0), PCAP_MAGIC);
+        assert_eq!(u16::from_le_bytes([buf[4], buf[5]]), 2);
+        assert_eq!(u16::from_le_bytes([buf[6], buf[7]]), 4);
+        assert_eq!(read_u32(&buf, 16), PCAP_SNAPLEN);
+        assert_eq!(read_u32(&buf, 20), LINKTYPE_ETHERNET);
+
+        // First record
+        assert_eq!(read_u32(&buf, 24 + 8), 3);
+        assert_eq!(read_u32(&buf, 24 + 12), 3);
+        assert_eq!(&buf[40..43], &[0x01, 0x02, 0x03]);
+
+        // Second record
+        assert_eq!(read_u32(&buf, 43 + 8), 2);
+        assert_eq!(read_u32(&buf, 43 + 12), 2);
+        assert_eq!(&buf[59..61], &[0x04, 0x05]);
+        assert_eq!(buf.len(), 61);
+    }
+
+    #[test]
+    fn test_read_back_records() {
+        let pcap = pcap_with(&[
+            (Duration::from_secs(10), &[0x01, 0x02]),
+            (Duration::from_secs(11), &[0x03]),
+        ]);
+        let mut reader = PcapReader::new(Cursor::new(pcap), 0.0).unwrap();
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![0x01, 0x02]));
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![0x03]));
+        assert_eq!(reader.read_packet(), ReadResult::Eof);
+    }
+
+    #[test]
+    fn test_replay_speed_paces_packets() {
+        let pcap = pcap_with(&[
+            (Duration::from_millis(1_000), &[0x01]),
+            (Duration::from_millis(1_200), &[0x02]),
+        ]);
+
+        let mut reader = PcapReader::new(Cursor::new(pcap.clone()), 1.0).unwrap();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        let start = Instant::now();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+
+        let mut reader = PcapReader::new(Cursor::new(pcap.clone()), 2.0).unwrap();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        let start = Instant::now();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(100));
+        assert!(elapsed < Duration::from_millis(200));
+
+        let mut reader = PcapReader::new(Cursor::new(pcap), 0.0).unwrap();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        let start = Instant::now();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_replay_out_of_order_timestamps() {
+        let pcap = pcap_with(&[
+            (Duration::from_secs(5), &[0x01]),
+            (Duration::from_secs(1), &[0x02]),
+        ]);
+        let mut reader = PcapReader::new(Cursor::new(pcap), 1.0).unwrap();
+        assert!(matches!(reader.read_packet(), ReadResult::Packet(_)));
+        let start = Instant::now();
+        assert_eq!(reader.read_packet(), ReadResult::Packet(vec![0x02]));
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_rejects_non_pcap() {
+        assert!(PcapReader::new(Cursor::new(vec![0u8; 24]), 1.0).is_err());
+        assert!(PcapReader::new(Cursor::new(pcap_with(&[])), -1.0).is_err());
+    }
+}
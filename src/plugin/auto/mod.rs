@@ -0,0 +1,203 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    plugin::{
+        grpc::GrpcHandler,
+        redis::handler::{RespConfig, RespHandler},
+        websocket::WsHandler,
+        ConnectionId, Metrics, Plugin,
+    },
+    post_processor::ProcessedResult,
+};
+
+const HTTP2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"HEAD ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+/// A protocol recognised from the first bytes a client sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Redis,
+    /// HTTP/2, which gRPC is carried over.
+    Grpc,
+    /// An HTTP/1 request line. WebSocket connections open with one to upgrade.
+    Http,
+}
+
+/// Guess the protocol of a connection from the first payload its client sent.
+pub fn detect(payload: &[u8]) -> Option<Protocol> {
+    if payload.starts_with(HTTP2_PREFACE) {
+        return Some(Protocol::Grpc);
+    }
+    if HTTP_METHODS.iter().any(|m| payload.starts_with(m)) {
+        return Some(Protocol::Http);
+    }
+    // Clients send commands as arrays of bulk strings, but a RESP reply can lead too
+    // when capture starts mid-connection
+    match payload.first()? {
+        b'*' | b'+' | b'-' | b':' | b'$' => Some(Protocol::Redis),
+        _ => None,
+    }
+}
+
+/// AutoHandler observes a port without knowing its protocol up front. The first
+/// payload of each connection picks the plugin every later packet of it goes to.
+///
+/// Only packets the observer attributes to a connection (those carrying metrics) are
+/// routed; server pushes outside any request/response pair are not observed.
+pub struct AutoHandler {
+    port: u16,
+    redis: RespHandler,
+    grpc: GrpcHandler,
+    websocket: WsHandler,
+    detected: Mutex<HashMap<ConnectionId, Protocol>>,
+}
+
+impl AutoHandler {
+    pub fn new(port: u16, redis: RespConfig) -> Self {
+        AutoHandler {
+            port,
+            redis: RespHandler::new(port, redis),
+            grpc: GrpcHandler::new(port),
+            websocket: WsHandler::new(port),
+            detected: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Plugin<ProcessedResult> for AutoHandler {
+    async fn port(&self) -> u16 {
+        self.port
+    }
+
+    async fn process(
+        &self,
+        buf: Vec<u8>,
+        metrics: Option<Metrics>,
+    ) -> Result<Option<ProcessedResult>> {
+        let Some(connection) = metrics.as_ref().map(|m| m.identifier.connection()) else {
+            return Ok(None);
+        };
+        let protocol = {
+            let mut detected = self.detected.lock().await;
+            match detected.get(&connection) {
+                Some(protocol) => *protocol,
+                None => match detect(&buf) {
+                    Some(protocol) => {
+                        debug!(?connection, ?protocol, "Detected protocol");
+                        detected.insert(connection, protocol);
+                        protocol
+                    }
+                    None => return Ok(None),
+                },
+            }
+        };
+
+        Ok(match protocol {
+            Protocol::Redis => self.redis.process(buf, metrics).await?.map(Into::into),
+            Protocol::Grpc => self.grpc.process(buf, metrics).await?.map(Into::into),
+            Protocol::Http => self.websocket.process(buf, metrics).await?.map(Into::into),
+        })
+    }
+
+    async fn close_connection(&self, connection: ConnectionId) {
+        let protocol = self.detected.lock().await.remove(&connection);
+        match protocol {
+            Some(Protocol::Redis) => self.redis.close_connection(connection).await,
+            Some(Protocol::Grpc) => self.grpc.close_connection(connection).await,
+            Some(Protocol::Http) => self.websocket.close_connection(connection).await,
+            None => {}
+        }
+    }
+
+    async fn flush(&self) {
+        self.detected.lock().await.clear();
+        self.redis.flush().await;
+        self.grpc.flush().await;
+        self.websocket.flush().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::RequestId;
+    use std::time::Duration;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            detect(b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n"),
+            Some(Protocol::Redis)
+        );
+        assert_eq!(
+            detect(b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n"),
+            Some(Protocol::Http)
+        );
+        assert_eq!(
+            detect(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+            Some(Protocol::Grpc)
+        );
+        assert_eq!(detect(b"\x16\x03\x01"), None);
+        assert_eq!(detect(b""), None);
+    }
+
+    fn metrics(connection: u64, latency: Option<Duration>) -> Option<Metrics> {
+        Some(Metrics {
+            identifier: RequestId::Connection(connection),
+            latency,
+            src_addr: None,
+            dst_addr: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_first_payload() {
+        let handler = AutoHandler::new(6379, RespConfig::default());
+        let latency = Some(Duration::from_millis(1));
+
+        let req = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec();
+        assert!(handler
+            .process(req, metrics(1, None))
+            .await
+            .unwrap()
+            .is_none());
+        // The reply is routed by the connection's cached protocol
+        let res = handler
+            .process(b"$1\r\nv\r\n".to_vec(), metrics(1, latency))
+            .await
+            .unwrap();
+        assert!(matches!(res, Some(ProcessedResult::Redis(r)) if r.key == "k"));
+
+        let req = b"GET /chat HTTP/1.1\r\nUpgrade: websocket\r\n\r\n".to_vec();
+        handler.process(req, metrics(2, None)).await.unwrap();
+        // A text frame of "hi"
+        let res = handler
+            .process(vec![0x81, 0x02, b'h', b'i'], metrics(2, latency))
+            .await
+            .unwrap();
+        assert!(matches!(res, Some(ProcessedResult::Prometheus(r)) if r.label == "text"));
+
+        let detected = handler.detected.lock().await;
+        assert_eq!(
+            detected.get(&ConnectionId::Connection(1)),
+            Some(&Protocol::Redis)
+        );
+        assert_eq!(
+            detected.get(&ConnectionId::Connection(2)),
+            Some(&Protocol::Http)
+        );
+    }
+}
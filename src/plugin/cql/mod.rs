@@ -0,0 +1,363 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    plugin::{ConnectionId, Metrics, Plugin},
+    post_processor::{ProcessedResult, PrometheusResult},
+};
+
+const FRAME_HEADER_LEN: usize = 9;
+/// Set in the version byte of frames sent by the server.
+const DIRECTION_RESPONSE: u8 = 0x80;
+const FLAG_COMPRESSION: u8 = 0x01;
+
+pub const OPCODE_ERROR: u8 = 0x00;
+pub const OPCODE_QUERY: u8 = 0x07;
+pub const OPCODE_RESULT: u8 = 0x08;
+
+#[derive(Debug, Clone)]
+pub struct CqlResult {
+    /// The query with its literals replaced by `?`, see `normalize_query`.
+    pub query: String,
+    pub is_error: bool,
+    /// Seconds.
+    pub latency: f64,
+}
+
+impl From<CqlResult> for ProcessedResult {
+    fn from(res: CqlResult) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: res.query,
+            is_error: res.is_error,
+            latency: res.latency,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+}
+
+/// A CQL native protocol frame (v3 and v4) as seen in a TCP payload.
+#[derive(Debug, PartialEq)]
+pub struct Frame<'a> {
+    pub version: u8,
+    pub is_response: bool,
+    pub flags: u8,
+    pub stream: i16,
+    pub opcode: u8,
+    pub body: &'a [u8],
+}
+
+/// Split a buffer into complete frames. A trailing partial frame is ignored, but a
+/// header of a protocol version other than 3 or 4 fails the whole buffer.
+pub fn parse_frames(mut buf: &[u8]) -> Result<Vec<Frame<'_>>> {
+    let mut frames = vec![];
+    while buf.len() >= FRAME_HEADER_LEN {
+        let version = buf[0] & !DIRECTION_RESPONSE;
+        if !(3..=4).contains(&version) {
+            anyhow::bail!("Unsupported CQL protocol version {}", version);
+        }
+        let len = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize;
+        let Some(body) = buf.get(FRAME_HEADER_LEN..FRAME_HEADER_LEN.saturating_add(len)) else {
+            break;
+        };
+        frames.push(Frame {
+            version,
+            is_response: buf[0] & DIRECTION_RESPONSE != 0,
+            flags: buf[1],
+            stream: i16::from_be_bytes([buf[2], buf[3]]),
+            opcode: buf[4],
+            body,
+        });
+        buf = &buf[FRAME_HEADER_LEN + len..];
+    }
+    Ok(frames)
+}
+
+/// The query string of a QUERY body, a `[long string]` ahead of its parameters.
+pub fn query_string(body: &[u8]) -> Result<String> {
+    let len = body
+        .get(..4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize)
+        .ok_or_else(|| anyhow::anyhow!("Truncated CQL query length"))?;
+    let query = body
+        .get(4..4usize.saturating_add(len))
+        .ok_or_else(|| anyhow::anyhow!("Truncated CQL query"))?;
+    Ok(String::from_utf8_lossy(query).into_owned())
+}
+
+/// The code and message of an ERROR body, an `[int]` followed by a `[string]`.
+pub fn error_message(body: &[u8]) -> Result<(u32, String)> {
+    let truncated = || anyhow::anyhow!("Truncated CQL error");
+    let code = body.get(..4).ok_or_else(truncated)?;
+    let code = u32::from_be_bytes([code[0], code[1], code[2], code[3]]);
+    let len = body.get(4..6).ok_or_else(truncated)?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    let message = body.get(6..6 + len).ok_or_else(truncated)?;
+    Ok((code, String::from_utf8_lossy(message).into_owned()))
+}
+
+/// Replace string, numeric, uuid and blob literals with `?` and collapse whitespace,
+/// so queries differing only in their values share a label.
+pub fn normalize_query(query: &str) -> String {
+    let mut out = String::with_capacity(query.len());
+    let mut chars = query.trim().trim_end_matches(';').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                // Quotes inside a string are escaped by doubling them
+                while let Some(c) = chars.next() {
+                    if c == '\'' && chars.next_if_eq(&'\'').is_none() {
+                        break;
+                    }
+                }
+                out.push('?');
+            }
+            // A digit starts a number unless it continues an identifier, and a minus
+            // is its sign where no operand precedes it
+            c if (c.is_ascii_digit()
+                && !out.ends_with(|p: char| p.is_alphanumeric() || p == '_'))
+                || (c == '-'
+                    && chars.peek().is_some_and(|c| c.is_ascii_digit())
+                    && !out
+                        .trim_end()
+                        .ends_with(|p: char| p.is_alphanumeric() || "_)?".contains(p))) =>
+            {
+                while chars
+                    .next_if(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-')
+                    .is_some()
+                {}
+                out.push('?');
+            }
+            c if c.is_whitespace() => {
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                out.push(' ');
+            }
+            c => out.push(c),
+        }
+    }
+    out.trim_end().to_string()
+}
+
+/// CqlHandler reports the latency and outcome of CQL QUERY requests. Requests are
+/// multiplexed over a connection by stream id and may be answered out of order, so
+/// responses are paired with requests by stream rather than by TCP sequence, and
+/// latency is measured from when each is handled.
+///
+/// Only frames in packets the observer attributes to a connection are seen. A
+/// response packet holding several frames reports the first paired one.
+pub struct CqlHandler {
+    port: u16,
+    /// Normalized query and start time of each open request.
+    pending: Mutex<HashMap<(ConnectionId, i16), (String, Instant)>>,
+}
+
+impl CqlHandler {
+    pub fn new(port: u16) -> Self {
+        CqlHandler {
+            port,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Plugin<CqlResult> for CqlHandler {
+    async fn port(&self) -> u16 {
+        self.port
+    }
+
+    async fn process(&self, buf: Vec<u8>, metrics: Option<Metrics>) -> Result<Option<CqlResult>> {
+        let Some(metrics) = metrics else {
+            return Ok(None);
+        };
+        let now = Instant::now();
+        let connection = metrics.identifier.connection();
+
+        let mut pending = self.pending.lock().await;
+        let mut result = None;
+        for frame in parse_frames(&buf)? {
+            let key = (connection, frame.stream);
+            match (frame.is_response, frame.opcode) {
+                // The body of a compressed query can't be read
+                (false, OPCODE_QUERY) if frame.flags & FLAG_COMPRESSION == 0 => {
+                    let query = normalize_query(&query_string(frame.body)?);
+                    pending.insert(key, (query, now));
+                }
+                (true, OPCODE_RESULT | OPCODE_ERROR) => {
+                    let Some((query, start)) = pending.remove(&key) else {
+                        continue;
+                    };
+                    // Only one result can be returned, so the request is dropped
+                    if result.is_some() {
+                        continue;
+                    }
+                    let is_error = frame.opcode == OPCODE_ERROR;
+                    if is_error {
+                        if let Ok((code, message)) = error_message(frame.body) {
+                            debug!(code, message, "CQL error");
+                        }
+                    }
+                    result = Some(CqlResult {
+                        query,
+                        is_error,
+                        latency: now.saturating_duration_since(start).as_secs_f64(),
+                    });
+                }
+                _ => {}
+            }
+        }
+        if let Some(res) = &result {
+            debug!(
+                query = %res.query,
+                latency_ms = res.latency * 1e3,
+                is_error = res.is_error,
+                "CQL response"
+            );
+        }
+        Ok(result)
+    }
+
+    async fn close_connection(&self, connection: ConnectionId) {
+        self.pending
+            .lock()
+            .await
+            .retain(|(c, _), _| *c != connection);
+    }
+
+    async fn flush(&self) {
+        self.pending.lock().await.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::RequestId;
+
+    fn frame(response: bool, stream: i16, opcode: u8, body: &[u8]) -> Vec<u8> {
+        let version = if response { 0x84 } else { 0x04 };
+        let mut buf = vec![version, 0];
+        buf.extend_from_slice(&stream.to_be_bytes());
+        buf.push(opcode);
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(body);
+        buf
+    }
+
+    fn query(stream: i16, query: &str) -> Vec<u8> {
+        let mut body = (query.len() as u32).to_be_bytes().to_vec();
+        body.extend_from_slice(query.as_bytes());
+        // Consistency ONE, no flags
+        body.extend_from_slice(&[0x00, 0x01, 0x00]);
+        frame(false, stream, OPCODE_QUERY, &body)
+    }
+
+    fn error(stream: i16, code: u32, message: &str) -> Vec<u8> {
+        let mut body = code.to_be_bytes().to_vec();
+        body.extend_from_slice(&(message.len() as u16).to_be_bytes());
+        body.extend_from_slice(message.as_bytes());
+        frame(true, stream, OPCODE_ERROR, &body)
+    }
+
+    fn void_result(stream: i16) -> Vec<u8> {
+        frame(true, stream, OPCODE_RESULT, &1u32.to_be_bytes())
+    }
+
+    fn metrics() -> Option<Metrics> {
+        Some(Metrics {
+            identifier: RequestId::Connection(1),
+            latency: None,
+            src_addr: None,
+            dst_addr: None,
+        })
+    }
+
+    #[test]
+    fn test_parse_query_frame() {
+        let buf = query(5, "SELECT * FROM users WHERE id = 42");
+        let frames = parse_frames(&buf).unwrap();
+        assert_eq!(frames.len(), 1);
+        let frame = &frames[0];
+        assert_eq!(
+            (frame.version, frame.is_response, frame.stream, frame.opcode),
+            (4, false, 5, OPCODE_QUERY)
+        );
+        assert_eq!(
+            query_string(frame.body).unwrap(),
+            "SELECT * FROM users WHERE id = 42"
+        );
+
+        // A frame cut short by the segment is left out
+        assert!(parse_frames(&buf[..buf.len() - 1]).unwrap().is_empty());
+        assert!(parse_frames(&[0x02, 0, 0, 0, 0x07, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_parse_error_frame() {
+        let buf = error(-1, 0x2200, "Invalid query");
+        let frames = parse_frames(&buf).unwrap();
+        assert_eq!(frames.len(), 1);
+        let frame = &frames[0];
+        assert!(frame.is_response);
+        assert_eq!((frame.stream, frame.opcode), (-1, OPCODE_ERROR));
+        assert_eq!(
+            error_message(frame.body).unwrap(),
+            (0x2200, "Invalid query".to_string())
+        );
+        assert!(error_message(&frame.body[..7]).is_err());
+    }
+
+    #[test]
+    fn test_normalize_query() {
+        assert_eq!(
+            normalize_query("SELECT name FROM users\n  WHERE id = 42 AND nick = 'o''brien';"),
+            "SELECT name FROM users WHERE id = ? AND nick = ?"
+        );
+        assert_eq!(
+            normalize_query(
+                "UPDATE t2 SET v = -1.5 WHERE k = 123e4567-e89b-12d3-a456-426614174000"
+            ),
+            "UPDATE t2 SET v = ? WHERE k = ?"
+        );
+        assert_eq!(
+            normalize_query("INSERT INTO t (k, b) VALUES (0x00ff, 3)"),
+            "INSERT INTO t (k, b) VALUES (?, ?)"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pairs_by_stream() {
+        let handler = CqlHandler::new(9042);
+        let mut requests = query(1, "SELECT * FROM a WHERE k = 1");
+        requests.extend(query(2, "SELECT * FROM b WHERE k = 2"));
+        assert!(handler
+            .process(requests, metrics())
+            .await
+            .unwrap()
+            .is_none());
+
+        // Answered out of order
+        let res = handler
+            .process(error(2, 0x2200, "unconfigured table b"), metrics())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.query, "SELECT * FROM b WHERE k = ?");
+        assert!(res.is_error);
+
+        let res = handler
+            .process(void_result(1), metrics())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.query, "SELECT * FROM a WHERE k = ?");
+        assert!(!res.is_error);
+
+        // Nothing is left to pair with
+        let res = handler.process(void_result(1), metrics()).await.unwrap();
+        assert!(res.is_none());
+        assert!(handler.pending.lock().await.is_empty());
+    }
+}
@@ -0,0 +1,652 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+
+/// RFC 7541 Appendix A.
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// RFC 7541 Appendix B, the code and its length in bits for every byte and, last,
+/// for EOS.
+const HUFFMAN: [(u32, u8); 257] = [
+    (0x1ff8, 13),
+    (0x7fffd8, 23),
+    (0xfffffe2, 28),
+    (0xfffffe3, 28),
+    (0xfffffe4, 28),
+    (0xfffffe5, 28),
+    (0xfffffe6, 28),
+    (0xfffffe7, 28),
+    (0xfffffe8, 28),
+    (0xffffea, 24),
+    (0x3ffffffc, 30),
+    (0xfffffe9, 28),
+    (0xfffffea, 28),
+    (0x3ffffffd, 30),
+    (0xfffffeb, 28),
+    (0xfffffec, 28),
+    (0xfffffed, 28),
+    (0xfffffee, 28),
+    (0xfffffef, 28),
+    (0xffffff0, 28),
+    (0xffffff1, 28),
+    (0xffffff2, 28),
+    (0x3ffffffe, 30),
+    (0xffffff3, 28),
+    (0xffffff4, 28),
+    (0xffffff5, 28),
+    (0xffffff6, 28),
+    (0xffffff7, 28),
+    (0xffffff8, 28),
+    (0xffffff9, 28),
+    (0xffffffa, 28),
+    (0xffffffb, 28),
+    (0x14, 6),
+    (0x3f8, 10),
+    (0x3f9, 10),
+    (0xffa, 12),
+    (0x1ff9, 13),
+    (0x15, 6),
+    (0xf8, 8),
+    (0x7fa, 11),
+    (0x3fa, 10),
+    (0x3fb, 10),
+    (0xf9, 8),
+    (0x7fb, 11),
+    (0xfa, 8),
+    (0x16, 6),
+    (0x17, 6),
+    (0x18, 6),
+    (0x0, 5),
+    (0x1, 5),
+    (0x2, 5),
+    (0x19, 6),
+    (0x1a, 6),
+    (0x1b, 6),
+    (0x1c, 6),
+    (0x1d, 6),
+    (0x1e, 6),
+    (0x1f, 6),
+    (0x5c, 7),
+    (0xfb, 8),
+    (0x7ffc, 15),
+    (0x20, 6),
+    (0xffb, 12),
+    (0x3fc, 10),
+    (0x1ffa, 13),
+    (0x21, 6),
+    (0x5d, 7),
+    (0x5e, 7),
+    (0x5f, 7),
+    (0x60, 7),
+    (0x61, 7),
+    (0x62, 7),
+    (0x63, 7),
+    (0x64, 7),
+    (0x65, 7),
+    (0x66, 7),
+    (0x67, 7),
+    (0x68, 7),
+    (0x69, 7),
+    (0x6a, 7),
+    (0x6b, 7),
+    (0x6c, 7),
+    (0x6d, 7),
+    (0x6e, 7),
+    (0x6f, 7),
+    (0x70, 7),
+    (0x71, 7),
+    (0x72, 7),
+    (0xfc, 8),
+    (0x73, 7),
+    (0xfd, 8),
+    (0x1ffb, 13),
+    (0x7fff0, 19),
+    (0x1ffc, 13),
+    (0x3ffc, 14),
+    (0x22, 6),
+    (0x7ffd, 15),
+    (0x3, 5),
+    (0x23, 6),
+    (0x4, 5),
+    (0x24, 6),
+    (0x5, 5),
+    (0x25, 6),
+    (0x26, 6),
+    (0x27, 6),
+    (0x6, 5),
+    (0x74, 7),
+    (0x75, 7),
+    (0x28, 6),
+    (0x29, 6),
+    (0x2a, 6),
+    (0x7, 5),
+    (0x2b, 6),
+    (0x76, 7),
+    (0x2c, 6),
+    (0x8, 5),
+    (0x9, 5),
+    (0x2d, 6),
+    (0x77, 7),
+    (0x78, 7),
+    (0x79, 7),
+    (0x7a, 7),
+    (0x7b, 7),
+    (0x7ffe, 15),
+    (0x7fc, 11),
+    (0x3ffd, 14),
+    (0x1ffd, 13),
+    (0xffffffc, 28),
+    (0xfffe6, 20),
+    (0x3fffd2, 22),
+    (0xfffe7, 20),
+    (0xfffe8, 20),
+    (0x3fffd3, 22),
+    (0x3fffd4, 22),
+    (0x3fffd5, 22),
+    (0x7fffd9, 23),
+    (0x3fffd6, 22),
+    (0x7fffda, 23),
+    (0x7fffdb, 23),
+    (0x7fffdc, 23),
+    (0x7fffdd, 23),
+    (0x7fffde, 23),
+    (0xffffeb, 24),
+    (0x7fffdf, 23),
+    (0xffffec, 24),
+    (0xffffed, 24),
+    (0x3fffd7, 22),
+    (0x7fffe0, 23),
+    (0xffffee, 24),
+    (0x7fffe1, 23),
+    (0x7fffe2, 23),
+    (0x7fffe3, 23),
+    (0x7fffe4, 23),
+    (0x1fffdc, 21),
+    (0x3fffd8, 22),
+    (0x7fffe5, 23),
+    (0x3fffd9, 22),
+    (0x7fffe6, 23),
+    (0x7fffe7, 23),
+    (0xffffef, 24),
+    (0x3fffda, 22),
+    (0x1fffdd, 21),
+    (0xfffe9, 20),
+    (0x3fffdb, 22),
+    (0x3fffdc, 22),
+    (0x7fffe8, 23),
+    (0x7fffe9, 23),
+    (0x1fffde, 21),
+    (0x7fffea, 23),
+    (0x3fffdd, 22),
+    (0x3fffde, 22),
+    (0xfffff0, 24),
+    (0x1fffdf, 21),
+    (0x3fffdf, 22),
+    (0x7fffeb, 23),
+    (0x7fffec, 23),
+    (0x1fffe0, 21),
+    (0x1fffe1, 21),
+    (0x3fffe0, 22),
+    (0x1fffe2, 21),
+    (0x7fffed, 23),
+    (0x3fffe1, 22),
+    (0x7fffee, 23),
+    (0x7fffef, 23),
+    (0xfffea, 20),
+    (0x3fffe2, 22),
+    (0x3fffe3, 22),
+    (0x3fffe4, 22),
+    (0x7ffff0, 23),
+    (0x3fffe5, 22),
+    (0x3fffe6, 22),
+    (0x7ffff1, 23),
+    (0x3ffffe0, 26),
+    (0x3ffffe1, 26),
+    (0xfffeb, 20),
+    (0x7fff1, 19),
+    (0x3fffe7, 22),
+    (0x7ffff2, 23),
+    (0x3fffe8, 22),
+    (0x1ffffec, 25),
+    (0x3ffffe2, 26),
+    (0x3ffffe3, 26),
+    (0x3ffffe4, 26),
+    (0x7ffffde, 27),
+    (0x7ffffdf, 27),
+    (0x3ffffe5, 26),
+    (0xfffff1, 24),
+    (0x1ffffed, 25),
+    (0x7fff2, 19),
+    (0x1fffe3, 21),
+    (0x3ffffe6, 26),
+    (0x7ffffe0, 27),
+    (0x7ffffe1, 27),
+    (0x3ffffe7, 26),
+    (0x7ffffe2, 27),
+    (0xfffff2, 24),
+    (0x1fffe4, 21),
+    (0x1fffe5, 21),
+    (0x3ffffe8, 26),
+    (0x3ffffe9, 26),
+    (0xffffffd, 28),
+    (0x7ffffe3, 27),
+    (0x7ffffe4, 27),
+    (0x7ffffe5, 27),
+    (0xfffec, 20),
+    (0xfffff3, 24),
+    (0xfffed, 20),
+    (0x1fffe6, 21),
+    (0x3fffe9, 22),
+    (0x1fffe7, 21),
+    (0x1fffe8, 21),
+    (0x7ffff3, 23),
+    (0x3fffea, 22),
+    (0x3fffeb, 22),
+    (0x1ffffee, 25),
+    (0x1ffffef, 25),
+    (0xfffff4, 24),
+    (0xfffff5, 24),
+    (0x3ffffea, 26),
+    (0x7ffff4, 23),
+    (0x3ffffeb, 26),
+    (0x7ffffe6, 27),
+    (0x3ffffec, 26),
+    (0x3ffffed, 26),
+    (0x7ffffe7, 27),
+    (0x7ffffe8, 27),
+    (0x7ffffe9, 27),
+    (0x7ffffea, 27),
+    (0x7ffffeb, 27),
+    (0xffffffe, 28),
+    (0x7ffffec, 27),
+    (0x7ffffed, 27),
+    (0x7ffffee, 27),
+    (0x7ffffef, 27),
+    (0x7fffff0, 27),
+    (0x3ffffee, 26),
+    (0x3fffffff, 30),
+];
+const EOS: u16 = 256;
+const MAX_CODE_LEN: usize = 30;
+
+/// The Huffman code is canonical: codes of one length are consecutive and follow
+/// the order of their symbols. A code of `len` bits decodes to
+/// `symbols[offset[len] + code - first[len]]` when it is less than `count[len]`
+/// past `first[len]`.
+struct Canonical {
+    first: [u32; MAX_CODE_LEN + 1],
+    count: [u32; MAX_CODE_LEN + 1],
+    offset: [usize; MAX_CODE_LEN + 1],
+    /// Symbols ordered by code length, then by symbol.
+    symbols: [u16; 257],
+}
+
+const CANONICAL: Canonical = canonical();
+
+const fn canonical() -> Canonical {
+    let mut c = Canonical {
+        first: [0; MAX_CODE_LEN + 1],
+        count: [0; MAX_CODE_LEN + 1],
+        offset: [0; MAX_CODE_LEN + 1],
+        symbols: [0; 257],
+    };
+    let mut sym = 0;
+    while sym < HUFFMAN.len() {
+        let (code, len) = HUFFMAN[sym];
+        if c.count[len as usize] == 0 {
+            c.first[len as usize] = code;
+        }
+        c.count[len as usize] += 1;
+        sym += 1;
+    }
+    let mut len = 1;
+    while len <= MAX_CODE_LEN {
+        c.offset[len] = c.offset[len - 1] + c.count[len - 1] as usize;
+        len += 1;
+    }
+    let mut next = c.offset;
+    let mut sym = 0;
+    while sym < HUFFMAN.len() {
+        let len = HUFFMAN[sym].1 as usize;
+        c.symbols[next[len]] = sym as u16;
+        next[len] += 1;
+        sym += 1;
+    }
+    c
+}
+
+const DEFAULT_TABLE_SIZE: usize = 4096;
+// Per RFC 7541 section 4.1 each entry costs its name and value plus 32 bytes.
+const ENTRY_OVERHEAD: usize = 32;
+
+pub type Header = (String, String);
+
+/// HPACK decoder holding the dynamic table of a single connection direction.
+pub struct Decoder {
+    dynamic: VecDeque<Header>,
+    size: usize,
+    max_size: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            dynamic: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    /// Decode a complete header block.
+    pub fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Header>> {
+        let mut headers = vec![];
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // Indexed header field
+                let (index, rest) = decode_int(block, 7)?;
+                block = rest;
+                headers.push(self.lookup(index)?);
+            } else if first & 0x40 != 0 {
+                // Literal with incremental indexing
+                let (header, rest) = self.decode_literal(block, 6)?;
+                block = rest;
+                self.insert(header.clone());
+                headers.push(header);
+            } else if first & 0x20 != 0 {
+                // Dynamic table size update
+                let (size, rest) = decode_int(block, 5)?;
+                block = rest;
+                self.max_size = size;
+                self.evict();
+            } else {
+                // Literal without indexing or never indexed
+                let (header, rest) = self.decode_literal(block, 4)?;
+                block = rest;
+                headers.push(header);
+            }
+        }
+        Ok(headers)
+    }
+
+    fn decode_literal<'a>(&self, block: &'a [u8], prefix: u8) -> Result<(Header, &'a [u8])> {
+        let (index, rest) = decode_int(block, prefix)?;
+        let (name, rest) = if index == 0 {
+            decode_string(rest)?
+        } else {
+            (self.lookup(index)?.0, rest)
+        };
+        let (value, rest) = decode_string(rest)?;
+        Ok(((name, value), rest))
+    }
+
+    fn lookup(&self, index: usize) -> Result<Header> {
+        if index == 0 {
+            return Err(anyhow::anyhow!("Invalid HPACK index 0"));
+        }
+        if index <= STATIC_TABLE.len() {
+            let (name, value) = STATIC_TABLE[index - 1];
+            return Ok((name.to_string(), value.to_string()));
+        }
+        self.dynamic
+            .get(index - STATIC_TABLE.len() - 1)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("HPACK index {} out of range", index))
+    }
+
+    fn insert(&mut self, header: Header) {
+        self.size += header.0.len() + header.1.len() + ENTRY_OVERHEAD;
+        self.dynamic.push_front(header);
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        while self.size > self.max_size {
+            match self.dynamic.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+/// Decode an HPACK integer with an N-bit prefix (RFC 7541 section 5.1).
+fn decode_int(buf: &[u8], prefix: u8) -> Result<(usize, &[u8])> {
+    let (&first, mut rest) = buf
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("Truncated HPACK integer"))?;
+    let mask = ((1u16 << prefix) - 1) as u8;
+    let mut value = (first & mask) as usize;
+    if value < mask as usize {
+        return Ok((value, rest));
+    }
+    let mut shift = 0;
+    loop {
+        let (&b, r) = rest
+            .split_first()
+            .ok_or_else(|| anyhow::anyhow!("Truncated HPACK integer"))?;
+        rest = r;
+        if shift > 28 {
+            return Err(anyhow::anyhow!("HPACK integer overflow"));
+        }
+        value += ((b & 0x7f) as usize) << shift;
+        shift += 7;
+        if b & 0x80 == 0 {
+            return Ok((value, rest));
+        }
+    }
+}
+
+fn decode_string(buf: &[u8]) -> Result<(String, &[u8])> {
+    let huffman = buf.first().is_some_and(|b| b & 0x80 != 0);
+    let (len, rest) = decode_int(buf, 7)?;
+    if rest.len() < len {
+        return Err(anyhow::anyhow!("Truncated HPACK string"));
+    }
+    let bytes = if huffman {
+        decode_huffman(&rest[..len])?
+    } else {
+        rest[..len].to_vec()
+    };
+    Ok((String::from_utf8(bytes)?, &rest[len..]))
+}
+
+/// Decode a Huffman coded string (RFC 7541 section 5.2).
+fn decode_huffman(data: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len() * 8 / 5);
+    let (mut code, mut len) = (0u32, 0usize);
+    for byte in data {
+        for i in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> i & 1);
+            len += 1;
+            // The code is complete, so every string of MAX_CODE_LEN bits has
+            // matched by then
+            let index = code.wrapping_sub(CANONICAL.first[len]);
+            if index < CANONICAL.count[len] {
+                let sym = CANONICAL.symbols[CANONICAL.offset[len] + index as usize];
+                if sym == EOS {
+                    return Err(anyhow::anyhow!("EOS in Huffman coded HPACK string"));
+                }
+                out.push(sym as u8);
+                (code, len) = (0, 0);
+            }
+        }
+    }
+    // The padding is the start of EOS: fewer than 8 bits, all ones
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(anyhow::anyhow!("Invalid Huffman padding in HPACK string"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_int() {
+        // RFC 7541 C.1
+        assert_eq!(decode_int(&[0x0a], 5).unwrap().0, 10);
+        assert_eq!(decode_int(&[0x1f, 0x9a, 0x0a], 5).unwrap().0, 1337);
+        assert_eq!(decode_int(&[0x2a], 8).unwrap().0, 42);
+        assert!(decode_int(&[0x1f, 0x9a], 5).is_err());
+    }
+
+    #[test]
+    fn test_decode_static_and_literal() {
+        // RFC 7541 C.3.1 and C.3.2 (requests without Huffman coding)
+        let mut decoder = Decoder::default();
+        let block = [
+            0x82, 0x86, 0x84, 0x41, 0x0f, b'w', b'w', b'w', b'.', b'e', b'x', b'a', b'm', b'p',
+            b'l', b'e', b'.', b'c', b'o', b'm',
+        ];
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(
+            headers,
+            vec![
+                (":method".to_string(), "GET".to_string()),
+                (":scheme".to_string(), "http".to_string()),
+                (":path".to_string(), "/".to_string()),
+                (":authority".to_string(), "www.example.com".to_string()),
+            ]
+        );
+
+        // The authority is now in the dynamic table at index 62
+        let block = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x08, b'n', b'o', b'-', b'c', b'a', b'c', b'h', b'e',
+        ];
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(headers[3].1, "www.example.com");
+        assert_eq!(
+            headers[4],
+            ("cache-control".to_string(), "no-cache".to_string())
+        );
+    }
+
+    #[test]
+    fn test_decode_huffman() {
+        // RFC 7541 C.4.1 to C.4.3 (the same requests with Huffman coding)
+        let mut decoder = Decoder::default();
+        let block = [
+            0x82, 0x86, 0x84, 0x41, 0x8c, 0xf1, 0xe3, 0xc2, 0xe5, 0xf2, 0x3a, 0x6b, 0xa0, 0xab,
+            0x90, 0xf4, 0xff,
+        ];
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(
+            headers[3],
+            (":authority".to_string(), "www.example.com".to_string())
+        );
+
+        let block = [
+            0x82, 0x86, 0x84, 0xbe, 0x58, 0x86, 0xa8, 0xeb, 0x10, 0x64, 0x9c, 0xbf,
+        ];
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(
+            headers[4],
+            ("cache-control".to_string(), "no-cache".to_string())
+        );
+
+        let block = [
+            0x82, 0x87, 0x85, 0xbf, 0x40, 0x88, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xa9, 0x7d, 0x7f,
+            0x89, 0x25, 0xa8, 0x49, 0xe9, 0x5b, 0xb8, 0xe8, 0xb4, 0xbf,
+        ];
+        let headers = decoder.decode(&block).unwrap();
+        assert_eq!(
+            headers[4],
+            ("custom-key".to_string(), "custom-value".to_string())
+        );
+    }
+
+    #[test]
+    fn test_decode_huffman_every_byte() {
+        // Encode each byte with the table and a padding of ones, then decode it back
+        let data: Vec<u8> = (0..=255).collect();
+        let mut bits = vec![];
+        for &b in &data {
+            let (code, len) = HUFFMAN[b as usize];
+            bits.extend((0..len).rev().map(|i| code >> i & 1 == 1));
+        }
+        while bits.len() % 8 != 0 {
+            bits.push(true);
+        }
+        let encoded: Vec<u8> = bits
+            .chunks(8)
+            .map(|byte| byte.iter().fold(0, |v, &bit| v << 1 | u8::from(bit)))
+            .collect();
+        assert_eq!(decode_huffman(&encoded).unwrap(), data);
+    }
+
+    #[test]
+    fn test_decode_huffman_invalid_padding() {
+        // '/' is 011000, then two bits of padding
+        assert_eq!(decode_huffman(&[0x63]).unwrap(), b"/");
+        // Padding that isn't all ones
+        assert!(decode_huffman(&[0x61]).is_err());
+        // A whole byte of padding
+        assert!(decode_huffman(&[0x63, 0xff]).is_err());
+        // EOS itself
+        assert!(decode_huffman(&[0xff, 0xff, 0xff, 0xff]).is_err());
+    }
+}
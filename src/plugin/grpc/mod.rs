@@ -0,0 +1,384 @@
+pub(crate) mod hpack;
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use tokio::sync::Mutex;
+use tracing::debug;
+
+use crate::{
+    plugin::{ConnectionId, Metrics, Plugin},
+    post_processor::{ProcessedResult, PrometheusResult},
+};
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_HEADERS: u8 = 0x4;
+const FLAG_PADDED: u8 = 0x8;
+const FLAG_PRIORITY: u8 = 0x20;
+
+#[derive(Debug, Clone)]
+pub struct GrpcResult {
+    pub method: String,
+    pub grpc_status: Option<u32>,
+    /// Seconds.
+    pub latency: f64,
+    pub is_error: bool,
+}
+
+impl From<GrpcResult> for ProcessedResult {
+    fn from(res: GrpcResult) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: res.method,
+            is_error: res.is_error,
+            latency: res.latency,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+}
+
+/// (client, server) addresses of a connection.
+type ConnectionKey = (SocketAddr, SocketAddr);
+
+/// HPACK is stateful, so every connection keeps a decoder per direction
+/// along with the method of each open stream.
+#[derive(Default)]
+struct Connection {
+    client: hpack::Decoder,
+    server: hpack::Decoder,
+    methods: HashMap<u32, String>,
+}
+
+/// GrpcHandler extracts the method (`:path`) of gRPC requests and the `grpc-status`
+/// of their responses from HTTP/2 HEADERS frames.
+///
+/// Only packets the observer can attribute to a connection (those carrying metrics)
+/// are decoded. If a header block is missed and the HPACK state desyncs, the
+/// connection's decoders are reset on the next decoding failure.
+pub struct GrpcHandler {
+    port: u16,
+    connections: Mutex<HashMap<ConnectionKey, Connection>>,
+}
+
+impl GrpcHandler {
+    pub fn new(port: u16) -> Self {
+        GrpcHandler {
+            port,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+struct Frame<'a> {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: &'a [u8],
+}
+
+/// Split a buffer into complete HTTP/2 frames. A trailing partial frame is ignored.
+fn parse_frames(mut buf: &[u8]) -> Vec<Frame<'_>> {
+    if buf.starts_with(PREFACE) {
+        buf = &buf[PREFACE.len()..];
+    }
+    let mut frames = vec![];
+    while buf.len() >= FRAME_HEADER_LEN {
+        let len = u32::from_be_bytes([0, buf[0], buf[1], buf[2]]) as usize;
+        if buf.len() < FRAME_HEADER_LEN + len {
+            break;
+        }
+        frames.push(Frame {
+            kind: buf[3],
+            flags: buf[4],
+            stream: u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) & 0x7fff_ffff,
+            payload: &buf[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len],
+        });
+        buf = &buf[FRAME_HEADER_LEN + len..];
+    }
+    frames
+}
+
+/// Strip padding and priority fields from a HEADERS frame payload.
+pub(crate) fn header_fragment(flags: u8, mut payload: &[u8]) -> Result<&[u8]> {
+    let mut pad = 0;
+    if flags & FLAG_PADDED != 0 {
+        pad = *payload
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("Truncated padded HEADERS frame"))?
+            as usize;
+        payload = &payload[1..];
+    }
+    if flags & FLAG_PRIORITY != 0 {
+        payload = payload
+            .get(5..)
+            .ok_or_else(|| anyhow::anyhow!("Truncated HEADERS priority"))?;
+    }
+    payload
+        .get(..payload.len().saturating_sub(pad))
+        .filter(|_| pad <= payload.len())
+        .ok_or_else(|| anyhow::anyhow!("Invalid HEADERS padding"))
+}
+
+/// Collect the header blocks in a buffer, joining CONTINUATION frames onto their HEADERS.
+fn header_blocks(buf: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    let mut blocks: Vec<(u32, Vec<u8>)> = vec![];
+    let mut open = false;
+    for frame in parse_frames(buf) {
+        match frame.kind {
+            FRAME_HEADERS => {
+                blocks.push((
+                    frame.stream,
+                    header_fragment(frame.flags, frame.payload)?.to_vec(),
+                ));
+                open = frame.flags & FLAG_END_HEADERS == 0;
+            }
+            FRAME_CONTINUATION if open => {
+                if let Some((_, block)) = blocks.last_mut() {
+                    block.extend_from_slice(frame.payload);
+                }
+                open = frame.flags & FLAG_END_HEADERS == 0;
+            }
+            _ => {}
+        }
+    }
+    Ok(blocks)
+}
+
+impl Plugin<GrpcResult> for GrpcHandler {
+    async fn port(&self) -> u16 {
+        self.port
+    }
+
+    async fn process(&self, buf: Vec<u8>, metrics: Option<Metrics>) -> Result<Option<GrpcResult>> {
+        let metrics = match metrics {
+            Some(metrics) => metrics,
+            None => return Ok(None),
+        };
+        let (src, dst) = match (metrics.src_addr, metrics.dst_addr) {
+            (Some(src), Some(dst)) => (src, dst),
+            _ => return Ok(None),
+        };
+        let is_request = dst.port() == self.port;
+        let key = if is_request { (src, dst) } else { (dst, src) };
+
+        let mut connections = self.connections.lock().await;
+        let conn = connections.entry(key).or_default();
+
+        let mut responses: Vec<(u32, Vec<hpack::Header>)> = vec![];
+        for (stream, block) in header_blocks(&buf)? {
+            let decoder = if is_request {
+                &mut conn.client
+            } else {
+                &mut conn.server
+            };
+            let headers = match decoder.decode(&block) {
+                Ok(headers) => headers,
+                Err(e) => {
+                    connections.remove(&key);
+                    return Err(e);
+                }
+            };
+
+            if is_request {
+                if let Some((_, path)) = headers.iter().find(|(name, _)| name == ":path") {
+                    conn.methods.insert(stream, path.clone());
+                }
+            } else {
+                responses.push((stream, headers));
+            }
+        }
+
+        let latency = match metrics.latency {
+            Some(latency) => latency,
+            None => return Ok(None),
+        };
+        // Report the first stream in this packet that belongs to a known request,
+        // merging its response headers and trailers.
+        let stream = match responses
+            .iter()
+            .map(|(stream, _)| *stream)
+            .find(|stream| conn.methods.contains_key(stream))
+        {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        let headers: Vec<&hpack::Header> = responses
+            .iter()
+            .filter(|(s, _)| *s == stream)
+            .flat_map(|(_, headers)| headers)
+            .collect();
+        let find = |key: &str| {
+            headers
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, v)| v.as_str())
+        };
+        let status = find(":status");
+        let grpc_status = find("grpc-status").and_then(|v| v.parse::<u32>().ok());
+        let method = if grpc_status.is_some() {
+            conn.methods.remove(&stream)
+        } else {
+            conn.methods.get(&stream).cloned()
+        }
+        .unwrap_or_default();
+
+        let result = GrpcResult {
+            method,
+            grpc_status,
+            latency: latency.as_secs_f64(),
+            is_error: grpc_status.is_some_and(|s| s != 0) || status.is_some_and(|s| s != "200"),
+        };
+        debug!(
+            method = %result.method,
+            grpc_status = ?result.grpc_status,
+            latency_ms = result.latency * 1e3,
+            "gRPC response"
+        );
+        Ok(Some(result))
+    }
+
+    async fn close_connection(&self, connection: ConnectionId) {
+        if let ConnectionId::Tcp(client) = connection {
+            self.connections
+                .lock()
+                .await
+                .retain(|(c, _), _| *c != client);
+        }
+    }
+
+    async fn flush(&self) {
+        self.connections.lock().await.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::RequestId;
+    use std::time::Duration;
+
+    fn frame(kind: u8, flags: u8, stream: u32, payload: &[u8]) -> Vec<u8> {
+        let len = (payload.len() as u32).to_be_bytes();
+        let mut buf = vec![len[1], len[2], len[3], kind, flags];
+        buf.extend_from_slice(&stream.to_be_bytes());
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    fn literal(name_index: u8, value: &str) -> Vec<u8> {
+        // Literal with incremental indexing, indexed name
+        let mut buf = vec![0x40 | name_index, value.len() as u8];
+        buf.extend_from_slice(value.as_bytes());
+        buf
+    }
+
+    fn new_name_literal(name: &str, value: &str) -> Vec<u8> {
+        // Literal without indexing, new name
+        let mut buf = vec![0x00, name.len() as u8];
+        buf.extend_from_slice(name.as_bytes());
+        buf.push(value.len() as u8);
+        buf.extend_from_slice(value.as_bytes());
+        buf
+    }
+
+    fn metrics(src: &str, dst: &str, latency: Option<Duration>) -> Option<Metrics> {
+        Some(Metrics {
+            identifier: RequestId::Connection(1),
+            latency,
+            src_addr: Some(src.parse().unwrap()),
+            dst_addr: Some(dst.parse().unwrap()),
+        })
+    }
+
+    fn request(path: &str) -> Vec<u8> {
+        // :method POST, :scheme http, :path <literal>
+        let mut block = vec![0x83, 0x86];
+        block.extend(literal(4, path));
+        let mut buf = PREFACE.to_vec();
+        buf.extend(frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &block));
+        buf
+    }
+
+    fn response(grpc_status: &str) -> Vec<u8> {
+        let mut buf = frame(FRAME_HEADERS, FLAG_END_HEADERS, 1, &[0x88]);
+        buf.extend(frame(0x0, 0, 1, &[0, 0, 0, 0, 0]));
+        buf.extend(frame(
+            FRAME_HEADERS,
+            FLAG_END_HEADERS,
+            1,
+            &new_name_literal("grpc-status", grpc_status),
+        ));
+        buf
+    }
+
+    #[test]
+    fn test_decode_static_table_path() {
+        let blocks = header_blocks(&request("/helloworld.Greeter/SayHello")).unwrap();
+        assert_eq!(blocks.len(), 1);
+        let headers = hpack::Decoder::default().decode(&blocks[0].1).unwrap();
+        assert_eq!(blocks[0].0, 1);
+        assert_eq!(
+            headers[2],
+            (
+                ":path".to_string(),
+                "/helloworld.Greeter/SayHello".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_header_fragment_padding_and_continuation() {
+        let mut payload = vec![2, 0, 0, 0, 0, 0, 0x83];
+        payload.extend_from_slice(&[0, 0]);
+        let mut buf = frame(FRAME_HEADERS, FLAG_PADDED | FLAG_PRIORITY, 3, &payload);
+        buf.extend(frame(FRAME_CONTINUATION, FLAG_END_HEADERS, 3, &[0x86]));
+        let blocks = header_blocks(&buf).unwrap();
+        assert_eq!(blocks, vec![(3, vec![0x83, 0x86])]);
+    }
+
+    #[tokio::test]
+    async fn test_request_response_pairing() {
+        let handler = GrpcHandler::new(50051);
+        let client = "10.0.0.2:40000";
+        let server = "10.0.0.1:50051";
+
+        let res = handler
+            .process(request("/pkg.Svc/Get"), metrics(client, server, None))
+            .await
+            .unwrap();
+        assert!(res.is_none());
+
+        let res = handler
+            .process(
+                response("14"),
+                metrics(server, client, Some(Duration::from_millis(7))),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.method, "/pkg.Svc/Get");
+        assert_eq!(res.grpc_status, Some(14));
+        assert_eq!(res.latency, 0.007);
+        assert!(res.is_error);
+
+        handler
+            .process(request("/pkg.Svc/Put"), metrics(client, server, None))
+            .await
+            .unwrap();
+        let res = handler
+            .process(
+                response("0"),
+                metrics(server, client, Some(Duration::from_millis(1))),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.method, "/pkg.Svc/Put");
+        assert!(!res.is_error);
+    }
+}
@@ -1,17 +1,81 @@
+pub mod auto;
+pub mod cql;
+pub mod grpc;
 pub mod redis;
+pub mod replication;
+pub mod websocket;
 
 use anyhow::Result;
+use std::future::Future;
+use std::net::SocketAddr;
+
+/// Key pairing a response with its request. Each source supplies whichever strategy it
+/// can; ids from different strategies never compare equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RequestId {
+    /// End-of-data sequence number of a request on the connection of the given client.
+    TcpSeq(u32, SocketAddr),
+    /// A protocol level transaction id, e.g. a DNS query id.
+    Transaction(u64),
+    /// A connection carrying one outstanding request at a time.
+    Connection(u64),
+}
+
+/// The connection a request was made on. Requests sharing a connection are answered
+/// in the order they were sent, so pending requests are queued per connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionId {
+    /// A TCP connection, identified by its client address.
+    Tcp(SocketAddr),
+    /// Transactions aren't ordered against each other, so each stands alone.
+    Transaction(u64),
+    Connection(u64),
+}
+
+impl RequestId {
+    pub fn connection(&self) -> ConnectionId {
+        match *self {
+            RequestId::TcpSeq(_, client) => ConnectionId::Tcp(client),
+            RequestId::Transaction(id) => ConnectionId::Transaction(id),
+            RequestId::Connection(id) => ConnectionId::Connection(id),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Metrics {
-    pub identifier: u32,
+    pub identifier: RequestId,
     pub latency: Option<std::time::Duration>,
+    /// Addresses of the packet the metrics were derived from.
+    /// None for sources without an IP layer.
+    pub src_addr: Option<SocketAddr>,
+    pub dst_addr: Option<SocketAddr>,
 }
 
 /// Plugin trait that defines the interface for a plugin.
 /// A plugin is a module that can parse a packet, process it and send the result to a handler.
 /// The plugin can be used to implement different types of handlers like a Redis handler, a HTTP handler etc.
+/// The observer shares one plugin between workers on different threads, so its futures
+/// are `Send`. Implementations write them as plain `async fn`s.
 pub trait Plugin<R>: Send + Sync {
-    async fn port(&self) -> u16;
-    async fn process(&self, input: Vec<u8>, metrics: Option<Metrics>) -> Result<Option<R>>;
+    fn port(&self) -> impl Future<Output = u16> + Send;
+    /// Every server port the plugin's protocol is spoken on, e.g. Redis and Sentinel.
+    /// Traffic to or from any of them is handed to the plugin. Defaults to `port`.
+    fn ports(&self) -> impl Future<Output = Vec<u16>> + Send {
+        async { vec![self.port().await] }
+    }
+    fn process(
+        &self,
+        input: Vec<u8>,
+        metrics: Option<Metrics>,
+    ) -> impl Future<Output = Result<Option<R>>> + Send;
+    /// Drop any state kept for a connection that closed or went idle.
+    fn close_connection(&self, _connection: ConnectionId) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+    /// Drop the state of every connection once capture has stopped. Requests still
+    /// waiting for a response will never get one.
+    fn flush(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
 }
@@ -1,41 +1,327 @@
 use anyhow::Result;
-use std::{collections::HashMap, sync::Arc};
+use lazy_static::lazy_static;
+use openssl::sha;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
 use tokio::sync::Mutex;
+use tracing::debug;
 
 use crate::{
-    plugin::{Metrics, Plugin},
-    post_processor::{ProcessedResult, PrometheusResult},
+    logging::hex_dump,
+    plugin::{ConnectionId, Metrics, Plugin},
+    post_processor::{KeyEventResult, ProcessedResult, PubSubResult},
+    tls,
+};
+
+pub use super::redact::RedactConfig;
+use super::redact::{redact, REDACTED};
+use super::resp_parser::{
+    parse_resp_ref, skip_attributes, valid_arity, ParserConfig, RespValue, RespValueRef,
 };
 
-use super::resp_parser::{parse_resp, RespValue};
+lazy_static! {
+    static ref PENDING_EVICTED: IntCounterVec = register_int_counter_vec!(
+        "aragorn_pending_requests_evicted_total",
+        "Number of Redis requests forgotten without a response to make room for newer ones",
+        &["port"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedisResultKind {
+    /// A request paired with its response.
+    Request,
+    /// A pub/sub message pushed by the server. `key` holds the channel and there
+    /// is no latency since nothing was requested.
+    PubSub,
+    /// A keyspace notification. `key` holds the key that changed and `command` the
+    /// event, e.g. `set` or `expired`.
+    KeyEvent,
+}
 
 #[derive(Debug, Clone)]
 pub struct RedisResult {
+    pub kind: RedisResultKind,
+    pub command: String,
     pub key: String,
     pub is_error: bool,
-    pub latency: u128,
+    /// Seconds from the request to its response.
+    pub latency: f64,
+    /// Size of the response: the value of an integer reply, the length of a bulk
+    /// string or the element count of an array. Only set when enabled in `RespConfig`.
+    pub response_size: Option<usize>,
+    /// Addresses of the client that sent the request and the server that answered it.
+    /// None for sources without an IP layer.
+    pub client_addr: Option<SocketAddr>,
+    pub server_addr: Option<SocketAddr>,
+    /// Host name the client asked for with TLS SNI, when its connection is decrypted.
+    pub server_name: Option<String>,
+    /// TLS version and cipher suite of the connection, when it is decrypted.
+    pub tls: Option<tls::Negotiated>,
+    /// Bytes of the TCP payloads carrying the request and the response.
+    pub request_bytes: usize,
+    pub response_bytes: usize,
 }
 
 impl From<RedisResult> for ProcessedResult {
     fn from(res: RedisResult) -> ProcessedResult {
-        ProcessedResult::Prometheus(PrometheusResult {
-            label: res.key,
-            is_error: res.is_error,
-            latency: res.latency,
-        })
+        match res.kind {
+            RedisResultKind::Request => ProcessedResult::Redis(res),
+            RedisResultKind::PubSub => ProcessedResult::PubSub(PubSubResult {
+                channel: res.key,
+                kind: res.command,
+            }),
+            RedisResultKind::KeyEvent => ProcessedResult::KeyEvent(KeyEventResult {
+                key: res.key,
+                event: res.command,
+            }),
+        }
+    }
+}
+
+/// Key label of commands that don't take a key, like PING.
+pub const NO_KEY: &str = "__nokey__";
+
+/// Decides whether a response counts as an error from its raw bytes and parsed value.
+pub type ErrorClassifier = fn(&[u8], &RespValueRef) -> bool;
+
+/// The default classifier: the response is a RESP simple or blob error.
+pub fn is_error_reply(buf: &[u8], _value: &RespValueRef) -> bool {
+    matches!(buf.first(), Some(b'-') | Some(b'!'))
+}
+
+#[derive(Debug, Clone)]
+pub struct RespConfig {
+    pub parser: ParserConfig,
+    /// Record the size of each response in the result. Off by default.
+    pub record_response_size: bool,
+    /// Requests whose key and value are masked before they reach any result.
+    pub redact: RedactConfig,
+    pub is_error: ErrorClassifier,
+    /// Log a hex dump of up to this many bytes of each payload that fails to parse,
+    /// at debug level. Off by default.
+    pub dump_unparsed: Option<usize>,
+    /// Connections with requests awaiting a response that are kept at once. The one
+    /// that least recently sent a request is forgotten to make room for another.
+    pub max_connections: usize,
+    /// Requests awaiting a response kept per connection. The oldest is forgotten to
+    /// make room for another.
+    pub max_pipeline: usize,
+}
+
+impl Default for RespConfig {
+    fn default() -> Self {
+        RespConfig {
+            parser: ParserConfig::default(),
+            record_response_size: false,
+            redact: RedactConfig::default(),
+            is_error: is_error_reply,
+            dump_unparsed: None,
+            max_connections: 65536,
+            max_pipeline: 1024,
+        }
+    }
+}
+
+/// Requests of a connection awaiting a response, each with the size of its payload.
+#[derive(Default)]
+struct Pending {
+    requests: VecDeque<(RespValue, usize)>,
+    /// Number of the request last added, to find the least recently used connection.
+    used: u64,
+}
+
+/// The pending requests of every connection, along with an index of the connections
+/// by `Pending::used` so the least recently used one is found without a scan.
+#[derive(Default)]
+struct Store {
+    connections: HashMap<ConnectionId, Pending>,
+    by_use: BTreeMap<u64, ConnectionId>,
+}
+
+impl Store {
+    /// Queue a request on a connection, numbered `used`.
+    fn push(&mut self, connection: ConnectionId, request: (RespValue, usize), used: u64) {
+        if let Some(pending) = self.connections.get(&connection) {
+            self.by_use.remove(&pending.used);
+        }
+        let pending = self.connections.entry(connection).or_default();
+        pending.used = used;
+        pending.requests.push_back(request);
+        self.by_use.insert(used, connection);
+    }
+
+    /// Take the oldest request of a connection, forgetting the connection once it has
+    /// none left.
+    fn pop(&mut self, connection: &ConnectionId) -> Option<(RespValue, usize)> {
+        let pending = self.connections.get_mut(connection)?;
+        let request = pending.requests.pop_front();
+        if pending.requests.is_empty() {
+            self.remove(connection);
+        }
+        request
+    }
+
+    fn remove(&mut self, connection: &ConnectionId) -> Option<Pending> {
+        let pending = self.connections.remove(connection)?;
+        self.by_use.remove(&pending.used);
+        Some(pending)
+    }
+
+    fn remove_least_recently_used(&mut self) -> Option<Pending> {
+        let (_, connection) = self.by_use.pop_first()?;
+        self.connections.remove(&connection)
+    }
+
+    fn clear(&mut self) {
+        self.connections.clear();
+        self.by_use.clear();
     }
 }
 
 pub struct RespHandler {
     port: u16,
-    key_map: Arc<Mutex<HashMap<u32, RespValue>>>,
+    /// Requests awaiting a response, oldest first, per connection. Redis answers the
+    /// requests on a connection in order, so with pipelining the i-th response
+    /// belongs to the i-th request.
+    ///
+    /// Responses can be lost, and connections end without the observer seeing it, so
+    /// the map is bounded by `max_connections` and `max_pipeline`.
+    key_map: Arc<Mutex<Store>>,
+    /// Requests stored so far, numbering them.
+    stored: AtomicU64,
+    cfg: RespConfig,
 }
 
 impl RespHandler {
-    pub fn new(port: u16) -> Self {
+    pub fn new(port: u16, cfg: RespConfig) -> Self {
         RespHandler {
             port,
-            key_map: Arc::new(Mutex::new(HashMap::new())),
+            key_map: Arc::new(Mutex::new(Store::default())),
+            stored: AtomicU64::new(0),
+            cfg,
+        }
+    }
+}
+
+/// Derive a numeric size from a raw response and its parsed value. Streamed
+/// strings and aggregates don't declare one.
+fn response_size(buf: &[u8], value: &RespValueRef) -> Option<usize> {
+    if buf.get(1) == Some(&b'?') {
+        return None;
+    }
+    match buf.first()? {
+        b':' => std::str::from_utf8(value.value?).ok()?.parse().ok(),
+        b'$' => Some(value.value.map_or(0, |v| v.len())),
+        b'*' => {
+            let digits = buf[1..].iter().take_while(|c| c.is_ascii_digit()).count();
+            std::str::from_utf8(&buf[1..1 + digits]).ok()?.parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// `EVAL` and `EVAL_RO` carry the script itself, where `EVALSHA` carries its hash.
+fn is_eval(command: &[u8]) -> bool {
+    command.eq_ignore_ascii_case(b"EVAL") || command.eq_ignore_ascii_case(b"EVAL_RO")
+}
+
+/// The SHA1 of a script in hex, as `SCRIPT LOAD` returns it and `EVALSHA` takes it.
+/// A script masked by redaction keeps its mask.
+fn script_sha(script: &[u8]) -> String {
+    if script == REDACTED.as_bytes() {
+        return REDACTED.to_string();
+    }
+    sha::sha1(script)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Recognise a keyspace notification from its channel and message, returning the key
+/// and the event. `__keyspace@<db>__:<key>` carries the event and
+/// `__keyevent@<db>__:<event>` the key.
+fn key_event(channel: &[u8], message: &[u8]) -> Option<(String, String)> {
+    let text = |b: &[u8]| String::from_utf8_lossy(b).into_owned();
+    let (space, rest) = if let Some(rest) = channel.strip_prefix(b"__keyspace@") {
+        (true, rest)
+    } else {
+        (false, channel.strip_prefix(b"__keyevent@")?)
+    };
+    let at = rest.windows(3).position(|w| w == b"__:")?;
+    let name = &rest[at + 3..];
+    Some(if space {
+        (text(name), text(message))
+    } else {
+        (text(message), text(name))
+    })
+}
+
+/// Recognise a pub/sub message delivered by the server, either as a RESP3 push
+/// or a RESP2 array: `message <channel> <payload>`, `pmessage <pattern> <channel>
+/// <payload>` or `smessage <channel> <payload>`.
+fn pubsub_message(value: &RespValueRef) -> Option<RedisResult> {
+    let kind = String::from_utf8_lossy(value.command?).to_ascii_lowercase();
+    if !matches!(kind.as_str(), "message" | "smessage" | "pmessage") {
+        return None;
+    }
+    let channel = value.key?;
+    let (kind, command, key) = match value.value.and_then(|v| key_event(channel, v)) {
+        Some((key, event)) => (RedisResultKind::KeyEvent, event, key),
+        None => (
+            RedisResultKind::PubSub,
+            kind,
+            String::from_utf8_lossy(channel).into_owned(),
+        ),
+    };
+    Some(RedisResult {
+        kind,
+        command,
+        key,
+        is_error: false,
+        latency: 0.0,
+        response_size: None,
+        client_addr: None,
+        server_addr: None,
+        server_name: None,
+        tls: None,
+        request_bytes: 0,
+        response_bytes: 0,
+    })
+}
+
+/// Whether a value is the confirmation of a `SUBSCRIBE`, `UNSUBSCRIBE` or one of
+/// their pattern and shard variants.
+fn is_subscription(value: &RespValueRef) -> bool {
+    const KINDS: &[&str] = &[
+        "subscribe",
+        "unsubscribe",
+        "psubscribe",
+        "punsubscribe",
+        "ssubscribe",
+        "sunsubscribe",
+    ];
+    value
+        .command
+        .is_some_and(|c| KINDS.iter().any(|k| k.as_bytes().eq_ignore_ascii_case(c)))
+}
+
+impl RespHandler {
+    /// Drop the request the observer paired a packet with, when the packet isn't its
+    /// response after all. Kept, the request would label the next response on the
+    /// connection, as the observer has already moved past it.
+    async fn drop_paired_request(&self, metrics: &Metrics) {
+        if metrics.latency.is_some() {
+            let connection = metrics.identifier.connection();
+            self.key_map.lock().await.pop(&connection);
         }
     }
 }
@@ -46,41 +332,784 @@ impl Plugin<RedisResult> for RespHandler {
     }
 
     async fn process(&self, buf: Vec<u8>, metrics: Option<Metrics>) -> Result<Option<RedisResult>> {
-        // Return if none and unpack the metrics
-        if metrics.is_none() {
-            return Ok(None);
+        let is_request = metrics.as_ref().is_some_and(|m| m.latency.is_none());
+        let parsed = parse_resp_ref(&buf, &self.cfg.parser)
+            .map_err(|e| e.to_string())
+            .and_then(|(_, input)| match is_request && !valid_arity(&input) {
+                // Bytes that only look like a request, e.g. read out of step with the stream
+                true => Err("wrong number of arguments for the command".to_string()),
+                false => Ok(input),
+            });
+        let mut input = match parsed {
+            Ok(input) => input,
+            Err(e) => {
+                if let Some(limit) = self.cfg.dump_unparsed {
+                    debug!(
+                        error = %e,
+                        len = buf.len(),
+                        "Unparsed payload\n{}",
+                        hex_dump(&buf, limit)
+                    );
+                }
+                // Packets we can't pair (e.g. the tail of a large response) aren't worth an error
+                let Some(metrics) = metrics else {
+                    return Ok(None);
+                };
+                self.drop_paired_request(&metrics).await;
+                return Err(anyhow::anyhow!("Failed to parse packet"));
+            }
+        };
+
+        redact(&self.cfg.redact, &mut input);
+
+        // Pushed messages are server initiated, so they never answer a request. Only
+        // the confirmation of a subscription is pushed in reply to one.
+        let reply = skip_attributes(&buf, &self.cfg.parser);
+        let message = pubsub_message(&input);
+        if message.is_some() || (reply.first() == Some(&b'>') && !is_subscription(&input)) {
+            if let Some(metrics) = &metrics {
+                self.drop_paired_request(metrics).await;
+            }
+            return Ok(message);
         }
-        // We already know that metrics is not None
-        let metrics = metrics.unwrap();
 
-        let resp = parse_resp(&buf).map_err(|_| anyhow::anyhow!("Failed to parse packet"))?;
-        let input = resp.1;
+        // Return if none and unpack the metrics
+        let metrics = match metrics {
+            Some(metrics) => metrics,
+            None => return Ok(None),
+        };
 
         let mut store = self.key_map.lock().await;
-        store
-            .entry(metrics.identifier)
-            .or_insert_with(|| input.clone());
-
-        if let Some(latency) = metrics.latency {
-            let status = if input.to_string().contains("ERR") {
-                "ERR"
+        let connection = metrics.identifier.connection();
+        let Some(latency) = metrics.latency else {
+            // The request outlives the payload, so it's copied out here. Only its
+            // command and key label the response, so a large value is left behind.
+            let mut request = RespValueRef {
+                value: None,
+                ..input
+            };
+            // So is the body of a script, which is labelled by its SHA1 instead
+            let sha = if request.command.is_some_and(is_eval) {
+                request.key.take().map(script_sha)
             } else {
-                "OK"
+                None
             };
-            // Print the latency and the key
-            let stored_value = store
-                .get(&metrics.identifier)
-                .ok_or_else(|| anyhow::anyhow!("Failed to get value from store"))?;
-            let key = stored_value.key.as_ref().unwrap().clone();
-            // clean up the store
-            store.remove(&metrics.identifier);
-            return Ok(Some(RedisResult {
-                key: key.clone(),
-                is_error: status == "ERR",
-                latency: latency.as_millis(),
-            }));
+            let mut request = request.into_owned();
+            if sha.is_some() {
+                request.key = sha;
+            }
+            let mut evicted = 0;
+            if store.connections.len() >= self.cfg.max_connections
+                && !store.connections.contains_key(&connection)
+            {
+                if let Some(pending) = store.remove_least_recently_used() {
+                    evicted += pending.requests.len();
+                }
+            }
+            let used = self.stored.fetch_add(1, Ordering::Relaxed);
+            store.push(connection, (request, buf.len()), used);
+            let pending = store.connections.get_mut(&connection).expect("just pushed");
+            if pending.requests.len() > self.cfg.max_pipeline {
+                pending.requests.pop_front();
+                evicted += 1;
+            }
+            if evicted > 0 {
+                debug!(evicted, "Forgetting requests left without a response");
+                PENDING_EVICTED
+                    .with_label_values(&[&self.port.to_string()])
+                    .inc_by(evicted as u64);
+            }
+            return Ok(None);
+        };
+
+        // Error replies and sizes are read from the bytes of the reply itself
+        let is_error = (self.cfg.is_error)(reply, &input);
+        let status = if is_error { "ERR" } else { "OK" };
+        let (stored_value, request_bytes) = store
+            .pop(&connection)
+            .ok_or_else(|| anyhow::anyhow!("Failed to get value from store"))?;
+        // Commands are case insensitive, so `get` and `GET` share a label
+        let command = stored_value
+            .command
+            .filter(|c| !c.is_empty())
+            .map(|c| c.to_ascii_uppercase())
+            .ok_or_else(|| anyhow::anyhow!("Request has no command"))?;
+        // A hash field is labelled along with its hash, e.g. `user:42:email`
+        let key = match (stored_value.key, stored_value.field) {
+            (Some(key), Some(field)) => format!("{}:{}", key, field),
+            (key, _) => key.unwrap_or_else(|| NO_KEY.to_string()),
+        };
+        let response_size = if self.cfg.record_response_size {
+            response_size(reply, &input)
+        } else {
+            None
+        };
+        // This is the response, so the client is whichever end isn't the server port
+        let (client_addr, server_addr) = if metrics.src_addr.is_some_and(|a| a.port() == self.port)
+        {
+            (metrics.dst_addr, metrics.src_addr)
+        } else {
+            (metrics.src_addr, metrics.dst_addr)
+        };
+        debug!(
+            key = %key,
+            latency_ms = latency.as_secs_f64() * 1e3,
+            status,
+            "Redis response"
+        );
+        Ok(Some(RedisResult {
+            kind: RedisResultKind::Request,
+            command,
+            key,
+            is_error,
+            latency: latency.as_secs_f64(),
+            response_size,
+            client_addr,
+            server_addr,
+            server_name: client_addr
+                .zip(server_addr)
+                .and_then(|(client, server)| tls::server_name(client, server)),
+            tls: client_addr
+                .zip(server_addr)
+                .and_then(|(client, server)| tls::negotiated(client, server)),
+            request_bytes,
+            response_bytes: buf.len(),
+        }))
+    }
+
+    async fn close_connection(&self, connection: ConnectionId) {
+        self.key_map.lock().await.remove(&connection);
+    }
+
+    async fn flush(&self) {
+        let mut key_map = self.key_map.lock().await;
+        let unanswered: usize = key_map.connections.values().map(|p| p.requests.len()).sum();
+        if unanswered > 0 {
+            debug!(unanswered, "Dropping requests left without a response");
         }
+        key_map.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logging::{self, testing::BufWriter, LogFormat};
+    use crate::plugin::RequestId;
+    use std::time::Duration;
+    use tracing::Level;
+
+    fn request_metrics(connection: u64) -> Option<Metrics> {
+        Some(Metrics {
+            identifier: RequestId::Connection(connection),
+            latency: None,
+            src_addr: None,
+            dst_addr: None,
+        })
+    }
+
+    fn response_metrics(connection: u64) -> Option<Metrics> {
+        Some(Metrics {
+            identifier: RequestId::Connection(connection),
+            latency: Some(Duration::from_millis(2)),
+            src_addr: None,
+            dst_addr: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_response_size_recorded() {
+        let handler = RespHandler::new(
+            6379,
+            RespConfig {
+                record_response_size: true,
+                ..Default::default()
+            },
+        );
+        let req = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n".to_vec();
+        assert!(handler
+            .process(req, request_metrics(1))
+            .await
+            .unwrap()
+            .is_none());
+
+        let res = handler
+            .process(b"$6\r\nfoobar\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command, "GET");
+        assert_eq!(res.key, "foo");
+        assert_eq!(res.response_size, Some(6));
+    }
+
+    #[tokio::test]
+    async fn test_response_size_disabled_by_default() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$5\r\nSCARD\r\n$3\r\nset\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b":12\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.response_size, None);
+    }
+
+    #[tokio::test]
+    async fn test_id_strategies_do_not_collide() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let client = "10.0.0.2:50000".parse().unwrap();
+        let metrics = |identifier, latency| {
+            Some(Metrics {
+                identifier,
+                latency,
+                src_addr: None,
+                dst_addr: None,
+            })
+        };
+        let by_connection = RequestId::Connection(1);
+        let by_seq = RequestId::TcpSeq(1, client);
+
+        let req = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n".to_vec();
+        handler
+            .process(req, metrics(by_connection, None))
+            .await
+            .unwrap();
+        let req = b"*2\r\n$3\r\nGET\r\n$1\r\nb\r\n".to_vec();
+        handler.process(req, metrics(by_seq, None)).await.unwrap();
+
+        let latency = Some(Duration::from_millis(1));
+        let res = handler
+            .process(b"$1\r\nx\r\n".to_vec(), metrics(by_seq, latency))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.key, "b");
+        let res = handler
+            .process(b"$1\r\nx\r\n".to_vec(), metrics(by_connection, latency))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.key, "a");
+    }
+
+    #[tokio::test]
+    async fn test_flush_drops_pending_requests() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        for connection in [1, 2] {
+            let get = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n".to_vec();
+            handler
+                .process(get, request_metrics(connection))
+                .await
+                .unwrap();
+        }
+        handler.flush().await;
+        assert!(handler.key_map.lock().await.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_requests_bounded() {
+        let handler = RespHandler::new(
+            6391,
+            RespConfig {
+                max_connections: 4,
+                max_pipeline: 3,
+                ..Default::default()
+            },
+        );
+        let get = |key: usize| format!("*2\r\n$3\r\nGET\r\n$2\r\n{:02}\r\n", key).into_bytes();
+        // Responses to a connection's requests are lost, so only its latest are kept
+        for key in 0..5 {
+            handler.process(get(key), request_metrics(0)).await.unwrap();
+        }
+        for connection in 1..4 {
+            handler
+                .process(get(10 + connection as usize), request_metrics(connection))
+                .await
+                .unwrap();
+        }
+        handler.process(get(5), request_metrics(0)).await.unwrap();
+        // A new connection takes the place of the least recently used, which isn't 0
+        handler.process(get(14), request_metrics(4)).await.unwrap();
+
+        let key_map = handler.key_map.lock().await;
+        assert_eq!(key_map.connections.len(), 4);
+        assert!(!key_map
+            .connections
+            .contains_key(&RequestId::Connection(1).connection()));
+        let pending = &key_map.connections[&RequestId::Connection(0).connection()].requests;
+        assert_eq!(pending.len(), 3);
+        // Connections are indexed by their latest request, oldest first
+        let by_use: Vec<_> = key_map.by_use.values().copied().collect();
+        let expected: Vec<_> = [2, 3, 0, 4]
+            .map(|connection| RequestId::Connection(connection).connection())
+            .into();
+        assert_eq!(by_use, expected);
+        drop(key_map);
+        // Requests 0 to 2 of connection 0, and connection 1's
+        assert_eq!(PENDING_EVICTED.with_label_values(&["6391"]).get(), 4);
+
+        // The oldest request kept on connection 0 answers its next response
+        let res = handler
+            .process(b"$1\r\nx\r\n".to_vec(), response_metrics(0))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.key, "03");
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_answered_in_order() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let client = "10.0.0.2:50000".parse().unwrap();
+        let metrics = |end, latency| {
+            Some(Metrics {
+                identifier: RequestId::TcpSeq(end, client),
+                latency,
+                src_addr: None,
+                dst_addr: None,
+            })
+        };
+
+        let get = b"*2\r\n$3\r\nGET\r\n$1\r\na\r\n".to_vec();
+        handler.process(get, metrics(10, None)).await.unwrap();
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n1\r\n".to_vec();
+        handler.process(set, metrics(20, None)).await.unwrap();
+
+        // Both responses arrive after the server read both requests
+        let latency = Some(Duration::from_millis(1));
+        let res = handler
+            .process(b"$1\r\nx\r\n".to_vec(), metrics(20, latency))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("GET", "a"));
+        let res = handler
+            .process(b"+OK\r\n".to_vec(), metrics(20, latency))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("SET", "b"));
+        assert!(handler.key_map.lock().await.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unparsed_response_drops_its_request() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        for key in ["a", "b"] {
+            let get = format!("*2\r\n$3\r\nGET\r\n$1\r\n{}\r\n", key);
+            handler
+                .process(get.into_bytes(), request_metrics(1))
+                .await
+                .unwrap();
+        }
+        // The reply to `GET a` is longer than the segment it starts in
+        let res = handler
+            .process(b"$4000\r\nxxxx".to_vec(), response_metrics(1))
+            .await;
+        assert!(res.is_err());
+        let res = handler
+            .process(b"$1\r\ny\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("GET", "b"));
+        assert!(handler.key_map.lock().await.connections.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_command_survives_into_result() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$4\r\nINCR\r\n$4\r\nhits\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b":3\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        let res = ProcessedResult::from(res);
+        match &res {
+            ProcessedResult::Redis(res) => {
+                assert_eq!(res.command, "INCR");
+                assert_eq!(res.key, "hits");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(
+            res.to_json(),
+            "{\"command\":\"INCR\",\"key\":\"hits\",\"is_error\":false,\"latency\":0.002,\"response_size\":null,\"client_addr\":null,\"server_addr\":null}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sub_millisecond_latency() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let metrics = Metrics {
+            latency: Some(Duration::from_micros(500)),
+            ..response_metrics(1).unwrap()
+        };
+        let res = handler
+            .process(b"$3\r\nbar\r\n".to_vec(), Some(metrics))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.latency, 0.0005);
+    }
+
+    #[tokio::test]
+    async fn test_client_addr() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let client: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let server: SocketAddr = "10.0.0.1:6379".parse().unwrap();
+        let metrics = |src, dst, latency| {
+            Some(Metrics {
+                identifier: RequestId::Connection(1),
+                latency,
+                src_addr: Some(src),
+                dst_addr: Some(dst),
+            })
+        };
+        let req = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec();
+        handler
+            .process(req, metrics(client, server, None))
+            .await
+            .unwrap();
+        let latency = Some(Duration::from_millis(1));
+        let res = handler
+            .process(b"+OK\r\n".to_vec(), metrics(server, client, latency))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.client_addr, Some(client));
+        assert_eq!(res.server_addr, Some(server));
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_forgotten() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        handler
+            .process(b"*1\r\n$4\r\nPING\r\n".to_vec(), request_metrics(2))
+            .await
+            .unwrap();
+        handler
+            .close_connection(RequestId::Connection(1).connection())
+            .await;
+        let key_map = handler.key_map.lock().await;
+        assert_eq!(key_map.connections.len(), 1);
+        assert!(key_map
+            .connections
+            .contains_key(&RequestId::Connection(2).connection()));
+        assert_eq!(key_map.by_use.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_keyless_request() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*1\r\n$4\r\nping\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"+PONG\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command, "PING");
+        assert_eq!(res.key, NO_KEY);
+        assert!(!res.is_error);
+    }
+
+    #[tokio::test]
+    async fn test_arity_mismatch_rejected() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let get = b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec();
+        handler.process(get, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"$1\r\nv\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("GET", "key"));
+
+        // GET without its key isn't queued, so the response has nothing to pair with
+        let get = b"*1\r\n$3\r\nGET\r\n".to_vec();
+        assert!(handler.process(get, request_metrics(1)).await.is_err());
+        assert!(handler
+            .process(b"$1\r\nv\r\n".to_vec(), response_metrics(1))
+            .await
+            .is_err());
+
+        // Responses aren't commands, even when they start with a command's name
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let get = b"*2\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n".to_vec();
+        assert!(handler.process(get, request_metrics(1)).await.is_err());
+        let lrange = b"*4\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n".to_vec();
+        handler.process(lrange, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"*1\r\n$3\r\nGET\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command, "LRANGE");
+    }
+
+    #[tokio::test]
+    async fn test_hash_field_label() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*3\r\n$4\r\nhget\r\n$7\r\nuser:42\r\n$5\r\nemail\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"$5\r\na@b.c\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command, "HGET");
+        assert_eq!(res.key, "user:42:email");
+    }
+
+    #[tokio::test]
+    async fn test_script_label() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let sha = "e0e1f9fabfc9d4800c877a703b823ac0578ff8db";
+        let evalsha = format!(
+            "*5\r\n$7\r\nEVALSHA\r\n$40\r\n{}\r\n$1\r\n1\r\n$3\r\nkey\r\n$3\r\narg\r\n",
+            sha
+        );
+        handler
+            .process(evalsha.into_bytes(), request_metrics(1))
+            .await
+            .unwrap();
+        let res = handler
+            .process(b":1\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("EVALSHA", sha));
+
+        // The script's own SHA1, so EVAL and EVALSHA of a script aggregate by label
+        let eval = b"*3\r\n$4\r\neval\r\n$8\r\nreturn 1\r\n$1\r\n0\r\n".to_vec();
+        handler.process(eval, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b":1\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("EVAL", sha));
+
+        let fcall = b"*3\r\n$5\r\nFCALL\r\n$5\r\nmyfun\r\n$1\r\n0\r\n".to_vec();
+        handler.process(fcall, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b":1\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.key, "myfun");
+    }
+
+    #[tokio::test]
+    async fn test_dump_unparsed() {
+        let buf = BufWriter::default();
+        let _guard = tracing::subscriber::set_default(logging::subscriber(
+            Level::DEBUG,
+            LogFormat::Text,
+            buf.clone(),
+        ));
+
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let _ = handler.process(b"HELLO?".to_vec(), None).await;
+        assert!(buf.contents().is_empty());
+
+        let handler = RespHandler::new(
+            6379,
+            RespConfig {
+                dump_unparsed: Some(4),
+                ..Default::default()
+            },
+        );
+        assert!(handler
+            .process(b"HELLO?".to_vec(), request_metrics(1))
+            .await
+            .is_err());
+        let out = buf.contents();
+        assert!(out.contains("Unparsed payload"));
+        assert!(out.contains("00000000  48 45 4c 4c"));
+        assert!(out.contains("|HELL|"));
+        assert!(out.contains("... 2 more bytes"));
+    }
+
+    #[tokio::test]
+    async fn test_response_event() {
+        let buf = BufWriter::default();
+        let _guard = tracing::subscriber::set_default(logging::subscriber(
+            Level::DEBUG,
+            LogFormat::Json,
+            buf.clone(),
+        ));
+
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        assert!(buf.contents().is_empty());
+
+        handler
+            .process(b"-ERR nope\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap();
+        let out = buf.contents();
+        assert_eq!(out.lines().count(), 1);
+        assert!(out.contains(
+            "\"fields\":{\"message\":\"Redis response\",\"key\":\"foo\",\"latency_ms\":2,\"status\":\"ERR\"}"
+        ));
+    }
+
+    async fn respond(handler: &RespHandler, response: &[u8]) -> RedisResult {
+        let req = b"*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        handler
+            .process(response.to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_classification() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        assert!(!respond(&handler, b"$5\r\nERROR\r\n").await.is_error);
+        assert!(!respond(&handler, b"+ERROR is fine\r\n").await.is_error);
+        assert!(
+            respond(&handler, b"-ERR unknown command\r\n")
+                .await
+                .is_error
+        );
+        assert!(respond(&handler, b"!10\r\nERR failed\r\n").await.is_error);
+        // Attributes ahead of the reply don't hide what it is
+        assert!(
+            respond(&handler, b"|1\r\n+a\r\n:1\r\n-ERR failed\r\n")
+                .await
+                .is_error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_custom_error_classifier() {
+        let handler = RespHandler::new(
+            6379,
+            RespConfig {
+                // Cache misses (RESP3 nulls) count as errors, error replies don't
+                is_error: |buf, _| buf.starts_with(b"_"),
+                ..Default::default()
+            },
+        );
+        assert!(respond(&handler, b"_\r\n").await.is_error);
+        assert!(
+            !respond(&handler, b"-ERR unknown command\r\n")
+                .await
+                .is_error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_auth_redacted() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"+OK\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command, "AUTH");
+        assert_eq!(res.key, "***");
+        assert!(!ProcessedResult::from(res).to_json().contains("secret"));
+    }
+
+    #[tokio::test]
+    async fn test_pubsub_message() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let req = b"*2\r\n$9\r\nSUBSCRIBE\r\n$4\r\nnews\r\n".to_vec();
+        handler.process(req, request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(
+                b">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n".to_vec(),
+                response_metrics(1),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.kind, RedisResultKind::Request);
+        assert_eq!(res.command, "SUBSCRIBE");
+
+        // Messages arrive unprompted, without pairing metrics
+        let push = b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n".to_vec();
+        let res = handler.process(push, None).await.unwrap().unwrap();
+        assert_eq!(res.kind, RedisResultKind::PubSub);
+        assert_eq!(res.key, "news");
+        match ProcessedResult::from(res) {
+            ProcessedResult::PubSub(res) => {
+                assert_eq!(res.channel, "news");
+                assert_eq!(res.kind, "message");
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // RESP2 pattern subscriptions report the concrete channel
+        let push = b"*4\r\n$8\r\npmessage\r\n$3\r\nn.*\r\n$6\r\nn.blog\r\n$2\r\nhi\r\n".to_vec();
+        let res = handler.process(push, None).await.unwrap().unwrap();
+        assert_eq!(res.key, "n.blog");
+        assert_eq!(res.command, "pmessage");
+    }
+
+    #[tokio::test]
+    async fn test_push_between_request_and_reply() {
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let get = |key: &str| format!("*2\r\n$3\r\nGET\r\n$1\r\n{}\r\n", key).into_bytes();
+        // Client side caching invalidates a key while `GET a` is in flight. The
+        // observer pairs the push, so the reply that follows comes unpaired.
+        handler.process(get("a"), request_metrics(1)).await.unwrap();
+        let invalidate = b">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\na\r\n".to_vec();
+        let res = handler
+            .process(invalidate, response_metrics(1))
+            .await
+            .unwrap();
+        assert!(res.is_none());
+        let res = handler
+            .process(b"$1\r\nx\r\n".to_vec(), None)
+            .await
+            .unwrap();
+        assert!(res.is_none());
+        assert!(handler.key_map.lock().await.connections.is_empty());
+
+        // A message is still reported, without taking the place of the reply
+        handler.process(get("b"), request_metrics(1)).await.unwrap();
+        let message = b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n".to_vec();
+        let res = handler
+            .process(message, response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.kind, RedisResultKind::PubSub);
+        assert_eq!(res.key, "news");
+
+        handler.process(get("c"), request_metrics(1)).await.unwrap();
+        let res = handler
+            .process(b"$1\r\ny\r\n".to_vec(), response_metrics(1))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("GET", "c"));
+    }
 
-        Ok(None)
+    #[test]
+    fn test_response_size() {
+        let cfg = ParserConfig::default();
+        let size = |buf: &[u8]| response_size(buf, &parse_resp_ref(buf, &cfg).unwrap().1);
+        assert_eq!(size(b":12\r\n"), Some(12));
+        assert_eq!(size(b"$3\r\nabc\r\n"), Some(3));
+        assert_eq!(size(b"*2\r\n:1\r\n:2\r\n"), Some(2));
+        assert_eq!(size(b"+OK\r\n"), None);
     }
 }
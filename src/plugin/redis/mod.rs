@@ -1,2 +1,3 @@
 pub mod handler;
-mod resp_parser;
+mod redact;
+pub mod resp_parser;
@@ -0,0 +1,135 @@
+use super::resp_parser::RespValueRef;
+
+pub const REDACTED: &str = "***";
+
+/// Which requests have their key and value masked before they reach any result.
+#[derive(Debug, Clone)]
+pub struct RedactConfig {
+    /// Commands whose arguments are always secret, matched case-insensitively.
+    pub commands: Vec<String>,
+    /// Glob patterns, as accepted by Redis' `KEYS`, for keys holding secrets.
+    pub key_patterns: Vec<String>,
+}
+
+impl Default for RedactConfig {
+    fn default() -> Self {
+        RedactConfig {
+            commands: ["AUTH", "HELLO", "MIGRATE"]
+                .iter()
+                .map(|c| c.to_string())
+                .collect(),
+            key_patterns: vec![],
+        }
+    }
+}
+
+/// Mask the key, field and value of a sensitive request in place. `CONFIG SET requirepass`
+/// carries the password past the fields a `RespValue` keeps, so it never needs masking.
+pub fn redact(cfg: &RedactConfig, value: &mut RespValueRef) {
+    let sensitive_command = value.command.is_some_and(|c| {
+        cfg.commands
+            .iter()
+            .any(|s| s.as_bytes().eq_ignore_ascii_case(c))
+    });
+    let sensitive_key = value
+        .key
+        .is_some_and(|k| cfg.key_patterns.iter().any(|p| glob_match(p.as_bytes(), k)));
+    if sensitive_command || sensitive_key {
+        for part in [&mut value.key, &mut value.field, &mut value.value] {
+            if part.is_some() {
+                *part = Some(REDACTED.as_bytes());
+            }
+        }
+    }
+}
+
+/// Match `*` (any run of bytes) and `?` (any single byte); everything else is literal.
+///
+/// Keys come off the wire, so this must not backtrack without bound. Only the last
+/// `*` is ever returned to: everything before it already matched, and letting it take
+/// one more byte covers whatever an earlier `*` could have. This is O(pattern * key)
+/// and doesn't recurse.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // The last `*` seen and where in `s` its match currently ends
+    let mut star: Option<(usize, usize)> = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p + 1;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p.min(pattern.len())..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match(b"session:*", b"session:42"));
+        assert!(glob_match(b"*token*", b"api_token_v2"));
+        assert!(glob_match(b"user:?", b"user:1"));
+        assert!(!glob_match(b"user:?", b"user:12"));
+        assert!(!glob_match(b"session:*", b"sessions"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"a*b*", b"axxbyy"));
+        assert!(glob_match(b"*ab", b"aab"));
+        assert!(!glob_match(b"a*b", b"axxbx"));
+        assert!(!glob_match(b"?", b""));
+    }
+
+    #[test]
+    fn test_glob_match_adversarial_key() {
+        // Backtracking over every `*` would take exponential time here, and recursing
+        // per byte would overflow the stack
+        let key = vec![b'a'; 100_000];
+        let started = std::time::Instant::now();
+        assert!(!glob_match(b"*a*a*a*a*a*a*a*b", &key));
+        assert!(glob_match(b"*a*a*a*a*a*a*a*", &key));
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_redact_key_pattern() {
+        let cfg = RedactConfig {
+            key_patterns: vec!["secret:*".to_string()],
+            ..Default::default()
+        };
+        let mut value = RespValueRef {
+            command: Some(b"HSET"),
+            key: Some(b"secret:db"),
+            field: Some(b"password"),
+            value: Some(b"hunter2"),
+            ..Default::default()
+        };
+        redact(&cfg, &mut value);
+        assert_eq!(value.command, Some(&b"HSET"[..]));
+        assert_eq!(value.key, Some(REDACTED.as_bytes()));
+        assert_eq!(value.field, Some(REDACTED.as_bytes()));
+        assert_eq!(value.value, Some(REDACTED.as_bytes()));
+
+        let mut value = RespValueRef {
+            command: Some(b"GET"),
+            key: Some(b"public"),
+            ..Default::default()
+        };
+        redact(&cfg, &mut value);
+        assert_eq!(value.key, Some(&b"public"[..]));
+    }
+}
@@ -2,15 +2,21 @@ use nom::{
     branch::alt,
     bytes::complete::{tag, take, take_while},
     character::complete::char,
+    combinator::{opt, recognize},
+    error::{Error, ErrorKind},
+    sequence::pair,
     IResult,
 };
 
+use crate::json;
 use std::{fmt, str};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct RespValue {
     pub command: Option<String>,
     pub key: Option<String>,
+    /// The field of a hash command, e.g. `f` in `HGET h f`.
+    pub field: Option<String>,
     pub value: Option<String>,
 }
 
@@ -18,127 +24,725 @@ impl fmt::Display for RespValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "RespValue {{ command: {:?}, key: {:?}, value: {:?} }}",
-            self.command, self.key, self.value
+            "RespValue {{ command: {:?}, key: {:?}, field: {:?}, value: {:?} }}",
+            self.command, self.key, self.field, self.value
         )
     }
 }
 
+impl RespValue {
+    /// Serialize the value as a single JSON object, with absent parts as null.
+    pub fn to_json(&self) -> String {
+        let field =
+            |v: &Option<String>| v.as_deref().map_or_else(|| "null".to_string(), json::quote);
+        format!(
+            "{{\"command\":{},\"key\":{},\"field\":{},\"value\":{}}}",
+            field(&self.command),
+            field(&self.key),
+            field(&self.field),
+            field(&self.value)
+        )
+    }
+}
+
+/// Limits applied while parsing untrusted RESP input.
+#[derive(Debug, Clone, Copy)]
+pub struct ParserConfig {
+    /// Maximum nesting depth of aggregate types before parsing fails.
+    pub max_depth: usize,
+    /// Maximum declared length of a bulk string, mirroring Redis' proto-max-bulk-len.
+    pub max_bulk_len: usize,
+    /// Maximum number of elements of an aggregate, counting each entry of a map once,
+    /// mirroring the multibulk length Redis accepts.
+    pub max_elements: usize,
+    /// Accept input cut short, as when only the head of a payload is parsed: a bulk
+    /// string running past the end keeps the bytes present, and an aggregate ending
+    /// early keeps the elements parsed so far.
+    pub allow_truncated: bool,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        ParserConfig {
+            max_depth: 32,
+            max_bulk_len: 512 * 1024 * 1024,
+            max_elements: 1024 * 1024,
+            allow_truncated: false,
+        }
+    }
+}
+
+/// A RESP value borrowing its parts from the parsed input, so parsing allocates only
+/// for aggregates. Convert it with `into_owned` once it has to outlive the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RespValueRef<'a> {
+    pub command: Option<&'a [u8]>,
+    pub key: Option<&'a [u8]>,
+    pub field: Option<&'a [u8]>,
+    pub value: Option<&'a [u8]>,
+    /// Number of elements a command style array declared, counting the command
+    /// itself, even when the input was cut short. 0 for other values.
+    pub args: usize,
+}
+
+impl RespValueRef<'_> {
+    /// Copy the parts out of the input. Invalid UTF-8 is replaced, as a payload cut
+    /// short may split a multi-byte character.
+    pub fn into_owned(self) -> RespValue {
+        let owned = |v: Option<&[u8]>| v.map(|v| String::from_utf8_lossy(v).into_owned());
+        RespValue {
+            command: owned(self.command),
+            key: owned(self.key),
+            field: owned(self.field),
+            value: owned(self.value),
+        }
+    }
+}
+
+impl From<RespValueRef<'_>> for RespValue {
+    fn from(value: RespValueRef<'_>) -> RespValue {
+        value.into_owned()
+    }
+}
+
 fn is_digit(c: u8) -> bool {
     c.is_ascii_digit()
 }
 
-fn parse_simple_string(input: &[u8]) -> IResult<&[u8], RespValue> {
+fn parse_length<'a>(input: &'a [u8], digits: &[u8]) -> Result<usize, nom::Err<Error<&'a [u8]>>> {
+    str::from_utf8(digits)
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .ok_or_else(|| nom::Err::Failure(Error::new(input, ErrorKind::Digit)))
+}
+
+fn parse_simple_string(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
     let (input, _) = char('+')(input)?;
-    let (input, s) = take_while(|c| c != b'\r')(input)?;
-    let (input, _) = tag("\r\n")(input)?;
-    let command = str::from_utf8(s).unwrap().to_string();
+    let (input, s) = parse_line(input)?;
     Ok((
         input,
-        RespValue {
-            command: Some(command),
-            key: None,
-            value: None,
+        RespValueRef {
+            command: Some(s),
+            ..Default::default()
         },
     ))
 }
 
-fn parse_error(input: &[u8]) -> IResult<&[u8], RespValue> {
+fn parse_error(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
     let (input, _) = char('-')(input)?;
-    let (input, s) = take_while(|c| c != b'\r')(input)?;
-    let (input, _) = tag("\r\n")(input)?;
-    let command = str::from_utf8(s).unwrap().to_string();
+    let (input, s) = parse_line(input)?;
     Ok((
         input,
-        RespValue {
-            command: Some(command),
-            key: None,
-            value: None,
+        RespValueRef {
+            command: Some(s),
+            ..Default::default()
         },
     ))
 }
 
-fn parse_integer(input: &[u8]) -> IResult<&[u8], RespValue> {
+fn parse_integer(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
     let (input, _) = char(':')(input)?;
-    let (input, s) = take_while(is_digit)(input)?;
+    let (input, s) = recognize(pair(opt(char('-')), take_while(is_digit)))(input)?;
     let (input, _) = tag("\r\n")(input)?;
-    let value = str::from_utf8(s).unwrap().to_string();
-    Ok((
-        input,
-        RespValue {
-            command: None,
-            key: None,
-            value: Some(value),
-        },
-    ))
+    Ok((input, scalar(Some(s))))
 }
 
-fn parse_bulk_string(input: &[u8]) -> IResult<&[u8], RespValue> {
+fn parse_bulk_string<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
     let (input, _) = char('$')(input)?;
+    // RESP2 null bulk string, as `GET` replies for a missing key
+    if let Ok((input, _)) = tag::<_, _, Error<&[u8]>>("-1\r\n")(input) {
+        return Ok((input, scalar(None)));
+    }
     let (input, length_str) = take_while(is_digit)(input)?;
-    let length = str::from_utf8(length_str)
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
-    let (input, _) = tag("\r\n")(input)?;
-    let (input, data) = take(length)(input)?;
+    let length = parse_length(input, length_str)?;
+    // Reject oversized claims before trusting them for `take`.
+    if length > cfg.max_bulk_len {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
     let (input, _) = tag("\r\n")(input)?;
-    let value = if data.is_empty() {
-        None
+    let (input, data) = if cfg.allow_truncated && input.len() < length + 2 {
+        // Skip the rest of the body, it isn't there
+        (&input[input.len()..], &input[..input.len().min(length)])
     } else {
-        Some(str::from_utf8(data).unwrap().to_string())
+        let (input, data) = take(length)(input)?;
+        let (input, _) = tag("\r\n")(input)?;
+        (input, data)
     };
+    Ok((input, scalar(Some(data).filter(|d| !d.is_empty()))))
+}
+
+/// RESP3 streamed string, `$?\r\n` followed by `;<len>\r\n<data>\r\n` chunks up to
+/// an empty `;0\r\n`. The chunks can't be joined without copying, so the value is
+/// the first one. `max_bulk_len` bounds their total length.
+fn parse_streamed_string<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (mut input, _) = tag("$?\r\n")(input)?;
+    let mut first = None;
+    let mut total = 0usize;
+    loop {
+        let mut chunk = |input| -> IResult<&'a [u8], &'a [u8]> {
+            let (input, _) = char(';')(input)?;
+            let (input, length_str) = take_while(is_digit)(input)?;
+            let length = parse_length(input, length_str)?;
+            total = total.saturating_add(length);
+            if total > cfg.max_bulk_len {
+                return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+            }
+            let (input, _) = tag("\r\n")(input)?;
+            if length == 0 {
+                return Ok((input, &[]));
+            }
+            let (input, data) = take(length)(input)?;
+            let (input, _) = tag("\r\n")(input)?;
+            Ok((input, data))
+        };
+        let (rest, data) = match chunk(input) {
+            Ok(parsed) => parsed,
+            // The input ended partway through this chunk
+            Err(nom::Err::Error(_)) if cfg.allow_truncated => {
+                return Ok((&input[input.len()..], scalar(first)))
+            }
+            Err(e) => return Err(e),
+        };
+        input = rest;
+        if data.is_empty() {
+            return Ok((input, scalar(first)));
+        }
+        first.get_or_insert(data);
+    }
+}
+
+fn scalar(value: Option<&[u8]>) -> RespValueRef<'_> {
+    RespValueRef {
+        value,
+        ..Default::default()
+    }
+}
 
+fn parse_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (input, s) = take_while(|c| c != b'\r')(input)?;
+    let (input, _) = tag("\r\n")(input)?;
+    Ok((input, s))
+}
+
+fn utf8_line(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    let (rest, s) = parse_line(input)?;
+    str::from_utf8(s).map_err(|_| nom::Err::Failure(Error::new(input, ErrorKind::Char)))?;
+    Ok((rest, s))
+}
+
+// RESP3 double, e.g. `,1.23\r\n` or `,inf\r\n`
+fn parse_double(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
+    let (input, _) = char(',')(input)?;
+    let (input, s) = utf8_line(input)?;
+    Ok((input, scalar(Some(s))))
+}
+
+// RESP3 boolean, `#t\r\n` or `#f\r\n`
+fn parse_boolean(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
+    let (input, _) = char('#')(input)?;
+    let (input, b) = alt((char('t'), char('f')))(input)?;
+    let (input, _) = tag("\r\n")(input)?;
+    let value: &[u8] = if b == 't' { b"true" } else { b"false" };
+    Ok((input, scalar(Some(value))))
+}
+
+// RESP3 big number, e.g. `(3492890328409238509324850943850943825024385\r\n`
+fn parse_big_number(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
+    let (input, _) = char('(')(input)?;
+    let (input, s) = utf8_line(input)?;
+    Ok((input, scalar(Some(s))))
+}
+
+// RESP3 null, `_\r\n`
+fn parse_null(input: &[u8]) -> IResult<&[u8], RespValueRef<'_>> {
+    let (input, _) = tag("_\r\n")(input)?;
+    Ok((input, scalar(None)))
+}
+
+// RESP3 verbatim string, e.g. `=15\r\ntxt:Some string\r\n`. The encoding prefix is dropped.
+fn parse_verbatim_string<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, _) = char('=')(input)?;
+    let (input, length_str) = take_while(is_digit)(input)?;
+    let length = parse_length(input, length_str)?;
+    if length > cfg.max_bulk_len {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    let (input, _) = tag("\r\n")(input)?;
+    let (input, data) = take(length)(input)?;
+    let (input, _) = tag("\r\n")(input)?;
+    let text = data
+        .get(4..)
+        .filter(|_| data.get(3) == Some(&b':'))
+        .unwrap_or(data);
+    Ok((input, scalar(Some(text))))
+}
+
+/// RESP3 blob error, e.g. `!21\r\nSYNTAX invalid syntax\r\n`. Like a simple error
+/// the message is kept as the command.
+fn parse_blob_error<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, _) = char('!')(input)?;
+    let (input, length_str) = take_while(is_digit)(input)?;
+    let length = parse_length(input, length_str)?;
+    if length > cfg.max_bulk_len {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    let (input, _) = tag("\r\n")(input)?;
+    let (input, data) = take(length)(input)?;
+    let (input, _) = tag("\r\n")(input)?;
     Ok((
         input,
-        RespValue {
-            command: None,
-            key: None,
-            value,
+        RespValueRef {
+            command: Some(data),
+            ..Default::default()
         },
     ))
 }
 
-fn parse_array(input: &[u8]) -> IResult<&[u8], RespValue> {
-    let (input, _) = char('*')(input)?;
+/// Parse the header and elements of an aggregate type. `per_entry` is the number
+/// of values each declared entry holds (2 for maps). Returns the number of values
+/// declared along with those parsed, which are fewer when the input was cut short.
+///
+/// A RESP3 streamed aggregate declares `?` instead of a length and ends with `.\r\n`.
+fn parse_aggregate<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+    prefix: char,
+    per_entry: usize,
+) -> IResult<&'a [u8], (usize, Vec<RespValueRef<'a>>)> {
+    let (input, _) = char(prefix)(input)?;
+    // Bail out before recursing so deeply nested arrays can't exhaust the stack.
+    if depth >= cfg.max_depth {
+        return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+    }
+    // Each value of a map entry is an element of its own
+    let max_values = cfg.max_elements.saturating_mul(per_entry);
+    if let Ok((input, _)) = tag::<_, _, Error<&[u8]>>("?\r\n")(input) {
+        let (input, values) = parse_streamed_elements(input, cfg, depth, max_values)?;
+        return Ok((input, (values.len(), values)));
+    }
+    // RESP2 null array, as `BLPOP` replies when it times out
+    if let Ok((input, _)) = tag::<_, _, Error<&[u8]>>("-1\r\n")(input) {
+        return Ok((input, (0, vec![])));
+    }
     let (input, length_str) = take_while(is_digit)(input)?;
-    let length = str::from_utf8(length_str)
-        .unwrap()
-        .parse::<usize>()
-        .unwrap();
+    // Reject oversized claims before parsing any element
+    let length = parse_length(input, length_str)?
+        .checked_mul(per_entry)
+        .filter(|&length| length <= max_values)
+        .ok_or_else(|| nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)))?;
     let (input, _) = tag("\r\n")(input)?;
     let mut input = input;
 
-    let mut values = Vec::with_capacity(length);
+    // Every element takes at least 3 bytes, so a short payload claiming many of them
+    // doesn't get to allocate for all
+    let mut values = Vec::with_capacity(length.min(input.len() / 3));
     for _ in 0..length {
-        let (new_input, value) = parse_resp(input)?;
+        let (new_input, value) = match parse_value(input, cfg, depth + 1) {
+            Ok(parsed) => parsed,
+            // The input ended partway through this element
+            Err(nom::Err::Error(_)) if cfg.allow_truncated => {
+                return Ok((&input[input.len()..], (length, values)))
+            }
+            Err(e) => return Err(e),
+        };
+        input = new_input;
+        values.push(value);
+        if cfg.allow_truncated && input.is_empty() {
+            break;
+        }
+    }
+    Ok((input, (length, values)))
+}
+
+/// Elements of a streamed aggregate up to its `.\r\n` end marker, failing past
+/// `max_values` of them.
+fn parse_streamed_elements<'a>(
+    mut input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+    max_values: usize,
+) -> IResult<&'a [u8], Vec<RespValueRef<'a>>> {
+    let mut values = vec![];
+    loop {
+        if let Ok((rest, _)) = tag::<_, _, Error<&[u8]>>(".\r\n")(input) {
+            return Ok((rest, values));
+        }
+        if values.len() >= max_values {
+            return Err(nom::Err::Failure(Error::new(input, ErrorKind::TooLarge)));
+        }
+        let (new_input, value) = match parse_value(input, cfg, depth + 1) {
+            Ok(parsed) => parsed,
+            Err(nom::Err::Error(_)) if cfg.allow_truncated => {
+                return Ok((&input[input.len()..], values))
+            }
+            Err(e) => return Err(e),
+        };
         input = new_input;
         values.push(value);
     }
+}
+
+/// Where a command keeps its key, field and value among its arguments, counting
+/// the command itself as 0.
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    key: Option<usize>,
+    field: Option<usize>,
+    value: Option<usize>,
+}
+
+const POSITIONAL: Layout = Layout {
+    key: Some(1),
+    field: None,
+    value: Some(2),
+};
+// Every argument is a key, or the rest are options rather than a value
+const KEYS: Layout = Layout {
+    key: Some(1),
+    field: None,
+    value: None,
+};
+// The script, its SHA1 or the function name stands in for the key. The keys the
+// script touches follow the number of them
+const SCRIPT: Layout = Layout {
+    key: Some(1),
+    field: None,
+    value: None,
+};
+// A message to a pattern subscription: the channel it was published to stands in
+// for the key, the pattern for the field and the message for the value
+const PMESSAGE: Layout = Layout {
+    key: Some(2),
+    field: Some(1),
+    value: Some(3),
+};
+const HASH_FIELD: Layout = Layout {
+    key: Some(1),
+    field: Some(2),
+    value: None,
+};
+const HASH_VALUE: Layout = Layout {
+    key: Some(1),
+    field: Some(2),
+    value: Some(3),
+};
+
+const LAYOUTS: &[(&str, Layout)] = &[
+    ("MGET", KEYS),
+    ("DEL", KEYS),
+    ("UNLINK", KEYS),
+    ("EXISTS", KEYS),
+    ("TOUCH", KEYS),
+    ("WATCH", KEYS),
+    ("GETEX", KEYS),
+    ("SINTER", KEYS),
+    ("SUNION", KEYS),
+    ("SDIFF", KEYS),
+    ("PFCOUNT", KEYS),
+    ("BLPOP", KEYS),
+    ("BRPOP", KEYS),
+    ("HGET", HASH_FIELD),
+    ("HMGET", HASH_FIELD),
+    ("HDEL", HASH_FIELD),
+    ("HEXISTS", HASH_FIELD),
+    ("HSTRLEN", HASH_FIELD),
+    ("HSET", HASH_VALUE),
+    ("HSETNX", HASH_VALUE),
+    ("HMSET", HASH_VALUE),
+    ("HINCRBY", HASH_VALUE),
+    ("HINCRBYFLOAT", HASH_VALUE),
+    ("EVAL", SCRIPT),
+    ("EVAL_RO", SCRIPT),
+    ("EVALSHA", SCRIPT),
+    ("EVALSHA_RO", SCRIPT),
+    ("FCALL", SCRIPT),
+    ("FCALL_RO", SCRIPT),
+    ("PMESSAGE", PMESSAGE),
+];
+
+/// Map the elements of a command style aggregate to command, key, field and value.
+/// Known commands pick the elements that mean those; anything else maps the first
+/// three in order, which suits the common `SET key value`.
+fn positional<'a>(values: &[RespValueRef<'a>], commands: bool) -> RespValueRef<'a> {
+    let command = values.first().and_then(|v| v.value);
+    let layout = command
+        .filter(|_| commands)
+        .and_then(|c| {
+            LAYOUTS
+                .iter()
+                .find(|(name, _)| name.as_bytes().eq_ignore_ascii_case(c))
+        })
+        .map_or(POSITIONAL, |&(_, layout)| layout);
+    let arg = |i: Option<usize>| i.and_then(|i| values.get(i)).and_then(|v| v.value);
+    RespValueRef {
+        command,
+        key: arg(layout.key),
+        field: arg(layout.field),
+        value: arg(layout.value),
+        ..Default::default()
+    }
+}
+
+/// Number of arguments commands take, counting the command itself, as Redis reports
+/// them in `COMMAND INFO`: exactly that many when positive, at least `-arity` when
+/// negative.
+const ARITY: &[(&str, i32)] = &[
+    ("GET", 2),
+    ("SET", -3),
+    ("SETNX", 3),
+    ("SETEX", 4),
+    ("PSETEX", 4),
+    ("GETSET", 3),
+    ("GETDEL", 2),
+    ("GETEX", -2),
+    ("MGET", -2),
+    ("MSET", -3),
+    ("APPEND", 3),
+    ("STRLEN", 2),
+    ("INCR", 2),
+    ("DECR", 2),
+    ("INCRBY", 3),
+    ("DECRBY", 3),
+    ("DEL", -2),
+    ("UNLINK", -2),
+    ("EXISTS", -2),
+    ("TOUCH", -2),
+    ("TYPE", 2),
+    ("EXPIRE", -3),
+    ("PEXPIRE", -3),
+    ("PERSIST", 2),
+    ("TTL", 2),
+    ("PTTL", 2),
+    ("KEYS", 2),
+    ("SCAN", -2),
+    ("LPUSH", -3),
+    ("RPUSH", -3),
+    ("LPOP", -2),
+    ("RPOP", -2),
+    ("LLEN", 2),
+    ("LINDEX", 3),
+    ("LRANGE", 4),
+    ("LSET", 4),
+    ("LREM", 4),
+    ("LTRIM", 4),
+    ("BLPOP", -3),
+    ("BRPOP", -3),
+    ("SADD", -3),
+    ("SREM", -3),
+    ("SCARD", 2),
+    ("SMEMBERS", 2),
+    ("SISMEMBER", 3),
+    ("SINTER", -2),
+    ("SUNION", -2),
+    ("SDIFF", -2),
+    ("ZADD", -4),
+    ("ZREM", -3),
+    ("ZCARD", 2),
+    ("ZSCORE", 3),
+    ("ZINCRBY", 4),
+    ("ZRANK", -3),
+    ("ZRANGE", -4),
+    ("HGET", 3),
+    ("HSET", -4),
+    ("HSETNX", 4),
+    ("HMSET", -4),
+    ("HMGET", -3),
+    ("HDEL", -3),
+    ("HEXISTS", 3),
+    ("HSTRLEN", 3),
+    ("HINCRBY", 4),
+    ("HINCRBYFLOAT", 4),
+    ("HGETALL", 2),
+    ("HKEYS", 2),
+    ("HVALS", 2),
+    ("HLEN", 2),
+    ("PFADD", -2),
+    ("PFCOUNT", -2),
+    ("WATCH", -2),
+    ("MULTI", 1),
+    ("EXEC", 1),
+    ("DISCARD", 1),
+    ("PING", -1),
+    ("ECHO", 2),
+    ("SELECT", 2),
+    ("AUTH", -2),
+    ("INFO", -1),
+    ("DBSIZE", 1),
+    ("PUBLISH", 3),
+    ("SUBSCRIBE", -2),
+    ("EVAL", -3),
+    ("EVALSHA", -3),
+    ("EVAL_RO", -3),
+    ("EVALSHA_RO", -3),
+    ("FCALL", -3),
+    ("FCALL_RO", -3),
+];
 
-    let command = values.first().and_then(|v| v.value.clone());
-    let key = values.get(1).and_then(|v| v.value.clone());
-    let value = values.get(2).and_then(|v| v.value.clone());
+/// Whether a command style array has as many arguments as its command takes. A
+/// mismatch means the bytes likely weren't a request, as when parsing starts out of
+/// step with the stream. Values other than arrays, and commands missing from the
+/// table, pass.
+pub fn valid_arity(value: &RespValueRef) -> bool {
+    let Some(command) = value.command.filter(|_| value.args > 0) else {
+        return true;
+    };
+    let args = value.args as i64;
+    ARITY
+        .iter()
+        .find(|(name, _)| name.as_bytes().eq_ignore_ascii_case(command))
+        .is_none_or(|&(_, arity)| match i64::from(arity) {
+            arity if arity < 0 => args >= -arity,
+            arity => args == arity,
+        })
+}
 
+fn parse_array<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, (args, values)) = parse_aggregate(input, cfg, depth, '*', 1)?;
     Ok((
         input,
-        RespValue {
-            command,
-            key,
-            value,
+        RespValueRef {
+            args,
+            ..positional(&values, true)
         },
     ))
 }
 
+// RESP3 set, laid out like an array
+fn parse_set<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, (_, values)) = parse_aggregate(input, cfg, depth, '~', 1)?;
+    Ok((input, positional(&values, false)))
+}
+
+// RESP3 push, e.g. `>3\r\n$7\r\nmessage\r\n$7\r\nchannel\r\n$5\r\nhello\r\n`
+fn parse_push<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, (_, values)) = parse_aggregate(input, cfg, depth, '>', 1)?;
+    // Mapped in order, except a pattern message is laid out like its RESP2 array
+    let pmessage = values
+        .first()
+        .and_then(|v| v.value)
+        .is_some_and(|c| c.eq_ignore_ascii_case(b"pmessage"));
+    Ok((input, positional(&values, pmessage)))
+}
+
+// RESP3 map. There's no single scalar to extract, so only the framing is consumed.
+fn parse_map<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, _) = parse_aggregate(input, cfg, depth, '%', 2)?;
+    Ok((input, scalar(None)))
+}
+
+// RESP3 attribute, e.g. `|1\r\n+ttl\r\n:3600\r\n` ahead of the reply it describes. The
+// attributes are metadata about the reply rather than part of it, so they're skipped
+// and the reply that follows is returned.
+fn parse_attribute<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let (input, _) = parse_aggregate(input, cfg, depth, '|', 2)?;
+    if cfg.allow_truncated && input.is_empty() {
+        return Ok((input, scalar(None)));
+    }
+    // Counted as a level, so a run of attributes can't recurse without limit
+    parse_value(input, cfg, depth + 1)
+}
+
+/// The reply in `input` without the attributes ahead of it, for reading it from its
+/// raw bytes. Input that doesn't start with a complete attribute is returned as is.
+pub fn skip_attributes<'a>(mut input: &'a [u8], cfg: &ParserConfig) -> &'a [u8] {
+    while input.first() == Some(&b'|') {
+        match parse_aggregate(input, cfg, 0, '|', 2) {
+            Ok((rest, _)) if rest.len() < input.len() => input = rest,
+            _ => break,
+        }
+    }
+    input
+}
+
 // General RESP parser that chooses the correct type
-pub fn parse_resp(input: &[u8]) -> IResult<&[u8], RespValue> {
-    alt((
-        parse_simple_string,
-        parse_error,
-        parse_integer,
-        parse_bulk_string,
-        parse_array,
-    ))(input)
+pub fn parse_resp<'a>(input: &'a [u8], cfg: &ParserConfig) -> IResult<&'a [u8], RespValue> {
+    let (input, value) = parse_resp_ref(input, cfg)?;
+    Ok((input, value.into_owned()))
+}
+
+/// Like `parse_resp`, but the value borrows from `input` instead of copying it.
+pub fn parse_resp_ref<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    parse_value(input, cfg, 0)
+}
+
+/// Read a value with the parser of the type its first byte announces. A byte that
+/// starts no RESP type fails outright, so a corrupt or misaligned payload is never
+/// partly taken for some other type.
+fn parse_value<'a>(
+    input: &'a [u8],
+    cfg: &ParserConfig,
+    depth: usize,
+) -> IResult<&'a [u8], RespValueRef<'a>> {
+    let parsed = match input.first() {
+        // Recoverable, as the input may have been cut short before this value
+        None => Err(nom::Err::Error(Error::new(input, ErrorKind::Eof))),
+        Some(b'+') => parse_simple_string(input),
+        Some(b'-') => parse_error(input),
+        Some(b':') => parse_integer(input),
+        Some(b'$') if input.get(1) == Some(&b'?') => parse_streamed_string(input, cfg),
+        Some(b'$') => parse_bulk_string(input, cfg),
+        Some(b'*') => parse_array(input, cfg, depth),
+        Some(b',') => parse_double(input),
+        Some(b'#') => parse_boolean(input),
+        Some(b'(') => parse_big_number(input),
+        Some(b'_') => parse_null(input),
+        Some(b'=') => parse_verbatim_string(input, cfg),
+        Some(b'!') => parse_blob_error(input, cfg),
+        Some(b'%') => parse_map(input, cfg, depth),
+        Some(b'~') => parse_set(input, cfg, depth),
+        Some(b'>') => parse_push(input, cfg, depth),
+        Some(b'|') => parse_attribute(input, cfg, depth),
+        Some(_) => Err(nom::Err::Failure(Error::new(input, ErrorKind::Switch))),
+    };
+    advanced(input, parsed)
+}
+
+/// Fail a parse that succeeded without consuming any of `input`. Aggregates and
+/// callers reading one value after another loop until the input runs out, so such a
+/// value would have them spin forever on the same bytes.
+fn advanced<'a, T>(input: &'a [u8], parsed: IResult<&'a [u8], T>) -> IResult<&'a [u8], T> {
+    match parsed {
+        Ok((rest, _)) if rest.len() >= input.len() => {
+            Err(nom::Err::Failure(Error::new(input, ErrorKind::Many0)))
+        }
+        parsed => parsed,
+    }
 }
 
 // Unit Tests
@@ -152,9 +756,10 @@ mod tests {
         let expected = RespValue {
             command: Some("OK".to_string()),
             key: None,
+            field: None,
             value: None,
         };
-        assert_eq!(parse_simple_string(input).unwrap().1, expected);
+        assert_eq!(parse_simple_string(input).unwrap().1.into_owned(), expected);
     }
 
     #[test]
@@ -163,9 +768,23 @@ mod tests {
         let expected = RespValue {
             command: Some("Error message".to_string()),
             key: None,
+            field: None,
+            value: None,
+        };
+        assert_eq!(parse_error(input).unwrap().1.into_owned(), expected);
+    }
+
+    #[test]
+    fn test_parse_blob_error() {
+        let cfg = ParserConfig::default();
+        let input = b"!21\r\nSYNTAX invalid syntax\r\n";
+        let expected = RespValue {
+            command: Some("SYNTAX invalid syntax".to_string()),
+            key: None,
+            field: None,
             value: None,
         };
-        assert_eq!(parse_error(input).unwrap().1, expected);
+        assert_eq!(parse_resp(input, &cfg).unwrap().1, expected);
     }
 
     #[test]
@@ -174,9 +793,18 @@ mod tests {
         let expected = RespValue {
             command: None,
             key: None,
+            field: None,
             value: Some("1000".to_string()),
         };
-        assert_eq!(parse_integer(input).unwrap().1, expected);
+        assert_eq!(parse_integer(input).unwrap().1.into_owned(), expected);
+    }
+
+    #[test]
+    fn test_parse_negative_integer() {
+        let (rest, value) = parse_resp(b":-2\r\n", &ParserConfig::default()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.value.as_deref(), Some("-2"));
+        assert!(parse_resp(b":--2\r\n", &ParserConfig::default()).is_err());
     }
 
     #[test]
@@ -185,9 +813,16 @@ mod tests {
         let expected = RespValue {
             command: None,
             key: None,
+            field: None,
             value: Some("foobar".to_string()),
         };
-        assert_eq!(parse_bulk_string(input).unwrap().1, expected);
+        assert_eq!(
+            parse_bulk_string(input, &ParserConfig::default())
+                .unwrap()
+                .1
+                .into_owned(),
+            expected
+        );
     }
 
     #[test]
@@ -196,9 +831,54 @@ mod tests {
         let expected = RespValue {
             command: None,
             key: None,
+            field: None,
             value: None,
         };
-        assert_eq!(parse_bulk_string(input).unwrap().1, expected);
+        assert_eq!(
+            parse_bulk_string(input, &ParserConfig::default())
+                .unwrap()
+                .1
+                .into_owned(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_parse_null_bulk_string() {
+        let (rest, value) = parse_resp(b"$-1\r\n", &ParserConfig::default()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(
+            value,
+            parse_resp(b"_\r\n", &ParserConfig::default()).unwrap().1
+        );
+        assert!(parse_resp(b"$-2\r\n", &ParserConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_null_array() {
+        let (rest, value) = parse_resp(b"*-1\r\n", &ParserConfig::default()).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.command, None);
+        assert_eq!(value.value, None);
+        // Whatever follows is the next value
+        let (rest, _) = parse_resp(b"*-1\r\n+OK\r\n", &ParserConfig::default()).unwrap();
+        assert_eq!(rest, b"+OK\r\n");
+    }
+
+    #[test]
+    fn test_parse_bulk_string_too_large() {
+        let input = b"$999999999\r\nfoo\r\n";
+        let cfg = ParserConfig {
+            max_bulk_len: 1024,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_bulk_string(input, &cfg),
+            Err(nom::Err::Failure(_))
+        ));
+
+        let input = b"$99999999999999999999999\r\nfoo\r\n";
+        assert!(parse_resp(input, &ParserConfig::default()).is_err());
     }
 
     #[test]
@@ -207,9 +887,557 @@ mod tests {
         let expected = RespValue {
             command: Some("ECHO".to_string()),
             key: Some("key".to_string()),
+            field: None,
             value: Some("value".to_string()),
         };
-        assert_eq!(parse_array(input).unwrap().1, expected);
+        assert_eq!(
+            parse_array(input, &ParserConfig::default(), 0)
+                .unwrap()
+                .1
+                .into_owned(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_valid_arity() {
+        let cfg = ParserConfig::default();
+        let arity = |input: &[u8]| valid_arity(&parse_resp_ref(input, &cfg).unwrap().1);
+        assert!(arity(b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n"));
+        assert!(!arity(b"*1\r\n$3\r\nGET\r\n"));
+        assert!(!arity(b"*3\r\n$3\r\nget\r\n$1\r\na\r\n$1\r\nb\r\n"));
+        // SET takes options after its value
+        assert!(!arity(b"*2\r\n$3\r\nSET\r\n$1\r\nk\r\n"));
+        assert!(arity(
+            b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n9\r\n"
+        ));
+        // Unknown commands and values other than arrays pass
+        assert!(arity(b"*1\r\n$7\r\nFOOBAR!\r\n"));
+        assert!(arity(b"+GET\r\n"));
+
+        // The declared count holds when the value is cut off
+        let truncated = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        let (_, value) =
+            parse_resp_ref(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\nabc", &truncated).unwrap();
+        assert_eq!(value.args, 3);
+        assert!(valid_arity(&value));
+    }
+
+    fn parse_command(input: &[u8]) -> RespValue {
+        parse_resp(input, &ParserConfig::default()).unwrap().1
+    }
+
+    #[test]
+    fn test_command_layouts() {
+        let hget = parse_command(b"*3\r\n$4\r\nHGET\r\n$6\r\nmyhash\r\n$7\r\nmyfield\r\n");
+        assert_eq!(hget.key.as_deref(), Some("myhash"));
+        assert_eq!(hget.field.as_deref(), Some("myfield"));
+        assert_eq!(hget.value, None);
+
+        let hset = parse_command(b"*4\r\n$4\r\nhset\r\n$1\r\nh\r\n$1\r\nf\r\n$1\r\nv\r\n");
+        assert_eq!(
+            (
+                hset.key.as_deref(),
+                hset.field.as_deref(),
+                hset.value.as_deref()
+            ),
+            (Some("h"), Some("f"), Some("v"))
+        );
+
+        // Trailing options don't shift the key or value
+        let set = parse_command(
+            b"*5\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n$2\r\nEX\r\n$2\r\n10\r\n",
+        );
+        assert_eq!(
+            (set.key.as_deref(), set.field, set.value.as_deref()),
+            (Some("key"), None, Some("value"))
+        );
+
+        // Later keys aren't values
+        let mget = parse_command(b"*3\r\n$4\r\nMGET\r\n$2\r\nk1\r\n$2\r\nk2\r\n");
+        assert_eq!(
+            (mget.key.as_deref(), mget.field, mget.value),
+            (Some("k1"), None, None)
+        );
+
+        // Replies and pushes are mapped in order whatever they start with
+        let push = parse_command(b">3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(push.value.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn test_parse_truncated() {
+        let cfg = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        // The value's body is cut off
+        let (rest, value) = parse_resp(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\nabc", &cfg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.command.as_deref(), Some("SET"));
+        assert_eq!(value.key.as_deref(), Some("k"));
+        assert_eq!(value.value.as_deref(), Some("abc"));
+
+        // Cut off inside the value's header
+        let (_, value) = parse_resp(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$10", &cfg).unwrap();
+        assert_eq!(value.key.as_deref(), Some("k"));
+        assert_eq!(value.value, None);
+
+        // Complete input parses the same either way, and strict parsing rejects the cut
+        let input = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        assert_eq!(
+            parse_resp(input, &cfg).unwrap(),
+            parse_resp(input, &ParserConfig::default()).unwrap()
+        );
+        assert!(parse_resp(
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\nabc",
+            &ParserConfig::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_to_json() {
+        let (_, value) = parse_resp(
+            b"*2\r\n$3\r\nGET\r\n$3\r\na\"b\r\n",
+            &ParserConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            value.to_json(),
+            r#"{"command":"GET","key":"a\"b","field":null,"value":null}"#
+        );
+    }
+
+    #[test]
+    fn test_parse_array_too_deep() {
+        let mut input = b"*1\r\n".repeat(100_000);
+        input.extend_from_slice(b":1\r\n");
+        let res = parse_resp(&input, &ParserConfig::default());
+        assert!(matches!(res, Err(nom::Err::Failure(_))));
+
+        let cfg = ParserConfig {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert!(parse_resp(b"*1\r\n*1\r\n:1\r\n", &cfg).is_ok());
+        assert!(parse_resp(b"*1\r\n*1\r\n*1\r\n:1\r\n", &cfg).is_err());
+    }
+
+    #[test]
+    fn test_parse_must_advance() {
+        let input = &b"*2\r\n+OK\r\n"[..];
+        // A value parsed out of nothing is refused, and can't be skipped as truncated
+        assert_eq!(
+            advanced(input, Ok((input, ()))),
+            Err(nom::Err::Failure(Error::new(input, ErrorKind::Many0)))
+        );
+        assert_eq!(
+            advanced(input, Ok((&input[4..], ()))),
+            Ok((&input[4..], ()))
+        );
+
+        // Reading value after value ends, and an array short of elements fails
+        // rather than waiting on more
+        let cfg = ParserConfig::default();
+        let mut rest = &b"+OK\r\n:1\r\n*2\r\n+OK\r\n"[..];
+        let mut parsed = 0;
+        while let Ok((next, _)) = parse_resp_ref(rest, &cfg) {
+            rest = next;
+            parsed += 1;
+        }
+        assert_eq!(parsed, 2);
+        assert_eq!(rest, b"*2\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn test_parse_too_many_elements() {
+        let too_large = |input: &[u8], cfg: &ParserConfig| {
+            matches!(
+                parse_resp(input, cfg),
+                Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge
+            )
+        };
+        // A tiny payload claiming a huge array fails at the header, even when a
+        // truncated payload is accepted
+        let cfg = ParserConfig::default();
+        assert!(too_large(b"*1048577\r\n", &cfg));
+        assert!(too_large(b"*99999999999\r\n:1\r\n", &cfg));
+        let truncated = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        assert!(too_large(b"*1048577\r\n$3\r\nGET\r\n", &truncated));
+        // At the limit, it's only short of elements
+        assert!(matches!(
+            parse_resp(b"*1048576\r\n:1\r\n", &cfg),
+            Err(nom::Err::Error(_))
+        ));
+
+        let cfg = ParserConfig {
+            max_elements: 2,
+            ..Default::default()
+        };
+        assert!(parse_resp(b"*2\r\n:1\r\n:2\r\n", &cfg).is_ok());
+        assert!(too_large(b"*3\r\n:1\r\n:2\r\n:3\r\n", &cfg));
+        // Map entries count once, and nested aggregates are limited too
+        assert!(parse_resp(b"%2\r\n:1\r\n:2\r\n:3\r\n:4\r\n", &cfg).is_ok());
+        assert!(too_large(b"*1\r\n~3\r\n:1\r\n:2\r\n:3\r\n", &cfg));
+        // As are streamed ones, which declare no length
+        assert!(parse_resp(b"*?\r\n:1\r\n:2\r\n.\r\n", &cfg).is_ok());
+        assert!(too_large(b"*?\r\n:1\r\n:2\r\n:3\r\n.\r\n", &cfg));
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let cfg = ParserConfig::default();
+        let input = b"%2\r\n+first\r\n:1\r\n+second\r\n:2\r\n+OK\r\n";
+        let (rest, value) = parse_resp(input, &cfg).unwrap();
+        assert_eq!(value, scalar(None).into_owned());
+        assert_eq!(rest, b"+OK\r\n");
+
+        // A map must contain both halves of every entry
+        assert!(parse_resp(b"%1\r\n+first\r\n", &cfg).is_err());
+    }
+
+    #[test]
+    fn test_unknown_type_byte() {
+        let input = b"?3\r\nfoo\r\n";
+        match parse_resp(input, &ParserConfig::default()) {
+            Err(nom::Err::Failure(e)) => {
+                assert_eq!(e.code, ErrorKind::Switch);
+                assert_eq!(e.input, input);
+            }
+            res => panic!("expected a failure, got {:?}", res),
+        }
+
+        // Inside an array too, even when truncated input is accepted
+        let cfg = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            parse_resp(b"*2\r\n$3\r\nGET\r\n?1\r\n", &cfg),
+            Err(nom::Err::Failure(_))
+        ));
+        // The end of the input is still a truncation
+        let (_, value) = parse_resp(b"*2\r\n$3\r\nGET\r\n", &cfg).unwrap();
+        assert_eq!(value.command.as_deref(), Some("GET"));
+    }
+
+    #[test]
+    fn test_parse_streamed_string() {
+        let cfg = ParserConfig::default();
+        let input = b"$?\r\n;4\r\nHell\r\n;5\r\no wor\r\n;1\r\nd\r\n;0\r\n+OK\r\n";
+        let (rest, value) = parse_resp(input, &cfg).unwrap();
+        assert_eq!(value, scalar(Some(b"Hell")).into_owned());
+        assert_eq!(rest, b"+OK\r\n");
+
+        // The limit applies to the chunks together
+        let cfg = ParserConfig {
+            max_bulk_len: 8,
+            ..Default::default()
+        };
+        assert!(matches!(parse_resp(input, &cfg), Err(nom::Err::Failure(_))));
+        assert!(parse_resp(b"$?\r\n;4\r\nHell\r\n", &ParserConfig::default()).is_err());
+    }
+
+    #[test]
+    fn test_parse_streamed_array() {
+        let cfg = ParserConfig::default();
+        let input = b"*?\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n.\r\n+OK\r\n";
+        let (rest, value) = parse_resp(input, &cfg).unwrap();
+        assert_eq!(value.command.as_deref(), Some("SET"));
+        assert_eq!(value.key.as_deref(), Some("k"));
+        assert_eq!(value.value.as_deref(), Some("v"));
+        assert_eq!(rest, b"+OK\r\n");
+
+        // Streamed maps and sets end the same way
+        let (rest, _) = parse_resp(b"%?\r\n+a\r\n:1\r\n.\r\n~?\r\n.\r\n", &cfg).unwrap();
+        assert_eq!(rest, b"~?\r\n.\r\n");
+
+        // Without its end marker, a streamed array is only accepted when truncated
+        let input = b"*?\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        assert!(parse_resp(input, &cfg).is_err());
+        let truncated = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        let (rest, value) = parse_resp(input, &truncated).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value.key.as_deref(), Some("k"));
+    }
+
+    #[test]
+    fn test_parse_attribute() {
+        let cfg = ParserConfig::default();
+        let input =
+            b"|1\r\n+key-popularity\r\n%2\r\n$1\r\na\r\n,0.1923\r\n$1\r\nb\r\n,0.0012\r\n+OK\r\n";
+        let (rest, value) = parse_resp(input, &cfg).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(value, parse_resp(b"+OK\r\n", &cfg).unwrap().1);
+        assert_eq!(skip_attributes(input, &cfg), b"+OK\r\n");
+
+        // Within an array, and with no attributes to skip
+        let (_, value) =
+            parse_resp(b"*2\r\n$3\r\nGET\r\n|1\r\n+a\r\n:1\r\n$1\r\nk\r\n", &cfg).unwrap();
+        assert_eq!(value.key.as_deref(), Some("k"));
+        assert_eq!(skip_attributes(b"-ERR\r\n", &cfg), b"-ERR\r\n");
+
+        // Attributes ahead of attributes still count toward the depth limit
+        let nested = b"|0\r\n".repeat(cfg.max_depth + 1);
+        assert!(parse_resp(&[nested, b"+OK\r\n".to_vec()].concat(), &cfg).is_err());
+    }
+
+    #[test]
+    fn test_parse_double() {
+        let cfg = ParserConfig::default();
+        let (_, value) = parse_resp(b",1.23\r\n", &cfg).unwrap();
+        assert_eq!(value, scalar(Some(b"1.23")).into_owned());
+        let (_, value) = parse_resp(b",-inf\r\n", &cfg).unwrap();
+        assert_eq!(value, scalar(Some(b"-inf")).into_owned());
+    }
+
+    #[test]
+    fn test_parse_boolean() {
+        let cfg = ParserConfig::default();
+        let (_, value) = parse_resp(b"#t\r\n", &cfg).unwrap();
+        assert_eq!(value, scalar(Some(b"true")).into_owned());
+        let (_, value) = parse_resp(b"#f\r\n", &cfg).unwrap();
+        assert_eq!(value, scalar(Some(b"false")).into_owned());
+        assert!(parse_resp(b"#x\r\n", &cfg).is_err());
+    }
+
+    #[test]
+    fn test_parse_other_resp3_types() {
+        let cfg = ParserConfig::default();
+        let parse = |input: &[u8]| parse_resp(input, &cfg).unwrap().1;
+        assert_eq!(
+            parse(b"(3492890328409238509324850943850943825024385\r\n").value,
+            Some("3492890328409238509324850943850943825024385".to_string())
+        );
+        assert_eq!(parse(b"_\r\n"), scalar(None).into_owned());
+        assert_eq!(
+            parse(b"=15\r\ntxt:Some string\r\n").value,
+            Some("Some string".to_string())
+        );
+        assert_eq!(
+            parse(b"~2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n").command,
+            Some("foo".to_string())
+        );
+        assert_eq!(
+            parse(b">3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$5\r\nhello\r\n"),
+            RespValue {
+                command: Some("message".to_string()),
+                key: Some("ch".to_string()),
+                field: None,
+                value: Some("hello".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_borrows_input() {
+        let input = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n+OK\r\n";
+        let (rest, value) = parse_resp_ref(input, &ParserConfig::default()).unwrap();
+        assert_eq!(rest, b"+OK\r\n");
+        for part in [value.command, value.key, value.value] {
+            let part = part.unwrap().as_ptr_range();
+            assert!(input.as_ptr_range().contains(&part.start));
+        }
+        assert_eq!(
+            value.into_owned(),
+            RespValue {
+                command: Some("SET".to_string()),
+                key: Some("k".to_string()),
+                field: None,
+                value: Some("value".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_resp_ref_values() {
+        let v = RespValueRef::default();
+        let cases: &[(&[u8], Option<RespValueRef>)] = &[
+            (
+                b"+OK\r\n",
+                Some(RespValueRef {
+                    command: Some(b"OK"),
+                    ..v
+                }),
+            ),
+            (
+                b"-ERR unknown command\r\n",
+                Some(RespValueRef {
+                    command: Some(b"ERR unknown command"),
+                    ..v
+                }),
+            ),
+            (
+                b":1000\r\n",
+                Some(RespValueRef {
+                    value: Some(b"1000"),
+                    ..v
+                }),
+            ),
+            (
+                b"$6\r\nfoobar\r\n",
+                Some(RespValueRef {
+                    value: Some(b"foobar"),
+                    ..v
+                }),
+            ),
+            (b"$0\r\n\r\n", Some(v)),
+            (
+                b"$4\r\n\xff\xfeab\r\n",
+                Some(RespValueRef {
+                    value: Some(b"\xff\xfeab"),
+                    ..v
+                }),
+            ),
+            (
+                b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n",
+                Some(RespValueRef {
+                    command: Some(b"GET"),
+                    key: Some(b"key"),
+                    args: 2,
+                    ..v
+                }),
+            ),
+            (
+                b"*1\r\n*2\r\n:1\r\n:2\r\n",
+                Some(RespValueRef { args: 1, ..v }),
+            ),
+            (b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\nabc", None),
+            (b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$10", None),
+            (b"$3\r\n\xe2\x82", None),
+            (
+                b",1.23\r\n",
+                Some(RespValueRef {
+                    value: Some(b"1.23"),
+                    ..v
+                }),
+            ),
+            (b",\xff\r\n", None),
+            (
+                b"#t\r\n",
+                Some(RespValueRef {
+                    value: Some(b"true"),
+                    ..v
+                }),
+            ),
+            (
+                b"(123456789012345678901234567890\r\n",
+                Some(RespValueRef {
+                    value: Some(b"123456789012345678901234567890"),
+                    ..v
+                }),
+            ),
+            (b"_\r\n", Some(v)),
+            (
+                b"=15\r\ntxt:Some string\r\n",
+                Some(RespValueRef {
+                    value: Some(b"Some string"),
+                    ..v
+                }),
+            ),
+            (
+                b"!21\r\nSYNTAX invalid syntax\r\n",
+                Some(RespValueRef {
+                    command: Some(b"SYNTAX invalid syntax"),
+                    ..v
+                }),
+            ),
+            (b"%1\r\n+a\r\n:1\r\n", Some(v)),
+            (
+                b"~1\r\n$3\r\nfoo\r\n",
+                Some(RespValueRef {
+                    command: Some(b"foo"),
+                    ..v
+                }),
+            ),
+            (
+                b">3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$5\r\nhello\r\n",
+                Some(RespValueRef {
+                    command: Some(b"message"),
+                    key: Some(b"ch"),
+                    value: Some(b"hello"),
+                    ..v
+                }),
+            ),
+            (b"?\r\n", None),
+        ];
+        for (input, expected) in cases {
+            let parsed = parse_resp_ref(input, &ParserConfig::default());
+            match expected {
+                Some(expected) => {
+                    let (rest, value) = parsed.unwrap();
+                    assert_eq!(rest, b"", "{}", input.escape_ascii());
+                    assert_eq!(value, *expected, "{}", input.escape_ascii());
+                    // Booleans are the one type rendered rather than sliced from the input
+                    if input[0] != b'#' {
+                        assert_borrowed(input, value);
+                    }
+                }
+                None => assert!(parsed.is_err(), "{}", input.escape_ascii()),
+            }
+        }
+
+        // Cut short inputs keep whatever arrived when truncation is allowed
+        let truncating = ParserConfig {
+            allow_truncated: true,
+            ..Default::default()
+        };
+        let set = RespValueRef {
+            command: Some(b"SET"),
+            key: Some(b"k"),
+            args: 3,
+            ..v
+        };
+        let cases: &[(&[u8], RespValueRef)] = &[
+            (
+                b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$100\r\nabc",
+                RespValueRef {
+                    value: Some(b"abc"),
+                    ..set
+                },
+            ),
+            (b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$10", set),
+            (
+                b"$3\r\n\xe2\x82",
+                RespValueRef {
+                    value: Some(b"\xe2\x82"),
+                    ..v
+                },
+            ),
+        ];
+        for (input, expected) in cases {
+            let (rest, value) = parse_resp_ref(input, &truncating).unwrap();
+            assert_eq!(rest, b"", "{}", input.escape_ascii());
+            assert_eq!(value, *expected, "{}", input.escape_ascii());
+            assert_borrowed(input, value);
+        }
+        assert!(parse_resp_ref(b",\xff\r\n", &truncating).is_err());
+    }
+
+    /// Assert every part of `value` is a slice of `input` rather than a copy.
+    fn assert_borrowed(input: &[u8], value: RespValueRef<'_>) {
+        let range = input.as_ptr_range();
+        for part in [value.command, value.key, value.field, value.value]
+            .into_iter()
+            .flatten()
+        {
+            let part = part.as_ptr_range();
+            assert!(
+                range.start <= part.start && part.end <= range.end,
+                "{}",
+                input.escape_ascii()
+            );
+        }
     }
 
     //#[test]
@@ -0,0 +1,215 @@
+use anyhow::Result;
+
+use crate::{
+    plugin::{
+        redis::resp_parser::{parse_resp_ref, ParserConfig},
+        Metrics, Plugin,
+    },
+    post_processor::{ProcessedResult, ReplicatedWrite, ReplicationResult},
+};
+
+/// Commands a master propagates that don't change any key.
+const NON_WRITES: [&[u8]; 6] = [
+    b"PING",
+    b"REPLCONF",
+    b"SELECT",
+    b"MULTI",
+    b"EXEC",
+    b"PUBLISH",
+];
+
+/// Length of the mark that ends a diskless RDB transfer, `$EOF:<mark>`.
+const EOF_MARK_LEN: usize = 40;
+
+impl From<ReplicationResult> for ProcessedResult {
+    fn from(res: ReplicationResult) -> ProcessedResult {
+        ProcessedResult::Replication(res)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Skip what a master sends before the command stream: its answer to PSYNC
+/// (`+FULLRESYNC <replid> <offset>` or `+CONTINUE`) and the RDB transfer, a bulk
+/// length without a trailing CRLF. Returns the rest of `buf`, empty when the RDB
+/// continues past it.
+fn skip_preamble(mut buf: &[u8]) -> &[u8] {
+    if buf.starts_with(b"+FULLRESYNC") || buf.starts_with(b"+CONTINUE") {
+        buf = find(buf, b"\r\n").map_or(&[], |end| &buf[end + 2..]);
+    }
+    if !buf.starts_with(b"$") {
+        return buf;
+    }
+    let Some(end) = find(buf, b"\r\n") else {
+        return &[];
+    };
+    let header = &buf[1..end];
+    let body = &buf[end + 2..];
+    if let Some(mark) = header.strip_prefix(b"EOF:") {
+        // Diskless: the RDB ends with the mark, its length is not known up front
+        if mark.len() != EOF_MARK_LEN {
+            return &[];
+        }
+        return find(body, mark).map_or(&[], |at| &body[at + mark.len()..]);
+    }
+    std::str::from_utf8(header)
+        .ok()
+        .and_then(|len| len.parse::<usize>().ok())
+        .and_then(|len| body.get(len..))
+        .unwrap_or_default()
+}
+
+/// The keys written by the commands in a payload of the replication stream.
+///
+/// A payload may start inside a command whose head was in the previous segment, or
+/// inside an RDB transfer. Parsing then resumes at the next line that starts a
+/// complete command, and a command cut off at the end of the payload is dropped.
+pub fn replicated_writes(buf: &[u8], cfg: &ParserConfig) -> Vec<ReplicatedWrite> {
+    let mut rest = skip_preamble(buf);
+    let mut writes = vec![];
+    while !rest.is_empty() {
+        let parsed = match rest[0] {
+            b'*' => parse_resp_ref(rest, cfg).ok(),
+            _ => None,
+        };
+        let Some((next, value)) = parsed else {
+            match find(&rest[1..], b"\r\n*") {
+                Some(at) => rest = &rest[at + 3..],
+                None => break,
+            }
+            continue;
+        };
+        rest = next;
+        let (Some(command), Some(key)) = (value.command, value.key) else {
+            continue;
+        };
+        let command = command.to_ascii_uppercase();
+        if NON_WRITES.contains(&command.as_slice()) {
+            continue;
+        }
+        writes.push(ReplicatedWrite {
+            command: String::from_utf8_lossy(&command).into_owned(),
+            key: String::from_utf8_lossy(key).into_owned(),
+        });
+    }
+    writes
+}
+
+/// ReplicationHandler observes the connections replicas open to a master on its
+/// Redis port, and reports the keys written by the commands the master propagates
+/// after PSYNC. The RDB snapshot sent on a full resync is skipped.
+///
+/// The master streams commands without being asked, so most segments don't pair
+/// with a request and each payload is read on its own. Commands split across
+/// segments are missed.
+pub struct ReplicationHandler {
+    port: u16,
+    parser: ParserConfig,
+}
+
+impl ReplicationHandler {
+    pub fn new(port: u16, parser: ParserConfig) -> Self {
+        ReplicationHandler { port, parser }
+    }
+}
+
+impl Plugin<ReplicationResult> for ReplicationHandler {
+    async fn port(&self) -> u16 {
+        self.port
+    }
+
+    async fn process(
+        &self,
+        buf: Vec<u8>,
+        metrics: Option<Metrics>,
+    ) -> Result<Option<ReplicationResult>> {
+        // The replica's own PSYNC, REPLCONF and PING are requests
+        if metrics.is_some_and(|m| m.latency.is_none()) {
+            return Ok(None);
+        }
+        let writes = replicated_writes(&buf, &self.parser);
+        Ok((!writes.is_empty()).then_some(ReplicationResult { writes }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::RequestId;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+        }
+        buf
+    }
+
+    fn keys(writes: &[ReplicatedWrite]) -> Vec<(&str, &str)> {
+        writes
+            .iter()
+            .map(|w| (w.command.as_str(), w.key.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_replicated_sets() {
+        let handler = ReplicationHandler::new(6379, ParserConfig::default());
+        let request = Metrics {
+            identifier: RequestId::Connection(1),
+            latency: None,
+            src_addr: None,
+            dst_addr: None,
+        };
+        let psync = command(&["PSYNC", "?", "-1"]);
+        assert!(handler
+            .process(psync, Some(request))
+            .await
+            .unwrap()
+            .is_none());
+
+        let fullresync = b"+FULLRESYNC 8de1787ba490483314a4d30f1c628bc5025eb761 0\r\n".to_vec();
+        assert!(handler.process(fullresync, None).await.unwrap().is_none());
+
+        // The end of the RDB and the first commands share a segment
+        let mut stream = b"$18\r\nREDIS0011\xfa\x00\x00\x00\xff\x01\x02\x03\x04".to_vec();
+        stream.extend(command(&["SELECT", "0"]));
+        stream.extend(command(&["SET", "a", "1"]));
+        stream.extend(command(&["PING"]));
+        stream.extend(command(&["set", "b", "2"]));
+        let res = handler.process(stream, None).await.unwrap().unwrap();
+        assert_eq!(keys(&res.writes), [("SET", "a"), ("SET", "b")]);
+
+        let res = handler
+            .process(command(&["SET", "c", "3"]), None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(keys(&res.writes), [("SET", "c")]);
+        assert_eq!(
+            ProcessedResult::from(res).to_json(),
+            "{\"writes\":[{\"command\":\"SET\",\"key\":\"c\"}]}"
+        );
+    }
+
+    #[test]
+    fn test_resync_after_partial_command() {
+        let cfg = ParserConfig::default();
+        let set = command(&["SET", "a", "1"]);
+        // The tail of a command from the previous segment, then a whole one, then the
+        // head of the next
+        let mut buf = set[6..].to_vec();
+        buf.extend(command(&["DEL", "b"]));
+        buf.extend(&set[..10]);
+        assert_eq!(keys(&replicated_writes(&buf, &cfg)), [("DEL", "b")]);
+
+        // Inside a diskless RDB transfer
+        let mark = "x".repeat(EOF_MARK_LEN);
+        let mut buf = format!("$EOF:{}\r\nREDIS0011\x00{}", mark, mark).into_bytes();
+        buf.extend(command(&["INCR", "hits"]));
+        assert_eq!(keys(&replicated_writes(&buf, &cfg)), [("INCR", "hits")]);
+        assert!(replicated_writes(b"REDIS0011\x00\x01\r\n\x02", &cfg).is_empty());
+    }
+}
@@ -0,0 +1,185 @@
+use anyhow::Result;
+
+use crate::{
+    plugin::{Metrics, Plugin},
+    post_processor::{ProcessedResult, PrometheusResult},
+};
+
+#[derive(Debug, Clone)]
+pub struct WsResult {
+    pub opcode: u8,
+    pub payload_len: u64,
+    /// Seconds.
+    pub latency: f64,
+}
+
+impl From<WsResult> for ProcessedResult {
+    fn from(res: WsResult) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: opcode_name(res.opcode).to_string(),
+            is_error: res.opcode == OPCODE_CLOSE,
+            latency: res.latency,
+            response_size: usize::try_from(res.payload_len).ok(),
+            labels: vec![],
+        })
+    }
+}
+
+const OPCODE_CLOSE: u8 = 0x8;
+
+fn opcode_name(opcode: u8) -> &'static str {
+    match opcode {
+        0x0 => "continuation",
+        0x1 => "text",
+        0x2 => "binary",
+        OPCODE_CLOSE => "close",
+        0x9 => "ping",
+        0xa => "pong",
+        _ => "reserved",
+    }
+}
+
+/// A WebSocket frame as seen in a single TCP payload. `payload` holds the (unmasked)
+/// bytes present in the segment, which may be fewer than `payload_len` for large frames.
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: u8,
+    pub masked: bool,
+    pub payload_len: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Parse the frame at the start of `buf` (RFC 6455 section 5.2). The HTTP upgrade
+/// handshake is expected to have already happened.
+pub fn parse_frame(buf: &[u8]) -> Result<Frame> {
+    let truncated = || anyhow::anyhow!("Truncated WebSocket frame header");
+    let b0 = *buf.first().ok_or_else(truncated)?;
+    let b1 = *buf.get(1).ok_or_else(truncated)?;
+    let fin = b0 & 0x80 != 0;
+    let opcode = b0 & 0x0f;
+    let masked = b1 & 0x80 != 0;
+
+    let (payload_len, mut offset) = match b1 & 0x7f {
+        126 => {
+            let ext = buf.get(2..4).ok_or_else(truncated)?;
+            (u16::from_be_bytes([ext[0], ext[1]]) as u64, 4)
+        }
+        127 => {
+            let ext: [u8; 8] = buf.get(2..10).ok_or_else(truncated)?.try_into()?;
+            let len = u64::from_be_bytes(ext);
+            if len >> 63 != 0 {
+                return Err(anyhow::anyhow!("Invalid 64-bit WebSocket payload length"));
+            }
+            (len, 10)
+        }
+        len => (len as u64, 2),
+    };
+
+    let mask = if masked {
+        let key: [u8; 4] = buf
+            .get(offset..offset + 4)
+            .ok_or_else(truncated)?
+            .try_into()?;
+        offset += 4;
+        Some(key)
+    } else {
+        None
+    };
+
+    let available = (buf.len() - offset).min(usize::try_from(payload_len).unwrap_or(usize::MAX));
+    let mut payload = buf[offset..offset + available].to_vec();
+    if let Some(key) = mask {
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= key[i % 4];
+        }
+    }
+
+    Ok(Frame {
+        fin,
+        opcode,
+        masked,
+        payload_len,
+        payload,
+    })
+}
+
+/// WsHandler reports the opcode and size of server frames answering a client frame.
+pub struct WsHandler {
+    port: u16,
+}
+
+impl WsHandler {
+    pub fn new(port: u16) -> Self {
+        WsHandler { port }
+    }
+}
+
+impl Plugin<WsResult> for WsHandler {
+    async fn port(&self) -> u16 {
+        self.port
+    }
+
+    async fn process(&self, buf: Vec<u8>, metrics: Option<Metrics>) -> Result<Option<WsResult>> {
+        let latency = match metrics.and_then(|m| m.latency) {
+            Some(latency) => latency,
+            None => return Ok(None),
+        };
+        let frame = parse_frame(&buf)?;
+        Ok(Some(WsResult {
+            opcode: frame.opcode,
+            payload_len: frame.payload_len,
+            latency: latency.as_secs_f64(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_masked_text_frame() {
+        // RFC 6455 section 5.7: a masked "Hello"
+        let buf = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let frame = parse_frame(&buf).unwrap();
+        assert_eq!(
+            frame,
+            Frame {
+                fin: true,
+                opcode: 0x1,
+                masked: true,
+                payload_len: 5,
+                payload: b"Hello".to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_extended_16_bit_length() {
+        let mut buf = vec![0x82, 0x7e, 0x01, 0x00];
+        buf.extend(std::iter::repeat_n(0xab, 256));
+        let frame = parse_frame(&buf).unwrap();
+        assert!(frame.fin);
+        assert_eq!(frame.opcode, 0x2);
+        assert!(!frame.masked);
+        assert_eq!(frame.payload_len, 256);
+        assert_eq!(frame.payload.len(), 256);
+    }
+
+    #[test]
+    fn test_parse_extended_64_bit_length_truncated_segment() {
+        let mut buf = vec![0x02, 0x7f];
+        buf.extend_from_slice(&70_000u64.to_be_bytes());
+        buf.extend_from_slice(&[1, 2, 3]);
+        let frame = parse_frame(&buf).unwrap();
+        assert!(!frame.fin);
+        assert_eq!(frame.payload_len, 70_000);
+        assert_eq!(frame.payload, vec![1, 2, 3]);
+
+        assert!(parse_frame(&[0x81]).is_err());
+        assert!(parse_frame(&[0x81, 0x7e, 0x01]).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+use super::{PostProcessor, ProcessedResult};
+use crate::json;
+use crate::plugin::redis::handler::RedisResult;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// The file is rotated before a record would grow it past this many bytes.
+    pub max_bytes: u64,
+    /// Number of rotated files kept next to the live one, as `<path>.1` (newest) to
+    /// `<path>.<max_files>`. At least one is always kept.
+    pub max_files: usize,
+    /// Include the client address in each record.
+    pub include_client_addr: bool,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig {
+            path: PathBuf::from("aragorn-audit.log"),
+            max_bytes: 100 * 1024 * 1024,
+            max_files: 5,
+            include_client_addr: false,
+        }
+    }
+}
+
+struct AuditFile {
+    file: File,
+    size: u64,
+}
+
+/// AuditPostProcessor appends a JSON line for every Redis request to a file, recording
+/// when it completed, its command, key, status and latency. Files are rotated by size
+/// with renames, so a reader never sees a partially rotated file.
+///
+/// Records are written synchronously and never dropped, unlike the OTLP sink.
+pub struct AuditPostProcessor {
+    cfg: AuditConfig,
+    out: Mutex<AuditFile>,
+}
+
+impl AuditPostProcessor {
+    pub fn new(cfg: AuditConfig) -> Result<Self> {
+        let out = open(&cfg.path)?;
+        Ok(AuditPostProcessor {
+            cfg,
+            out: Mutex::new(out),
+        })
+    }
+
+    fn record(&self, res: &RedisResult) -> String {
+        let mut line = format!(
+            "{{\"timestamp\":{},\"command\":{},\"key\":{},\"status\":{},\"latency\":{}",
+            json::quote(&format_timestamp(SystemTime::now())),
+            json::quote(&res.command),
+            json::quote(&res.key),
+            json::quote(if res.is_error { "ERR" } else { "OK" }),
+            res.latency,
+        );
+        if self.cfg.include_client_addr {
+            let addr = res.client_addr.map(|a| a.ip().to_string());
+            line.push_str(&format!(
+                ",\"client\":{}",
+                addr.map_or_else(|| "null".to_string(), |a| json::quote(&a))
+            ));
+        }
+        line.push_str("}\n");
+        line
+    }
+
+    /// Shift `<path>.N` to `<path>.N+1`, dropping the oldest, then move the live file
+    /// to `<path>.1` and start a new one.
+    fn rotate(&self, out: &mut AuditFile) -> Result<()> {
+        let max_files = self.cfg.max_files.max(1);
+        for n in (1..max_files).rev() {
+            match fs::rename(rotated(&self.cfg.path, n), rotated(&self.cfg.path, n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        fs::rename(&self.cfg.path, rotated(&self.cfg.path, 1))?;
+        *out = open(&self.cfg.path)?;
+        Ok(())
+    }
+}
+
+fn open(path: &Path) -> Result<AuditFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(AuditFile { file, size })
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    name.into()
+}
+
+/// Format a time as RFC 3339 in UTC with millisecond precision.
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[async_trait]
+impl PostProcessor for AuditPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let ProcessedResult::Redis(res) = input else {
+            return Ok(());
+        };
+        let line = self.record(&res);
+        let mut out = self.out.lock().unwrap();
+        if out.size > 0 && out.size + line.len() as u64 > self.cfg.max_bytes {
+            self.rotate(&mut out)?;
+        }
+        out.file.write_all(line.as_bytes())?;
+        out.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::redis::handler::RedisResultKind;
+    use std::time::Duration;
+
+    fn result(key: &str) -> ProcessedResult {
+        ProcessedResult::Redis(RedisResult {
+            kind: RedisResultKind::Request,
+            command: "GET".to_string(),
+            key: key.to_string(),
+            is_error: false,
+            latency: 0.003,
+            response_size: None,
+            client_addr: Some("10.0.0.2:50000".parse().unwrap()),
+            server_addr: Some("10.0.0.1:6379".parse().unwrap()),
+            server_name: None,
+            tls: None,
+            request_bytes: 0,
+            response_bytes: 0,
+        })
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_timestamp(time), "2024-02-29T12:34:56.789Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[tokio::test]
+    async fn test_rotates_by_size() {
+        let dir = std::env::temp_dir().join(format!("aragorn-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let audit = AuditPostProcessor::new(AuditConfig {
+            path: path.clone(),
+            max_bytes: 1024,
+            max_files: 1,
+            include_client_addr: true,
+        })
+        .unwrap();
+
+        for i in 0..10 {
+            audit
+                .post_process(result(&format!("key{}", i)))
+                .await
+                .unwrap();
+        }
+
+        let live = fs::read_to_string(&path).unwrap();
+        let old = fs::read_to_string(rotated(&path, 1)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(live.len() <= 1024 && old.len() <= 1024);
+        let lines: Vec<&str> = old.lines().chain(live.lines()).collect();
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].contains("\"command\":\"GET\",\"key\":\"key0\",\"status\":\"OK\""));
+        assert!(lines[9].ends_with(
+            "\"key\":\"key9\",\"status\":\"OK\",\"latency\":0.003,\"client\":\"10.0.0.2\"}"
+        ));
+    }
+}
@@ -0,0 +1,169 @@
+use super::{PostProcessor, ProcessedResult};
+use crate::json;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub struct EwmaConfig {
+    /// Weight of each new latency, from 0 (exclusive) to 1. Higher values follow
+    /// changes faster.
+    pub alpha: f64,
+    /// Labels tracked at once. The least recently updated is evicted beyond this.
+    pub max_keys: usize,
+}
+
+impl Default for EwmaConfig {
+    fn default() -> Self {
+        EwmaConfig {
+            alpha: 0.1,
+            max_keys: 1000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Averages {
+    /// Average latency and last update of each label.
+    values: HashMap<String, (f64, u64)>,
+    /// Labels by last update, oldest first.
+    order: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+/// EwmaPostProcessor keeps an exponentially weighted moving average of latency per
+/// label, in milliseconds. Clones share the averages, so one can be handed to the
+/// observer and another to the HTTP server.
+#[derive(Clone)]
+pub struct EwmaPostProcessor {
+    averages: Arc<Mutex<Averages>>,
+    alpha: f64,
+    max_keys: usize,
+}
+
+impl EwmaPostProcessor {
+    pub fn new(cfg: EwmaConfig) -> Result<Self> {
+        if !(cfg.alpha > 0.0 && cfg.alpha <= 1.0) {
+            anyhow::bail!("EWMA alpha must be in (0, 1], got {}", cfg.alpha);
+        }
+        Ok(EwmaPostProcessor {
+            averages: Arc::new(Mutex::new(Averages::default())),
+            alpha: cfg.alpha,
+            max_keys: cfg.max_keys,
+        })
+    }
+
+    /// The average of `label`, if it is tracked.
+    pub async fn get(&self, label: &str) -> Option<f64> {
+        let averages = self.averages.lock().await;
+        averages.values.get(label).map(|&(avg, _)| avg)
+    }
+
+    /// The averages as a JSON object from label to milliseconds, sorted by label.
+    pub async fn to_json(&self) -> String {
+        let averages = self.averages.lock().await;
+        let mut entries: Vec<_> = averages.values.iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let entries: Vec<String> = entries
+            .into_iter()
+            .map(|(label, (avg, _))| format!("{}:{}", json::quote(label), avg))
+            .collect();
+        format!("{{{}}}", entries.join(","))
+    }
+}
+
+#[async_trait]
+impl PostProcessor for EwmaPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let latency = match &input {
+            ProcessedResult::Prometheus(res) => res.latency,
+            ProcessedResult::Redis(res) => res.latency,
+            ProcessedResult::PubSub(_)
+            | ProcessedResult::Replication(_)
+            | ProcessedResult::KeyEvent(_) => return Ok(()),
+        } * 1e3;
+        if self.max_keys == 0 {
+            return Ok(());
+        }
+
+        let mut averages = self.averages.lock().await;
+        let averages = &mut *averages;
+        averages.clock += 1;
+        let now = averages.clock;
+        let label = input.label();
+        match averages.values.get_mut(label) {
+            Some((avg, updated)) => {
+                *avg += self.alpha * (latency - *avg);
+                averages.order.remove(updated);
+                *updated = now;
+            }
+            None => {
+                if averages.values.len() >= self.max_keys {
+                    if let Some((_, oldest)) = averages.order.pop_first() {
+                        averages.values.remove(&oldest);
+                    }
+                }
+                averages.values.insert(label.to_string(), (latency, now));
+            }
+        }
+        averages.order.insert(now, label.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PrometheusResult;
+
+    fn result(label: &str, latency_ms: u32) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: label.to_string(),
+            is_error: false,
+            latency: f64::from(latency_ms) / 1e3,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_converges() {
+        let ewma = EwmaPostProcessor::new(EwmaConfig {
+            alpha: 0.5,
+            ..Default::default()
+        })
+        .unwrap();
+        ewma.post_process(result("foo", 100)).await.unwrap();
+        assert_eq!(ewma.get("foo").await, Some(100.0));
+        ewma.post_process(result("foo", 0)).await.unwrap();
+        assert_eq!(ewma.get("foo").await, Some(50.0));
+
+        for _ in 0..20 {
+            ewma.post_process(result("foo", 10)).await.unwrap();
+        }
+        let avg = ewma.get("foo").await.unwrap();
+        assert!((avg - 10.0).abs() < 0.001, "{}", avg);
+        assert!(avg > 10.0);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_least_recently_updated() {
+        let ewma = EwmaPostProcessor::new(EwmaConfig {
+            alpha: 0.5,
+            max_keys: 2,
+        })
+        .unwrap();
+        for (label, latency) in [("a", 1), ("b", 2), ("a", 3), ("c", 4)] {
+            ewma.post_process(result(label, latency)).await.unwrap();
+        }
+        assert_eq!(ewma.get("b").await, None);
+        assert_eq!(ewma.to_json().await, "{\"a\":2,\"c\":4}");
+
+        assert!(EwmaPostProcessor::new(EwmaConfig {
+            alpha: 0.0,
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
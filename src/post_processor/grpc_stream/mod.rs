@@ -0,0 +1,651 @@
+use super::{PostProcessor, ProcessedResult};
+use crate::plugin::grpc::{header_fragment, hpack};
+use anyhow::Result;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::future::pending;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+lazy_static! {
+    static ref SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "aragorn_grpc_stream_subscribers",
+        "Number of clients subscribed to the gRPC result stream"
+    )
+    .unwrap();
+    static ref DROPPED_SUBSCRIBERS: IntCounter = register_int_counter!(
+        "aragorn_grpc_stream_dropped_subscribers_total",
+        "Number of gRPC subscribers disconnected for falling behind the result stream"
+    )
+    .unwrap();
+}
+
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+const FRAME_HEADER_LEN: usize = 9;
+// The default SETTINGS_MAX_FRAME_SIZE, which every peer accepts and the server
+// doesn't raise
+const MAX_FRAME_SIZE: usize = 16384;
+const DEFAULT_WINDOW: i64 = 65535;
+
+const FRAME_DATA: u8 = 0x0;
+const FRAME_HEADERS: u8 = 0x1;
+const FRAME_RST_STREAM: u8 = 0x3;
+const FRAME_SETTINGS: u8 = 0x4;
+const FRAME_PING: u8 = 0x6;
+const FRAME_GOAWAY: u8 = 0x7;
+const FRAME_WINDOW_UPDATE: u8 = 0x8;
+const FRAME_CONTINUATION: u8 = 0x9;
+
+const FLAG_END_STREAM: u8 = 0x1;
+const FLAG_ACK: u8 = 0x1;
+const FLAG_END_HEADERS: u8 = 0x4;
+
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const PROTOCOL_ERROR: u32 = 0x1;
+const REFUSED_STREAM: u32 = 0x7;
+// Requests are a handful of headers, so this leaves plenty of room
+const MAX_HEADER_BLOCK: usize = 64 * 1024;
+
+const SUBSCRIBE_PATH: &str = "/aragorn.Export/Subscribe";
+const GRPC_OK: &str = "0";
+const GRPC_RESOURCE_EXHAUSTED: &str = "8";
+const GRPC_UNIMPLEMENTED: &str = "12";
+
+pub struct GrpcStreamConfig {
+    /// Results buffered for each subscriber before it counts as too slow.
+    pub capacity: usize,
+}
+
+impl Default for GrpcStreamConfig {
+    fn default() -> Self {
+        GrpcStreamConfig { capacity: 1024 }
+    }
+}
+
+/// GrpcStreamPostProcessor streams every result to the clients subscribed through
+/// `serve`, as the `aragorn.Export/Subscribe` call in proto/export.proto. Results are
+/// broadcast to the subscribers; one that falls more than `capacity` results behind
+/// is ended with RESOURCE_EXHAUSTED, so a slow client never blocks capture.
+#[derive(Clone)]
+pub struct GrpcStreamPostProcessor {
+    tx: broadcast::Sender<ProcessedResult>,
+}
+
+impl GrpcStreamPostProcessor {
+    pub fn new(cfg: GrpcStreamConfig) -> Self {
+        let (tx, _) = broadcast::channel(cfg.capacity.max(1));
+        GrpcStreamPostProcessor { tx }
+    }
+}
+
+#[async_trait]
+impl PostProcessor for GrpcStreamPostProcessor {
+    async fn post_process(&self, res: ProcessedResult) -> Result<()> {
+        // Only fails when nobody is subscribed
+        let _ = self.tx.send(res);
+        Ok(())
+    }
+}
+
+/// Serve subscriptions to `processor` on `listener`. This speaks just enough HTTP/2
+/// for gRPC clients over cleartext with prior knowledge. Calls to other methods end
+/// with UNIMPLEMENTED and requests that aren't gRPC are reset. There is one
+/// subscription at a time per connection.
+pub async fn serve(listener: TcpListener, processor: GrpcStreamPostProcessor) -> Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let tx = processor.tx.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, tx).await {
+                debug!("gRPC subscriber {} disconnected: {:?}", peer, e);
+            }
+        });
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    stream: u32,
+    payload: Vec<u8>,
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Frame> {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header).await?;
+    let len = u32::from_be_bytes([0, header[0], header[1], header[2]]) as usize;
+    anyhow::ensure!(len <= MAX_FRAME_SIZE, "Frame of {} bytes is too large", len);
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    Ok(Frame {
+        kind: header[3],
+        flags: header[4],
+        stream: u32::from_be_bytes([header[5], header[6], header[7], header[8]]) & 0x7fff_ffff,
+        payload,
+    })
+}
+
+fn write_frame(out: &mut Vec<u8>, kind: u8, flags: u8, stream: u32, payload: &[u8]) {
+    out.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&[kind, flags]);
+    out.extend_from_slice(&stream.to_be_bytes());
+    out.extend_from_slice(payload);
+}
+
+/// Encode an HPACK integer with an N-bit prefix (RFC 7541 section 5.1).
+fn encode_int(block: &mut Vec<u8>, mut value: usize, prefix: u8) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        block.push(value as u8);
+        return;
+    }
+    block.push(max as u8);
+    value -= max;
+    while value >= 0x80 {
+        block.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+/// Append a header as a literal without indexing, so the client's decoder never
+/// depends on table state kept here.
+fn encode_header(block: &mut Vec<u8>, name: &str, value: &str) {
+    block.push(0);
+    for s in [name, value] {
+        encode_int(block, s.len(), 7);
+        block.extend_from_slice(s.as_bytes());
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8 & 0x7f) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn put_uint(buf: &mut Vec<u8>, field: u64, v: u64) {
+    put_varint(buf, field << 3);
+    put_varint(buf, v);
+}
+
+fn put_double(buf: &mut Vec<u8>, field: u64, v: f64) {
+    put_varint(buf, field << 3 | 1);
+    buf.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_bytes(buf: &mut Vec<u8>, field: u64, v: &[u8]) {
+    put_varint(buf, field << 3 | 2);
+    put_varint(buf, v.len() as u64);
+    buf.extend_from_slice(v);
+}
+
+/// Encode a result as an `aragorn.Record` protobuf message.
+fn encode_record(res: &ProcessedResult) -> Vec<u8> {
+    let (is_error, latency, response_size) = match res {
+        ProcessedResult::Prometheus(res) => (res.is_error, res.latency, res.response_size),
+        ProcessedResult::Redis(res) => (res.is_error, res.latency, res.response_size),
+        ProcessedResult::PubSub(_)
+        | ProcessedResult::Replication(_)
+        | ProcessedResult::KeyEvent(_) => (false, 0.0, None),
+    };
+    let mut buf = vec![];
+    put_bytes(&mut buf, 1, res.label().as_bytes());
+    if is_error {
+        put_uint(&mut buf, 2, 1);
+    }
+    if latency != 0.0 {
+        put_double(&mut buf, 3, latency);
+    }
+    if let Some(size) = response_size {
+        put_uint(&mut buf, 4, size as u64);
+    }
+    put_bytes(&mut buf, 5, res.to_json().as_bytes());
+    buf
+}
+
+/// A gRPC length prefixed message, uncompressed.
+fn grpc_message(res: &ProcessedResult) -> Vec<u8> {
+    let record = encode_record(res);
+    let mut message = Vec::with_capacity(5 + record.len());
+    message.push(0);
+    message.extend_from_slice(&(record.len() as u32).to_be_bytes());
+    message.extend_from_slice(&record);
+    message
+}
+
+struct Subscription {
+    stream: u32,
+    rx: broadcast::Receiver<ProcessedResult>,
+    /// Flow control window the client granted the stream.
+    window: i64,
+    /// Bytes of the current message that didn't fit in the window yet.
+    pending: Vec<u8>,
+}
+
+impl Subscription {
+    fn new(stream: u32, rx: broadcast::Receiver<ProcessedResult>, window: i64) -> Self {
+        SUBSCRIBERS.inc();
+        Subscription {
+            stream,
+            rx,
+            window,
+            pending: vec![],
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.dec();
+    }
+}
+
+async fn next_result(sub: &mut Option<Subscription>) -> Result<ProcessedResult, RecvError> {
+    match sub {
+        Some(sub) => sub.rx.recv().await,
+        None => pending().await,
+    }
+}
+
+struct Connection {
+    writer: OwnedWriteHalf,
+    tx: broadcast::Sender<ProcessedResult>,
+    /// Frames waiting to be written.
+    out: Vec<u8>,
+    /// Connection flow control window the client granted.
+    window: i64,
+    initial_window: i64,
+    /// Decodes the request headers, with the dynamic table the client encodes against.
+    decoder: hpack::Decoder,
+    /// A header block still waiting for its CONTINUATION frames.
+    headers: Option<(u32, Vec<u8>)>,
+    sub: Option<Subscription>,
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    tx: broadcast::Sender<ProcessedResult>,
+) -> Result<()> {
+    let (mut reader, writer) = stream.into_split();
+    let mut preface = [0u8; PREFACE.len()];
+    reader.read_exact(&mut preface).await?;
+    anyhow::ensure!(preface == PREFACE, "Not an HTTP/2 connection");
+
+    // Frames are read on their own task so a read is never cancelled halfway
+    let (frames_tx, mut frames) = mpsc::channel(16);
+    let read_task = tokio::spawn(async move {
+        while let Ok(frame) = read_frame(&mut reader).await {
+            if frames_tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let mut conn = Connection {
+        writer,
+        tx,
+        out: vec![],
+        window: DEFAULT_WINDOW,
+        initial_window: DEFAULT_WINDOW,
+        decoder: hpack::Decoder::default(),
+        headers: None,
+        sub: None,
+    };
+    let res = conn.run(&mut frames).await;
+    read_task.abort();
+    res
+}
+
+impl Connection {
+    async fn run(&mut self, frames: &mut mpsc::Receiver<Frame>) -> Result<()> {
+        // The defaults suit a server that only sends
+        write_frame(&mut self.out, FRAME_SETTINGS, 0, 0, &[]);
+        self.flush().await?;
+        loop {
+            let ready = matches!(&self.sub, Some(sub) if sub.pending.is_empty());
+            tokio::select! {
+                frame = frames.recv() => match frame {
+                    Some(frame) => {
+                        if !self.handle_frame(frame) {
+                            return Ok(());
+                        }
+                    }
+                    // The client hung up
+                    None => return Ok(()),
+                },
+                res = next_result(&mut self.sub), if ready => match res {
+                    Ok(res) => {
+                        let sub = self.sub.as_mut().expect("subscribed");
+                        sub.pending = grpc_message(&res);
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        DROPPED_SUBSCRIBERS.inc();
+                        warn!("Dropping gRPC subscriber {} results behind", skipped);
+                        let message = format!("Fell {} results behind", skipped);
+                        self.end_stream(GRPC_RESOURCE_EXHAUSTED, Some(&message));
+                        self.flush().await?;
+                        return Ok(());
+                    }
+                    Err(RecvError::Closed) => {
+                        self.end_stream(GRPC_OK, None);
+                        self.flush().await?;
+                        return Ok(());
+                    }
+                },
+            }
+            self.send_pending();
+            self.flush().await?;
+        }
+    }
+
+    /// Handle a frame from the client. Returns false once the connection is done.
+    fn handle_frame(&mut self, frame: Frame) -> bool {
+        match frame.kind {
+            FRAME_SETTINGS if frame.flags & FLAG_ACK == 0 => {
+                for setting in frame.payload.chunks_exact(6) {
+                    let id = u16::from_be_bytes([setting[0], setting[1]]);
+                    let value =
+                        u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+                    if id == SETTINGS_INITIAL_WINDOW_SIZE {
+                        // A new initial window shifts the windows of open streams too
+                        if let Some(sub) = &mut self.sub {
+                            sub.window += i64::from(value) - self.initial_window;
+                        }
+                        self.initial_window = i64::from(value);
+                    }
+                }
+                write_frame(&mut self.out, FRAME_SETTINGS, FLAG_ACK, 0, &[]);
+            }
+            FRAME_PING if frame.flags & FLAG_ACK == 0 => {
+                write_frame(&mut self.out, FRAME_PING, FLAG_ACK, 0, &frame.payload);
+            }
+            FRAME_WINDOW_UPDATE if frame.payload.len() == 4 => {
+                let increment = u32::from_be_bytes([
+                    frame.payload[0],
+                    frame.payload[1],
+                    frame.payload[2],
+                    frame.payload[3],
+                ]) & 0x7fff_ffff;
+                match &mut self.sub {
+                    _ if frame.stream == 0 => self.window += i64::from(increment),
+                    Some(sub) if sub.stream == frame.stream => sub.window += i64::from(increment),
+                    _ => {}
+                }
+            }
+            // Client initiated streams are odd, anything else is a protocol error
+            FRAME_HEADERS if frame.stream % 2 == 1 => {
+                let Ok(fragment) = header_fragment(frame.flags, &frame.payload) else {
+                    return false;
+                };
+                self.headers = Some((frame.stream, fragment.to_vec()));
+                if frame.flags & FLAG_END_HEADERS != 0 {
+                    return self.request();
+                }
+            }
+            FRAME_HEADERS => return false,
+            FRAME_CONTINUATION => {
+                match &mut self.headers {
+                    Some((stream, block))
+                        if *stream == frame.stream
+                            && block.len() + frame.payload.len() <= MAX_HEADER_BLOCK =>
+                    {
+                        block.extend_from_slice(&frame.payload);
+                    }
+                    _ => return false,
+                }
+                if frame.flags & FLAG_END_HEADERS != 0 {
+                    return self.request();
+                }
+            }
+            // The request message. Give the connection window back so it never runs out
+            FRAME_DATA if !frame.payload.is_empty() => {
+                let increment = (frame.payload.len() as u32).to_be_bytes();
+                write_frame(&mut self.out, FRAME_WINDOW_UPDATE, 0, 0, &increment);
+            }
+            FRAME_RST_STREAM => {
+                if matches!(&self.sub, Some(sub) if sub.stream == frame.stream) {
+                    self.sub = None;
+                }
+            }
+            FRAME_GOAWAY => return false,
+            _ => {}
+        }
+        true
+    }
+
+    /// Answer the request whose header block just completed, subscribing it if it
+    /// calls Subscribe. Returns false once the connection is done.
+    fn request(&mut self) -> bool {
+        let Some((stream, block)) = self.headers.take() else {
+            return true;
+        };
+        // Refused requests are decoded too, to keep the dynamic table in sync
+        let Ok(headers) = self.decoder.decode(&block) else {
+            return false;
+        };
+        let find = |key: &str| {
+            headers
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, v)| v.as_str())
+        };
+        // application/grpc, optionally with a message format like +proto
+        let grpc = find("content-type").is_some_and(|v| {
+            v.strip_prefix("application/grpc")
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(['+', ';']))
+        });
+
+        if matches!(&self.sub, Some(sub) if sub.stream == stream) {
+            // Trailers ending the subscription's request
+        } else if find(":method") != Some("POST") || !grpc {
+            let code = PROTOCOL_ERROR.to_be_bytes();
+            write_frame(&mut self.out, FRAME_RST_STREAM, 0, stream, &code);
+        } else if find(":path") != Some(SUBSCRIBE_PATH) {
+            let mut block = vec![];
+            encode_header(&mut block, ":status", "200");
+            encode_header(&mut block, "content-type", "application/grpc");
+            encode_header(&mut block, "grpc-status", GRPC_UNIMPLEMENTED);
+            encode_header(&mut block, "grpc-message", "Only Subscribe is served");
+            let flags = FLAG_END_HEADERS | FLAG_END_STREAM;
+            write_frame(&mut self.out, FRAME_HEADERS, flags, stream, &block);
+        } else if self.sub.is_some() {
+            let code = REFUSED_STREAM.to_be_bytes();
+            write_frame(&mut self.out, FRAME_RST_STREAM, 0, stream, &code);
+        } else {
+            let mut block = vec![];
+            encode_header(&mut block, ":status", "200");
+            encode_header(&mut block, "content-type", "application/grpc");
+            write_frame(
+                &mut self.out,
+                FRAME_HEADERS,
+                FLAG_END_HEADERS,
+                stream,
+                &block,
+            );
+            let rx = self.tx.subscribe();
+            self.sub = Some(Subscription::new(stream, rx, self.initial_window));
+        }
+        true
+    }
+
+    /// Send as much of the current message as the flow control windows allow.
+    fn send_pending(&mut self) {
+        let Some(sub) = &mut self.sub else {
+            return;
+        };
+        while !sub.pending.is_empty() {
+            let window = self.window.min(sub.window).max(0) as usize;
+            let n = sub.pending.len().min(MAX_FRAME_SIZE).min(window);
+            if n == 0 {
+                break;
+            }
+            let chunk: Vec<u8> = sub.pending.drain(..n).collect();
+            write_frame(&mut self.out, FRAME_DATA, 0, sub.stream, &chunk);
+            self.window -= n as i64;
+            sub.window -= n as i64;
+        }
+    }
+
+    /// End the subscription with trailers carrying the gRPC status.
+    fn end_stream(&mut self, status: &str, message: Option<&str>) {
+        if let Some(sub) = self.sub.take() {
+            let mut block = vec![];
+            encode_header(&mut block, "grpc-status", status);
+            if let Some(message) = message {
+                encode_header(&mut block, "grpc-message", message);
+            }
+            let flags = FLAG_END_HEADERS | FLAG_END_STREAM;
+            write_frame(&mut self.out, FRAME_HEADERS, flags, sub.stream, &block);
+        }
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if !self.out.is_empty() {
+            self.writer.write_all(&self.out).await?;
+            self.out.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PrometheusResult;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn result(label: &str) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: label.to_string(),
+            is_error: false,
+            latency: 0.5,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+
+    fn request_headers(path: &str, content_type: &str) -> Vec<u8> {
+        let mut block = vec![];
+        for (name, value) in [
+            (":method", "POST"),
+            (":scheme", "http"),
+            (":path", path),
+            (":authority", "localhost"),
+            ("content-type", content_type),
+            ("te", "trailers"),
+        ] {
+            encode_header(&mut block, name, value);
+        }
+        block
+    }
+
+    /// Read frames until one of `kind`, skipping SETTINGS and the like.
+    async fn next_frame<R: AsyncRead + Unpin>(reader: &mut R, kind: u8) -> Frame {
+        loop {
+            let frame = timeout(Duration::from_secs(5), read_frame(reader))
+                .await
+                .unwrap()
+                .unwrap();
+            if frame.kind == kind {
+                return frame;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streams_to_subscriber() {
+        let processor = GrpcStreamPostProcessor::new(GrpcStreamConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, processor.clone()));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut out = PREFACE.to_vec();
+        write_frame(&mut out, FRAME_SETTINGS, 0, 0, &[]);
+        let block = request_headers(SUBSCRIBE_PATH, "application/grpc");
+        write_frame(&mut out, FRAME_HEADERS, FLAG_END_HEADERS, 1, &block);
+        write_frame(&mut out, FRAME_DATA, FLAG_END_STREAM, 1, &[0; 5]);
+        client.write_all(&out).await.unwrap();
+
+        // The response headers are sent once subscribed
+        let (mut reader, _writer) = client.into_split();
+        let frame = next_frame(&mut reader, FRAME_HEADERS).await;
+        assert_eq!(frame.stream, 1);
+
+        processor.post_process(result("foo")).await.unwrap();
+        processor.post_process(result("bar")).await.unwrap();
+
+        let mut data = vec![];
+        while data.len() < grpc_message(&result("foo")).len() * 2 {
+            let frame = timeout(Duration::from_secs(5), read_frame(&mut reader))
+                .await
+                .unwrap()
+                .unwrap();
+            if frame.kind == FRAME_DATA {
+                assert_eq!(frame.stream, 1);
+                data.extend(frame.payload);
+            }
+        }
+        let (foo, bar) = data.split_at(data.len() / 2);
+        assert_eq!(foo, grpc_message(&result("foo")));
+        assert_eq!(bar, grpc_message(&result("bar")));
+        // label = "foo", latency = 0.5, then the JSON
+        assert_eq!(
+            foo[5..20],
+            [0x0a, 3, b'f', b'o', b'o', 0x19, 0, 0, 0, 0, 0, 0, 0xe0, 0x3f, 0x2a]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejects_other_requests() {
+        let processor = GrpcStreamPostProcessor::new(GrpcStreamConfig::default());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, processor.clone()));
+
+        let client = TcpStream::connect(addr).await.unwrap();
+        let (mut reader, mut writer) = client.into_split();
+        let mut out = PREFACE.to_vec();
+        write_frame(&mut out, FRAME_SETTINGS, 0, 0, &[]);
+        let block = request_headers("/aragorn.Export/Other", "application/grpc");
+        write_frame(&mut out, FRAME_HEADERS, FLAG_END_HEADERS, 1, &block);
+        writer.write_all(&out).await.unwrap();
+
+        // Another method ends right away with UNIMPLEMENTED
+        let frame = next_frame(&mut reader, FRAME_HEADERS).await;
+        assert_eq!(frame.stream, 1);
+        assert_eq!(frame.flags, FLAG_END_HEADERS | FLAG_END_STREAM);
+        let headers = hpack::Decoder::default().decode(&frame.payload).unwrap();
+        assert!(headers.contains(&("grpc-status".to_string(), GRPC_UNIMPLEMENTED.to_string())));
+
+        // Not gRPC at all
+        let mut out = vec![];
+        let block = request_headers(SUBSCRIBE_PATH, "application/json");
+        write_frame(&mut out, FRAME_HEADERS, FLAG_END_HEADERS, 3, &block);
+        writer.write_all(&out).await.unwrap();
+        let frame = next_frame(&mut reader, FRAME_RST_STREAM).await;
+        assert_eq!(frame.stream, 3);
+        assert_eq!(frame.payload, PROTOCOL_ERROR.to_be_bytes());
+
+        // Subscribe still works, with its header block split over a CONTINUATION
+        let mut out = vec![];
+        let block = request_headers(SUBSCRIBE_PATH, "application/grpc+proto");
+        let (head, tail) = block.split_at(10);
+        write_frame(&mut out, FRAME_HEADERS, 0, 5, head);
+        write_frame(&mut out, FRAME_CONTINUATION, FLAG_END_HEADERS, 5, tail);
+        writer.write_all(&out).await.unwrap();
+        let frame = next_frame(&mut reader, FRAME_HEADERS).await;
+        assert_eq!(frame.stream, 5);
+        assert_eq!(frame.flags, FLAG_END_HEADERS);
+        let headers = hpack::Decoder::default().decode(&frame.payload).unwrap();
+        assert!(headers.contains(&(":status".to_string(), "200".to_string())));
+    }
+}
@@ -0,0 +1,71 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// JsonlPostProcessor appends every result to a file as a line of JSON, as
+/// `ProcessedResult::to_json` writes it. Unlike the audit log it takes results of
+/// every protocol and isn't rotated.
+pub struct JsonlPostProcessor {
+    out: Mutex<File>,
+}
+
+impl JsonlPostProcessor {
+    pub fn new(path: &Path) -> Result<Self> {
+        let out = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(JsonlPostProcessor {
+            out: Mutex::new(out),
+        })
+    }
+}
+
+#[async_trait]
+impl PostProcessor for JsonlPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let mut line = input.to_json();
+        line.push('\n');
+        self.out.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::{PrometheusResult, PubSubResult};
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_one_line_per_result() {
+        let path = std::env::temp_dir().join(format!("aragorn-{}.jsonl", std::process::id()));
+        let jsonl = JsonlPostProcessor::new(&path).unwrap();
+        jsonl
+            .post_process(ProcessedResult::Prometheus(PrometheusResult {
+                label: "foo".to_string(),
+                is_error: true,
+                latency: 0.25,
+                response_size: Some(3),
+                labels: vec![],
+            }))
+            .await
+            .unwrap();
+        jsonl
+            .post_process(ProcessedResult::PubSub(PubSubResult {
+                channel: "news".to_string(),
+                kind: "message".to_string(),
+            }))
+            .await
+            .unwrap();
+
+        let out = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            out,
+            "{\"label\":\"foo\",\"is_error\":true,\"latency\":0.25,\"response_size\":3,\"labels\":{}}\n\
+             {\"channel\":\"news\",\"kind\":\"message\"}\n"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use tokio::sync::mpsc;
+use tracing::error;
+
+lazy_static! {
+    static ref DELIVERY_ERRORS: IntCounter = register_int_counter!(
+        "aragorn_kafka_delivery_errors_total",
+        "Number of results that failed to be produced to Kafka"
+    )
+    .unwrap();
+    static ref DROPPED: IntCounter = register_int_counter!(
+        "aragorn_kafka_dropped_total",
+        "Number of results dropped because the Kafka queue was full"
+    )
+    .unwrap();
+}
+
+/// Producer is the minimal surface the Kafka post processor needs from a client.
+/// Implemented by `BrokerProducer` and by mocks in tests.
+#[async_trait]
+pub trait Producer: Send + Sync + 'static {
+    async fn send(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()>;
+}
+
+pub struct KafkaConfig {
+    /// Comma separated `host:port` of the brokers to bootstrap from.
+    pub brokers: String,
+    pub topic: String,
+    pub queue_size: usize,
+}
+
+impl Default for KafkaConfig {
+    fn default() -> Self {
+        KafkaConfig {
+            brokers: "localhost:9092".to_string(),
+            topic: "aragorn".to_string(),
+            queue_size: 1024,
+        }
+    }
+}
+
+/// KafkaPostProcessor produces every result as a JSON record keyed by its label.
+/// Results are handed to a background task through a bounded queue; when the queue
+/// is full the result is dropped so a slow broker never blocks packet capture.
+pub struct KafkaPostProcessor {
+    tx: mpsc::Sender<ProcessedResult>,
+}
+
+impl KafkaPostProcessor {
+    pub fn new<P: Producer>(producer: P, cfg: KafkaConfig) -> Self {
+        let (tx, mut rx) = mpsc::channel::<ProcessedResult>(cfg.queue_size);
+        let topic = cfg.topic;
+        tokio::spawn(async move {
+            while let Some(res) = rx.recv().await {
+                let key = res.label().to_string();
+                if let Err(e) = producer
+                    .send(&topic, &key, res.to_json().into_bytes())
+                    .await
+                {
+                    DELIVERY_ERRORS.inc();
+                    error!("Failed to produce to kafka: {:?}", e);
+                }
+            }
+        });
+        KafkaPostProcessor { tx }
+    }
+
+    /// Produce to the brokers in `cfg`.
+    #[cfg(feature = "kafka")]
+    pub fn connect(cfg: KafkaConfig) -> Result<Self> {
+        let producer = BrokerProducer::new(&cfg.brokers)?;
+        Ok(Self::new(producer, cfg))
+    }
+}
+
+#[async_trait]
+impl PostProcessor for KafkaPostProcessor {
+    async fn post_process(&self, res: ProcessedResult) -> Result<()> {
+        match self.tx.try_send(res) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                DROPPED.inc();
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(anyhow::anyhow!("Kafka producer task has stopped"))
+            }
+        }
+    }
+}
+
+/// Counts the records librdkafka gave up on delivering.
+#[cfg(feature = "kafka")]
+struct DeliveryContext;
+
+#[cfg(feature = "kafka")]
+impl rdkafka::ClientContext for DeliveryContext {}
+
+#[cfg(feature = "kafka")]
+impl rdkafka::producer::ProducerContext for DeliveryContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, result: &rdkafka::message::DeliveryResult<'_>, _: ()) {
+        if let Err((e, _)) = result {
+            DELIVERY_ERRORS.inc();
+            error!("Failed to deliver to kafka: {:?}", e);
+        }
+    }
+}
+
+/// A producer backed by librdkafka. `send` only queues the record in the client,
+/// which batches and delivers it from its own thread.
+#[cfg(feature = "kafka")]
+pub struct BrokerProducer {
+    producer: rdkafka::producer::ThreadedProducer<DeliveryContext>,
+}
+
+#[cfg(feature = "kafka")]
+impl BrokerProducer {
+    pub fn new(brokers: &str) -> Result<Self> {
+        let producer = rdkafka::ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(DeliveryContext)?;
+        Ok(BrokerProducer { producer })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl Producer for BrokerProducer {
+    async fn send(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()> {
+        let record = rdkafka::producer::BaseRecord::to(topic)
+            .key(key)
+            .payload(&payload);
+        self.producer.send(record).map_err(|(e, _)| e)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl Drop for BrokerProducer {
+    fn drop(&mut self) {
+        use rdkafka::producer::Producer as _;
+        // Deliver what is still queued rather than losing it with the client
+        if let Err(e) = self.producer.flush(std::time::Duration::from_secs(5)) {
+            error!("Failed to flush kafka producer: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PrometheusResult;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // (topic, key, payload)
+    type Sent = Vec<(String, String, Vec<u8>)>;
+
+    #[derive(Clone, Default)]
+    struct MockProducer {
+        sent: Arc<Mutex<Sent>>,
+    }
+
+    #[async_trait]
+    impl Producer for MockProducer {
+        async fn send(&self, topic: &str, key: &str, payload: Vec<u8>) -> Result<()> {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((topic.to_string(), key.to_string(), payload));
+            Ok(())
+        }
+    }
+
+    fn result(label: &str) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: label.to_string(),
+            is_error: false,
+            latency: 0.003,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_one_message_per_result() {
+        let producer = MockProducer::default();
+        let processor = KafkaPostProcessor::new(
+            producer.clone(),
+            KafkaConfig {
+                topic: "requests".to_string(),
+                ..Default::default()
+            },
+        );
+
+        processor.post_process(result("foo")).await.unwrap();
+        processor.post_process(result("bar")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let sent = producer.sent.lock().unwrap();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0, "requests");
+        assert_eq!(sent[0].1, "foo");
+        assert_eq!(sent[1].1, "bar");
+        assert_eq!(
+            String::from_utf8(sent[0].2.clone()).unwrap(),
+            "{\"label\":\"foo\",\"is_error\":false,\"latency\":0.003,\"response_size\":null,\"labels\":{}}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops() {
+        // A producer that never returns holds the first result in the task
+        struct Stuck;
+
+        #[async_trait]
+        impl Producer for Stuck {
+            async fn send(&self, _: &str, _: &str, _: Vec<u8>) -> Result<()> {
+                std::future::pending().await
+            }
+        }
+
+        let processor = KafkaPostProcessor::new(
+            Stuck,
+            KafkaConfig {
+                queue_size: 1,
+                ..Default::default()
+            },
+        );
+        let dropped = DROPPED.get();
+        processor.post_process(result("a")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        processor.post_process(result("b")).await.unwrap();
+        processor.post_process(result("c")).await.unwrap();
+        assert_eq!(DROPPED.get() - dropped, 1);
+    }
+}
@@ -1,18 +1,149 @@
+pub mod audit;
+pub mod ewma;
+pub mod grpc_stream;
+pub mod jsonl;
+pub mod kafka;
+pub mod otlp;
 pub mod prometheus;
+pub mod recent;
+pub mod reconnect;
+pub mod statsd;
+pub mod summary;
+pub mod webhook;
 
+use crate::json;
+use crate::plugin::redis::handler::RedisResult;
 use anyhow::Result;
 use async_trait::async_trait;
+use std::net::SocketAddr;
 
 #[derive(Debug, Clone)]
 pub enum ProcessedResult {
     Prometheus(PrometheusResult),
+    /// A Redis request with everything the handler knows about it. Each processor
+    /// picks the parts it can represent.
+    Redis(RedisResult),
+    PubSub(PubSubResult),
+    Replication(ReplicationResult),
+    KeyEvent(KeyEventResult),
+}
+
+impl ProcessedResult {
+    pub fn label(&self) -> &str {
+        match self {
+            ProcessedResult::Prometheus(res) => &res.label,
+            ProcessedResult::Redis(res) => &res.key,
+            ProcessedResult::PubSub(res) => &res.channel,
+            ProcessedResult::Replication(res) => res.writes.first().map_or("", |w| &w.key),
+            ProcessedResult::KeyEvent(res) => &res.key,
+        }
+    }
+
+    /// Serialize the result as a single JSON object.
+    pub fn to_json(&self) -> String {
+        match self {
+            ProcessedResult::Prometheus(res) => format!(
+                "{{\"label\":{},\"is_error\":{},\"latency\":{},\"response_size\":{},\"labels\":{{{}}}}}",
+                json::quote(&res.label),
+                res.is_error,
+                res.latency,
+                res.response_size
+                    .map_or_else(|| "null".to_string(), |s| s.to_string()),
+                res.labels
+                    .iter()
+                    .map(|(k, v)| format!("{}:{}", json::quote(k), json::quote(v)))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            ProcessedResult::Redis(res) => format!(
+                "{{\"command\":{},\"key\":{},\"is_error\":{},\"latency\":{},\"response_size\":{},\"client_addr\":{},\"server_addr\":{}}}",
+                json::quote(&res.command),
+                json::quote(&res.key),
+                res.is_error,
+                res.latency,
+                res.response_size
+                    .map_or_else(|| "null".to_string(), |s| s.to_string()),
+                quote_addr(res.client_addr),
+                quote_addr(res.server_addr),
+            ),
+            ProcessedResult::PubSub(res) => format!(
+                "{{\"channel\":{},\"kind\":{}}}",
+                json::quote(&res.channel),
+                json::quote(&res.kind)
+            ),
+            ProcessedResult::Replication(res) => format!(
+                "{{\"writes\":[{}]}}",
+                res.writes
+                    .iter()
+                    .map(|w| format!(
+                        "{{\"command\":{},\"key\":{}}}",
+                        json::quote(&w.command),
+                        json::quote(&w.key)
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+            ProcessedResult::KeyEvent(res) => format!(
+                "{{\"key\":{},\"event\":{}}}",
+                json::quote(&res.key),
+                json::quote(&res.event)
+            ),
+        }
+    }
+}
+
+fn quote_addr(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(|| "null".to_string(), |a| json::quote(&a.to_string()))
 }
 
 #[derive(Debug, Clone)]
 pub struct PrometheusResult {
     pub label: String,
     pub is_error: bool,
-    pub latency: u128,
+    /// Seconds.
+    pub latency: f64,
+    pub response_size: Option<usize>,
+    /// Additional label dimensions beyond `label`, e.g. ("command", "GET").
+    /// Processors fill in an empty value for dimensions a result doesn't carry.
+    pub labels: Vec<(String, String)>,
+}
+
+impl PrometheusResult {
+    /// Value of the named extra label, if the result carries it.
+    pub fn label_value(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A pub/sub message observed on the wire, counted separately from requests.
+#[derive(Debug, Clone)]
+pub struct PubSubResult {
+    pub channel: String,
+    /// The push type, e.g. `message` or `pmessage`.
+    pub kind: String,
+}
+
+/// A keyspace notification Redis published for a key that changed.
+#[derive(Debug, Clone)]
+pub struct KeyEventResult {
+    pub key: String,
+    /// The event, e.g. `set`, `del` or `expired`.
+    pub event: String,
+}
+
+/// Writes a master sent a replica over a replication connection, in stream order.
+#[derive(Debug, Clone)]
+pub struct ReplicationResult {
+    pub writes: Vec<ReplicatedWrite>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplicatedWrite {
+    pub command: String,
+    pub key: String,
 }
 
 /// PostProcessor trait that defines the interface for a post processor.
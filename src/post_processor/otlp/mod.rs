@@ -0,0 +1,168 @@
+use super::{PostProcessor, ProcessedResult};
+use crate::http_client::Endpoint;
+use crate::json;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tracing::error;
+
+lazy_static! {
+    static ref EXPORT_ERRORS: IntCounter = register_int_counter!(
+        "aragorn_otlp_export_errors_total",
+        "Number of batches that failed to be exported over OTLP"
+    )
+    .unwrap();
+    static ref DROPPED: IntCounter = register_int_counter!(
+        "aragorn_otlp_dropped_total",
+        "Number of results dropped because the OTLP queue was full"
+    )
+    .unwrap();
+}
+
+pub struct OtlpConfig {
+    /// Base URL of the collector's OTLP/HTTP endpoint, e.g. `http://localhost:4318`.
+    /// Logs are posted to `<url>/v1/logs`. Only plain http is supported.
+    pub url: String,
+    /// How long results gather before they are exported together.
+    pub interval: Duration,
+    /// Most results in one export.
+    pub max_batch: usize,
+    pub queue_size: usize,
+}
+
+impl Default for OtlpConfig {
+    fn default() -> Self {
+        OtlpConfig {
+            url: "http://localhost:4318".to_string(),
+            interval: Duration::from_secs(1),
+            max_batch: 512,
+            queue_size: 4096,
+        }
+    }
+}
+
+/// An OTLP/JSON logs request holding a log record per result. The body of a record
+/// is the result's JSON, and its label is the `label` attribute.
+fn export_request(batch: &[(SystemTime, ProcessedResult)]) -> String {
+    let records: Vec<String> = batch
+        .iter()
+        .map(|(time, res)| {
+            let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+            format!(
+                "{{\"timeUnixNano\":\"{}\",\"body\":{{\"stringValue\":{}}},\"attributes\":[{{\"key\":\"label\",\"value\":{{\"stringValue\":{}}}}}]}}",
+                nanos,
+                json::quote(&res.to_json()),
+                json::quote(res.label())
+            )
+        })
+        .collect();
+    format!(
+        "{{\"resourceLogs\":[{{\"resource\":{{\"attributes\":[{{\"key\":\"service.name\",\"value\":{{\"stringValue\":\"aragorn\"}}}}]}},\"scopeLogs\":[{{\"scope\":{{\"name\":\"aragorn\"}},\"logRecords\":[{}]}}]}}]}}",
+        records.join(",")
+    )
+}
+
+/// OtlpPostProcessor exports every result as a log record to an OpenTelemetry
+/// collector over OTLP/HTTP with JSON encoding. Results are batched by a background
+/// task. They are dropped when its queue is full, so a slow collector never blocks
+/// capture, and a batch that fails to export is dropped after being logged.
+pub struct OtlpPostProcessor {
+    tx: mpsc::Sender<(SystemTime, ProcessedResult)>,
+}
+
+impl OtlpPostProcessor {
+    pub fn new(cfg: OtlpConfig) -> Result<Self> {
+        let endpoint = Endpoint::new(&cfg.url)?.join("/v1/logs");
+        let (tx, mut rx) = mpsc::channel(cfg.queue_size);
+        tokio::spawn(async move {
+            while let Some(first) = rx.recv().await {
+                // Give the rest of the batch time to arrive
+                tokio::time::sleep(cfg.interval).await;
+                let mut batch = vec![first];
+                while batch.len() < cfg.max_batch {
+                    match rx.try_recv() {
+                        Ok(res) => batch.push(res),
+                        Err(_) => break,
+                    }
+                }
+                let body = export_request(&batch);
+                if let Err(e) = endpoint.post("application/json", body.as_bytes()).await {
+                    EXPORT_ERRORS.inc();
+                    error!(
+                        "Failed to export {} results over OTLP: {:?}",
+                        batch.len(),
+                        e
+                    );
+                }
+            }
+        });
+        Ok(OtlpPostProcessor { tx })
+    }
+}
+
+#[async_trait]
+impl PostProcessor for OtlpPostProcessor {
+    async fn post_process(&self, res: ProcessedResult) -> Result<()> {
+        match self.tx.try_send((SystemTime::now(), res)) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                DROPPED.inc();
+                Ok(())
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(anyhow!("OTLP export task has stopped"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PubSubResult;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn message(channel: &str) -> ProcessedResult {
+        ProcessedResult::PubSub(PubSubResult {
+            channel: channel.to_string(),
+            kind: "message".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_exports_batch() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let otlp = OtlpPostProcessor::new(OtlpConfig {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            interval: Duration::from_millis(50),
+            ..Default::default()
+        })
+        .unwrap();
+        otlp.post_process(message("a")).await.unwrap();
+        otlp.post_process(message("b")).await.unwrap();
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        // The request is complete once its body closes the JSON document
+        while !request.ends_with(b"]}]}]}") {
+            let mut buf = [0; 4096];
+            let n = socket.read(&mut buf).await.unwrap();
+            assert!(n > 0, "{}", String::from_utf8_lossy(&request));
+            request.extend_from_slice(&buf[..n]);
+        }
+        socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+
+        let request = String::from_utf8(request).unwrap();
+        assert!(request.starts_with("POST /v1/logs HTTP/1.1\r\n"));
+        // Both results share the export
+        assert!(request.contains(
+            "\"body\":{\"stringValue\":\"{\\\"channel\\\":\\\"a\\\",\\\"kind\\\":\\\"message\\\"}\"}"
+        ));
+        assert!(request
+            .contains("\"attributes\":[{\"key\":\"label\",\"value\":{\"stringValue\":\"b\"}}]"));
+    }
+}
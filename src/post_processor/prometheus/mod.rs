@@ -1,29 +1,225 @@
-use super::{PostProcessor, ProcessedResult};
+use super::{PostProcessor, ProcessedResult, PrometheusResult};
+use crate::openmetrics;
 use anyhow::Result;
 use async_trait::async_trait;
-use prometheus::{register_counter_vec, register_histogram_vec, CounterVec, HistogramVec};
+use prometheus::{CounterVec, HistogramOpts, HistogramVec, Opts, Registry};
+use std::net::SocketAddr;
+
+/// Extra label dimensions registered by default alongside `key`.
+const DEFAULT_LABELS: &[&str] = &["command"];
+
+/// How the latency histogram is bucketed.
+#[derive(Debug, Clone, Default)]
+pub enum LatencyBuckets {
+    /// The client library's default buckets, 5ms to 10s.
+    #[default]
+    Default,
+    /// Exponential buckets laid out like a Prometheus native histogram of the given
+    /// schema: boundaries are powers of `2^(2^-schema)` covering `min` to `max`.
+    Exponential { schema: i32, min: f64, max: f64 },
+}
+
+impl LatencyBuckets {
+    fn buckets(&self) -> Result<Vec<f64>> {
+        match *self {
+            LatencyBuckets::Default => Ok(prometheus::DEFAULT_BUCKETS.to_vec()),
+            LatencyBuckets::Exponential { schema, min, max } => schema_buckets(schema, min, max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusConfig {
+    pub latency_buckets: LatencyBuckets,
+    /// Add a `server_name` label holding the host name TLS clients asked for.
+    pub server_name_label: bool,
+    /// Add `tls_version` and `tls_cipher` labels holding what TLS connections negotiated.
+    pub tls_labels: bool,
+}
+
+/// Bucket boundaries `2^(k * 2^-schema)` from the one at or below `min` to the one at or
+/// above `max`. Each bucket is at most `2^(2^-schema)` times wider than the previous, so
+/// the relative error of an estimated quantile is bounded regardless of the range.
+pub fn schema_buckets(schema: i32, min: f64, max: f64) -> Result<Vec<f64>> {
+    if !(-4..=8).contains(&schema) {
+        anyhow::bail!("Schema must be between -4 and 8, got {}", schema);
+    }
+    if !(min > 0.0 && max > min) {
+        anyhow::bail!("Invalid bucket range {} to {}", min, max);
+    }
+    let scale = 2f64.powi(schema);
+    let first = (min.log2() * scale).floor() as i32;
+    let last = (max.log2() * scale).ceil() as i32;
+    Ok((first..=last)
+        .map(|k| 2f64.powf(f64::from(k) / scale))
+        .collect())
+}
 
 pub struct PrometheusPostProcessor {
+    /// `key` followed by the extra label names, in registration order.
+    label_names: Vec<String>,
     requests: CounterVec,
     errors: CounterVec,
     latency: HistogramVec,
+    latency_buckets: Vec<f64>,
+    response_size: HistogramVec,
+    request_bytes: HistogramVec,
+    response_bytes: HistogramVec,
+    pubsub_messages: CounterVec,
+    replicated_writes: CounterVec,
+    key_events: CounterVec,
+}
+
+impl Default for PrometheusPostProcessor {
+    fn default() -> Self {
+        Self::new(PrometheusConfig::default()).unwrap()
+    }
 }
 
 impl PrometheusPostProcessor {
-    pub fn new() -> Self {
-        let requests =
-            register_counter_vec!("requests_total", "Number of requests", &["key"]).unwrap();
+    pub fn new(cfg: PrometheusConfig) -> Result<Self> {
+        let mut labels = DEFAULT_LABELS.to_vec();
+        if cfg.server_name_label {
+            labels.push("server_name");
+        }
+        if cfg.tls_labels {
+            labels.extend(["tls_version", "tls_cipher"]);
+        }
+        Self::with_config(prometheus::default_registry(), &labels, &cfg)
+    }
 
-        let errors = register_counter_vec!("errors_total", "Number of errors", &["key"]).unwrap();
+    /// Register the metrics in `registry` with a `key` label plus `extra_labels`.
+    pub fn with_registry(registry: &Registry, extra_labels: &[&str]) -> Result<Self> {
+        Self::with_config(registry, extra_labels, &PrometheusConfig::default())
+    }
 
-        let latency =
-            register_histogram_vec!("latency_seconds", "Request latency in seconds", &["key"])
-                .unwrap();
+    pub fn with_config(
+        registry: &Registry,
+        extra_labels: &[&str],
+        cfg: &PrometheusConfig,
+    ) -> Result<Self> {
+        let label_names: Vec<String> = std::iter::once("key")
+            .chain(extra_labels.iter().copied())
+            .map(String::from)
+            .collect();
+        let names: Vec<&str> = label_names.iter().map(String::as_str).collect();
+
+        let requests = CounterVec::new(Opts::new("requests_total", "Number of requests"), &names)?;
+        let errors = CounterVec::new(Opts::new("errors_total", "Number of errors"), &names)?;
+        let latency_buckets = cfg.latency_buckets.buckets()?;
+        let latency = HistogramVec::new(
+            HistogramOpts::new("latency_seconds", "Request latency in seconds")
+                .buckets(latency_buckets.clone()),
+            &names,
+        )?;
+        let response_size = HistogramVec::new(
+            HistogramOpts::new(
+                "response_size",
+                "Size of the response (integer value, bulk string length or array length)",
+            )
+            .buckets(prometheus::exponential_buckets(1.0, 4.0, 12)?),
+            &names,
+        )?;
+
+        // 64 bytes to 16MB, to tell small values from oversized ones
+        let byte_buckets = prometheus::exponential_buckets(64.0, 4.0, 10)?;
+        let request_bytes = HistogramVec::new(
+            HistogramOpts::new(
+                "aragorn_request_bytes",
+                "Size of the TCP payload carrying a request, in bytes",
+            )
+            .buckets(byte_buckets.clone()),
+            &["command"],
+        )?;
+        let response_bytes = HistogramVec::new(
+            HistogramOpts::new(
+                "aragorn_response_bytes",
+                "Size of the TCP payload carrying a response, in bytes",
+            )
+            .buckets(byte_buckets),
+            &["command"],
+        )?;
+
+        let pubsub_messages = CounterVec::new(
+            Opts::new("pubsub_messages_total", "Number of pub/sub messages"),
+            &["channel", "kind"],
+        )?;
+        let replicated_writes = CounterVec::new(
+            Opts::new(
+                "replicated_writes_total",
+                "Number of writes replicated from a master",
+            ),
+            &["key", "command"],
+        )?;
+        let key_events = CounterVec::new(
+            Opts::new(
+                "key_events_total",
+                "Number of keyspace notifications, by key and event",
+            ),
+            &["key", "event"],
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(latency.clone()))?;
+        registry.register(Box::new(response_size.clone()))?;
+        registry.register(Box::new(request_bytes.clone()))?;
+        registry.register(Box::new(response_bytes.clone()))?;
+        registry.register(Box::new(pubsub_messages.clone()))?;
+        registry.register(Box::new(replicated_writes.clone()))?;
+        registry.register(Box::new(key_events.clone()))?;
 
-        PrometheusPostProcessor {
+        Ok(PrometheusPostProcessor {
+            label_names,
             requests,
             errors,
             latency,
+            latency_buckets,
+            response_size,
+            request_bytes,
+            response_bytes,
+            pubsub_messages,
+            replicated_writes,
+            key_events,
+        })
+    }
+
+    /// Record a result. When the client is known its address is kept as the exemplar
+    /// of the latency bucket, served with OpenMetrics.
+    fn observe(&self, res: PrometheusResult, client: Option<SocketAddr>) {
+        // A result carrying only `label` still works: missing dimensions are empty.
+        let values: Vec<&str> = std::iter::once(res.label.as_str())
+            .chain(
+                self.label_names[1..]
+                    .iter()
+                    .map(|name| res.label_value(name).unwrap_or_default()),
+            )
+            .collect();
+
+        self.requests.with_label_values(&values).inc();
+        self.latency.with_label_values(&values).observe(res.latency);
+        if let Some(client) = client {
+            let labels: Vec<(&str, &str)> = self
+                .label_names
+                .iter()
+                .map(String::as_str)
+                .zip(values.iter().copied())
+                .collect();
+            openmetrics::record_exemplar(
+                "latency_seconds",
+                &labels,
+                &self.latency_buckets,
+                res.latency,
+                vec![("client".to_string(), client.to_string())],
+            );
+        }
+        if let Some(size) = res.response_size {
+            self.response_size
+                .with_label_values(&values)
+                .observe(size as f64);
+        }
+        if res.is_error {
+            self.errors.with_label_values(&values).inc();
         }
     }
 }
@@ -32,19 +228,365 @@ impl PrometheusPostProcessor {
 impl PostProcessor for PrometheusPostProcessor {
     async fn post_process(&self, res: ProcessedResult) -> Result<()> {
         match res {
-            ProcessedResult::Prometheus(res) => {
-                let label = res.label;
-                let latency = res.latency;
-
-                self.requests.with_label_values(&[&label]).inc();
-                self.latency
-                    .with_label_values(&[&label])
-                    .observe(latency as f64);
-                if res.is_error {
-                    self.errors.with_label_values(&[&label]).inc();
+            ProcessedResult::Prometheus(res) => self.observe(res, None),
+            ProcessedResult::Redis(res) => {
+                self.request_bytes
+                    .with_label_values(&[&res.command])
+                    .observe(res.request_bytes as f64);
+                self.response_bytes
+                    .with_label_values(&[&res.command])
+                    .observe(res.response_bytes as f64);
+                let mut labels = vec![("command".to_string(), res.command)];
+                if let Some(name) = res.server_name {
+                    labels.push(("server_name".to_string(), name));
                 }
+                if let Some(tls) = res.tls {
+                    labels.push(("tls_version".to_string(), tls.version));
+                    labels.push(("tls_cipher".to_string(), tls.cipher));
+                }
+                self.observe(
+                    PrometheusResult {
+                        label: res.key,
+                        is_error: res.is_error,
+                        latency: res.latency,
+                        response_size: res.response_size,
+                        labels,
+                    },
+                    res.client_addr,
+                )
+            }
+            ProcessedResult::PubSub(res) => {
+                self.pubsub_messages
+                    .with_label_values(&[&res.channel, &res.kind])
+                    .inc();
+            }
+            ProcessedResult::Replication(res) => {
+                for write in res.writes {
+                    self.replicated_writes
+                        .with_label_values(&[&write.key, &write.command])
+                        .inc();
+                }
+            }
+            ProcessedResult::KeyEvent(res) => {
+                self.key_events
+                    .with_label_values(&[&res.key, &res.event])
+                    .inc();
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Encoder, TextEncoder};
+
+    fn export(registry: &Registry) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_multiple_label_dimensions() {
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(&registry, &["command"]).unwrap();
+
+        processor
+            .post_process(ProcessedResult::Prometheus(PrometheusResult {
+                label: "foo".to_string(),
+                is_error: false,
+                latency: 0.001,
+                response_size: None,
+                labels: vec![("command".to_string(), "GET".to_string())],
+            }))
+            .await
+            .unwrap();
+        // A single label result is still accepted
+        processor
+            .post_process(ProcessedResult::Prometheus(PrometheusResult {
+                label: "bar".to_string(),
+                is_error: true,
+                latency: 0.001,
+                response_size: None,
+                labels: vec![],
+            }))
+            .await
+            .unwrap();
+
+        let out = export(&registry);
+        assert!(out.contains("requests_total{command=\"GET\",key=\"foo\"} 1"));
+        assert!(out.contains("latency_seconds_count{command=\"GET\",key=\"foo\"} 1"));
+        assert!(out.contains("latency_seconds_sum{command=\"GET\",key=\"foo\"} 0.001"));
+        assert!(out.contains("requests_total{command=\"\",key=\"bar\"} 1"));
+        assert!(out.contains("errors_total{command=\"\",key=\"bar\"} 1"));
+    }
+
+    #[test]
+    fn test_schema_buckets() {
+        assert_eq!(
+            schema_buckets(0, 0.3, 3.0).unwrap(),
+            vec![0.25, 0.5, 1.0, 2.0, 4.0]
+        );
+        let buckets = schema_buckets(2, 1.0, 2.0).unwrap();
+        assert_eq!(buckets.len(), 5);
+        assert_eq!((buckets[0], buckets[4]), (1.0, 2.0));
+        assert!((buckets[2] - 2f64.sqrt()).abs() < 1e-12);
+        assert!(schema_buckets(9, 1.0, 2.0).is_err());
+        assert!(schema_buckets(0, 0.0, 2.0).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_payload_bytes() {
+        use crate::plugin::redis::handler::{RespConfig, RespHandler};
+        use crate::plugin::{Metrics, Plugin, RequestId};
+        use std::time::Duration;
+
+        let metrics = |latency| {
+            Some(Metrics {
+                identifier: RequestId::Connection(1),
+                latency,
+                src_addr: None,
+                dst_addr: None,
+            })
+        };
+        let handler = RespHandler::new(6379, RespConfig::default());
+        // A 1000 byte payload
+        let mut set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$972\r\n".to_vec();
+        set.extend_from_slice(&[b'v'; 972]);
+        set.extend_from_slice(b"\r\n");
+        assert_eq!(set.len(), 1000);
+        handler.process(set, metrics(None)).await.unwrap();
+        let res = handler
+            .process(b"+OK\r\n".to_vec(), metrics(Some(Duration::from_millis(1))))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(&registry, &["command"]).unwrap();
+        processor.post_process(res.into()).await.unwrap();
+
+        let out = export(&registry);
+        assert!(out.contains("aragorn_request_bytes_bucket{command=\"SET\",le=\"256\"} 0"));
+        assert!(out.contains("aragorn_request_bytes_bucket{command=\"SET\",le=\"1024\"} 1"));
+        assert!(out.contains("aragorn_request_bytes_sum{command=\"SET\"} 1000"));
+        assert!(out.contains("aragorn_response_bytes_bucket{command=\"SET\",le=\"64\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_key_events() {
+        use crate::plugin::redis::handler::{RespConfig, RespHandler};
+        use crate::plugin::Plugin;
+
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(&registry, &["command"]).unwrap();
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let notifications: [&[u8]; 4] = [
+            b"*3\r\n$7\r\nmessage\r\n$21\r\n__keyspace@0__:user:1\r\n$3\r\nset\r\n",
+            b">3\r\n$7\r\nmessage\r\n$21\r\n__keyspace@0__:user:1\r\n$3\r\nset\r\n",
+            b"*4\r\n$8\r\npmessage\r\n$16\r\n__keyevent@*__:*\r\n$22\r\n__keyevent@0__:expired\r\n$9\r\nsession:9\r\n",
+            b">4\r\n$8\r\npmessage\r\n$16\r\n__keyevent@*__:*\r\n$22\r\n__keyevent@0__:expired\r\n$9\r\nsession:9\r\n",
+        ];
+        for notification in notifications {
+            let res = handler
+                .process(notification.to_vec(), None)
+                .await
+                .unwrap()
+                .unwrap();
+            processor.post_process(res.into()).await.unwrap();
+        }
+
+        let out = export(&registry);
+        assert!(out.contains("key_events_total{event=\"set\",key=\"user:1\"} 2"));
+        assert!(out.contains("key_events_total{event=\"expired\",key=\"session:9\"} 2"));
+        assert!(!out.contains("pubsub_messages_total{"));
+    }
+
+    #[tokio::test]
+    async fn test_server_name_label() {
+        use crate::plugin::redis::handler::{RedisResult, RedisResultKind};
+
+        let registry = Registry::new();
+        let processor =
+            PrometheusPostProcessor::with_registry(&registry, &["command", "server_name"]).unwrap();
+        for server_name in [Some("cache.example.com"), None] {
+            let result = ProcessedResult::Redis(RedisResult {
+                kind: RedisResultKind::Request,
+                command: "GET".to_string(),
+                key: "foo".to_string(),
+                is_error: false,
+                latency: 0.003,
+                response_size: None,
+                client_addr: None,
+                server_addr: None,
+                server_name: server_name.map(String::from),
+                tls: None,
+                request_bytes: 0,
+                response_bytes: 0,
+            });
+            processor.post_process(result).await.unwrap();
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains(
+            "requests_total{command=\"GET\",key=\"foo\",server_name=\"cache.example.com\"} 1"
+        ));
+        assert!(output.contains("requests_total{command=\"GET\",key=\"foo\",server_name=\"\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_tls_labels() {
+        use crate::plugin::redis::handler::{RedisResult, RedisResultKind};
+        use crate::tls::Negotiated;
+
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(
+            &registry,
+            &["command", "tls_version", "tls_cipher"],
+        )
+        .unwrap();
+        let result = ProcessedResult::Redis(RedisResult {
+            kind: RedisResultKind::Request,
+            command: "GET".to_string(),
+            key: "foo".to_string(),
+            is_error: false,
+            latency: 0.003,
+            response_size: None,
+            client_addr: None,
+            server_addr: None,
+            server_name: None,
+            tls: Some(Negotiated {
+                version: "TLSv1.1".to_string(),
+                cipher: "TLS_RSA_WITH_3DES_EDE_CBC_SHA".to_string(),
+            }),
+            request_bytes: 0,
+            response_bytes: 0,
+        });
+        processor.post_process(result).await.unwrap();
+
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&registry.gather(), &mut buffer)
+            .unwrap();
+        let output = String::from_utf8(buffer).unwrap();
+        assert!(output.contains(
+            "requests_total{command=\"GET\",key=\"foo\",tls_cipher=\"TLS_RSA_WITH_3DES_EDE_CBC_SHA\",tls_version=\"TLSv1.1\"} 1"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_exponential_latency_buckets() {
+        let registry = Registry::new();
+        let cfg = PrometheusConfig {
+            latency_buckets: LatencyBuckets::Exponential {
+                schema: 0,
+                min: 1.0,
+                max: 64.0,
+            },
+            ..Default::default()
+        };
+        let processor = PrometheusPostProcessor::with_config(&registry, &[], &cfg).unwrap();
+        processor
+            .post_process(ProcessedResult::Prometheus(PrometheusResult {
+                label: "foo".to_string(),
+                is_error: false,
+                latency: 3.0,
+                response_size: None,
+                labels: vec![],
+            }))
+            .await
+            .unwrap();
+
+        let out = export(&registry);
+        assert!(out.contains("latency_seconds_bucket{key=\"foo\",le=\"2\"} 0"));
+        assert!(out.contains("latency_seconds_bucket{key=\"foo\",le=\"4\"} 1"));
+        assert!(out.contains("latency_seconds_bucket{key=\"foo\",le=\"64\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_openmetrics_exemplar() {
+        use crate::plugin::redis::handler::{RedisResult, RedisResultKind};
+
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(&registry, DEFAULT_LABELS).unwrap();
+        let result = |key: &str, client_addr| {
+            ProcessedResult::Redis(RedisResult {
+                kind: RedisResultKind::Request,
+                command: "GET".to_string(),
+                key: key.to_string(),
+                is_error: false,
+                latency: 0.003,
+                response_size: None,
+                client_addr,
+                server_addr: None,
+                server_name: None,
+                tls: None,
+                request_bytes: 0,
+                response_bytes: 0,
+            })
+        };
+        let client = "10.0.0.2:50000".parse().unwrap();
+        processor
+            .post_process(result("exemplar", Some(client)))
+            .await
+            .unwrap();
+        processor
+            .post_process(result("no-exemplar", None))
+            .await
+            .unwrap();
+
+        let out = openmetrics::encode(&registry.gather());
+        let exemplars: Vec<&str> = out.lines().filter(|l| l.contains(" # {")).collect();
+        assert_eq!(exemplars.len(), 1);
+        assert!(exemplars[0].starts_with(
+            "latency_seconds_bucket{command=\"GET\",key=\"exemplar\",le=\"0.005\"} 1 # {client=\"10.0.0.2:50000\"} 0.003 "
+        ));
+        assert!(out.contains("requests_total{command=\"GET\",key=\"no-exemplar\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_redis_command_label() {
+        use crate::plugin::redis::handler::{RespConfig, RespHandler};
+        use crate::plugin::{Metrics, Plugin, RequestId};
+        use std::time::Duration;
+
+        let registry = Registry::new();
+        let processor = PrometheusPostProcessor::with_registry(&registry, DEFAULT_LABELS).unwrap();
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let metrics = |latency| {
+            Some(Metrics {
+                identifier: RequestId::Connection(1),
+                latency,
+                src_addr: None,
+                dst_addr: None,
+            })
+        };
+        let exchanges: [(&[u8], &[u8]); 3] = [
+            (b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$1\r\nv\r\n", b"+OK\r\n"),
+            (b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n", b"$1\r\nv\r\n"),
+            (b"*1\r\n$4\r\nPING\r\n", b"+PONG\r\n"),
+        ];
+        for (req, res) in exchanges {
+            handler.process(req.to_vec(), metrics(None)).await.unwrap();
+            let res = handler
+                .process(res.to_vec(), metrics(Some(Duration::from_millis(1))))
+                .await
+                .unwrap()
+                .unwrap();
+            processor.post_process(res.into()).await.unwrap();
+        }
+
+        let out = export(&registry);
+        assert!(out.contains("requests_total{command=\"SET\",key=\"k\"} 1"));
+        assert!(out.contains("requests_total{command=\"GET\",key=\"k\"} 1"));
+        assert!(out.contains("requests_total{command=\"PING\",key=\"__nokey__\"} 1"));
+    }
+}
@@ -0,0 +1,72 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// RecentRequestsPostProcessor keeps the last `capacity` results in memory for live
+/// debugging. Clones share the same buffer, so one can be handed to the observer and
+/// another to the HTTP server.
+#[derive(Clone)]
+pub struct RecentRequestsPostProcessor {
+    buffer: Arc<Mutex<VecDeque<ProcessedResult>>>,
+    capacity: usize,
+}
+
+impl RecentRequestsPostProcessor {
+    pub fn new(capacity: usize) -> Self {
+        RecentRequestsPostProcessor {
+            buffer: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    /// The buffered results as a JSON array, newest first.
+    pub async fn to_json(&self) -> String {
+        let buffer = self.buffer.lock().await;
+        let entries: Vec<String> = buffer.iter().rev().map(|res| res.to_json()).collect();
+        format!("[{}]", entries.join(","))
+    }
+}
+
+#[async_trait]
+impl PostProcessor for RecentRequestsPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut buffer = self.buffer.lock().await;
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        buffer.push_back(input);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PubSubResult;
+
+    fn result(channel: &str) -> ProcessedResult {
+        ProcessedResult::PubSub(PubSubResult {
+            channel: channel.to_string(),
+            kind: "message".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_keeps_most_recent() {
+        let recent = RecentRequestsPostProcessor::new(2);
+        let sink = recent.clone();
+        for channel in ["a", "b", "c"] {
+            sink.post_process(result(channel)).await.unwrap();
+        }
+        assert_eq!(
+            recent.to_json().await,
+            "[{\"channel\":\"c\",\"kind\":\"message\"},{\"channel\":\"b\",\"kind\":\"message\"}]"
+        );
+    }
+}
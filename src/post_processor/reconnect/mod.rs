@@ -0,0 +1,206 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+lazy_static! {
+    static ref DROPPED: IntCounter = register_int_counter!(
+        "aragorn_reconnect_dropped_total",
+        "Number of results dropped because a disconnected sink's buffer was full"
+    )
+    .unwrap();
+}
+
+pub struct ReconnectConfig {
+    /// Wait before the first reconnection attempt, doubled after every failure.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Results held while the sink is down. The oldest are dropped beyond this.
+    pub buffer_size: usize,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        ReconnectConfig {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            buffer_size: 1024,
+        }
+    }
+}
+
+struct State<P> {
+    sink: Option<P>,
+    buffer: VecDeque<ProcessedResult>,
+    backoff: Duration,
+    retry_at: Instant,
+}
+
+/// ReconnectingPostProcessor wraps a network sink built by `connect`. Any error from
+/// the sink is taken to mean its connection is gone: the sink is dropped and rebuilt
+/// with exponential backoff, and results are buffered in the meantime and delivered
+/// in order once it is back.
+///
+/// Reconnection is attempted from `post_process` once the backoff has passed, so a
+/// sink whose `connect` can hang should bound it with a timeout.
+pub struct ReconnectingPostProcessor<P, F> {
+    connect: F,
+    cfg: ReconnectConfig,
+    state: Mutex<State<P>>,
+}
+
+impl<P, F, Fut> ReconnectingPostProcessor<P, F>
+where
+    P: PostProcessor,
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<P>> + Send,
+{
+    /// The sink is first connected when the first result arrives.
+    pub fn new(connect: F, cfg: ReconnectConfig) -> Self {
+        let state = State {
+            sink: None,
+            buffer: VecDeque::new(),
+            backoff: cfg.initial_backoff,
+            retry_at: Instant::now(),
+        };
+        ReconnectingPostProcessor {
+            connect,
+            cfg,
+            state: Mutex::new(state),
+        }
+    }
+
+    fn disconnected(&self, state: &mut State<P>, e: anyhow::Error) {
+        warn!("Sink failed, reconnecting in {:?}: {:?}", state.backoff, e);
+        state.sink = None;
+        state.retry_at = Instant::now() + state.backoff;
+        state.backoff = (state.backoff * 2).min(self.cfg.max_backoff);
+    }
+}
+
+#[async_trait]
+impl<P, F, Fut> PostProcessor for ReconnectingPostProcessor<P, F>
+where
+    P: PostProcessor,
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = Result<P>> + Send,
+{
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let mut state = self.state.lock().await;
+        if state.buffer.len() >= self.cfg.buffer_size.max(1) {
+            state.buffer.pop_front();
+            DROPPED.inc();
+        }
+        state.buffer.push_back(input);
+
+        if state.sink.is_none() {
+            if Instant::now() < state.retry_at {
+                return Ok(());
+            }
+            match (self.connect)().await {
+                Ok(sink) => state.sink = Some(sink),
+                Err(e) => {
+                    self.disconnected(&mut state, e);
+                    return Ok(());
+                }
+            }
+        }
+
+        while let Some(res) = state.buffer.front().cloned() {
+            let sink = state.sink.as_ref().expect("sink is connected");
+            if let Err(e) = sink.post_process(res).await {
+                self.disconnected(&mut state, e);
+                return Ok(());
+            }
+            state.buffer.pop_front();
+        }
+        state.backoff = self.cfg.initial_backoff;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PubSubResult;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Fails every result if `fail` is set, like a sink whose connection is gone.
+    struct MockSink {
+        fail: bool,
+        delivered: Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl PostProcessor for MockSink {
+        async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("connection reset");
+            }
+            self.delivered
+                .lock()
+                .unwrap()
+                .push(input.label().to_string());
+            Ok(())
+        }
+    }
+
+    fn result(channel: &str) -> ProcessedResult {
+        ProcessedResult::PubSub(PubSubResult {
+            channel: channel.to_string(),
+            kind: "message".to_string(),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_buffers_until_reconnected() {
+        let connects = Arc::new(AtomicUsize::new(0));
+        let delivered = Arc::new(std::sync::Mutex::new(vec![]));
+        let processor = ReconnectingPostProcessor::new(
+            || {
+                let fail = connects.fetch_add(1, Ordering::SeqCst) == 0;
+                let delivered = delivered.clone();
+                async move { Ok(MockSink { fail, delivered }) }
+            },
+            ReconnectConfig {
+                initial_backoff: Duration::from_millis(20),
+                ..Default::default()
+            },
+        );
+
+        processor.post_process(result("a")).await.unwrap();
+        // Still backing off, so this is only buffered
+        processor.post_process(result("b")).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+        assert!(delivered.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        processor.post_process(result("c")).await.unwrap();
+        assert_eq!(connects.load(Ordering::SeqCst), 2);
+        assert_eq!(*delivered.lock().unwrap(), vec!["a", "b", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_buffer_is_bounded() {
+        let processor = ReconnectingPostProcessor::new(
+            || async { Err::<MockSink, _>(anyhow::anyhow!("connection refused")) },
+            ReconnectConfig {
+                buffer_size: 2,
+                ..Default::default()
+            },
+        );
+        for channel in ["a", "b", "c"] {
+            processor.post_process(result(channel)).await.unwrap();
+        }
+        let state = processor.state.lock().await;
+        let buffered: Vec<&str> = state.buffer.iter().map(|r| r.label()).collect();
+        assert_eq!(buffered, vec!["b", "c"]);
+    }
+}
@@ -0,0 +1,153 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+
+pub struct StatsdConfig {
+    /// `host:port` of the StatsD server.
+    pub addr: String,
+    /// Prepended to every metric name.
+    pub prefix: String,
+}
+
+impl Default for StatsdConfig {
+    fn default() -> Self {
+        StatsdConfig {
+            addr: "localhost:8125".to_string(),
+            prefix: "aragorn".to_string(),
+        }
+    }
+}
+
+/// StatsdPostProcessor sends the metrics of every result to a StatsD server over UDP,
+/// named by label: `<prefix>.requests.<label>` and `<prefix>.errors.<label>` counters
+/// and a `<prefix>.latency.<label>` timer in milliseconds. The metrics of a result
+/// share a datagram.
+///
+/// Like StatsD clients generally, it doesn't wait for the server: a datagram the
+/// socket can't take right away is dropped.
+pub struct StatsdPostProcessor {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    prefix: String,
+}
+
+impl StatsdPostProcessor {
+    pub fn new(cfg: StatsdConfig) -> Result<Self> {
+        let addr = cfg
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("StatsD address {} doesn't resolve", cfg.addr))?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.set_nonblocking(true)?;
+        Ok(StatsdPostProcessor {
+            socket,
+            addr,
+            prefix: cfg.prefix,
+        })
+    }
+
+    fn metrics(&self, input: &ProcessedResult) -> Vec<String> {
+        let name =
+            |metric: &str, label: &str| format!("{}.{}.{}", self.prefix, metric, bucket(label));
+        let (is_error, latency) = match input {
+            ProcessedResult::Prometheus(res) => (res.is_error, res.latency),
+            ProcessedResult::Redis(res) => (res.is_error, res.latency),
+            ProcessedResult::PubSub(res) => {
+                return vec![format!("{}:1|c", name("pubsub_messages", &res.channel))]
+            }
+            ProcessedResult::Replication(res) => {
+                return res
+                    .writes
+                    .iter()
+                    .map(|w| format!("{}:1|c", name("replicated_writes", &w.key)))
+                    .collect()
+            }
+            ProcessedResult::KeyEvent(res) => {
+                return vec![format!("{}:1|c", name("key_events", &res.key))]
+            }
+        };
+        let label = input.label();
+        let mut metrics = vec![format!("{}:1|c", name("requests", label))];
+        if is_error {
+            metrics.push(format!("{}:1|c", name("errors", label)));
+        }
+        metrics.push(format!("{}:{}|ms", name("latency", label), latency * 1e3));
+        metrics
+    }
+}
+
+/// A label as one segment of a metric name: StatsD separates names with `.` and
+/// values with `:` and `|`, so anything but letters, digits, `-` and `_` is replaced.
+fn bucket(label: &str) -> String {
+    label
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+#[async_trait]
+impl PostProcessor for StatsdPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let packet = self.metrics(&input).join("\n");
+        match self.socket.send_to(packet.as_bytes(), self.addr) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugin::redis::handler::{RedisResult, RedisResultKind};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_sends_request_metrics() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let statsd = StatsdPostProcessor::new(StatsdConfig {
+            addr: server.local_addr().unwrap().to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        statsd
+            .post_process(ProcessedResult::Redis(RedisResult {
+                kind: RedisResultKind::Request,
+                command: "GET".to_string(),
+                key: "user:1".to_string(),
+                is_error: true,
+                latency: 0.0025,
+                response_size: None,
+                client_addr: None,
+                server_addr: None,
+                server_name: None,
+                tls: None,
+                request_bytes: 0,
+                response_bytes: 0,
+            }))
+            .await
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf[..n]).unwrap(),
+            "aragorn.requests.user_1:1|c\naragorn.errors.user_1:1|c\naragorn.latency.user_1:2.5|ms"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use super::{PostProcessor, ProcessedResult};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+pub struct SummaryConfig {
+    /// Keys listed in the summary, those with the most requests first.
+    pub top_keys: usize,
+}
+
+impl Default for SummaryConfig {
+    fn default() -> Self {
+        SummaryConfig { top_keys: 10 }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    /// Latency of every request, in seconds.
+    latencies: Vec<f64>,
+    errors: u64,
+}
+
+/// Counts and latency percentiles of a set of requests.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totals {
+    pub requests: u64,
+    pub errors: u64,
+    /// Seconds.
+    pub p50: f64,
+    pub p99: f64,
+}
+
+impl Totals {
+    fn new(latencies: &mut [f64], errors: u64) -> Self {
+        latencies.sort_by(f64::total_cmp);
+        Totals {
+            requests: latencies.len() as u64,
+            errors,
+            p50: percentile(latencies, 0.5),
+            p99: percentile(latencies, 0.99),
+        }
+    }
+
+    /// Fraction of the requests that failed, 0 without requests.
+    pub fn error_rate(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+        self.errors as f64 / self.requests as f64
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// What `SummaryPostProcessor` saw: the totals over every request, and per key for
+/// the busiest keys.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Summary {
+    pub total: Totals,
+    pub keys: Vec<(String, Totals)>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let row = |f: &mut fmt::Formatter<'_>, key: &str, width: usize, t: &Totals| {
+            writeln!(
+                f,
+                "{:<width$}  {:>9}  {:>6.1}%  {:>9.3}ms  {:>9.3}ms",
+                key,
+                t.requests,
+                t.error_rate() * 100.0,
+                t.p50 * 1e3,
+                t.p99 * 1e3,
+            )
+        };
+        let width = self
+            .keys
+            .iter()
+            .map(|(key, _)| key.len())
+            .chain(["TOTAL".len()])
+            .max()
+            .unwrap_or_default();
+        writeln!(
+            f,
+            "{:<width$}  {:>9}  {:>7}  {:>11}  {:>11}",
+            "KEY", "REQUESTS", "ERRORS", "P50", "P99"
+        )?;
+        for (key, totals) in &self.keys {
+            row(f, key, width, totals)?;
+        }
+        row(f, "TOTAL", width, &self.total)
+    }
+}
+
+/// SummaryPostProcessor keeps the latency of every request to summarize a short
+/// capture when it ends. Memory grows with the number of requests, so it is meant
+/// for captures of a fixed duration. Clones share the same stats.
+#[derive(Clone)]
+pub struct SummaryPostProcessor {
+    stats: Arc<Mutex<HashMap<String, Stats>>>,
+    top_keys: usize,
+}
+
+impl SummaryPostProcessor {
+    pub fn new(cfg: SummaryConfig) -> Self {
+        SummaryPostProcessor {
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            top_keys: cfg.top_keys,
+        }
+    }
+
+    pub async fn summary(&self) -> Summary {
+        let stats = self.stats.lock().await;
+        let mut all = vec![];
+        let mut errors = 0;
+        let mut keys: Vec<(String, Totals)> = stats
+            .iter()
+            .map(|(key, stats)| {
+                all.extend_from_slice(&stats.latencies);
+                errors += stats.errors;
+                let mut latencies = stats.latencies.clone();
+                (key.clone(), Totals::new(&mut latencies, stats.errors))
+            })
+            .collect();
+        keys.sort_by(|a, b| b.1.requests.cmp(&a.1.requests).then(a.0.cmp(&b.0)));
+        keys.truncate(self.top_keys);
+        Summary {
+            total: Totals::new(&mut all, errors),
+            keys,
+        }
+    }
+}
+
+#[async_trait]
+impl PostProcessor for SummaryPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let (is_error, latency) = match &input {
+            ProcessedResult::Prometheus(res) => (res.is_error, res.latency),
+            ProcessedResult::Redis(res) => (res.is_error, res.latency),
+            ProcessedResult::PubSub(_)
+            | ProcessedResult::Replication(_)
+            | ProcessedResult::KeyEvent(_) => return Ok(()),
+        };
+        let mut stats = self.stats.lock().await;
+        let stats = stats.entry(input.label().to_string()).or_default();
+        stats.latencies.push(latency);
+        stats.errors += u64::from(is_error);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PrometheusResult;
+
+    fn result(label: &str, latency_ms: u32, is_error: bool) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: label.to_string(),
+            is_error,
+            latency: f64::from(latency_ms) / 1e3,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_summary() {
+        let summary = SummaryPostProcessor::new(SummaryConfig { top_keys: 1 });
+        for i in 1..=100 {
+            let label = if i % 4 == 0 { "cold" } else { "hot" };
+            summary
+                .post_process(result(label, i, i % 20 == 0))
+                .await
+                .unwrap();
+        }
+
+        let res = summary.summary().await;
+        assert_eq!(res.total.requests, 100);
+        assert_eq!(res.total.error_rate(), 0.05);
+        assert_eq!(res.total.p50, 0.05);
+        assert_eq!(res.total.p99, 0.099);
+
+        // Only the busiest key is listed
+        assert_eq!(res.keys.len(), 1);
+        let (key, hot) = &res.keys[0];
+        assert_eq!((key.as_str(), hot.requests, hot.errors), ("hot", 75, 0));
+        assert_eq!(hot.p99, 0.099);
+
+        let table = res.to_string();
+        assert!(table.starts_with("KEY  "), "{}", table);
+        assert!(table.contains("\nhot           75     0.0%"), "{}", table);
+        assert!(table.contains("\nTOTAL        100     5.0%     50.000ms     99.000ms\n"));
+    }
+}
@@ -0,0 +1,275 @@
+use super::{PostProcessor, ProcessedResult};
+use crate::http_client::Endpoint;
+use crate::json;
+use anyhow::Result;
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{error, warn};
+
+lazy_static! {
+    static ref DELIVERY_ERRORS: IntCounter = register_int_counter!(
+        "aragorn_webhook_delivery_errors_total",
+        "Number of alerts that failed to be posted to the webhook"
+    )
+    .unwrap();
+}
+
+/// Number of slices the window is counted in. It moves on a slice at a time.
+const SLICES: u64 = 10;
+
+pub struct WebhookConfig {
+    /// Where alerts are posted, e.g. `http://localhost:8080/alerts`. Only plain http
+    /// is supported.
+    pub url: String,
+    /// A label alerts when its p99 latency over the window is above this.
+    pub threshold: Duration,
+    pub window: Duration,
+    /// After alerting, a label stays quiet for this long even if it is still slow.
+    pub cooldown: Duration,
+    /// Requests a label needs within the window before it may alert, so a few slow
+    /// requests on a quiet key don't fire.
+    pub min_requests: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            url: "http://localhost:8080/alerts".to_string(),
+            threshold: Duration::from_millis(100),
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(300),
+            min_requests: 100,
+        }
+    }
+}
+
+/// Requests and those slower than the threshold, per slice of the window.
+#[derive(Default)]
+struct Window {
+    /// (slice, requests, slow), oldest first.
+    slices: VecDeque<(u64, u64, u64)>,
+    last_alert: Option<Instant>,
+}
+
+impl Window {
+    fn record(&mut self, slice: u64, slow: bool) {
+        while self
+            .slices
+            .front()
+            .is_some_and(|&(s, _, _)| s + SLICES <= slice)
+        {
+            self.slices.pop_front();
+        }
+        match self.slices.back_mut() {
+            Some((s, requests, slow_requests)) if *s == slice => {
+                *requests += 1;
+                *slow_requests += u64::from(slow);
+            }
+            _ => self.slices.push_back((slice, 1, u64::from(slow))),
+        }
+    }
+
+    fn counts(&self) -> (u64, u64) {
+        self.slices
+            .iter()
+            .fold((0, 0), |(r, s), &(_, requests, slow)| {
+                (r + requests, s + slow)
+            })
+    }
+}
+
+#[derive(Default)]
+struct Windows {
+    labels: HashMap<String, Window>,
+    /// The slice labels were last swept in.
+    swept: u64,
+}
+
+/// Whether the p99 of `requests` latencies is above the threshold, given how many of
+/// them are: the nearest rank p99 is above it when fewer than `ceil(0.99 * requests)`
+/// latencies are at or below it.
+fn p99_above(requests: u64, slow: u64) -> bool {
+    (requests - slow) * 100 < requests * 99
+}
+
+/// WebhookPostProcessor posts a JSON alert when the p99 latency of a label over a
+/// sliding window goes above a threshold, then holds further alerts for that label
+/// for a cooldown.
+///
+/// Latencies aren't kept: each label counts its requests, and those slower than the
+/// threshold, per tenth of the window, which is enough to tell where its p99 lies.
+pub struct WebhookPostProcessor {
+    endpoint: Endpoint,
+    cfg: WebhookConfig,
+    start: Instant,
+    windows: Mutex<Windows>,
+}
+
+impl WebhookPostProcessor {
+    pub fn new(cfg: WebhookConfig) -> Result<Self> {
+        if cfg.window.is_zero() {
+            anyhow::bail!("The alert window must be longer than zero");
+        }
+        Ok(WebhookPostProcessor {
+            endpoint: Endpoint::new(&cfg.url)?,
+            cfg,
+            start: Instant::now(),
+            windows: Mutex::new(Windows::default()),
+        })
+    }
+
+    fn alert(&self, label: &str, requests: u64, slow: u64) -> String {
+        format!(
+            "{{\"label\":{},\"threshold\":{},\"window\":{},\"requests\":{},\"slow\":{}}}",
+            json::quote(label),
+            self.cfg.threshold.as_secs_f64(),
+            self.cfg.window.as_secs_f64(),
+            requests,
+            slow
+        )
+    }
+}
+
+#[async_trait]
+impl PostProcessor for WebhookPostProcessor {
+    async fn post_process(&self, input: ProcessedResult) -> Result<()> {
+        let latency = match &input {
+            ProcessedResult::Prometheus(res) => res.latency,
+            ProcessedResult::Redis(res) => res.latency,
+            ProcessedResult::PubSub(_)
+            | ProcessedResult::Replication(_)
+            | ProcessedResult::KeyEvent(_) => return Ok(()),
+        };
+        let now = Instant::now();
+        let slice_len = self.cfg.window.as_secs_f64() / SLICES as f64;
+        let slice = (now.duration_since(self.start).as_secs_f64() / slice_len) as u64;
+
+        let mut windows = self.windows.lock().await;
+        // Labels that went quiet would otherwise be kept forever
+        if slice >= windows.swept + SLICES {
+            windows.swept = slice;
+            windows.labels.retain(|_, w| {
+                w.slices.back().is_some_and(|&(s, _, _)| s + SLICES > slice)
+                    || w.last_alert
+                        .is_some_and(|t| now.duration_since(t) < self.cfg.cooldown)
+            });
+        }
+        let label = input.label();
+        let window = windows.labels.entry(label.to_string()).or_default();
+        window.record(slice, latency > self.cfg.threshold.as_secs_f64());
+        let (requests, slow) = window.counts();
+        if requests < self.cfg.min_requests || !p99_above(requests, slow) {
+            return Ok(());
+        }
+        if window
+            .last_alert
+            .is_some_and(|t| now.duration_since(t) < self.cfg.cooldown)
+        {
+            return Ok(());
+        }
+        window.last_alert = Some(now);
+        drop(windows);
+
+        warn!(
+            "p99 latency of {} is above the alert threshold, {} of {} requests were slower",
+            label, slow, requests
+        );
+        let body = self.alert(label, requests, slow);
+        let endpoint = self.endpoint.clone();
+        // Posting mustn't hold up capture
+        tokio::spawn(async move {
+            if let Err(e) = endpoint.post("application/json", body.as_bytes()).await {
+                DELIVERY_ERRORS.inc();
+                error!("Failed to post alert to the webhook: {:?}", e);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post_processor::PrometheusResult;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    fn result(label: &str, latency_ms: u32) -> ProcessedResult {
+        ProcessedResult::Prometheus(PrometheusResult {
+            label: label.to_string(),
+            is_error: false,
+            latency: f64::from(latency_ms) / 1e3,
+            response_size: None,
+            labels: vec![],
+        })
+    }
+
+    #[test]
+    fn test_p99_above() {
+        assert!(!p99_above(100, 1));
+        assert!(p99_above(100, 2));
+        assert!(!p99_above(1000, 10));
+        assert!(p99_above(10, 1));
+        assert!(!p99_above(10, 0));
+    }
+
+    #[tokio::test]
+    async fn test_alerts_once_per_cooldown() {
+        // A webhook receiver passing on the body of each request
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                while !request.ends_with(b"}") {
+                    let mut buf = [0; 1024];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+                tx.send(String::from_utf8(request).unwrap()).unwrap();
+            }
+        });
+
+        let webhook = WebhookPostProcessor::new(WebhookConfig {
+            url: format!("http://{}/hooks/latency", addr),
+            threshold: Duration::from_millis(10),
+            window: Duration::from_secs(60),
+            cooldown: Duration::from_secs(3600),
+            min_requests: 10,
+        })
+        .unwrap();
+
+        // Fast requests, then enough slow ones to move the p99 past the threshold
+        for _ in 0..20 {
+            webhook.post_process(result("hot", 1)).await.unwrap();
+        }
+        for _ in 0..5 {
+            webhook.post_process(result("hot", 50)).await.unwrap();
+            webhook.post_process(result("cold", 50)).await.unwrap();
+        }
+
+        let request = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(request.starts_with("POST /hooks/latency HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            "\r\n\r\n{\"label\":\"hot\",\"threshold\":0.01,\"window\":60,\"requests\":21,\"slow\":1}"
+        ));
+
+        // Still slow, but within the cooldown. `cold` never had enough requests
+        for _ in 0..10 {
+            webhook.post_process(result("hot", 50)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(rx.try_recv().is_err());
+    }
+}
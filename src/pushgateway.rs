@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use prometheus::{gather, Encoder, TextEncoder};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Duration, MissedTickBehavior};
+use tracing::error;
+
+/// Where and how often metrics are pushed.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    /// Base URL of the Pushgateway, e.g. `http://localhost:9091`. Only plain http is supported.
+    pub url: String,
+    /// The job label metrics are grouped under.
+    pub job: String,
+    pub interval: Duration,
+}
+
+impl Default for PushConfig {
+    fn default() -> Self {
+        PushConfig {
+            url: "http://localhost:9091".to_string(),
+            job: "aragorn".to_string(),
+            interval: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Pushes the default registry to a Prometheus Pushgateway, for capture runs too short
+/// lived to be scraped.
+#[derive(Debug, Clone)]
+pub struct Pusher {
+    /// `host:port` to connect to.
+    addr: String,
+    host: String,
+    path: String,
+    interval: Duration,
+}
+
+impl Pusher {
+    pub fn new(cfg: PushConfig) -> Result<Self> {
+        let rest = cfg
+            .url
+            .strip_prefix("http://")
+            .ok_or_else(|| anyhow!("Pushgateway URL must start with http://: {}", cfg.url))?;
+        let (host, base) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if host.is_empty() {
+            return Err(anyhow!("Pushgateway URL has no host: {}", cfg.url));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(Pusher {
+            addr,
+            host: host.to_string(),
+            path: format!("{}/metrics/job/{}", base.trim_end_matches('/'), cfg.job),
+            interval: cfg.interval,
+        })
+    }
+
+    /// Push on every interval, forever. Failed pushes are logged and retried on the next tick.
+    pub async fn run(self) {
+        let mut ticker = time::interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick completes immediately, and there's nothing to push yet
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.push().await {
+                error!("Failed to push metrics: {:?}", e);
+            }
+        }
+    }
+
+    /// Replace the job's metrics on the Pushgateway with the current values.
+    pub async fn push(&self) -> Result<()> {
+        let mut body = vec![];
+        TextEncoder::new().encode(&gather(), &mut body)?;
+
+        let mut socket = TcpStream::connect(&self.addr).await?;
+        let head = format!(
+            "PUT {} HTTP/1.1\r\nHost: {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        socket.write_all(head.as_bytes()).await?;
+        socket.write_all(&body).await?;
+
+        let mut response = vec![];
+        socket.read_to_end(&mut response).await?;
+        let status = String::from_utf8_lossy(&response)
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Malformed response from the Pushgateway"))?;
+        if !(200..300).contains(&status) {
+            return Err(anyhow!("Pushgateway responded with status {}", status));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_url() {
+        let pusher = Pusher::new(PushConfig {
+            url: "http://gateway:9091/prefix/".to_string(),
+            job: "capture".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(pusher.addr, "gateway:9091");
+        assert_eq!(pusher.path, "/prefix/metrics/job/capture");
+
+        let pusher = Pusher::new(PushConfig {
+            url: "http://gateway".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(pusher.addr, "gateway:80");
+        assert_eq!(pusher.path, "/metrics/job/aragorn");
+
+        assert!(Pusher::new(PushConfig {
+            url: "https://gateway".to_string(),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pushes_on_interval() {
+        prometheus::register_int_counter!("aragorn_push_test_total", "Test counter")
+            .unwrap()
+            .inc();
+
+        // A sink recording the requests it receives
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 64 * 1024];
+                let mut n = 0;
+                // Read until the whole body announced by Content-Length arrived
+                loop {
+                    n += socket.read(&mut request[n..]).await.unwrap();
+                    let text = String::from_utf8_lossy(&request[..n]).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let len: usize = head
+                            .lines()
+                            .find_map(|l| l.strip_prefix("Content-Length: "))
+                            .unwrap()
+                            .parse()
+                            .unwrap();
+                        if body.len() >= len {
+                            tx.send(text).unwrap();
+                            break;
+                        }
+                    }
+                }
+                socket.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+            }
+        });
+
+        let pusher = Pusher::new(PushConfig {
+            url: format!("http://{}", addr),
+            job: "test".to_string(),
+            interval: Duration::from_millis(50),
+        })
+        .unwrap();
+        let start = time::Instant::now();
+        tokio::spawn(pusher.run());
+
+        for _ in 0..2 {
+            let request = time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(request.starts_with("PUT /metrics/job/test HTTP/1.1\r\n"));
+            assert!(request.contains("aragorn_push_test_total 1"));
+        }
+        // Nothing is pushed before the first interval elapses
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+}
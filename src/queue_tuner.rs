@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+/// How often the limit is reconsidered.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// QueueTuner sizes a queue from what it sees of it. After an interval in which
+/// anything was dropped, the limit doubles, up to `max`. After one in which the queue
+/// never got past a quarter of the limit, it halves, down to `min`. A burst is thus
+/// absorbed from the next interval on, and the room is given back once it has passed.
+#[derive(Debug)]
+pub struct QueueTuner {
+    min: usize,
+    max: usize,
+    limit: usize,
+    interval_start: Instant,
+    dropped: bool,
+    /// Most items queued at once during the interval.
+    peak: usize,
+}
+
+impl QueueTuner {
+    pub fn new(min: usize, max: usize, now: Instant) -> Self {
+        let min = min.max(1);
+        QueueTuner {
+            min,
+            max: max.max(min),
+            limit: min,
+            interval_start: now,
+            dropped: false,
+            peak: 0,
+        }
+    }
+
+    /// Items that may be queued at once.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// The most the limit can grow to.
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Record an item arriving while `queued` are waiting. Returns whether it fits;
+    /// if not, the caller drops it.
+    pub fn admit(&mut self, queued: usize, now: Instant) -> bool {
+        if now.duration_since(self.interval_start) >= ADJUST_INTERVAL {
+            self.adjust(now);
+        }
+        self.peak = self.peak.max(queued);
+        let fits = queued < self.limit;
+        self.dropped |= !fits;
+        fits
+    }
+
+    fn adjust(&mut self, now: Instant) {
+        if self.dropped {
+            self.limit = self.limit.saturating_mul(2).min(self.max);
+        } else if self.peak < self.limit / 4 {
+            self.limit = (self.limit / 2).max(self.min);
+        }
+        self.interval_start = now;
+        self.dropped = false;
+        self.peak = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grows_under_drops_and_shrinks_when_idle() {
+        let start = Instant::now();
+        let mut tuner = QueueTuner::new(100, 1000, start);
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        // The queue stays full, so every interval drops
+        for secs in 0..5 {
+            assert!(tuner.admit(0, at(secs)));
+            let limit = tuner.limit();
+            assert!(!tuner.admit(limit, at(secs)));
+        }
+        assert_eq!(tuner.limit(), 1000);
+
+        // Busy but keeping up: the limit holds
+        tuner.admit(600, at(5));
+        tuner.admit(600, at(6));
+        assert_eq!(tuner.limit(), 1000);
+
+        // Idle: back down to the minimum, and no further
+        for secs in 7..12 {
+            assert!(tuner.admit(0, at(secs)));
+        }
+        assert_eq!(tuner.limit(), 100);
+    }
+}
@@ -0,0 +1,343 @@
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::sync::atomic::{AtomicU32, Ordering};
+use tracing::error;
+
+use crate::tun::{PacketReader, ReadResult};
+
+// From linux/if_packet.h, which the libc crate doesn't cover
+#[cfg(target_os = "linux")]
+const PACKET_RX_RING: libc::c_int = 5;
+#[cfg(target_os = "linux")]
+const PACKET_VERSION: libc::c_int = 10;
+#[cfg(target_os = "linux")]
+const TPACKET_V3: libc::c_int = 2;
+const TP_STATUS_KERNEL: u32 = 0;
+const TP_STATUS_USER: u32 = 1;
+
+// Offsets into struct tpacket_block_desc, whose header is a tpacket_hdr_v1
+const BLOCK_STATUS: usize = 8;
+const BLOCK_NUM_PKTS: usize = 12;
+const BLOCK_FIRST_PKT: usize = 16;
+// Offsets into struct tpacket3_hdr
+const PKT_NEXT_OFFSET: usize = 0;
+const PKT_SNAPLEN: usize = 12;
+const PKT_MAC: usize = 24;
+
+/// How long to wait for a block before polling again, in milliseconds.
+const POLL_TIMEOUT_MS: libc::c_int = 100;
+
+#[derive(Debug, Clone)]
+pub struct RingConfig {
+    /// Bytes in each block of the ring, a multiple of the page size.
+    pub block_size: u32,
+    pub block_count: u32,
+    /// Milliseconds after which the kernel hands over a block that isn't full, so
+    /// packets aren't held back when traffic is light.
+    pub block_timeout: u32,
+}
+
+impl Default for RingConfig {
+    fn default() -> Self {
+        RingConfig {
+            block_size: 1 << 20,
+            block_count: 64,
+            block_timeout: 10,
+        }
+    }
+}
+
+#[repr(C)]
+#[cfg(target_os = "linux")]
+struct TpacketReq3 {
+    block_size: libc::c_uint,
+    block_nr: libc::c_uint,
+    frame_size: libc::c_uint,
+    frame_nr: libc::c_uint,
+    retire_blk_tov: libc::c_uint,
+    sizeof_priv: libc::c_uint,
+    feature_req_word: libc::c_uint,
+}
+
+/// RingPacketReader captures from an interface through an `AF_PACKET` socket with a
+/// TPACKET_V3 ring mapped into memory. The kernel fills whole blocks of packets and
+/// hands them over without a system call per packet, which keeps up with far more
+/// traffic than the pnet reader. Packets are copied out of a block before it is given
+/// back to the kernel.
+///
+/// Only available on Linux. Elsewhere `new` fails, and `LivePacketReader` should be
+/// used instead.
+pub struct RingPacketReader {
+    fd: OwnedFd,
+    ring: *mut u8,
+    cfg: RingConfig,
+    /// The next block to be handed over.
+    block: usize,
+    packets: VecDeque<Vec<u8>>,
+}
+
+// SAFETY: the mapping is only reached through the reader that owns it.
+unsafe impl Send for RingPacketReader {}
+
+impl RingPacketReader {
+    pub fn new(interface_name: &str, cfg: RingConfig) -> Result<Self> {
+        let (fd, ring) = open_ring(interface_name, &cfg)?;
+        Ok(RingPacketReader {
+            fd,
+            ring,
+            cfg,
+            block: 0,
+            packets: VecDeque::new(),
+        })
+    }
+
+    fn ring_len(&self) -> usize {
+        self.cfg.block_size as usize * self.cfg.block_count as usize
+    }
+
+    /// Copy the packets out of the current block if the kernel has handed it over,
+    /// then give it back. Returns false if the block is still the kernel's.
+    fn take_block(&mut self) -> bool {
+        let offset = self.block * self.cfg.block_size as usize;
+        // SAFETY: the block lies within the mapping, and its status word is 4 byte
+        // aligned as blocks start on page boundaries.
+        let status = unsafe { &*(self.ring.add(offset + BLOCK_STATUS) as *const AtomicU32) };
+        if status.load(Ordering::Acquire) & TP_STATUS_USER == 0 {
+            return false;
+        }
+        // SAFETY: the kernel doesn't touch a block until it is handed back below.
+        let block = unsafe {
+            std::slice::from_raw_parts(self.ring.add(offset), self.cfg.block_size as usize)
+        };
+        self.packets
+            .extend(block_packets(block).map(<[u8]>::to_vec));
+        status.store(TP_STATUS_KERNEL, Ordering::Release);
+        self.block = (self.block + 1) % self.cfg.block_count as usize;
+        true
+    }
+
+    /// Wait for the kernel to hand over a block, or for the timeout.
+    fn wait(&self) -> io::Result<()> {
+        let mut pfd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN | libc::POLLERR,
+            revents: 0,
+        };
+        // SAFETY: pfd is a valid pollfd that outlives the call.
+        if unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        if pfd.revents & libc::POLLERR != 0 {
+            return Err(io::Error::other("Capture socket reported an error"));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RingPacketReader {
+    fn drop(&mut self) {
+        // SAFETY: the ring was mapped with this length and nothing borrows it past self.
+        unsafe { libc::munmap(self.ring as *mut libc::c_void, self.ring_len()) };
+    }
+}
+
+impl PacketReader for RingPacketReader {
+    fn read_packet(&mut self) -> ReadResult {
+        let mut waited = false;
+        loop {
+            if let Some(packet) = self.packets.pop_front() {
+                return ReadResult::Packet(packet);
+            }
+            if self.take_block() {
+                continue;
+            }
+            if waited {
+                return ReadResult::WouldBlock;
+            }
+            if let Err(e) = self.wait() {
+                error!("Capture ring failed: {}", e);
+                return ReadResult::Eof;
+            }
+            waited = true;
+        }
+    }
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<usize> {
+    let b = buf.get(at..at + 4)?;
+    Some(u32::from_ne_bytes([b[0], b[1], b[2], b[3]]) as usize)
+}
+
+/// The packets of a TPACKET_V3 block, starting at its link layer header. Stops at
+/// anything pointing outside the block.
+fn block_packets(block: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let count = read_u32(block, BLOCK_NUM_PKTS).unwrap_or(0);
+    let mut at = read_u32(block, BLOCK_FIRST_PKT);
+    (0..count).map_while(move |_| {
+        let hdr = at?;
+        let snaplen = read_u32(block, hdr + PKT_SNAPLEN)?;
+        let mac = block.get(hdr + PKT_MAC..hdr + PKT_MAC + 2)?;
+        let start = hdr + u16::from_ne_bytes([mac[0], mac[1]]) as usize;
+        let packet = block.get(start..start + snaplen)?;
+        at = match read_u32(block, hdr + PKT_NEXT_OFFSET)? {
+            0 => None,
+            next => Some(hdr + next),
+        };
+        Some(packet)
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn open_ring(interface_name: &str, cfg: &RingConfig) -> Result<(OwnedFd, *mut u8)> {
+    use std::ffi::CString;
+    use std::os::fd::FromRawFd;
+
+    let check = |ret: libc::c_int| {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    };
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    // SAFETY: plain socket creation, the descriptor is owned right away.
+    let fd = check(unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW, protocol.into()) })?;
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let setsockopt = |name, value: *const libc::c_void, len: usize| {
+        // SAFETY: value points at `len` readable bytes for the duration of the call.
+        check(unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_PACKET,
+                name,
+                value,
+                len as libc::socklen_t,
+            )
+        })
+    };
+    setsockopt(
+        PACKET_VERSION,
+        &TPACKET_V3 as *const _ as *const libc::c_void,
+        std::mem::size_of::<libc::c_int>(),
+    )?;
+    // Frames only size the ring in V3, packets are packed into blocks as they come
+    let frame_size = 2048;
+    let req = TpacketReq3 {
+        block_size: cfg.block_size,
+        block_nr: cfg.block_count,
+        frame_size,
+        frame_nr: cfg.block_size / frame_size * cfg.block_count,
+        retire_blk_tov: cfg.block_timeout,
+        sizeof_priv: 0,
+        feature_req_word: 0,
+    };
+    setsockopt(
+        PACKET_RX_RING,
+        &req as *const _ as *const libc::c_void,
+        std::mem::size_of::<TpacketReq3>(),
+    )?;
+
+    let len = cfg.block_size as usize * cfg.block_count as usize;
+    // SAFETY: maps the ring just configured on the socket, unmapped on drop.
+    let ring = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if ring == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error().into());
+    }
+    let unmap = || unsafe { libc::munmap(ring, len) };
+
+    let name = CString::new(interface_name)?;
+    // SAFETY: name is a valid C string.
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        unmap();
+        return Err(anyhow::anyhow!("Device not found"));
+    }
+    // SAFETY: sockaddr_ll is plain data, so all zeroes is a valid value.
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = index as libc::c_int;
+    // SAFETY: addr is a valid sockaddr_ll of the given length.
+    let bound = check(unsafe {
+        libc::bind(
+            fd.as_raw_fd(),
+            &addr as *const _ as *const libc::sockaddr,
+            std::mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    });
+    if let Err(e) = bound {
+        unmap();
+        return Err(e.into());
+    }
+    Ok((fd, ring as *mut u8))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_ring(_interface_name: &str, _cfg: &RingConfig) -> Result<(OwnedFd, *mut u8)> {
+    Err(anyhow::anyhow!(
+        "Capturing through a TPACKET_V3 ring is only supported on Linux"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_u32(block: &mut [u8], at: usize, v: u32) {
+        block[at..at + 4].copy_from_slice(&v.to_ne_bytes());
+    }
+
+    /// Lay out packets in a block the way the kernel does, each behind a tpacket3_hdr
+    /// padded to 48 bytes.
+    fn block(packets: &[&[u8]]) -> Vec<u8> {
+        let mut block = vec![0u8; 4096];
+        put_u32(&mut block, BLOCK_STATUS, TP_STATUS_USER);
+        put_u32(&mut block, BLOCK_NUM_PKTS, packets.len() as u32);
+        put_u32(&mut block, BLOCK_FIRST_PKT, 48);
+        let mut at = 48;
+        for (i, packet) in packets.iter().enumerate() {
+            let next = (48 + packet.len()).next_multiple_of(16);
+            if i + 1 < packets.len() {
+                put_u32(&mut block, at + PKT_NEXT_OFFSET, next as u32);
+            }
+            put_u32(&mut block, at + PKT_SNAPLEN, packet.len() as u32);
+            block[at + PKT_MAC..at + PKT_MAC + 2].copy_from_slice(&48u16.to_ne_bytes());
+            block[at + 48..at + 48 + packet.len()].copy_from_slice(packet);
+            at += next;
+        }
+        block
+    }
+
+    #[test]
+    fn test_block_packets() {
+        let block = block(&[b"first frame", b"second", &[0xab; 100]]);
+        let packets: Vec<&[u8]> = block_packets(&block).collect();
+        assert_eq!(packets, vec![&b"first frame"[..], b"second", &[0xab; 100]]);
+
+        // An empty block, and one whose packet runs past the end
+        assert_eq!(block_packets(&[0u8; 64]).count(), 0);
+        let mut bad = block.clone();
+        put_u32(&mut bad, 48 + PKT_SNAPLEN, 1 << 20);
+        assert_eq!(block_packets(&bad).count(), 0);
+    }
+
+    #[test]
+    fn test_unknown_interface() {
+        assert!(RingPacketReader::new("aragorn-does-not-exist", RingConfig::default()).is_err());
+    }
+}
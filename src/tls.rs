@@ -0,0 +1,968 @@
+use anyhow::Result;
+use lazy_static::lazy_static;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{self, Cipher};
+use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet::packet::ip::IpNextHeaderProtocols;
+use pnet::packet::ipv4::{self, Ipv4Flags, Ipv4Packet, MutableIpv4Packet};
+use pnet::packet::tcp::{self, MutableTcpPacket, TcpFlags, TcpPacket};
+use pnet::packet::Packet;
+use std::collections::{HashMap, VecDeque};
+use std::net::{SocketAddr, SocketAddrV4};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::warn;
+
+use crate::tun::{PacketReader, ReadResult};
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+const CONTENT_HANDSHAKE: u8 = 22;
+const CONTENT_APPLICATION_DATA: u8 = 23;
+
+const HANDSHAKE_CLIENT_HELLO: u8 = 1;
+const HANDSHAKE_SERVER_HELLO: u8 = 2;
+
+const EXTENSION_SERVER_NAME: u16 = 0;
+const EXTENSION_SUPPORTED_VERSIONS: u16 = 43;
+const SERVER_NAME_HOST: u8 = 0;
+
+const RECORD_HEADER_LEN: usize = 5;
+/// Largest record allowed on the wire: 2^14 bytes of plaintext plus expansion.
+const MAX_RECORD_LEN: usize = 16384 + 2048;
+const EXPLICIT_NONCE_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Connections followed at once. The oldest is dropped to make room for a new one.
+const MAX_SESSIONS: usize = 4096;
+
+/// What the hellos of a connection told about it.
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Hellos {
+    /// Host name asked for in the ClientHello.
+    server_name: Option<String>,
+    /// Version and cipher suite chosen in the ServerHello.
+    negotiated: Option<(u16, u16)>,
+}
+
+/// The hellos of recent connections. Kept past the end of a connection, as its last
+/// responses are handled after the reader has seen it close.
+#[derive(Default)]
+struct Connections {
+    /// Each connection's hellos with the order they were learned in, to find the oldest.
+    hellos: HashMap<ConnectionKey, (Hellos, u64)>,
+    learned: u64,
+}
+
+lazy_static! {
+    static ref CONNECTIONS: Mutex<Connections> = Mutex::new(Connections::default());
+}
+
+/// The host name the client of a decrypted connection asked for with SNI, if any.
+pub fn server_name(client: SocketAddr, server: SocketAddr) -> Option<String> {
+    let connections = CONNECTIONS.lock().unwrap();
+    let hellos = connections.hellos.get(&(client, server));
+    hellos.and_then(|(hellos, _)| hellos.server_name.clone())
+}
+
+/// The TLS version and cipher suite a decrypted connection negotiated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Negotiated {
+    /// As OpenSSL names it, e.g. `TLSv1.2`.
+    pub version: String,
+    /// The IANA name, e.g. `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`.
+    pub cipher: String,
+}
+
+/// What the server of a TLS connection chose in its ServerHello, once that was seen.
+/// Known even for connections that can't be decrypted, e.g. TLS 1.3 ones.
+pub fn negotiated(client: SocketAddr, server: SocketAddr) -> Option<Negotiated> {
+    let connections = CONNECTIONS.lock().unwrap();
+    let hellos = connections.hellos.get(&(client, server));
+    let (version, cipher) = hellos.and_then(|(hellos, _)| hellos.negotiated)?;
+    Some(Negotiated {
+        version: version_name(version),
+        cipher: cipher_suite_name(cipher),
+    })
+}
+
+/// Add what was learned from the hellos of a connection to what is known of it.
+fn remember_hellos(key: ConnectionKey, learned: Hellos) {
+    let connections = &mut *CONNECTIONS.lock().unwrap();
+    let hellos = &mut connections.hellos;
+    if hellos.len() >= MAX_SESSIONS && !hellos.contains_key(&key) {
+        let oldest = hellos
+            .iter()
+            .min_by_key(|(_, (_, learned))| *learned)
+            .map(|(key, _)| *key);
+        if let Some(oldest) = oldest {
+            hellos.remove(&oldest);
+        }
+    }
+    connections.learned += 1;
+    let (known, order) = hellos.entry(key).or_default();
+    known.server_name = learned.server_name.or(known.server_name.take());
+    known.negotiated = learned.negotiated.or(known.negotiated);
+    *order = connections.learned;
+}
+
+fn forget_hellos(key: &ConnectionKey) {
+    CONNECTIONS.lock().unwrap().hellos.remove(key);
+}
+
+/// Master secrets from a key log in the NSS format that SSLKEYLOGFILE produces, keyed
+/// by client random. Only the TLS 1.2 `CLIENT_RANDOM` lines are used.
+#[derive(Default)]
+pub struct KeyLog {
+    path: Option<PathBuf>,
+    secrets: HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl KeyLog {
+    /// Read the key log at `path`. It is read again whenever a secret is missing, since
+    /// clients keep appending to it as they connect.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let mut keylog = KeyLog {
+            path: Some(path.as_ref().to_path_buf()),
+            ..Default::default()
+        };
+        keylog.reload()?;
+        Ok(keylog)
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let mut keylog = KeyLog::default();
+        keylog.add(text);
+        keylog
+    }
+
+    fn add(&mut self, text: &str) {
+        for line in text.lines() {
+            let mut fields = line.split_whitespace();
+            if fields.next() != Some("CLIENT_RANDOM") {
+                continue;
+            }
+            let random = fields.next().and_then(decode_hex);
+            let secret = fields.next().and_then(decode_hex);
+            if let (Some(Ok(random)), Some(secret)) = (random.map(<[u8; 32]>::try_from), secret) {
+                self.secrets.insert(random, secret);
+            }
+        }
+    }
+
+    fn reload(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let text = std::fs::read_to_string(path)?;
+            self.add(&text);
+        }
+        Ok(())
+    }
+
+    fn master_secret(&mut self, client_random: &[u8; 32]) -> Option<Vec<u8>> {
+        if !self.secrets.contains_key(client_random) {
+            if let Err(e) = self.reload() {
+                warn!("Failed to read the TLS key log: {:?}", e);
+            }
+        }
+        self.secrets.get(client_random).cloned()
+    }
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+struct CipherSuite {
+    cipher: Cipher,
+    digest: MessageDigest,
+    key_len: usize,
+}
+
+fn cipher_suite(id: u16) -> Option<CipherSuite> {
+    match id {
+        // TLS_RSA_, TLS_ECDHE_ECDSA_ and TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+        0x009c | 0xc02b | 0xc02f => Some(CipherSuite {
+            cipher: Cipher::aes_128_gcm(),
+            digest: MessageDigest::sha256(),
+            key_len: 16,
+        }),
+        // TLS_RSA_, TLS_ECDHE_ECDSA_ and TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384
+        0x009d | 0xc02c | 0xc030 => Some(CipherSuite {
+            cipher: Cipher::aes_256_gcm(),
+            digest: MessageDigest::sha384(),
+            key_len: 32,
+        }),
+        _ => None,
+    }
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".to_string(),
+        0x0301 => "TLSv1".to_string(),
+        0x0302 => "TLSv1.1".to_string(),
+        0x0303 => "TLSv1.2".to_string(),
+        0x0304 => "TLSv1.3".to_string(),
+        other => format!("{:#06x}", other),
+    }
+}
+
+/// The IANA name of the cipher suites seen in practice, weak ones included, and the
+/// code point of any other.
+fn cipher_suite_name(id: u16) -> String {
+    let name = match id {
+        0x0004 => "TLS_RSA_WITH_RC4_128_MD5",
+        0x0005 => "TLS_RSA_WITH_RC4_128_SHA",
+        0x000a => "TLS_RSA_WITH_3DES_EDE_CBC_SHA",
+        0x002f => "TLS_RSA_WITH_AES_128_CBC_SHA",
+        0x0035 => "TLS_RSA_WITH_AES_256_CBC_SHA",
+        0x003c => "TLS_RSA_WITH_AES_128_CBC_SHA256",
+        0x009c => "TLS_RSA_WITH_AES_128_GCM_SHA256",
+        0x009d => "TLS_RSA_WITH_AES_256_GCM_SHA384",
+        0xc009 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA",
+        0xc00a => "TLS_ECDHE_ECDSA_WITH_AES_256_CBC_SHA",
+        0xc012 => "TLS_ECDHE_RSA_WITH_3DES_EDE_CBC_SHA",
+        0xc013 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA",
+        0xc014 => "TLS_ECDHE_RSA_WITH_AES_256_CBC_SHA",
+        0xc023 => "TLS_ECDHE_ECDSA_WITH_AES_128_CBC_SHA256",
+        0xc027 => "TLS_ECDHE_RSA_WITH_AES_128_CBC_SHA256",
+        0xc02b => "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256",
+        0xc02c => "TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384",
+        0xc02f => "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256",
+        0xc030 => "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384",
+        0xcca8 => "TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256",
+        0xcca9 => "TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256",
+        0x1301 => "TLS_AES_128_GCM_SHA256",
+        0x1302 => "TLS_AES_256_GCM_SHA384",
+        0x1303 => "TLS_CHACHA20_POLY1305_SHA256",
+        other => return format!("{:#06x}", other),
+    };
+    name.to_string()
+}
+
+/// The TLS 1.2 pseudorandom function (RFC 5246 section 5).
+fn prf(
+    digest: MessageDigest,
+    secret: &[u8],
+    label: &[u8],
+    seed: &[u8],
+    len: usize,
+) -> Result<Vec<u8>> {
+    let key = PKey::hmac(secret)?;
+    let hmac = |parts: &[&[u8]]| -> Result<Vec<u8>> {
+        let mut signer = Signer::new(digest, &key)?;
+        for part in parts {
+            signer.update(part)?;
+        }
+        Ok(signer.sign_to_vec()?)
+    };
+    let seed = [label, seed].concat();
+    let mut a = hmac(&[&seed])?;
+    let mut out = Vec::with_capacity(len);
+    while out.len() < len {
+        out.extend(hmac(&[&a, &seed])?);
+        a = hmac(&[&a])?;
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+struct SessionKeys {
+    suite: CipherSuite,
+    client_key: Vec<u8>,
+    client_salt: Vec<u8>,
+    server_key: Vec<u8>,
+    server_salt: Vec<u8>,
+}
+
+/// One direction of a TLS connection.
+#[derive(Default)]
+struct HalfStream {
+    /// Bytes that don't make up a whole record yet, starting at TCP sequence `buf_seq`.
+    buf: Vec<u8>,
+    buf_seq: u32,
+    next_seq: Option<u32>,
+    /// Set once this side has sent ChangeCipherSpec. Its records are numbered from 0.
+    encrypted: bool,
+    record_seq: u64,
+}
+
+impl HalfStream {
+    fn push(&mut self, seq: u32, payload: &[u8]) -> Result<()> {
+        let skip = match self.next_seq {
+            None => {
+                self.buf_seq = seq;
+                0
+            }
+            Some(next) => {
+                // Retransmitted bytes are skipped, but a gap can't be recovered from
+                let behind = next.wrapping_sub(seq) as i32;
+                if behind < 0 {
+                    anyhow::bail!("Missed {} bytes of the TLS stream", -behind);
+                }
+                behind as usize
+            }
+        };
+        if skip < payload.len() {
+            self.buf.extend_from_slice(&payload[skip..]);
+            self.next_seq = Some(seq.wrapping_add(payload.len() as u32));
+        }
+        Ok(())
+    }
+
+    /// The next complete record and the TCP sequence number it started at.
+    fn next_record(&mut self) -> Result<Option<(u32, Vec<u8>)>> {
+        if self.buf.len() < RECORD_HEADER_LEN {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([self.buf[3], self.buf[4]]) as usize;
+        if len > MAX_RECORD_LEN {
+            anyhow::bail!("TLS record of {} bytes is too large", len);
+        }
+        if self.buf.len() < RECORD_HEADER_LEN + len {
+            return Ok(None);
+        }
+        let record: Vec<u8> = self.buf.drain(..RECORD_HEADER_LEN + len).collect();
+        let seq = self.buf_seq;
+        self.buf_seq = seq.wrapping_add(record.len() as u32);
+        Ok(Some((seq, record)))
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    /// Order the session was opened in, to find the oldest.
+    opened: u64,
+    client_random: Option<[u8; 32]>,
+    server_random: Option<[u8; 32]>,
+    cipher_suite: Option<u16>,
+    /// What the hellos told, until it is handed to `CONNECTIONS`.
+    learned: Hellos,
+    keys: Option<SessionKeys>,
+    client: HalfStream,
+    server: HalfStream,
+    /// Set once the connection can't be followed. Its payloads are dropped from then on.
+    failed: bool,
+}
+
+impl Session {
+    fn half(&mut self, from_client: bool) -> &mut HalfStream {
+        if from_client {
+            &mut self.client
+        } else {
+            &mut self.server
+        }
+    }
+
+    /// Feed a segment's payload and return the plaintext of every application data
+    /// record it completed, along with the TCP sequence number the record started at.
+    fn process(
+        &mut self,
+        from_client: bool,
+        seq: u32,
+        payload: &[u8],
+        keylog: &mut KeyLog,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        self.half(from_client).push(seq, payload)?;
+        let mut plaintext = vec![];
+        while let Some((seq, record)) = self.half(from_client).next_record()? {
+            if self.half(from_client).encrypted {
+                let data = self.decrypt(from_client, &record, keylog)?;
+                if record[0] == CONTENT_APPLICATION_DATA {
+                    plaintext.push((seq, data));
+                }
+                continue;
+            }
+            match record[0] {
+                CONTENT_HANDSHAKE => self.read_handshake(&record[RECORD_HEADER_LEN..]),
+                CONTENT_CHANGE_CIPHER_SPEC => self.half(from_client).encrypted = true,
+                _ => {}
+            }
+        }
+        Ok(plaintext)
+    }
+
+    /// Pick the randoms, version, cipher suite and server name out of the hellos. Messages split
+    /// across records are skipped, which the hellos practically never are.
+    fn read_handshake(&mut self, mut body: &[u8]) {
+        while body.len() >= 4 {
+            let len = u32::from_be_bytes([0, body[1], body[2], body[3]]) as usize;
+            let Some(msg) = body.get(4..4 + len) else {
+                break;
+            };
+            let random = msg.get(2..34).and_then(|r| <[u8; 32]>::try_from(r).ok());
+            match body[0] {
+                HANDSHAKE_CLIENT_HELLO => {
+                    self.client_random = random;
+                    self.learned.server_name = parse_server_name(msg);
+                }
+                HANDSHAKE_SERVER_HELLO => {
+                    self.server_random = random;
+                    let negotiated = parse_server_hello(msg);
+                    self.cipher_suite = negotiated.map(|(_, suite)| suite);
+                    self.learned.negotiated = negotiated;
+                }
+                _ => {}
+            }
+            body = &body[4 + len..];
+        }
+    }
+
+    fn derive_keys(&self, keylog: &mut KeyLog) -> Result<SessionKeys> {
+        let (Some(client_random), Some(server_random), Some(id)) =
+            (self.client_random, self.server_random, self.cipher_suite)
+        else {
+            anyhow::bail!("The TLS handshake was not seen");
+        };
+        let suite = cipher_suite(id)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported cipher suite {:#06x}", id))?;
+        let master = keylog.master_secret(&client_random).ok_or_else(|| {
+            anyhow::anyhow!(
+                "No secret logged for client random {}",
+                client_random
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+            )
+        })?;
+        let seed = [server_random, client_random].concat();
+        let block = prf(
+            suite.digest,
+            &master,
+            b"key expansion",
+            &seed,
+            2 * (suite.key_len + 4),
+        )?;
+        let (client_key, rest) = block.split_at(suite.key_len);
+        let (server_key, rest) = rest.split_at(suite.key_len);
+        let (client_salt, server_salt) = rest.split_at(4);
+        Ok(SessionKeys {
+            suite,
+            client_key: client_key.to_vec(),
+            client_salt: client_salt.to_vec(),
+            server_key: server_key.to_vec(),
+            server_salt: server_salt.to_vec(),
+        })
+    }
+
+    /// Decrypt an AES-GCM record (RFC 5288).
+    fn decrypt(
+        &mut self,
+        from_client: bool,
+        record: &[u8],
+        keylog: &mut KeyLog,
+    ) -> Result<Vec<u8>> {
+        if self.keys.is_none() {
+            self.keys = Some(self.derive_keys(keylog)?);
+        }
+        let keys = self.keys.as_ref().expect("keys were derived");
+        let (key, salt, half) = if from_client {
+            (&keys.client_key, &keys.client_salt, &mut self.client)
+        } else {
+            (&keys.server_key, &keys.server_salt, &mut self.server)
+        };
+
+        let fragment = &record[RECORD_HEADER_LEN..];
+        if fragment.len() < EXPLICIT_NONCE_LEN + TAG_LEN {
+            anyhow::bail!("Truncated TLS record");
+        }
+        let (explicit_nonce, rest) = fragment.split_at(EXPLICIT_NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let nonce = [salt.as_slice(), explicit_nonce].concat();
+        let mut aad = half.record_seq.to_be_bytes().to_vec();
+        aad.extend_from_slice(&record[..3]);
+        aad.extend_from_slice(&(ciphertext.len() as u16).to_be_bytes());
+        half.record_seq += 1;
+
+        Ok(symm::decrypt_aead(
+            keys.suite.cipher,
+            key,
+            Some(&nonce),
+            &aad,
+            ciphertext,
+            tag,
+        )?)
+    }
+}
+
+fn be16(buf: &[u8], at: usize) -> Option<usize> {
+    let b = buf.get(at..at + 2)?;
+    Some(u16::from_be_bytes([b[0], b[1]]) as usize)
+}
+
+/// The host name in the server_name extension (RFC 6066 section 3) of a ClientHello
+/// message, without its handshake header. Lowercased, as DNS names are case insensitive.
+fn parse_server_name(hello: &[u8]) -> Option<String> {
+    // Skip the version and random, then the session id, cipher suites and compression
+    // methods, each prefixed with its length
+    let mut at = 34;
+    at += 1 + *hello.get(at)? as usize;
+    at += 2 + be16(hello, at)?;
+    at += 1 + *hello.get(at)? as usize;
+    let len = be16(hello, at)?;
+    let mut extensions = hello.get(at + 2..at + 2 + len)?;
+    while extensions.len() >= 4 {
+        let len = be16(extensions, 2)?;
+        let body = extensions.get(4..4 + len)?;
+        if be16(extensions, 0)? == EXTENSION_SERVER_NAME as usize {
+            // A list of (type, length, name), of which only host names are defined
+            let mut list = body.get(2..)?;
+            while list.len() >= 3 {
+                let len = be16(list, 1)?;
+                let name = list.get(3..3 + len)?;
+                if list[0] == SERVER_NAME_HOST {
+                    return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+                }
+                list = &list[3 + len..];
+            }
+            return None;
+        }
+        extensions = &extensions[4 + len..];
+    }
+    None
+}
+
+/// The version and cipher suite chosen in a ServerHello message, without its handshake
+/// header. TLS 1.3 leaves the version field at TLS 1.2 for the sake of middleboxes and
+/// puts the real one in the supported_versions extension (RFC 8446 section 4.2.1).
+fn parse_server_hello(hello: &[u8]) -> Option<(u16, u16)> {
+    let mut version = be16(hello, 0)? as u16;
+    // Skip the version and random, then the session id
+    let mut at = 34;
+    at += 1 + *hello.get(at)? as usize;
+    let suite = be16(hello, at)? as u16;
+    // Then the suite and compression method. Extensions are optional before TLS 1.3
+    at += 3;
+    let mut extensions = match be16(hello, at) {
+        Some(len) => hello.get(at + 2..at + 2 + len)?,
+        None => &[],
+    };
+    while extensions.len() >= 4 {
+        let len = be16(extensions, 2)?;
+        let body = extensions.get(4..4 + len)?;
+        if be16(extensions, 0)? == EXTENSION_SUPPORTED_VERSIONS as usize {
+            version = be16(body, 0)? as u16;
+        }
+        extensions = &extensions[4 + len..];
+    }
+    Some((version, suite))
+}
+
+/// Whether a client payload opens a TLS connection.
+fn is_client_hello(payload: &[u8]) -> bool {
+    payload.len() > RECORD_HEADER_LEN
+        && payload[0] == CONTENT_HANDSHAKE
+        && payload[1] == 3
+        && payload[RECORD_HEADER_LEN] == HANDSHAKE_CLIENT_HELLO
+}
+
+/// (client, server) addresses of a TLS connection.
+type ConnectionKey = (SocketAddr, SocketAddr);
+
+/// DecryptingPacketReader sits in front of another packet reader and decrypts the TLS
+/// 1.2 connections in its traffic using the master secrets of a key log, as written by
+/// clients run with SSLKEYLOGFILE. This makes TLS traffic observable without kernel
+/// probes.
+///
+/// Each segment of a TLS connection is replaced by one frame per application data record
+/// it completes, carrying the plaintext at the sequence number the record started at,
+/// so requests still pair with the acknowledgements of their responses. Segments that
+/// complete none (the handshake, or the start of a large record) are passed on without
+/// their payload. Everything else is passed through untouched.
+///
+/// Only AES-GCM cipher suites are supported. A connection that can't be followed, for
+/// a missed segment or a secret that was never logged, is ignored from then on.
+///
+/// The host name each client asks for with SNI is kept, for `server_name` to label
+/// the connection's requests with, and so are the version and cipher suite its server
+/// chose, for `negotiated`.
+pub struct DecryptingPacketReader<R> {
+    inner: R,
+    keylog: KeyLog,
+    sessions: HashMap<ConnectionKey, Session>,
+    opened: u64,
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl<R: PacketReader> DecryptingPacketReader<R> {
+    pub fn new(inner: R, keylog: KeyLog) -> Self {
+        DecryptingPacketReader {
+            inner,
+            keylog,
+            sessions: HashMap::new(),
+            opened: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn process(&mut self, frame: Vec<u8>) {
+        let Some(segment) = Segment::parse(&frame) else {
+            self.pending.push_back(frame);
+            return;
+        };
+        let forward = (segment.src, segment.dst);
+        let (key, from_client) = if self.sessions.contains_key(&forward) {
+            (forward, true)
+        } else if self.sessions.contains_key(&(segment.dst, segment.src)) {
+            ((segment.dst, segment.src), false)
+        } else if is_client_hello(&frame[segment.payload_start..]) {
+            self.open(forward);
+            (forward, true)
+        } else {
+            self.pending.push_back(frame);
+            return;
+        };
+
+        let session = self.sessions.get_mut(&key).expect("session exists");
+        let payload = &frame[segment.payload_start..];
+        let mut records = vec![];
+        if !session.failed && !payload.is_empty() {
+            match session.process(from_client, segment.seq, payload, &mut self.keylog) {
+                Ok(plaintext) => records = plaintext,
+                Err(e) => {
+                    warn!(client = %key.0, server = %key.1, "Not decrypting TLS connection: {:?}", e);
+                    session.failed = true;
+                }
+            }
+        }
+        let learned = std::mem::take(&mut session.learned);
+        if learned != Hellos::default() {
+            remember_hellos(key, learned);
+        }
+        if segment.flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            self.sessions.remove(&key);
+        }
+
+        if payload.is_empty() {
+            self.pending.push_back(frame);
+        } else if records.is_empty() {
+            self.pending
+                .push_back(segment.with_payload(&frame, segment.seq, &[]));
+        } else {
+            for (seq, plaintext) in records {
+                self.pending
+                    .push_back(segment.with_payload(&frame, seq, &plaintext));
+            }
+        }
+    }
+
+    fn open(&mut self, key: ConnectionKey) {
+        if self.sessions.len() >= MAX_SESSIONS {
+            let oldest = self
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.opened)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                self.sessions.remove(&oldest);
+            }
+        }
+        // What was learned of an earlier connection on the same addresses is stale
+        forget_hellos(&key);
+        self.opened += 1;
+        self.sessions.insert(
+            key,
+            Session {
+                opened: self.opened,
+                ..Default::default()
+            },
+        );
+    }
+}
+
+impl<R: PacketReader> PacketReader for DecryptingPacketReader<R> {
+    fn read_packet(&mut self) -> ReadResult {
+        loop {
+            if let Some(frame) = self.pending.pop_front() {
+                return ReadResult::Packet(frame);
+            }
+            match self.inner.read_packet() {
+                ReadResult::Packet(frame) => self.process(frame),
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Where the headers of an unfragmented Ethernet/IPv4/TCP frame end.
+struct Segment {
+    src: SocketAddr,
+    dst: SocketAddr,
+    seq: u32,
+    flags: u8,
+    ip_start: usize,
+    tcp_start: usize,
+    payload_start: usize,
+}
+
+impl Segment {
+    fn parse(frame: &[u8]) -> Option<Segment> {
+        let ethernet = EthernetPacket::new(frame)?;
+        if ethernet.get_ethertype() != EtherTypes::Ipv4 {
+            return None;
+        }
+        let ip = Ipv4Packet::new(ethernet.payload())?;
+        if ip.get_next_level_protocol() != IpNextHeaderProtocols::Tcp
+            || ip.get_flags() & Ipv4Flags::MoreFragments != 0
+            || ip.get_fragment_offset() != 0
+        {
+            return None;
+        }
+        let tcp = TcpPacket::new(ip.payload())?;
+        let ip_start = frame.len() - ethernet.payload().len();
+        let tcp_start = ip_start + ip.get_header_length() as usize * 4;
+        let payload_start = tcp_start + tcp.get_data_offset() as usize * 4;
+        // The payload runs to the end of the frame, so drop any Ethernet padding
+        if payload_start + tcp.payload().len() != frame.len() {
+            return None;
+        }
+        Some(Segment {
+            src: SocketAddrV4::new(ip.get_source(), tcp.get_source()).into(),
+            dst: SocketAddrV4::new(ip.get_destination(), tcp.get_destination()).into(),
+            seq: tcp.get_sequence(),
+            flags: tcp.get_flags(),
+            ip_start,
+            tcp_start,
+            payload_start,
+        })
+    }
+
+    /// A copy of the frame's headers carrying `payload` at sequence number `seq`.
+    fn with_payload(&self, frame: &[u8], seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut out = frame[..self.payload_start].to_vec();
+        out.extend_from_slice(payload);
+        let ip_len = out.len() - self.ip_start;
+        let (src, dst) = {
+            let mut ip = MutableIpv4Packet::new(&mut out[self.ip_start..]).expect("parsed before");
+            ip.set_total_length(ip_len as u16);
+            ip.set_checksum(ipv4::checksum(&ip.to_immutable()));
+            (ip.get_source(), ip.get_destination())
+        };
+        let mut tcp = MutableTcpPacket::new(&mut out[self.tcp_start..]).expect("parsed before");
+        tcp.set_sequence(seq);
+        tcp.set_checksum(tcp::ipv4_checksum(&tcp.to_immutable(), &src, &dst));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tun::testing::tcp_frame;
+
+    const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 2], 50000);
+    const SERVER: ([u8; 4], u16) = ([10, 0, 0, 1], 6380);
+
+    impl PacketReader for std::vec::IntoIter<Vec<u8>> {
+        fn read_packet(&mut self) -> ReadResult {
+            match self.next() {
+                Some(frame) => ReadResult::Packet(frame),
+                None => ReadResult::Eof,
+            }
+        }
+    }
+
+    /// The fixture session as frames, the server's first flight split in two.
+    fn fixture_frames() -> Vec<Vec<u8>> {
+        let client = include_bytes!("../tests/fixtures/tls12/client.bin");
+        let server = include_bytes!("../tests/fixtures/tls12/server.bin");
+        let client = [&client[..136], &client[136..229], &client[229..]];
+        let server = [
+            &server[..300],
+            &server[300..618],
+            &server[618..669],
+            &server[669..],
+        ];
+        let (mut c, mut s) = (1000u32, 5000u32);
+        let mut frame = |from_client: bool, data: &[u8]| {
+            let (src, dst, seq, ack) = if from_client {
+                (CLIENT, SERVER, &mut c, s)
+            } else {
+                (SERVER, CLIENT, &mut s, c)
+            };
+            let frame = tcp_frame(src, dst, TcpFlags::ACK | TcpFlags::PSH, *seq, ack, data);
+            *seq += data.len() as u32;
+            frame
+        };
+        vec![
+            frame(true, client[0]),
+            frame(false, server[0]),
+            frame(false, server[1]),
+            frame(true, client[1]),
+            frame(false, server[2]),
+            frame(true, client[2]),
+            frame(false, server[3]),
+        ]
+    }
+
+    fn read_all(reader: &mut impl PacketReader) -> Vec<(u32, Vec<u8>)> {
+        std::iter::from_fn(|| match reader.read_packet() {
+            ReadResult::Packet(frame) => Some(frame),
+            _ => None,
+        })
+        .map(|frame| {
+            let segment = Segment::parse(&frame).unwrap();
+            (segment.seq, frame[segment.payload_start..].to_vec())
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_decrypt_tls12_fixture() {
+        let keylog = KeyLog::parse(include_str!("../tests/fixtures/tls12/keylog.txt"));
+        let mut reader = DecryptingPacketReader::new(fixture_frames().into_iter(), keylog);
+        let frames = read_all(&mut reader);
+
+        assert_eq!(frames.len(), 7);
+        let data: Vec<_> = frames.iter().filter(|(_, p)| !p.is_empty()).collect();
+        assert_eq!(
+            data,
+            vec![
+                &(1229, b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n".to_vec()),
+                &(5669, b"$1\r\nv\r\n".to_vec())
+            ]
+        );
+        assert!(!reader.sessions.values().any(|s| s.failed));
+    }
+
+    #[tokio::test]
+    async fn test_decrypted_requests_are_paired() {
+        use crate::plugin::redis::handler::{RedisResult, RespConfig, RespHandler};
+        use crate::post_processor::recent::RecentRequestsPostProcessor;
+        use crate::tun::{ObsConfig, Observer};
+        use std::sync::Arc;
+        use tokio::sync::Mutex;
+
+        let keylog = KeyLog::parse(include_str!("../tests/fixtures/tls12/keylog.txt"));
+        let reader = DecryptingPacketReader::new(fixture_frames().into_iter(), keylog);
+        let mut obs = Observer::new(ObsConfig::default());
+        let recent = RecentRequestsPostProcessor::new(10);
+        obs.add_post_processor(Arc::new(Mutex::new(recent.clone())));
+        let handler = Arc::new(RespHandler::new(SERVER.1, RespConfig::default()));
+        Arc::new(obs)
+            .capture_packets::<_, RedisResult>(reader, handler)
+            .await
+            .unwrap();
+
+        let recent = recent.to_json().await;
+        assert!(recent.starts_with("[{\"command\":\"GET\",\"key\":\"k\",\"is_error\":false"));
+        assert!(recent
+            .contains("\"client_addr\":\"10.0.0.2:50000\",\"server_addr\":\"10.0.0.1:6380\"}]"));
+    }
+
+    #[test]
+    fn test_parse_server_name() {
+        let hello = include_bytes!("../tests/fixtures/tls12/client_hello_sni.bin");
+        assert!(is_client_hello(hello));
+        let msg = &hello[RECORD_HEADER_LEN + 4..];
+        assert_eq!(parse_server_name(msg).as_deref(), Some("cache.example.com"));
+
+        // The session fixture's client sent no SNI
+        let hello = include_bytes!("../tests/fixtures/tls12/client.bin");
+        assert_eq!(parse_server_name(&hello[RECORD_HEADER_LEN + 4..136]), None);
+        // Nor does a truncated hello panic
+        assert_eq!(parse_server_name(&msg[..120]), None);
+    }
+
+    #[test]
+    fn test_parse_server_hello() {
+        // TLS 1.2, where the version field holds the version chosen
+        let server = include_bytes!("../tests/fixtures/tls12/server.bin");
+        let msg = &server[RECORD_HEADER_LEN + 4..RECORD_HEADER_LEN + 0x5d];
+        assert_eq!(parse_server_hello(msg), Some((0x0303, 0xc02b)));
+
+        // TLS 1.3, where it's TLS 1.2 and the version is in supported_versions
+        let server = include_bytes!("../tests/fixtures/tls13/server_hello.bin");
+        let msg = &server[RECORD_HEADER_LEN + 4..];
+        assert_eq!(be16(msg, 0), Some(0x0303));
+        assert_eq!(parse_server_hello(msg), Some((0x0304, 0x1302)));
+        assert_eq!(parse_server_hello(&msg[..30]), None);
+
+        assert_eq!(version_name(0x0304), "TLSv1.3");
+        assert_eq!(version_name(0x7f1c), "0x7f1c");
+        assert_eq!(cipher_suite_name(0x1302), "TLS_AES_256_GCM_SHA384");
+        assert_eq!(cipher_suite_name(0x00ff), "0x00ff");
+    }
+
+    #[test]
+    fn test_negotiated_recorded() {
+        // The session can't be decrypted without its secret, but its hellos are read
+        let mut reader =
+            DecryptingPacketReader::new(fixture_frames().into_iter(), KeyLog::default());
+        read_all(&mut reader);
+        let client = SocketAddr::from(CLIENT);
+        let server = SocketAddr::from(SERVER);
+        assert_eq!(
+            negotiated(client, server),
+            Some(Negotiated {
+                version: "TLSv1.2".to_string(),
+                cipher: "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256".to_string(),
+            })
+        );
+        assert_eq!(server_name(client, server), None);
+    }
+
+    #[test]
+    fn test_server_name_recorded() {
+        let client = SocketAddr::from(([10, 0, 0, 3], 50000));
+        let server = SocketAddr::from((SERVER.0, SERVER.1));
+        let hello = include_bytes!("../tests/fixtures/tls12/client_hello_sni.bin");
+        let frame = tcp_frame(
+            ([10, 0, 0, 3], 50000),
+            SERVER,
+            TcpFlags::ACK | TcpFlags::PSH,
+            1000,
+            0,
+            hello,
+        );
+        let mut reader = DecryptingPacketReader::new(vec![frame].into_iter(), KeyLog::default());
+        read_all(&mut reader);
+        assert_eq!(
+            server_name(client, server).as_deref(),
+            Some("cache.example.com")
+        );
+        assert_eq!(server_name(server, client), None);
+    }
+
+    #[test]
+    fn test_unknown_key_ignored() {
+        let mut reader =
+            DecryptingPacketReader::new(fixture_frames().into_iter(), KeyLog::default());
+        let frames = read_all(&mut reader);
+        assert_eq!(frames.len(), 7);
+        assert!(frames.iter().all(|(_, payload)| payload.is_empty()));
+
+        // Other traffic passes through
+        let plain = tcp_frame(
+            CLIENT,
+            ([10, 0, 0, 1], 6379),
+            TcpFlags::ACK,
+            1,
+            1,
+            b"PING\r\n",
+        );
+        let mut reader =
+            DecryptingPacketReader::new(vec![plain.clone()].into_iter(), KeyLog::default());
+        assert_eq!(reader.read_packet(), ReadResult::Packet(plain));
+    }
+
+    #[test]
+    fn test_keylog_parse() {
+        let keylog = KeyLog::parse(
+            "# comment\nCLIENT_RANDOM 00 11\nCLIENT_TRAFFIC_SECRET_0 aa bb\nCLIENT_RANDOM \
+             0000000000000000000000000000000000000000000000000000000000000001 0a0b\n",
+        );
+        assert_eq!(keylog.secrets.len(), 1);
+        let mut random = [0u8; 32];
+        random[31] = 1;
+        assert_eq!(keylog.secrets[&random], vec![0x0a, 0x0b]);
+    }
+}
@@ -1,29 +1,269 @@
 use anyhow::Result;
+use async_trait::async_trait;
+use clap::ValueEnum;
+use futures_util::future::join_all;
+use lazy_static::lazy_static;
 use pnet::packet::ethernet::{EtherTypes, EthernetPacket};
 use pnet::packet::ip::IpNextHeaderProtocols;
 use pnet::packet::ipv4::Ipv4Packet;
-use pnet::packet::tcp::TcpPacket;
+use pnet::packet::tcp::{TcpFlags, TcpOptionNumbers, TcpPacket};
 use pnet::packet::Packet;
-use std::collections::HashMap;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, HistogramVec,
+    IntCounterVec, IntGaugeVec,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::{watch, Mutex};
+use tokio::sync::{mpsc, watch, Mutex};
 use tokio::time::Duration;
 use tracing::error;
 
-use crate::plugin::{Metrics, Plugin};
+use crate::anonymize::Anonymizer;
+use crate::ip_fragment::{self, Reassembler};
+use crate::logging::RateLimiter;
+use crate::pcap::PacketRecorder;
+use crate::plugin::{ConnectionId, Metrics, Plugin, RequestId};
 use crate::post_processor::{PostProcessor, ProcessedResult};
+use crate::queue_tuner::QueueTuner;
+
+lazy_static! {
+    static ref ACTIVE_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "aragorn_active_connections",
+        "Number of distinct client connections seen on the monitored port",
+        &["port"]
+    )
+    .unwrap();
+    static ref TCP_RESETS: IntCounterVec = register_int_counter_vec!(
+        "aragorn_tcp_resets",
+        "Number of TCP segments with the RST flag seen on the monitored port",
+        &["port"]
+    )
+    .unwrap();
+    static ref PACKETS_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "aragorn_packets_dropped_total",
+        "Number of captured packets dropped because the processing queue was full",
+        &["port"]
+    )
+    .unwrap();
+    static ref QUEUE_LIMIT: IntGaugeVec = register_int_gauge_vec!(
+        "aragorn_capture_queue_limit",
+        "Number of captured packets currently allowed to wait for processing",
+        &["port"]
+    )
+    .unwrap();
+    static ref PACKETS_CAPTURED: IntCounterVec = register_int_counter_vec!(
+        "aragorn_packets_captured_total",
+        "Number of packets read from the capture source",
+        &["port"]
+    )
+    .unwrap();
+    static ref BYTES_CAPTURED: IntCounterVec = register_int_counter_vec!(
+        "aragorn_bytes_captured_total",
+        "Number of bytes read from the capture source",
+        &["port"]
+    )
+    .unwrap();
+    static ref PACKETS_PARSED: IntCounterVec = register_int_counter_vec!(
+        "aragorn_packets_parsed_total",
+        "Number of captured packets processed without error",
+        &["port"]
+    )
+    .unwrap();
+    static ref PARSE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "aragorn_parse_errors_total",
+        "Number of captured packets that failed to parse",
+        &["port"]
+    )
+    .unwrap();
+    static ref CAPTURE_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "aragorn_capture_info",
+        "The interface packets are captured from",
+        &["interface"]
+    )
+    .unwrap();
+    static ref TCP_FINS: IntCounterVec = register_int_counter_vec!(
+        "aragorn_tcp_fins",
+        "Number of TCP segments with the FIN flag seen on the monitored port",
+        &["port"]
+    )
+    .unwrap();
+    static ref TCP_WINDOW: HistogramVec = register_histogram_vec!(
+        "aragorn_tcp_window_bytes",
+        "Receive window advertised in TCP segments on the monitored port, by the end advertising it",
+        &["port", "side"],
+        prometheus::exponential_buckets(1024.0, 4.0, 9).unwrap()
+    )
+    .unwrap();
+    static ref TCP_ZERO_WINDOWS: IntCounterVec = register_int_counter_vec!(
+        "aragorn_tcp_zero_windows_total",
+        "Number of times an end of a connection on the monitored port closed its receive window",
+        &["port", "side"]
+    )
+    .unwrap();
+    static ref REQUEST_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "aragorn_request_timeouts_total",
+        "Number of requests on the monitored port dropped after waiting longer than the request TTL for a response",
+        &["port"]
+    )
+    .unwrap();
+    static ref ZERO_WINDOW_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "aragorn_tcp_zero_window_connections",
+        "Number of connections on the monitored port whose receive window is currently closed",
+        &["port"]
+    )
+    .unwrap();
+}
+
+/// Label of the end of a connection a segment came from, indexed like `ConnectionState::window_scale`.
+const SIDES: [&str; 2] = ["client", "server"];
+
+/// Everything the observer keeps about one connection. It all goes at once when the
+/// connection closes or goes idle, so churned connections don't accumulate.
+struct ConnectionState {
+    /// The monitored server port, for connections seen on the packet path.
+    port: Option<u16>,
+    last_seen: Instant,
+    /// Set once a FIN or RST is seen. The entry then holds no other state and only stays
+    /// until it goes idle, so trailing ACKs don't resurrect the connection.
+    closed: bool,
+    /// Requests awaiting their response, oldest first.
+    pending: VecDeque<(RequestId, Instant)>,
+    /// End of the furthest data the client has sent, to tell retransmissions apart.
+    client_end: Option<u32>,
+    /// Whether the newest pending request may still grow: its last segment didn't end
+    /// a write (no PSH) and the server hasn't sent data since.
+    request_open: bool,
+    /// Window scale each end offered in its SYN, client first. Windows are only scaled
+    /// once both did, so connections whose handshake wasn't seen report raw windows.
+    window_scale: [Option<u8>; 2],
+    /// Whether each end's last advertised window was zero, client first.
+    zero_window: [bool; 2],
+}
+
+impl ConnectionState {
+    fn new(port: Option<u16>, now: Instant) -> Self {
+        ConnectionState {
+            port,
+            last_seen: now,
+            closed: false,
+            pending: VecDeque::new(),
+            client_end: None,
+            request_open: false,
+            window_scale: [None; 2],
+            zero_window: [false; 2],
+        }
+    }
+
+    /// What the connection adds to `ACTIVE_CONNECTIONS` and `ZERO_WINDOW_CONNECTIONS`.
+    fn gauges(&self) -> [i64; 2] {
+        match self.port {
+            Some(_) if !self.closed => [1, i64::from(self.zero_window.contains(&true))],
+            _ => [0, 0],
+        }
+    }
+}
+
+type ConnectionTable = HashMap<ConnectionId, ConnectionState>;
+
+/// The state of a packet path connection, counted in the gauges when it is new.
+fn connection_entry(
+    connections: &mut ConnectionTable,
+    client: SocketAddr,
+    port: u16,
+    now: Instant,
+) -> &mut ConnectionState {
+    match connections.entry(ConnectionId::Tcp(client)) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => {
+            let state = entry.insert(ConnectionState::new(Some(port), now));
+            update_connection_gauges(state.port, [0, 0], state.gauges());
+            state
+        }
+    }
+}
+
+/// The outcome of one `PacketReader::read_packet` call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadResult {
+    Packet(Vec<u8>),
+    /// Nothing arrived in time, but more may follow. Lets the capture loop notice
+    /// it has been stopped on a quiet interface.
+    WouldBlock,
+    /// The source is exhausted or failed for good.
+    Eof,
+}
 
 pub trait PacketReader {
-    fn read_packet(&mut self) -> Option<Vec<u8>>;
+    fn read_packet(&mut self) -> ReadResult;
+
+    /// Like `read_packet`, but may return the packet in `buf`, reusing its allocation.
+    /// Readers that copy frames out of a buffer of their own should implement this;
+    /// the capture loop hands back the buffers of packets it has finished with.
+    fn read_packet_into(&mut self, buf: Vec<u8>) -> ReadResult {
+        drop(buf);
+        self.read_packet()
+    }
+}
+
+/// A reader chosen at runtime, e.g. from the command line.
+pub type BoxedPacketReader = Box<dyn PacketReader + Send>;
+
+impl<R: PacketReader + ?Sized> PacketReader for Box<R> {
+    fn read_packet(&mut self) -> ReadResult {
+        (**self).read_packet()
+    }
+
+    fn read_packet_into(&mut self, buf: Vec<u8>) -> ReadResult {
+        (**self).read_packet_into(buf)
+    }
+}
+
+/// Direction of an application payload relative to the monitored server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Request,
+    Response,
+}
+
+/// An application payload observed outside of the packet path (e.g. on a unix socket),
+/// where there are no TCP sequence numbers to pair requests and responses with.
+#[derive(Debug, Clone)]
+pub struct Payload {
+    /// Synthetic identifier of the connection the payload was seen on.
+    pub connection: u32,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// PayloadReader is the payload level counterpart of PacketReader.
+#[async_trait]
+pub trait PayloadReader: Send {
+    async fn read_payload(&mut self) -> Option<Payload>;
 }
 
 pub struct Observer {
-    syn_packets: Arc<Mutex<HashMap<u32, Instant>>>,
-    ttl: Duration,
+    connections: Arc<Mutex<ConnectionTable>>,
+    /// Connections the cleanup task evicted, which the plugin hasn't been told about yet.
+    evicted: Arc<Mutex<Vec<ConnectionId>>>,
+    fragments: Mutex<Reassembler>,
+    settings: Arc<watch::Sender<Settings>>,
     cleanup_interval: Duration,
+    queue_depth: usize,
+    max_queue_depth: usize,
+    workers: usize,
+    max_parse_bytes: usize,
+    direction: DirectionFilter,
+    anonymizer: Option<Anonymizer>,
+    error_log: Mutex<RateLimiter>,
 
     post_processors: Vec<Arc<Mutex<dyn PostProcessor>>>,
+    recorder: Option<Mutex<PacketRecorder>>,
 
     stop_tx: watch::Sender<bool>,
     stop_rx: watch::Receiver<bool>,
@@ -31,14 +271,90 @@ pub struct Observer {
 
 pub struct ObsConfig {
     pub ttl: Duration,
+    /// How long a connection may stay idle before it no longer counts as active and
+    /// everything kept about it, in the observer and the plugin, is dropped.
+    pub connection_ttl: Duration,
     pub cleanup_interval: Duration,
+    /// Number of captured packets that may wait for processing before new ones are dropped.
+    pub queue_depth: usize,
+    /// Let the queue grow up to this many packets while packets are dropped, and
+    /// shrink back to `queue_depth` once they no longer are. None keeps it fixed.
+    pub max_queue_depth: Option<usize>,
+    /// Number of packets handled concurrently. Each connection is handled by one
+    /// worker, so its packets stay in order.
+    pub workers: usize,
+    /// Only the first this many bytes of each payload are handed to the handler, enough
+    /// for the command and key without copying large bodies. None hands over everything.
+    pub max_parse_bytes: Option<usize>,
+    /// Identical handler errors are logged at most once per interval.
+    pub error_log_interval: Duration,
+    /// Fraction of client IPs whose traffic is observed, between 0.0 and 1.0. The choice
+    /// is a hash of the IP, so every packet of a sampled connection is kept.
+    pub sample_rate: f64,
+    /// The directions of traffic handed to the handler.
+    pub direction: DirectionFilter,
+    /// Anonymize client addresses before results reach the post-processors.
+    pub anonymizer: Option<Anonymizer>,
+}
+
+/// Which directions of a connection to observe, relative to the monitored server.
+///
+/// Latency is measured by pairing a request with its response, so with one direction
+/// alone nothing is paired: handlers only see payloads that stand on their own, such
+/// as pub/sub messages or replicated writes with `Response`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum DirectionFilter {
+    #[default]
+    Both,
+    /// What clients send to the server.
+    Request,
+    /// What the server sends back.
+    Response,
+}
+
+impl DirectionFilter {
+    fn allows(self, direction: Direction) -> bool {
+        match self {
+            DirectionFilter::Both => true,
+            DirectionFilter::Request => direction == Direction::Request,
+            DirectionFilter::Response => direction == Direction::Response,
+        }
+    }
+}
+
+/// The part of `ObsConfig` that can be changed while the observer runs, through
+/// `Observer::settings`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub ttl: Duration,
+    pub connection_ttl: Duration,
+    pub sample_rate: f64,
+}
+
+impl ObsConfig {
+    pub fn settings(&self) -> Settings {
+        Settings {
+            ttl: self.ttl,
+            connection_ttl: self.connection_ttl,
+            sample_rate: self.sample_rate,
+        }
+    }
 }
 
 impl Default for ObsConfig {
     fn default() -> Self {
         ObsConfig {
             ttl: Duration::from_secs(5),
+            connection_ttl: Duration::from_secs(60),
             cleanup_interval: Duration::from_secs(1),
+            queue_depth: 4096,
+            max_queue_depth: None,
+            workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            max_parse_bytes: None,
+            error_log_interval: Duration::from_secs(10),
+            sample_rate: 1.0,
+            direction: DirectionFilter::Both,
+            anonymizer: None,
         }
     }
 }
@@ -46,14 +362,25 @@ impl Default for ObsConfig {
 impl Observer {
     /// Create a new Observer instance.
     /// Default TTL is 5 seconds.
+    /// Default connection TTL is 60 seconds.
     /// Default cleanup interval is 1 second.
     pub fn new(cfg: ObsConfig) -> Self {
         let (stop_tx, stop_rx) = watch::channel(false);
         Observer {
-            syn_packets: Arc::new(Mutex::new(HashMap::new())),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            evicted: Arc::new(Mutex::new(vec![])),
+            fragments: Mutex::new(Reassembler::default()),
             post_processors: vec![],
-            ttl: cfg.ttl,
+            recorder: None,
+            settings: Arc::new(watch::Sender::new(cfg.settings())),
             cleanup_interval: cfg.cleanup_interval,
+            queue_depth: cfg.queue_depth,
+            max_queue_depth: cfg.max_queue_depth.unwrap_or(cfg.queue_depth),
+            workers: cfg.workers.max(1),
+            max_parse_bytes: cfg.max_parse_bytes.unwrap_or(usize::MAX),
+            direction: cfg.direction,
+            anonymizer: cfg.anonymizer,
+            error_log: Mutex::new(RateLimiter::new(cfg.error_log_interval)),
             stop_tx,
             stop_rx,
         }
@@ -63,27 +390,227 @@ impl Observer {
         self.post_processors.push(post_processor);
     }
 
+    /// Record every captured packet into the given pcap recorder.
+    /// The recorder is flushed when capture stops.
+    pub fn set_recorder(&mut self, recorder: PacketRecorder) {
+        self.recorder = Some(Mutex::new(recorder));
+    }
+
+    /// Change settings while capturing with `send_replace`. Sampling applies from the
+    /// next packet and the TTLs from the next cleanup.
+    pub fn settings(&self) -> Arc<watch::Sender<Settings>> {
+        self.settings.clone()
+    }
+
     pub fn start_cleanup(&self) {
-        let syn_packets = self.syn_packets.clone();
-        let ttl = self.ttl;
+        let connections = self.connections.clone();
+        let evicted = self.evicted.clone();
+        let settings = self.settings.clone();
         let cleanup_interval = self.cleanup_interval;
         let cleanup_fn = async move {
             loop {
                 tokio::time::sleep(cleanup_interval).await;
-                let mut syn_packets = syn_packets.lock().await;
+                let Settings {
+                    ttl,
+                    connection_ttl,
+                    ..
+                } = *settings.borrow();
                 let now = Instant::now();
-                syn_packets.retain(|_, v| now.duration_since(*v) < ttl);
+                let mut connections = connections.lock().await;
+                let mut idle = vec![];
+                connections.retain(|id, c| {
+                    let waiting = c.pending.len();
+                    c.pending
+                        .retain(|(_, sent)| now.duration_since(*sent) < ttl);
+                    let idle_for = now.duration_since(c.last_seen);
+                    // Requests of an idle connection go with it, answered or not
+                    let timed_out = if idle_for < connection_ttl {
+                        waiting - c.pending.len()
+                    } else {
+                        waiting
+                    };
+                    if timed_out > 0 {
+                        let port = c.port.map(|p| p.to_string()).unwrap_or_default();
+                        REQUEST_TIMEOUTS
+                            .with_label_values(&[&port])
+                            .inc_by(timed_out as u64);
+                    }
+                    if idle_for < connection_ttl {
+                        return true;
+                    }
+                    update_connection_gauges(c.port, c.gauges(), [0, 0]);
+                    // The plugin already dropped closed connections
+                    if !c.closed {
+                        idle.push(*id);
+                    }
+                    false
+                });
+                drop(connections);
+                evicted.lock().await.extend(idle);
             }
         };
         tokio::spawn(cleanup_fn);
     }
 
+    /// Read packets on a dedicated thread and process them here. The two sides are
+    /// decoupled by a bounded queue so slow processing never stalls the interface:
+    /// when the queue is full new packets are dropped (and counted) instead.
+    /// Capture ends when stopped or once the reader reports `ReadResult::Eof`.
+    ///
+    /// Packets are handed to `workers` workers by connection, see `Router`. Each
+    /// worker is a task of its own sharing the handler, so connections are parsed in
+    /// parallel and one worker waiting on a post-processor doesn't hold up the others.
     pub async fn capture_packets<H, R>(
-        &self,
-        mut reader: impl PacketReader,
+        self: &Arc<Self>,
+        mut reader: impl PacketReader + Send + 'static,
         // TODO: These two should be paired and we need to expose a register method to have
         // more of these pairs and not take them as inputs here.
-        handler: Arc<Mutex<H>>,
+        handler: Arc<H>,
+    ) -> Result<()>
+    where
+        R: Send + 'static + Into<ProcessedResult>,
+        H: Plugin<R> + 'static,
+    {
+        // Resolved once, as every packet is matched against them
+        let ports: Arc<[u16]> = handler.ports().await.into();
+        let port = ports
+            .iter()
+            .map(u16::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let dropped = PACKETS_DROPPED.with_label_values(&[&port]);
+        let captured = PACKETS_CAPTURED.with_label_values(&[&port]);
+        let bytes = BYTES_CAPTURED.with_label_values(&[&port]);
+        let parsed = PACKETS_PARSED.with_label_values(&[&port]);
+        let parse_errors = PARSE_ERRORS.with_label_values(&[&port]);
+        let queue_limit = QUEUE_LIMIT.with_label_values(&[&port]);
+        let mut tuner = QueueTuner::new(self.queue_depth, self.max_queue_depth, Instant::now());
+        // The channel only allocates for packets actually queued, so it can be sized for
+        // the most the tuner allows
+        let (tx, mut rx) = mpsc::channel(tuner.max());
+        // Buffers of handled packets go back to the reader, so a steady capture
+        // allocates nothing per packet. At most every queued packet and one per worker
+        // can be in flight.
+        let (free_tx, free_rx) = std::sync::mpsc::sync_channel(
+            tx.max_capacity() + self.workers * (self.queue_depth + 1),
+        );
+        let worker_dropped = dropped.clone();
+        std::thread::Builder::new()
+            .name("aragorn-reader".to_string())
+            .spawn(move || {
+                loop {
+                    let buf = free_rx.try_recv().unwrap_or_default();
+                    let packet = match reader.read_packet_into(buf) {
+                        ReadResult::Packet(packet) => packet,
+                        ReadResult::WouldBlock if tx.is_closed() => break,
+                        ReadResult::WouldBlock => continue,
+                        ReadResult::Eof => break,
+                    };
+                    captured.inc();
+                    bytes.inc_by(packet.len() as u64);
+                    // TODO: This isnt the most reliable way to measure time.
+                    // Ideally we should be using the timestamp from the packet header/kernel.
+                    // But this isnt easy enough. One way to do this is to set SO_TIMESTAMP on the socket
+                    // and then read the timestamp from the packet header. For the purpose of the
+                    // POC and simplicity, we are using this method temporarily. Moreover, this also
+                    // doesn't work if we are playing back a pcap file.
+                    let now = Instant::now();
+                    let admitted = tuner.admit(tx.max_capacity() - tx.capacity(), now);
+                    queue_limit.set(tuner.limit() as i64);
+                    if !admitted {
+                        dropped.inc();
+                        continue;
+                    }
+                    match tx.try_send((now, packet)) {
+                        Ok(()) => {}
+                        Err(mpsc::error::TrySendError::Full(_)) => dropped.inc(),
+                        Err(mpsc::error::TrySendError::Closed(_)) => break,
+                    }
+                }
+            })?;
+
+        // Each worker queues as many packets as the capture queue does. One that falls
+        // behind drops its own packets rather than holding up the routing of every other
+        // worker's
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..self.workers)
+            .map(|_| mpsc::channel(self.queue_depth))
+            .unzip();
+        let mut router = Router::new(senders.len());
+        let route_free_tx = free_tx.clone();
+        let route = async {
+            let mut stop_rx = self.stop_rx.clone();
+            loop {
+                tokio::select! {
+                    _ = stop_rx.changed() => {
+                        if *stop_rx.borrow() {
+                            break;
+                        }
+                    }
+                    packet = rx.recv() => {
+                        let Some((timestamp, packet)) = packet else {
+                            break;
+                        };
+                        if let Some(recorder) = &self.recorder {
+                            if let Err(e) = recorder.lock().await.record(&packet) {
+                                error!("Failed to record packet: {:?}", e);
+                            }
+                        }
+                        self.close_evicted(&*handler).await;
+                        let worker = router.route(&packet, timestamp);
+                        match senders[worker].try_send((timestamp, packet)) {
+                            Ok(()) => {}
+                            Err(mpsc::error::TrySendError::Full((_, packet))) => {
+                                worker_dropped.inc();
+                                let _ = route_free_tx.try_send(packet);
+                            }
+                            // A worker only goes away when another one failed
+                            Err(mpsc::error::TrySendError::Closed(_)) => break,
+                        }
+                    }
+                }
+            }
+            drop(senders);
+        };
+        let workers: Vec<_> = receivers
+            .into_iter()
+            .map(|mut rx| {
+                let (obs, handler, ports) = (self.clone(), handler.clone(), ports.clone());
+                let (parsed, parse_errors) = (parsed.clone(), parse_errors.clone());
+                let free_tx = free_tx.clone();
+                tokio::spawn(async move {
+                    while let Some((timestamp, packet)) = rx.recv().await {
+                        let res = obs
+                            .handle_packet(&*handler, &ports, &packet, timestamp)
+                            .await;
+                        // The reader allocates afresh if the free list is full or gone
+                        let _ = free_tx.try_send(packet);
+                        match &res {
+                            Ok(_) => parsed.inc(),
+                            Err(_) => parse_errors.inc(),
+                        }
+                        obs.dispatch(res).await?;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect();
+        route.await;
+        for res in join_all(workers).await {
+            res??;
+        }
+        handler.flush().await;
+        if let Some(recorder) = &self.recorder {
+            recorder.lock().await.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Feed payloads from a non packet source into the handler. Latency is measured
+    /// from a request to the next response on the same connection.
+    pub async fn capture_payloads<H, R>(
+        &self,
+        mut reader: impl PayloadReader,
+        handler: Arc<H>,
     ) -> Result<()>
     where
         R: Send + 'static + Into<ProcessedResult>,
@@ -97,50 +624,118 @@ impl Observer {
                         break;
                     }
                 }
-                Some(packet) = async { reader.read_packet() } => {
-                    let res = self.handle_packet(&handler, packet).await;
-                    match res {
-                        Ok(x) => {
-                            if let Some(result) = x {
-                                let result = &result.into();
-                                for post_processor in &self.post_processors {
-                                    post_processor.lock().await.post_process(result.clone()).await?;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Error: {:?}", e);
-                        }
+                Some(payload) = reader.read_payload() => {
+                    self.close_evicted(&*handler).await;
+                    let res = self.handle_payload(&*handler, payload).await;
+                    self.dispatch(res).await?;
+                }
+            }
+        }
+        handler.flush().await;
+        Ok(())
+    }
+
+    /// Hand a result to every post-processor concurrently, so a slow sink doesn't hold up
+    /// the others. A worker only dispatches its next result once all processors are done
+    /// with this one, so each processor sees the results of a connection in capture order.
+    /// A failing processor is logged and doesn't affect the others.
+    async fn dispatch<R>(&self, res: Result<Option<R>>) -> Result<()>
+    where
+        R: Into<ProcessedResult>,
+    {
+        match res {
+            Ok(x) => {
+                if let Some(result) = x {
+                    let mut result: ProcessedResult = result.into();
+                    if let Some(anonymizer) = &self.anonymizer {
+                        result = anonymizer.result(result);
+                    }
+                    let results = join_all(self.post_processors.iter().map(|post_processor| {
+                        let result = result.clone();
+                        async move { post_processor.lock().await.post_process(result).await }
+                    }))
+                    .await;
+                    for e in results.into_iter().filter_map(Result::err) {
+                        error!("Post-processor error: {:?}", e);
                     }
                 }
             }
+            Err(e) => {
+                // A stream of malformed packets fails the same way over and over
+                let logged = self
+                    .error_log
+                    .lock()
+                    .await
+                    .check(&e.to_string(), Instant::now());
+                match logged {
+                    Some(0) => error!("Error: {:?}", e),
+                    Some(suppressed) => error!(suppressed, "Error: {:?}", e),
+                    None => {}
+                }
+            }
         }
         Ok(())
     }
 
+    async fn handle_payload<H, R>(&self, handler: &H, payload: Payload) -> Result<Option<R>>
+    where
+        R: Send + 'static,
+        H: Plugin<R>,
+    {
+        let mut payload = payload;
+        if payload.data.is_empty() || !self.direction.allows(payload.direction) {
+            return Ok(None);
+        }
+        payload.data.truncate(self.max_parse_bytes);
+        // The connection id stands in for the seq/ack pairing used on the packet path.
+        let identifier = RequestId::Connection(payload.connection.into());
+        let now = Instant::now();
+        let mut connections = self.connections.lock().await;
+        let state = connections
+            .entry(identifier.connection())
+            .or_insert_with(|| ConnectionState::new(None, now));
+        state.last_seen = now;
+        let queue = &mut state.pending;
+        let metrics = match payload.direction {
+            Direction::Request => {
+                queue.push_back((identifier, now));
+                Some(Metrics {
+                    identifier,
+                    latency: None,
+                    src_addr: None,
+                    dst_addr: None,
+                })
+            }
+            Direction::Response => queue.pop_front().map(|(_, time)| Metrics {
+                identifier,
+                latency: Some(time.elapsed()),
+                src_addr: None,
+                dst_addr: None,
+            }),
+        };
+        drop(connections);
+
+        handler.process(payload.data, metrics).await
+    }
+
     async fn handle_packet<H, R>(
         &self,
-        handler: &Arc<Mutex<H>>,
-        packet: Vec<u8>,
+        handler: &H,
+        ports: &[u16],
+        packet: &[u8],
+        timestamp: Instant,
     ) -> Result<Option<R>>
     where
         R: Send + 'static,
         H: Plugin<R>,
     {
-        // TODO: This isnt the most reliable way to measure time.
-        // Ideally we should be using the timestamp from the packet header/kernel.
-        // But this isnt easy enough. One way to do this is to set SO_TIMESTAMP on the socket
-        // and then read the timestamp from the packet header. For the purpose of the
-        // POC and simplicity, we are using this method temporarily. Moreover, this also
-        // doesn't work if we are playing back a pcap file.
-        let timestamp = Instant::now();
-        if let Some(ethernet_packet) = EthernetPacket::new(&packet) {
+        if let Some(ethernet_packet) = EthernetPacket::new(packet) {
             #[allow(clippy::single_match)]
             match ethernet_packet.get_ethertype() {
                 EtherTypes::Ipv4 => {
                     if let Some(ipv4_packet) = Ipv4Packet::new(ethernet_packet.payload()) {
                         return self
-                            .handle_ipv4_packet(handler, ipv4_packet, timestamp)
+                            .handle_ipv4_packet(handler, ports, ipv4_packet, timestamp)
                             .await;
                     }
                 }
@@ -152,7 +747,34 @@ impl Observer {
 
     async fn handle_ipv4_packet<H, R>(
         &self,
-        handler: &Arc<Mutex<H>>,
+        handler: &H,
+        ports: &[u16],
+        ipv4_packet: Ipv4Packet<'_>,
+        timestamp: Instant,
+    ) -> Result<Option<R>>
+    where
+        R: Send + 'static,
+        H: Plugin<R>,
+    {
+        if ip_fragment::is_fragment(&ipv4_packet) {
+            let datagram = self.fragments.lock().await.push(&ipv4_packet, timestamp);
+            return match datagram.as_deref().and_then(Ipv4Packet::new) {
+                Some(datagram) => {
+                    self.handle_datagram(handler, ports, datagram, timestamp)
+                        .await
+                }
+                None => Ok(None),
+            };
+        }
+        self.handle_datagram(handler, ports, ipv4_packet, timestamp)
+            .await
+    }
+
+    /// Handle a complete, unfragmented IPv4 datagram.
+    async fn handle_datagram<H, R>(
+        &self,
+        handler: &H,
+        ports: &[u16],
         ipv4_packet: Ipv4Packet<'_>,
         timestamp: Instant,
     ) -> Result<Option<R>>
@@ -162,16 +784,19 @@ impl Observer {
     {
         match ipv4_packet.get_next_level_protocol() {
             IpNextHeaderProtocols::Tcp => {
-                self.handle_tcp_packet(handler, ipv4_packet, timestamp)
+                self.handle_tcp_packet(handler, ports, ipv4_packet, timestamp)
                     .await
             }
             _ => Ok(None),
         }
     }
 
+    /// Hand the payload of a TCP segment to the handler when either end of it is one of
+    /// `ports`, the handler's ports as resolved when capture started.
     async fn handle_tcp_packet<H, R>(
         &self,
-        handler: &Arc<Mutex<H>>,
+        handler: &H,
+        ports: &[u16],
         ipv4_packet: Ipv4Packet<'_>,
         timestamp: Instant,
     ) -> Result<Option<R>>
@@ -181,57 +806,221 @@ impl Observer {
     {
         let tcp_packet = TcpPacket::new(ipv4_packet.payload())
             .ok_or_else(|| anyhow::anyhow!("Failed to parse TCP packet from IPv4 payload"))?;
-        let port = handler.lock().await.port().await;
         let dst_port = tcp_packet.get_destination();
         let src_port = tcp_packet.get_source();
-        if dst_port != port && src_port != port {
+        // The server port of this connection, from here on treated as the monitored port
+        let port = if ports.contains(&dst_port) {
+            dst_port
+        } else if ports.contains(&src_port) {
+            src_port
+        } else {
             return Ok(None); // Skip if the port does not match
+        };
+        let direction = if dst_port == port {
+            Direction::Request
+        } else {
+            Direction::Response
+        };
+        if !self.direction.allows(direction) {
+            return Ok(None);
+        }
+        let client_ip = if dst_port == port {
+            ipv4_packet.get_source()
+        } else {
+            ipv4_packet.get_destination()
+        };
+        let sample_rate = self.settings.borrow().sample_rate;
+        if !is_sampled(client_ip, sample_rate) {
+            return Ok(None);
         }
 
-        let metrics = self.get_metrics(&tcp_packet, timestamp, port).await;
+        let src_addr: SocketAddr =
+            SocketAddrV4::new(ipv4_packet.get_source(), tcp_packet.get_source()).into();
+        let dst_addr: SocketAddr =
+            SocketAddrV4::new(ipv4_packet.get_destination(), tcp_packet.get_destination()).into();
+        let client = if dst_port == port { src_addr } else { dst_addr };
+        self.track_connection(&tcp_packet, client, timestamp, port)
+            .await;
+        let metrics = self
+            .get_metrics(&tcp_packet, client, timestamp, port)
+            .await
+            .map(|m| Metrics {
+                src_addr: Some(src_addr),
+                dst_addr: Some(dst_addr),
+                ..m
+            });
 
         let payload = tcp_packet.payload();
-        if payload.is_empty() {
-            return Ok(None); // Skip if payload is empty
+        let payload = &payload[..payload.len().min(self.max_parse_bytes)];
+        let res = if payload.is_empty() {
+            Ok(None) // Skip if payload is empty
+        } else {
+            handler.process(payload.to_vec(), metrics).await
+        };
+
+        // Closing happens last, as a response may carry the FIN
+        if tcp_packet.get_flags() & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            self.close_connection(handler, ConnectionId::Tcp(client))
+                .await;
         }
+        res
+    }
 
-        handler
-            .lock()
-            .await
-            .process(payload.to_vec(), metrics)
-            .await
+    async fn track_connection(
+        &self,
+        tcp_packet: &TcpPacket<'_>,
+        client: SocketAddr,
+        timestamp: Instant,
+        port: u16,
+    ) {
+        let flags = tcp_packet.get_flags();
+        if flags & TcpFlags::RST != 0 {
+            TCP_RESETS.with_label_values(&[&port.to_string()]).inc();
+        }
+        if flags & TcpFlags::FIN != 0 {
+            TCP_FINS.with_label_values(&[&port.to_string()]).inc();
+        }
+
+        let mut connections = self.connections.lock().await;
+        let state = connection_entry(&mut connections, client, port, timestamp);
+        let before = state.gauges();
+        state.last_seen = timestamp;
+        let side = usize::from(tcp_packet.get_source() == port);
+        if flags & TcpFlags::SYN != 0 {
+            // A new handshake on a reused 4-tuple reopens it
+            state.closed = false;
+            state.zero_window[side] = false;
+            state.window_scale[side] = window_scale(tcp_packet);
+        }
+        // The window of a reset is meaningless
+        if flags & TcpFlags::RST == 0 {
+            let shift = match state.window_scale {
+                // Windows in the handshake itself are never scaled
+                [Some(client), Some(server)] if flags & TcpFlags::SYN == 0 => {
+                    [client, server][side].min(14)
+                }
+                _ => 0,
+            };
+            let window = u32::from(tcp_packet.get_window()) << shift;
+            let mut buf = [0; 5];
+            let labels = [port_label(port, &mut buf), SIDES[side]];
+            TCP_WINDOW
+                .with_label_values(&labels)
+                .observe(f64::from(window));
+            if window == 0 && !state.zero_window[side] {
+                TCP_ZERO_WINDOWS.with_label_values(&labels).inc();
+            }
+            state.zero_window[side] = window == 0;
+        }
+        update_connection_gauges(state.port, before, state.gauges());
+    }
+
+    /// Drop what is kept about a connection that saw a FIN or RST, here and in the plugin.
+    async fn close_connection<H, R>(&self, handler: &H, id: ConnectionId)
+    where
+        H: Plugin<R>,
+    {
+        let mut connections = self.connections.lock().await;
+        let Some(state) = connections.get_mut(&id).filter(|state| !state.closed) else {
+            return;
+        };
+        let before = state.gauges();
+        state.closed = true;
+        state.pending.clear();
+        update_connection_gauges(state.port, before, state.gauges());
+        drop(connections);
+        handler.close_connection(id).await;
+    }
+
+    /// Tell the plugin about the idle connections the cleanup task evicted.
+    async fn close_evicted<H, R>(&self, handler: &H)
+    where
+        H: Plugin<R>,
+    {
+        let evicted = std::mem::take(&mut *self.evicted.lock().await);
+        if evicted.is_empty() {
+            return;
+        }
+        for id in evicted {
+            handler.close_connection(id).await;
+        }
     }
 
+    /// Pair application requests with their responses using TCP sequence numbers.
+    ///
+    /// Only segments carrying data take part; SYN/FIN handshakes and bare ACKs are
+    /// ignored, so neither connection setup nor a delayed ACK from the server's stack
+    /// is mistaken for a response.
+    ///
+    /// 1. A client data segment ending at `seq + len` is a request. It is queued as
+    ///    pending on its connection under that end-of-data sequence number, timestamped
+    ///    at send. A request larger than a segment arrives as several, so a segment
+    ///    continuing one that didn't end a write (no PSH), with no server data between
+    ///    them, extends that request to its own end instead. A retransmission, ending
+    ///    no further than data already seen, is ignored.
+    /// 2. A server data segment acknowledging at least `seq + len` of the oldest pending
+    ///    request is the response to it, since requests on a connection are answered in
+    ///    order. The request is dequeued and the elapsed time is the application latency.
+    ///    With pipelining one ack may cover several requests, which are then answered
+    ///    by successive segments.
+    /// 3. Pending requests that never see a response expire after the observer TTL, or
+    ///    when their connection closes.
+    ///
+    /// Both sides report the end-of-data sequence number on the client's connection as
+    /// the identifier, so a plugin can correlate the request it saw with the response.
     async fn get_metrics(
         &self,
         tcp_packet: &TcpPacket<'_>,
+        client: SocketAddr,
         timestamp: Instant,
         port: u16,
     ) -> Option<Metrics> {
-        let dst_port = tcp_packet.get_destination();
-        let src_port = tcp_packet.get_source();
-        let ack_flag = tcp_packet.get_flags() & pnet::packet::tcp::TcpFlags::ACK != 0;
-
-        if !ack_flag {
-            return None; // Skip if the packet is not an ACK
+        let len = tcp_packet.payload().len() as u32;
+        if len == 0 {
+            return None;
         }
 
-        if dst_port == port {
-            let mut syn_packets = self.syn_packets.lock().await;
-            let identifier = tcp_packet.get_acknowledgement();
-            syn_packets.insert(identifier, timestamp);
+        let mut connections = self.connections.lock().await;
+        let state = connection_entry(&mut connections, client, port, timestamp);
+        if tcp_packet.get_destination() == port {
+            let seq = tcp_packet.get_sequence();
+            let end = seq.wrapping_add(len);
+            if state
+                .client_end
+                .is_some_and(|seen| seen.wrapping_sub(end) as i32 >= 0)
+            {
+                return None;
+            }
+            let identifier = RequestId::TcpSeq(end, client);
+            let continues = state.request_open && state.client_end == Some(seq);
+            match state.pending.back_mut() {
+                Some((id, _)) if continues => *id = identifier,
+                _ => state.pending.push_back((identifier, timestamp)),
+            }
+            state.client_end = Some(end);
+            state.request_open = tcp_packet.get_flags() & TcpFlags::PSH == 0;
             return Some(Metrics {
                 identifier,
                 latency: None,
+                src_addr: None,
+                dst_addr: None,
             });
         }
-        if src_port == port {
-            let mut syn_packets = self.syn_packets.lock().await;
-            if let Some(time) = syn_packets.remove(&tcp_packet.get_sequence()) {
-                let elapsed = time.elapsed();
+        // Whatever the client sends next is a new request
+        state.request_open = false;
+        if tcp_packet.get_source() == port && tcp_packet.get_flags() & TcpFlags::ACK != 0 {
+            let ack = tcp_packet.get_acknowledgement();
+            // Sequence numbers wrap, so compare by distance
+            let answered = |end: u32| ack.wrapping_sub(end) as i32 >= 0;
+            if let Some((identifier, time)) = state
+                .pending
+                .pop_front_if(|(id, _)| matches!(id, RequestId::TcpSeq(end, _) if answered(*end)))
+            {
                 return Some(Metrics {
-                    identifier: tcp_packet.get_sequence(),
-                    latency: Some(elapsed),
+                    identifier,
+                    latency: Some(timestamp.saturating_duration_since(time)),
+                    src_addr: None,
+                    dst_addr: None,
                 });
             }
         }
@@ -243,44 +1032,262 @@ impl Observer {
     }
 }
 
+/// Fragments of one datagram are matched on (source, destination, identification, protocol).
+type FragmentKey = (Ipv4Addr, Ipv4Addr, u16, u8);
+
+/// Picks the worker, out of `workers`, that handles a captured frame. Both directions
+/// of a TCP connection hash to the same worker.
+///
+/// IP fragments carry no ports past the first, so the first fragment of a datagram is
+/// routed by its ports and the rest follow it. That keeps the reassembled segment on
+/// its connection's worker. Fragments arriving ahead of the first can only go by their
+/// addresses.
+struct Router {
+    workers: usize,
+    /// The worker of each fragmented datagram, with when its first fragment was seen.
+    fragments: HashMap<FragmentKey, (usize, Instant)>,
+    timeout: Duration,
+    max_pending: usize,
+}
+
+impl Router {
+    fn new(workers: usize) -> Self {
+        // As long and as many as the reassembler holds on to
+        Router {
+            workers,
+            fragments: HashMap::new(),
+            timeout: Duration::from_secs(30),
+            max_pending: 1024,
+        }
+    }
+
+    fn route(&mut self, frame: &[u8], now: Instant) -> usize {
+        if self.workers <= 1 {
+            return 0;
+        }
+        let Some(ethernet_packet) = EthernetPacket::new(frame) else {
+            return 0;
+        };
+        let Some(ipv4_packet) = Ipv4Packet::new(ethernet_packet.payload())
+            .filter(|_| ethernet_packet.get_ethertype() == EtherTypes::Ipv4)
+        else {
+            return 0;
+        };
+        let is_tcp = ipv4_packet.get_next_level_protocol() == IpNextHeaderProtocols::Tcp;
+        let fragment = ip_fragment::is_fragment(&ipv4_packet).then(|| {
+            (
+                ipv4_packet.get_source(),
+                ipv4_packet.get_destination(),
+                ipv4_packet.get_identification(),
+                ipv4_packet.get_next_level_protocol().0,
+            )
+        });
+        if let Some(key) = fragment {
+            self.fragments
+                .retain(|_, (_, seen)| now.saturating_duration_since(*seen) < self.timeout);
+            if let Some(&(worker, _)) = self.fragments.get(&key) {
+                return worker;
+            }
+        }
+        // Only an unfragmented segment or the first fragment holds the TCP header
+        let ports = match TcpPacket::new(ipv4_packet.payload()) {
+            Some(tcp) if is_tcp && ipv4_packet.get_fragment_offset() == 0 => {
+                (tcp.get_source(), tcp.get_destination())
+            }
+            _ => (0, 0),
+        };
+        let mut ends = [
+            (ipv4_packet.get_source(), ports.0),
+            (ipv4_packet.get_destination(), ports.1),
+        ];
+        ends.sort();
+        let mut hasher = DefaultHasher::new();
+        ends.hash(&mut hasher);
+        let worker = (hasher.finish() % self.workers as u64) as usize;
+        if let Some(key) = fragment.filter(|_| self.fragments.len() < self.max_pending) {
+            self.fragments.insert(key, (worker, now));
+        }
+        worker
+    }
+}
+
+/// Report the interface packets are captured from as `aragorn_capture_info`.
+pub fn report_interface(name: &str) {
+    CAPTURE_INFO.with_label_values(&[name]).set(1);
+}
+
+/// Whether traffic of `client` falls within the sampled fraction of client IPs.
+fn is_sampled(client: Ipv4Addr, rate: f64) -> bool {
+    if rate >= 1.0 {
+        return true;
+    }
+    // FNV-1a, so the same IP is sampled the same way across restarts
+    let hash = client.octets().iter().fold(0x811c_9dc5u32, |h, &b| {
+        (h ^ b as u32).wrapping_mul(0x0100_0193)
+    });
+    (hash as f64) < rate * u32::MAX as f64
+}
+
+/// The window scale shift offered in the options of a SYN segment.
+fn window_scale(tcp_packet: &TcpPacket) -> Option<u8> {
+    tcp_packet
+        .get_options_iter()
+        .find(|option| option.get_number() == TcpOptionNumbers::WSCALE)
+        .and_then(|option| option.payload().first().copied())
+}
+
+/// Move the connection gauges of `port` by what a connection's change of state
+/// changed its part of them, so they're kept without counting the table.
+/// The decimal digits of a port, written to `buf` so a label costs no allocation.
+fn port_label(port: u16, buf: &mut [u8; 5]) -> &str {
+    let mut rest = &mut buf[..];
+    write!(rest, "{}", port).expect("a port fits in 5 digits");
+    let len = 5 - rest.len();
+    std::str::from_utf8(&buf[..len]).expect("digits are ASCII")
+}
+
+fn update_connection_gauges(port: Option<u16>, before: [i64; 2], after: [i64; 2]) {
+    let Some(port) = port.filter(|_| before != after) else {
+        return;
+    };
+    let port = port.to_string();
+    ACTIVE_CONNECTIONS
+        .with_label_values(&[&port])
+        .add(after[0] - before[0]);
+    ZERO_WINDOW_CONNECTIONS
+        .with_label_values(&[&port])
+        .add(after[1] - before[1]);
+}
+
+#[cfg(test)]
+pub mod testing {
+    use pnet::packet::ethernet::{EtherTypes, MutableEthernetPacket};
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+
+    /// Build an Ethernet/IPv4/TCP frame.
+    pub fn tcp_frame(
+        src: ([u8; 4], u16),
+        dst: ([u8; 4], u16),
+        flags: u8,
+        seq: u32,
+        ack: u32,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let ip_len = 20 + 20 + payload.len();
+        let mut buf = vec![0u8; 14 + ip_len];
+        MutableEthernetPacket::new(&mut buf)
+            .unwrap()
+            .set_ethertype(EtherTypes::Ipv4);
+
+        let mut ip = MutableIpv4Packet::new(&mut buf[14..]).unwrap();
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length(ip_len as u16);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Tcp);
+        ip.set_source(src.0.into());
+        ip.set_destination(dst.0.into());
+
+        let mut tcp = MutableTcpPacket::new(&mut buf[34..]).unwrap();
+        tcp.set_source(src.1);
+        tcp.set_destination(dst.1);
+        tcp.set_sequence(seq);
+        tcp.set_acknowledgement(ack);
+        tcp.set_data_offset(5);
+        tcp.set_flags(flags);
+        tcp.set_window(65535);
+        tcp.set_payload(payload);
+        buf
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::testing::tcp_frame;
     use crate::post_processor::PrometheusResult;
+    use pnet::packet::ipv4::MutableIpv4Packet;
+    use pnet::packet::tcp::MutableTcpPacket;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
     use super::*;
 
-    // Mock the PacketReader trait
-    struct MockPacketReader {
-        packets: Vec<Vec<u8>>,
+    struct PortPlugin(u16);
+
+    impl Plugin<MockResult> for PortPlugin {
+        async fn port(&self) -> u16 {
+            self.0
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            Ok(None)
+        }
     }
 
-    impl PacketReader for MockPacketReader {
-        fn read_packet(&mut self) -> Option<Vec<u8>> {
-            self.packets.pop()
+    struct MultiPortPlugin(Vec<u16>, std::sync::Mutex<Vec<Vec<u8>>>);
+
+    impl Plugin<MockResult> for MultiPortPlugin {
+        async fn port(&self) -> u16 {
+            self.0[0]
+        }
+
+        async fn ports(&self) -> Vec<u16> {
+            self.0.clone()
+        }
+
+        async fn process(
+            &self,
+            input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            self.1.lock().unwrap().push(input);
+            Ok(None)
         }
     }
 
     #[tokio::test]
-    async fn test_get_metrics() {
+    async fn test_plugin_with_multiple_ports() {
         let obs = Observer::new(ObsConfig::default());
-        let tcp_packet = TcpPacket::new(&[0; 20]).unwrap();
-        let timestamp = Instant::now();
-        let port = 1234;
-        let metrics = obs.get_metrics(&tcp_packet, timestamp, port).await;
-        assert!(metrics.is_none());
+        let plugin = MultiPortPlugin(vec![6379, 26379], Default::default());
+        let client = ([10, 0, 0, 2], 50000);
+        for (port, payload) in [(6379, b"redis"), (26379, b"sntnl"), (80, b"other")] {
+            let frame = tcp_frame(client, ([10, 0, 0, 1], port), TcpFlags::ACK, 1, 1, payload);
+            obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+                .await
+                .unwrap();
+        }
+        // Server side traffic on the second port matches too
+        let frame = tcp_frame(
+            ([10, 0, 0, 1], 26379),
+            client,
+            TcpFlags::ACK,
+            1,
+            6,
+            b"reply",
+        );
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        let seen = plugin.1.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![b"redis".to_vec(), b"sntnl".to_vec(), b"reply".to_vec()]
+        );
     }
 
-    struct MockPlugin;
-
-    impl MockPlugin {
-        fn new() -> Self {
-            MockPlugin
-        }
+    #[derive(Default)]
+    struct ClosingPlugin {
+        closed: std::sync::Mutex<Vec<ConnectionId>>,
     }
 
-    impl Plugin<MockResult> for MockPlugin {
+    impl Plugin<MockResult> for ClosingPlugin {
         async fn port(&self) -> u16 {
-            1234
+            6379
         }
 
         async fn process(
@@ -290,41 +1297,975 @@ mod tests {
         ) -> Result<Option<MockResult>> {
             Ok(None)
         }
+
+        async fn close_connection(&self, connection: ConnectionId) {
+            self.closed.lock().unwrap().push(connection);
+        }
     }
 
-    struct MockResult;
+    #[tokio::test]
+    async fn test_idle_connection_evicted() {
+        let obs = Observer::new(ObsConfig {
+            connection_ttl: Duration::from_millis(50),
+            cleanup_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        obs.start_cleanup();
+        let plugin = ClosingPlugin::default();
+        let client = ([10, 0, 0, 2], 50000);
+        let frame = tcp_frame(
+            client,
+            ([10, 0, 0, 1], 6379),
+            TcpFlags::ACK,
+            1,
+            1,
+            b"*1\r\n",
+        );
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        let id = ConnectionId::Tcp(SocketAddr::from(client));
+        assert_eq!(obs.connections.lock().await[&id].pending.len(), 1);
 
-    impl From<MockResult> for ProcessedResult {
-        fn from(_res: MockResult) -> ProcessedResult {
-            ProcessedResult::Prometheus(PrometheusResult {
-                label: "test".to_string(),
-                is_error: false,
-                latency: 0,
-            })
-        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(obs.connections.lock().await.is_empty());
+        // The plugin hears about it before the next packet is handled
+        assert!(plugin.closed.lock().unwrap().is_empty());
+        obs.close_evicted(&plugin).await;
+        assert_eq!(*plugin.closed.lock().unwrap(), vec![id]);
     }
 
     #[tokio::test]
-    async fn test_capture_packets() {
-        let reader = MockPacketReader {
-            // TODO: send a fake tcp packet
+    async fn test_request_timeout_counted() {
+        let obs = Observer::new(ObsConfig {
+            ttl: Duration::from_millis(50),
+            cleanup_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        obs.start_cleanup();
+        let plugin = PortPlugin(5151);
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 5151);
+        let timeouts = REQUEST_TIMEOUTS.with_label_values(&["5151"]);
+
+        // Answered in time
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 1, 1, b"PING");
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        let frame = tcp_frame(server, client, TcpFlags::ACK, 1, 5, b"PONG");
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        // Never answered
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 5, 5, b"PING");
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(timeouts.get(), 0);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(timeouts.get(), 1);
+        let id = ConnectionId::Tcp(SocketAddr::from(client));
+        assert!(obs.connections.lock().await[&id].pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_closed_connection_dropped() {
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = ClosingPlugin::default();
+        let client = ([10, 0, 0, 2], 50001);
+        let server = ([10, 0, 0, 1], 6379);
+        let id = ConnectionId::Tcp(SocketAddr::from(client));
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 1, 1, b"*1\r\n");
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+
+        let frame = tcp_frame(client, server, TcpFlags::FIN | TcpFlags::ACK, 5, 1, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert!(obs.connections.lock().await[&id].pending.is_empty());
+        assert_eq!(*plugin.closed.lock().unwrap(), vec![id]);
+
+        // The server's FIN and the last ACK don't close it again
+        let frame = tcp_frame(server, client, TcpFlags::FIN | TcpFlags::ACK, 1, 6, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 6, 2, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(plugin.closed.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rst_and_fin_counters() {
+        let port = 4343;
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = PortPlugin(port);
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], port);
+        let resets = TCP_RESETS.with_label_values(&["4343"]);
+        let fins = TCP_FINS.with_label_values(&["4343"]);
+
+        let frame = tcp_frame(client, server, TcpFlags::RST, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(resets.get(), 1);
+        assert_eq!(fins.get(), 0);
+
+        let frame = tcp_frame(server, client, TcpFlags::FIN | TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(resets.get(), 1);
+        assert_eq!(fins.get(), 1);
+
+        // Other ports are not counted
+        let frame = tcp_frame(client, ([10, 0, 0, 1], 80), TcpFlags::RST, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(resets.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_active_connections_gauge() {
+        let port = 4242;
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = PortPlugin(port);
+        let server = ([10, 0, 0, 1], port);
+        let gauge = ACTIVE_CONNECTIONS.with_label_values(&["4242"]);
+
+        for client_port in [50000, 50001] {
+            let frame = tcp_frame(
+                ([10, 0, 0, 2], client_port),
+                server,
+                TcpFlags::SYN,
+                0,
+                0,
+                &[],
+            );
+            obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+                .await
+                .unwrap();
+        }
+        // Server side traffic for an existing connection doesn't add a new one
+        let frame = tcp_frame(server, ([10, 0, 0, 2], 50000), TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(gauge.get(), 2);
+
+        let frame = tcp_frame(([10, 0, 0, 2], 50001), server, TcpFlags::RST, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(gauge.get(), 1);
+
+        // A trailing ACK on the reset connection doesn't bring it back
+        let frame = tcp_frame(server, ([10, 0, 0, 2], 50001), TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(gauge.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_gauges_follow_eviction() {
+        let port = 4343;
+        let obs = Observer::new(ObsConfig {
+            connection_ttl: Duration::from_millis(50),
+            cleanup_interval: Duration::from_millis(10),
+            ..Default::default()
+        });
+        obs.start_cleanup();
+        let plugin = PortPlugin(port);
+        let active = ACTIVE_CONNECTIONS.with_label_values(&["4343"]);
+        let stalled = ZERO_WINDOW_CONNECTIONS.with_label_values(&["4343"]);
+
+        let frame = tcp_frame(
+            ([10, 0, 0, 2], 50000),
+            ([10, 0, 0, 1], port),
+            TcpFlags::ACK,
+            0,
+            0,
+            &[],
+        );
+        obs.handle_packet(
+            &plugin,
+            &plugin.ports().await,
+            &with_window(frame, 0),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!((active.get(), stalled.get()), (1, 1));
+
+        // An idle connection leaves both gauges as it leaves the table
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(obs.connections.lock().await.is_empty());
+        assert_eq!((active.get(), stalled.get()), (0, 0));
+    }
+
+    fn with_window(mut frame: Vec<u8>, window: u16) -> Vec<u8> {
+        MutableTcpPacket::new(&mut frame[34..])
+            .unwrap()
+            .set_window(window);
+        frame
+    }
+
+    #[tokio::test]
+    async fn test_zero_window() {
+        let port = 4444;
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = PortPlugin(port);
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], port);
+        let zero_windows = TCP_ZERO_WINDOWS.with_label_values(&["4444", "client"]);
+        let stalled = ZERO_WINDOW_CONNECTIONS.with_label_values(&["4444"]);
+
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(
+            &plugin,
+            &plugin.ports().await,
+            &with_window(frame, 0),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(zero_windows.get(), 1);
+        assert_eq!(stalled.get(), 1);
+
+        // Repeated zero windows are the same stall
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(
+            &plugin,
+            &plugin.ports().await,
+            &with_window(frame, 0),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(zero_windows.get(), 1);
+        assert_eq!(
+            TCP_ZERO_WINDOWS
+                .with_label_values(&["4444", "server"])
+                .get(),
+            0
+        );
+
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(&plugin, &plugin.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        assert_eq!(zero_windows.get(), 1);
+        assert_eq!(stalled.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_window_scale() {
+        let port = 4545;
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = PortPlugin(port);
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], port);
+        let windows = TCP_WINDOW.with_label_values(&["4545", "server"]);
+
+        // NOP, then window scale 7
+        for (src, dst, flags) in [
+            (client, server, TcpFlags::SYN),
+            (server, client, TcpFlags::SYN | TcpFlags::ACK),
+        ] {
+            let mut frame = tcp_frame(src, dst, flags, 0, 0, &[1, 3, 3, 7]);
+            MutableTcpPacket::new(&mut frame[34..])
+                .unwrap()
+                .set_data_offset(6);
+            obs.handle_packet(
+                &plugin,
+                &plugin.ports().await,
+                &with_window(frame, 512),
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        }
+        // The SYN-ACK window is unscaled
+        assert_eq!(windows.get_sample_sum(), 512.0);
+
+        let frame = tcp_frame(server, client, TcpFlags::ACK, 0, 0, &[]);
+        obs.handle_packet(
+            &plugin,
+            &plugin.ports().await,
+            &with_window(frame, 512),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(windows.get_sample_count(), 2);
+        assert_eq!(windows.get_sample_sum(), 512.0 + 512.0 * 128.0);
+    }
+
+    // Mock the PacketReader trait
+    struct MockPacketReader {
+        packets: Vec<Vec<u8>>,
+    }
+
+    impl PacketReader for MockPacketReader {
+        fn read_packet(&mut self) -> ReadResult {
+            match self.packets.pop() {
+                Some(packet) => ReadResult::Packet(packet),
+                None => ReadResult::Eof,
+            }
+        }
+    }
+
+    /// Reads a packet every millisecond.
+    struct PacedReader(MockPacketReader);
+
+    impl PacketReader for PacedReader {
+        fn read_packet(&mut self) -> ReadResult {
+            std::thread::sleep(Duration::from_millis(1));
+            self.0.read_packet()
+        }
+    }
+
+    /// Replays a script of reads, then would block for ever. Flags when dropped.
+    struct ScriptedReader {
+        script: VecDeque<ReadResult>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl PacketReader for ScriptedReader {
+        fn read_packet(&mut self) -> ReadResult {
+            self.script.pop_front().unwrap_or_else(|| {
+                std::thread::sleep(Duration::from_millis(1));
+                ReadResult::WouldBlock
+            })
+        }
+    }
+
+    impl Drop for ScriptedReader {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_paired_with_acknowledging_response() {
+        let obs = Observer::new(ObsConfig::default());
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 6379);
+        let segment = |frame: &[u8]| frame[34..].to_vec();
+        let peer: SocketAddr = "10.0.0.2:50000".parse().unwrap();
+        let start = Instant::now();
+
+        // Handshake segments carry no data and are ignored
+        let syn = segment(&tcp_frame(client, server, TcpFlags::SYN, 99, 0, &[]));
+        let tcp = TcpPacket::new(&syn).unwrap();
+        assert!(obs.get_metrics(&tcp, peer, start, 6379).await.is_none());
+
+        let req = segment(&tcp_frame(
+            client,
+            server,
+            TcpFlags::ACK,
+            100,
+            500,
+            b"PING\r\n",
+        ));
+        let tcp = TcpPacket::new(&req).unwrap();
+        let metrics = obs.get_metrics(&tcp, peer, start, 6379).await.unwrap();
+        assert_eq!(metrics.identifier, RequestId::TcpSeq(106, peer));
+        assert!(metrics.latency.is_none());
+
+        // A bare ACK of the request isn't the response
+        let ack = segment(&tcp_frame(server, client, TcpFlags::ACK, 500, 106, &[]));
+        let tcp = TcpPacket::new(&ack).unwrap();
+        let later = start + Duration::from_millis(1);
+        assert!(obs.get_metrics(&tcp, peer, later, 6379).await.is_none());
+
+        // Data for another request doesn't match
+        let other = segment(&tcp_frame(
+            server,
+            client,
+            TcpFlags::ACK,
+            500,
+            90,
+            b"+OK\r\n",
+        ));
+        let tcp = TcpPacket::new(&other).unwrap();
+        assert!(obs.get_metrics(&tcp, peer, later, 6379).await.is_none());
+
+        let res = segment(&tcp_frame(
+            server,
+            client,
+            TcpFlags::ACK,
+            500,
+            106,
+            b"+PONG\r\n",
+        ));
+        let tcp = TcpPacket::new(&res).unwrap();
+        let later = start + Duration::from_millis(5);
+        let metrics = obs.get_metrics(&tcp, peer, later, 6379).await.unwrap();
+        assert_eq!(metrics.identifier, RequestId::TcpSeq(106, peer));
+        assert_eq!(metrics.latency, Some(Duration::from_millis(5)));
+
+        // The request is consumed by its response
+        assert!(obs.get_metrics(&tcp, peer, later, 6379).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_requests_paired_in_order() {
+        let obs = Observer::new(ObsConfig::default());
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 6379);
+        let peer = "10.0.0.2:50000".parse().unwrap();
+        let start = Instant::now();
+        let segment = |frame: &[u8]| frame[34..].to_vec();
+        let at = |ms| start + Duration::from_millis(ms);
+
+        // Each request is a write of its own, so its segment carries PSH
+        for (seq, ms) in [(100, 0), (110, 1)] {
+            let req = segment(&tcp_frame(
+                client,
+                server,
+                TcpFlags::ACK | TcpFlags::PSH,
+                seq,
+                500,
+                b"0123456789",
+            ));
+            let tcp = TcpPacket::new(&req).unwrap();
+            assert!(obs.get_metrics(&tcp, peer, at(ms), 6379).await.is_some());
+        }
+        // A retransmission isn't another request
+        let req = segment(&tcp_frame(
+            client,
+            server,
+            TcpFlags::ACK | TcpFlags::PSH,
+            110,
+            500,
+            b"0123456789",
+        ));
+        let tcp = TcpPacket::new(&req).unwrap();
+        assert!(obs.get_metrics(&tcp, peer, at(2), 6379).await.is_none());
+
+        // The server read both before answering, so both responses ack the second
+        let mut paired = vec![];
+        for (seq, ms) in [(500, 5), (505, 6), (510, 7)] {
+            let res = segment(&tcp_frame(
+                server,
+                client,
+                TcpFlags::ACK,
+                seq,
+                120,
+                b"+OK\r\n",
+            ));
+            let tcp = TcpPacket::new(&res).unwrap();
+            paired.push(
+                obs.get_metrics(&tcp, peer, at(ms), 6379)
+                    .await
+                    .map(|m| (m.identifier, m.latency)),
+            );
+        }
+        assert_eq!(
+            paired,
+            vec![
+                Some((RequestId::TcpSeq(110, peer), Some(Duration::from_millis(5)))),
+                Some((RequestId::TcpSeq(120, peer), Some(Duration::from_millis(5)))),
+                None,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_request_spanning_segments() {
+        let obs = Observer::new(ObsConfig::default());
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 6379);
+        let peer = "10.0.0.2:50000".parse().unwrap();
+        let start = Instant::now();
+        let segment = |frame: &[u8]| frame[34..].to_vec();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut paired = vec![];
+        let mut send = async |from_client: bool, flags, seq, ack, ms| {
+            let (src, dst) = if from_client {
+                (client, server)
+            } else {
+                (server, client)
+            };
+            let data = segment(&tcp_frame(src, dst, flags, seq, ack, b"0123456789"));
+            let tcp = TcpPacket::new(&data).unwrap();
+            let metrics = obs.get_metrics(&tcp, peer, at(ms), 6379).await;
+            if !from_client {
+                paired.push(metrics.map(|m| (m.identifier, m.latency)));
+            }
+        };
+
+        // One request in two segments, the write ending with the second
+        send(true, TcpFlags::ACK, 100, 500, 0).await;
+        send(true, TcpFlags::ACK | TcpFlags::PSH, 110, 500, 1).await;
+        send(false, TcpFlags::ACK | TcpFlags::PSH, 500, 120, 3).await;
+        // Then two requests answered one at a time
+        send(true, TcpFlags::ACK | TcpFlags::PSH, 120, 510, 4).await;
+        send(false, TcpFlags::ACK | TcpFlags::PSH, 510, 130, 6).await;
+        send(true, TcpFlags::ACK | TcpFlags::PSH, 130, 520, 7).await;
+        send(false, TcpFlags::ACK | TcpFlags::PSH, 520, 140, 10).await;
+
+        let ms = |ms| Some(Duration::from_millis(ms));
+        assert_eq!(
+            paired,
+            vec![
+                Some((RequestId::TcpSeq(120, peer), ms(3))),
+                Some((RequestId::TcpSeq(130, peer), ms(2))),
+                Some((RequestId::TcpSeq(140, peer), ms(3))),
+            ]
+        );
+        let id = ConnectionId::Tcp(peer);
+        assert!(obs.connections.lock().await[&id].pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_get_metrics() {
+        let obs = Observer::new(ObsConfig::default());
+        let tcp_packet = TcpPacket::new(&[0; 20]).unwrap();
+        let timestamp = Instant::now();
+        let port = 1234;
+        let client = "127.0.0.1:40000".parse().unwrap();
+        let metrics = obs.get_metrics(&tcp_packet, client, timestamp, port).await;
+        assert!(metrics.is_none());
+    }
+
+    struct MockPlugin;
+
+    impl MockPlugin {
+        fn new() -> Self {
+            MockPlugin
+        }
+    }
+
+    impl Plugin<MockResult> for MockPlugin {
+        async fn port(&self) -> u16 {
+            1234
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            Ok(None)
+        }
+    }
+
+    struct MockResult;
+
+    impl From<MockResult> for ProcessedResult {
+        fn from(_res: MockResult) -> ProcessedResult {
+            ProcessedResult::Prometheus(PrometheusResult {
+                label: "test".to_string(),
+                is_error: false,
+                latency: 0.0,
+                response_size: None,
+                labels: vec![],
+            })
+        }
+    }
+
+    struct SlowPostProcessor {
+        delay: Duration,
+        fail: bool,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl PostProcessor for SlowPostProcessor {
+        async fn post_process(&self, _result: ProcessedResult) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.fail {
+                return Err(anyhow::anyhow!("sink unavailable"));
+            }
+            Ok(())
+        }
+    }
+
+    struct MockPayloadReader {
+        payloads: Vec<Payload>,
+    }
+
+    #[async_trait]
+    impl PayloadReader for MockPayloadReader {
+        async fn read_payload(&mut self) -> Option<Payload> {
+            if self.payloads.is_empty() {
+                // Park like a real socket with nothing to read
+                std::future::pending::<()>().await;
+            }
+            Some(self.payloads.remove(0))
+        }
+    }
+
+    // (payload, identifier, has latency)
+    type Seen = Vec<(Vec<u8>, Option<RequestId>, bool)>;
+
+    #[derive(Default)]
+    struct RecordingPlugin {
+        seen: std::sync::Mutex<Seen>,
+    }
+
+    impl Plugin<MockResult> for RecordingPlugin {
+        async fn port(&self) -> u16 {
+            0
+        }
+
+        async fn process(
+            &self,
+            input: Vec<u8>,
+            metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            let id = metrics.as_ref().map(|m| m.identifier);
+            let has_latency = metrics.and_then(|m| m.latency).is_some();
+            self.seen.lock().unwrap().push((input, id, has_latency));
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capture_payloads() {
+        let reader = MockPayloadReader {
+            payloads: vec![
+                Payload {
+                    connection: 7,
+                    direction: Direction::Request,
+                    data: b"*1\r\n$4\r\nPING\r\n".to_vec(),
+                },
+                Payload {
+                    connection: 7,
+                    direction: Direction::Response,
+                    data: b"+PONG\r\n".to_vec(),
+                },
+                // A response without a pending request can't be paired
+                Payload {
+                    connection: 8,
+                    direction: Direction::Response,
+                    data: b"+OK\r\n".to_vec(),
+                },
+            ],
+        };
+        let plugin = Arc::new(RecordingPlugin::default());
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+
+        let capture_task = {
+            let obs = obs.clone();
+            let plugin = plugin.clone();
+            tokio::spawn(async move { obs.capture_payloads(reader, plugin).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        obs.stop();
+        capture_task.await.unwrap().unwrap();
+
+        let seen = plugin.seen.lock().unwrap();
+        assert_eq!(seen.len(), 3);
+        assert_eq!(
+            seen[0],
+            (
+                b"*1\r\n$4\r\nPING\r\n".to_vec(),
+                Some(RequestId::Connection(7)),
+                false
+            )
+        );
+        assert_eq!(
+            seen[1],
+            (b"+PONG\r\n".to_vec(), Some(RequestId::Connection(7)), true)
+        );
+        assert_eq!(seen[2], (b"+OK\r\n".to_vec(), None, false));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_by_client_ip() {
+        let obs = Observer::new(ObsConfig {
+            sample_rate: 0.5,
+            ..Default::default()
+        });
+        let ips: Vec<[u8; 4]> = (1..=255).map(|i| [10, 0, 0, i]).collect();
+        let kept = *ips
+            .iter()
+            .find(|ip| is_sampled((**ip).into(), 0.5))
+            .unwrap();
+        let dropped = *ips
+            .iter()
+            .find(|ip| !is_sampled((**ip).into(), 0.5))
+            .unwrap();
+
+        let plugin = RecordingPlugin::default();
+        let server = ([10, 0, 1, 1], 0);
+        for i in 0..3 {
+            for client in [kept, dropped] {
+                let request = tcp_frame((client, 5000), server, TcpFlags::ACK, i, 0, b"req");
+                let response = tcp_frame(server, (client, 5000), TcpFlags::ACK, 0, i + 3, b"res");
+                for frame in [request, response] {
+                    obs.handle_packet::<_, MockResult>(
+                        &plugin,
+                        &plugin.ports().await,
+                        &frame,
+                        Instant::now(),
+                    )
+                    .await
+                    .unwrap();
+                }
+            }
+        }
+
+        // Both directions of the kept client's connection reach the plugin, none of the other
+        let seen = plugin.seen.lock().unwrap();
+        assert_eq!(seen.len(), 6);
+        assert!(seen.iter().all(|(_, id, _)| matches!(
+            id,
+            Some(RequestId::TcpSeq(_, addr)) if addr.ip() == std::net::IpAddr::from(kept)
+        )));
+        assert_eq!(seen.iter().filter(|(_, _, latency)| *latency).count(), 3);
+
+        assert!(ips.iter().all(|ip| is_sampled((*ip).into(), 1.0)));
+        assert!(ips.iter().all(|ip| !is_sampled((*ip).into(), 0.0)));
+    }
+
+    #[tokio::test]
+    async fn test_request_direction_only() {
+        let obs = Observer::new(ObsConfig {
+            direction: DirectionFilter::Request,
+            ..Default::default()
+        });
+        let plugin = RecordingPlugin::default();
+        let (client, server) = (([10, 0, 0, 1], 5000), ([10, 0, 1, 1], 0));
+        let request = tcp_frame(client, server, TcpFlags::ACK, 0, 0, b"req");
+        let response = tcp_frame(server, client, TcpFlags::ACK, 0, 3, b"res");
+        for frame in [request, response] {
+            obs.handle_packet::<_, MockResult>(
+                &plugin,
+                &plugin.ports().await,
+                &frame,
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        }
+        let seen = plugin.seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, b"req");
+        assert!(!seen[0].2);
+    }
+
+    #[tokio::test]
+    async fn test_reloaded_sample_rate() {
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = RecordingPlugin::default();
+        let request = |seq| {
+            tcp_frame(
+                ([10, 0, 0, 1], 5000),
+                ([10, 0, 1, 1], 0),
+                TcpFlags::ACK,
+                seq,
+                0,
+                b"req",
+            )
+        };
+
+        obs.handle_packet::<_, MockResult>(
+            &plugin,
+            &plugin.ports().await,
+            &request(0),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        let settings = obs.settings();
+        let current = *settings.borrow();
+        settings.send_replace(Settings {
+            sample_rate: 0.0,
+            ..current
+        });
+        obs.handle_packet::<_, MockResult>(
+            &plugin,
+            &plugin.ports().await,
+            &request(3),
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(plugin.seen.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_request_reassembled() {
+        let obs = Observer::new(ObsConfig::default());
+        let plugin = RecordingPlugin::default();
+        let payload = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n";
+        let frame = tcp_frame(
+            ([10, 0, 0, 1], 5000),
+            ([10, 0, 0, 2], 0),
+            TcpFlags::ACK | TcpFlags::PSH,
+            1,
+            1,
+            payload,
+        );
+        // The first fragment carries the TCP header and part of the request
+        let fragments = crate::ip_fragment::testing::fragment(&frame[14..], 40);
+        assert_eq!(fragments.len(), 2);
+        for fragment in fragments {
+            let mut frame = frame[..14].to_vec();
+            frame.extend_from_slice(&fragment);
+            obs.handle_packet::<_, MockResult>(
+                &plugin,
+                &plugin.ports().await,
+                &frame,
+                Instant::now(),
+            )
+            .await
+            .unwrap();
+        }
+        let seen = plugin.seen.lock().unwrap();
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0].0, payload);
+    }
+
+    #[tokio::test]
+    async fn test_result_carries_addresses() {
+        use crate::plugin::redis::handler::{RespConfig, RespHandler};
+
+        let obs = Observer::new(ObsConfig::default());
+        let handler = RespHandler::new(6379, RespConfig::default());
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 6379);
+        let request = b"*2\r\n$3\r\nGET\r\n$1\r\nk\r\n";
+        let frame = tcp_frame(client, server, TcpFlags::ACK, 1, 1, request);
+        obs.handle_packet(&handler, &handler.ports().await, &frame, Instant::now())
+            .await
+            .unwrap();
+        let ack = 1 + request.len() as u32;
+        let frame = tcp_frame(server, client, TcpFlags::ACK, 1, ack, b"$1\r\nv\r\n");
+        let res = obs
+            .handle_packet(&handler, &handler.ports().await, &frame, Instant::now())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(res.client_addr, Some(SocketAddr::from(client)));
+        assert_eq!(res.server_addr, Some(SocketAddr::from(server)));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_errors_collapsed() {
+        let buf = crate::logging::testing::BufWriter::default();
+        let _guard = tracing::subscriber::set_default(crate::logging::subscriber(
+            tracing::Level::ERROR,
+            crate::logging::LogFormat::Json,
+            buf.clone(),
+        ));
+        let obs = Observer::new(ObsConfig {
+            error_log_interval: Duration::from_millis(100),
+            ..Default::default()
+        });
+
+        for _ in 0..50 {
+            obs.dispatch::<MockResult>(Err(anyhow::anyhow!("Failed to parse packet")))
+                .await
+                .unwrap();
+        }
+        obs.dispatch::<MockResult>(Err(anyhow::anyhow!("Truncated frame")))
+            .await
+            .unwrap();
+        assert_eq!(buf.contents().lines().count(), 2);
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        obs.dispatch::<MockResult>(Err(anyhow::anyhow!("Failed to parse packet")))
+            .await
+            .unwrap();
+        let out = buf.contents();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].contains("Failed to parse packet"));
+        assert!(lines[2].contains("\"suppressed\":49"));
+    }
+
+    #[tokio::test]
+    async fn test_max_parse_bytes() {
+        use crate::plugin::redis::handler::{RespConfig, RespHandler};
+        use crate::plugin::redis::resp_parser::ParserConfig;
+
+        let obs = Observer::new(ObsConfig {
+            max_parse_bytes: Some(64),
+            ..Default::default()
+        });
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 0);
+        let mut request = b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$10000\r\n".to_vec();
+        request.extend(std::iter::repeat_n(b'x', 10000));
+        request.extend_from_slice(b"\r\n");
+        let request = tcp_frame(client, server, TcpFlags::ACK, 1, 1, &request);
+        let response = tcp_frame(server, client, TcpFlags::ACK, 1, 10_036, b"+OK\r\n");
+
+        // Only the head of the request reaches the plugin
+        let plugin = RecordingPlugin::default();
+        obs.handle_packet::<_, MockResult>(
+            &plugin,
+            &plugin.ports().await,
+            &request,
+            Instant::now(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(plugin.seen.lock().unwrap()[0].0.len(), 64);
+
+        // which is enough for the command and key
+        let obs = Observer::new(ObsConfig {
+            max_parse_bytes: Some(64),
+            ..Default::default()
+        });
+        let handler = RespHandler::new(
+            0,
+            RespConfig {
+                parser: ParserConfig {
+                    allow_truncated: true,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let res = obs
+            .handle_packet(&handler, &handler.ports().await, &request, Instant::now())
+            .await
+            .unwrap();
+        assert!(res.is_none());
+        let res = obs
+            .handle_packet(&handler, &handler.ports().await, &response, Instant::now())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((res.command.as_str(), res.key.as_str()), ("SET", "big"));
+    }
+
+    #[tokio::test]
+    async fn test_capture_packets() {
+        let reader = MockPacketReader {
+            // TODO: send a fake tcp packet
             packets: vec![vec![
                 0x45, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00, 0x7f, 0x00,
                 0x00, 0x01, 0x7f, 0x00, 0x00, 0x01,
             ]],
         };
-        let plugin = Arc::new(Mutex::new(MockPlugin::new()));
-        let obs = Arc::new(Mutex::new(Observer::new(ObsConfig::default())));
+        let plugin = Arc::new(MockPlugin::new());
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
 
-        let stop_tx = obs.lock().await.stop_tx.clone();
+        let stop_tx = obs.stop_tx.clone();
         // Clone the Arc and receiver to pass into the spawned task
         let obs_clone = Arc::clone(&obs);
 
         // Start the packet capture in a separate task
         let capture_task =
-            tokio::spawn(
-                async move { obs_clone.lock().await.capture_packets(reader, plugin).await },
-            );
+            tokio::spawn(async move { obs_clone.capture_packets(reader, plugin).await });
 
         // Run the capture for a short duration and then signal stop
         tokio::time::sleep(Duration::from_secs(1)).await;
@@ -336,9 +2277,472 @@ mod tests {
         // Assert that the result is Ok
         assert!(res.is_ok());
 
-        // Look at whats in the syn_packets hashmap
-        let obs = obs.lock().await;
-        let syn_packets = obs.syn_packets.lock().await;
-        assert_eq!(syn_packets.len(), 0);
+        // Nothing is pending
+        let connections = obs.connections.lock().await;
+        assert!(connections.values().all(|c| c.pending.is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_is_concurrent() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut obs = Observer::new(ObsConfig::default());
+        for fail in [false, true] {
+            obs.add_post_processor(Arc::new(Mutex::new(SlowPostProcessor {
+                delay: Duration::from_millis(300),
+                fail,
+                calls: calls.clone(),
+            })));
+        }
+
+        let start = Instant::now();
+        obs.dispatch(Ok(Some(MockResult))).await.unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_millis(600), "took {:?}", elapsed);
+    }
+
+    struct BlockedPlugin {
+        processed: Arc<AtomicUsize>,
+    }
+
+    impl Plugin<MockResult> for BlockedPlugin {
+        async fn port(&self) -> u16 {
+            4444
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            self.processed.fetch_add(1, Ordering::SeqCst);
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_packets() {
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 4444);
+        let reader = MockPacketReader {
+            packets: (0..10)
+                .map(|i| tcp_frame(client, server, TcpFlags::ACK, i * 4, 0, b"PING"))
+                .collect(),
+        };
+        let processed = Arc::new(AtomicUsize::new(0));
+        let plugin = Arc::new(BlockedPlugin {
+            processed: processed.clone(),
+        });
+        let obs = Arc::new(Observer::new(ObsConfig {
+            queue_depth: 1,
+            ..Default::default()
+        }));
+
+        // Capture ends by itself once the reader is exhausted and the queue drained
+        obs.capture_packets(reader, plugin).await.unwrap();
+
+        let dropped = PACKETS_DROPPED.with_label_values(&["4444"]).get() as usize;
+        assert!(dropped > 0);
+        assert_eq!(dropped + processed.load(Ordering::SeqCst), 10);
+    }
+
+    #[tokio::test]
+    async fn test_capture_stats() {
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 4545);
+        let good = tcp_frame(client, server, TcpFlags::ACK, 0, 0, b"PING");
+        // Claim a TCP segment too short to hold a header
+        let mut bad = tcp_frame(client, server, TcpFlags::ACK, 0, 0, &[]);
+        MutableIpv4Packet::new(&mut bad[14..])
+            .unwrap()
+            .set_total_length(30);
+        let total = (good.len() + bad.len()) as u64;
+        let reader = MockPacketReader {
+            packets: vec![good, bad],
+        };
+        let plugin = Arc::new(PortPlugin(4545));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        obs.capture_packets(reader, plugin).await.unwrap();
+
+        let get = |counter: &IntCounterVec| counter.with_label_values(&["4545"]).get();
+        assert_eq!(get(&PACKETS_CAPTURED), 2);
+        assert_eq!(get(&BYTES_CAPTURED), total);
+        assert_eq!(get(&PACKETS_PARSED), 1);
+        assert_eq!(get(&PARSE_ERRORS), 1);
+    }
+
+    #[tokio::test]
+    async fn test_capture_would_block_continues() {
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 4646);
+        let packet = tcp_frame(client, server, TcpFlags::ACK, 0, 0, b"PING");
+        let reader = ScriptedReader {
+            script: VecDeque::from([
+                ReadResult::WouldBlock,
+                ReadResult::WouldBlock,
+                ReadResult::Packet(packet),
+                ReadResult::Eof,
+            ]),
+            dropped: Arc::new(AtomicBool::new(false)),
+        };
+        let plugin = Arc::new(PortPlugin(4646));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        obs.capture_packets(reader, plugin).await.unwrap();
+
+        assert_eq!(PACKETS_CAPTURED.with_label_values(&["4646"]).get(), 1);
+        assert_eq!(PACKETS_PARSED.with_label_values(&["4646"]).get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_capture_ends_at_eof() {
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 4747);
+        let packet = tcp_frame(client, server, TcpFlags::ACK, 0, 0, b"PING");
+        let reader = ScriptedReader {
+            script: VecDeque::from([
+                ReadResult::Packet(packet.clone()),
+                ReadResult::Eof,
+                ReadResult::Packet(packet),
+            ]),
+            dropped: Arc::new(AtomicBool::new(false)),
+        };
+        let plugin = Arc::new(PortPlugin(4747));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        obs.capture_packets(reader, plugin).await.unwrap();
+
+        // Nothing after the end of the stream is read
+        assert_eq!(PACKETS_CAPTURED.with_label_values(&["4747"]).get(), 1);
+    }
+
+    /// Copies frames into the buffers it is handed, like the live reader, counting
+    /// those that came back from the capture loop.
+    struct RecyclingReader {
+        frames: VecDeque<Vec<u8>>,
+        reused: Arc<AtomicUsize>,
+    }
+
+    impl PacketReader for RecyclingReader {
+        fn read_packet(&mut self) -> ReadResult {
+            self.read_packet_into(Vec::new())
+        }
+
+        fn read_packet_into(&mut self, mut buf: Vec<u8>) -> ReadResult {
+            // Give the workers time to hand buffers back
+            std::thread::sleep(Duration::from_millis(5));
+            let Some(frame) = self.frames.pop_front() else {
+                return ReadResult::Eof;
+            };
+            if buf.capacity() > 0 {
+                self.reused.fetch_add(1, Ordering::SeqCst);
+            }
+            buf.clear();
+            buf.extend_from_slice(&frame);
+            ReadResult::Packet(buf)
+        }
+    }
+
+    #[test]
+    fn test_boxed_readers() {
+        let reused = Arc::new(AtomicUsize::new(0));
+        let mut readers: Vec<BoxedPacketReader> = vec![
+            Box::new(MockPacketReader {
+                packets: vec![vec![1]],
+            }),
+            Box::new(RecyclingReader {
+                frames: VecDeque::from([vec![2, 2]]),
+                reused: reused.clone(),
+            }),
+        ];
+        assert_eq!(readers[0].read_packet(), ReadResult::Packet(vec![1]));
+        assert_eq!(readers[0].read_packet(), ReadResult::Eof);
+        // A reader's own read_packet_into is kept behind the box
+        let buf = Vec::with_capacity(64);
+        assert_eq!(
+            readers[1].read_packet_into(buf),
+            ReadResult::Packet(vec![2, 2])
+        );
+        assert_eq!(reused.load(Ordering::SeqCst), 1);
+        assert_eq!(readers[1].read_packet(), ReadResult::Eof);
+    }
+
+    #[tokio::test]
+    async fn test_capture_reuses_packet_buffers() {
+        let client = ([10, 0, 0, 2], 50000);
+        let server = ([10, 0, 0, 1], 4848);
+        // Payloads shrink, so a reused buffer holding stale bytes would show
+        let payloads: Vec<Vec<u8>> = (0..10).map(|i| vec![b'a' + i; 20 - i as usize]).collect();
+        let mut seq = 0;
+        let frames = payloads
+            .iter()
+            .map(|p| {
+                let frame = tcp_frame(client, server, TcpFlags::ACK, seq, 0, p);
+                seq += p.len() as u32;
+                frame
+            })
+            .collect();
+        let reused = Arc::new(AtomicUsize::new(0));
+        let reader = RecyclingReader {
+            frames,
+            reused: reused.clone(),
+        };
+        let plugin = Arc::new(MultiPortPlugin(vec![4848], std::sync::Mutex::new(vec![])));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        obs.capture_packets(reader, plugin.clone()).await.unwrap();
+
+        assert!(reused.load(Ordering::SeqCst) > 0);
+        assert_eq!(*plugin.1.lock().unwrap(), payloads);
+    }
+
+    struct FlushPlugin(Arc<AtomicBool>);
+
+    impl Plugin<MockResult> for FlushPlugin {
+        async fn port(&self) -> u16 {
+            4949
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            Ok(None)
+        }
+
+        async fn flush(&self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stop_flushes_plugin() {
+        let flushed = Arc::new(AtomicBool::new(false));
+        let reader = ScriptedReader {
+            script: VecDeque::new(),
+            dropped: Arc::new(AtomicBool::new(false)),
+        };
+        let plugin = Arc::new(FlushPlugin(flushed.clone()));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        let stop_tx = obs.stop_tx.clone();
+        let capture = tokio::spawn(async move { obs.capture_packets(reader, plugin).await });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!flushed.load(Ordering::SeqCst));
+        stop_tx.send(true).unwrap();
+        capture.await.unwrap().unwrap();
+        assert!(flushed.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn test_stop_releases_blocked_reader() {
+        let dropped = Arc::new(AtomicBool::new(false));
+        let reader = ScriptedReader {
+            script: VecDeque::new(),
+            dropped: dropped.clone(),
+        };
+        let plugin = Arc::new(PortPlugin(4848));
+        let obs = Arc::new(Observer::new(ObsConfig::default()));
+        let stop_tx = obs.stop_tx.clone();
+        let capture = tokio::spawn(async move { obs.capture_packets(reader, plugin).await });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stop_tx.send(true).unwrap();
+        capture.await.unwrap().unwrap();
+
+        // The reader thread sees the queue close on its next WouldBlock and exits
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !dropped.load(Ordering::SeqCst) {
+            assert!(Instant::now() < deadline, "reader thread still running");
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    #[test]
+    fn test_port_label() {
+        let mut buf = [0; 5];
+        for port in [0, 80, 6379, 65535] {
+            assert_eq!(port_label(port, &mut buf), port.to_string());
+        }
+    }
+
+    #[test]
+    fn test_connection_stays_on_worker() {
+        let mut router = Router::new(4);
+        let now = Instant::now();
+        let server = ([10, 0, 0, 1], 6379);
+        let mut used = std::collections::HashSet::new();
+        for port in 50000..50032 {
+            let client = ([10, 0, 0, 2], port);
+            let worker = router.route(&tcp_frame(client, server, TcpFlags::SYN, 0, 0, &[]), now);
+            for frame in [
+                tcp_frame(client, server, TcpFlags::ACK, 1, 1, b"PING\r\n"),
+                tcp_frame(server, client, TcpFlags::ACK, 1, 7, b"+PONG\r\n"),
+                tcp_frame(client, server, TcpFlags::FIN, 7, 8, &[]),
+            ] {
+                assert_eq!(router.route(&frame, now), worker);
+            }
+            used.insert(worker);
+        }
+        assert!(used.len() > 1, "{:?}", used);
+        assert!(used.iter().all(|&w| w < 4));
+        assert_eq!(router.route(&[0; 4], now), 0);
+    }
+
+    #[test]
+    fn test_fragments_follow_their_connection() {
+        let mut router = Router::new(4);
+        let now = Instant::now();
+        let server = ([10, 0, 0, 1], 6379);
+        let payload = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$5\r\nvalue\r\n";
+        for port in 50000..50032 {
+            let client = ([10, 0, 0, 2], port);
+            let mut frame = tcp_frame(client, server, TcpFlags::ACK, 1, 1, payload);
+            MutableIpv4Packet::new(&mut frame[14..])
+                .unwrap()
+                .set_identification(port);
+            let worker = router.route(&frame, now);
+            let fragments = crate::ip_fragment::testing::fragment(&frame[14..], 40);
+            assert_eq!(fragments.len(), 2);
+            for fragment in fragments {
+                let mut fragment_frame = frame[..14].to_vec();
+                fragment_frame.extend_from_slice(&fragment);
+                assert_eq!(router.route(&fragment_frame, now), worker, "port {}", port);
+            }
+        }
+        // Forgotten once the reassembler would have given up on them
+        assert_eq!(router.fragments.len(), 32);
+        router.route(&[0; 4], now);
+        let later = now + Duration::from_secs(31);
+        let frame = tcp_frame(([10, 0, 0, 2], 50000), server, TcpFlags::ACK, 1, 1, payload);
+        let fragment = &crate::ip_fragment::testing::fragment(&frame[14..], 40)[1];
+        let mut fragment_frame = frame[..14].to_vec();
+        fragment_frame.extend_from_slice(fragment);
+        router.route(&fragment_frame, later);
+        assert_eq!(router.fragments.len(), 1);
+    }
+
+    /// Holds back payloads of `SLOW` until the gate opens.
+    struct GatedPlugin {
+        gate: watch::Receiver<bool>,
+        fast: Arc<AtomicUsize>,
+    }
+
+    impl Plugin<MockResult> for GatedPlugin {
+        async fn port(&self) -> u16 {
+            5151
+        }
+
+        async fn process(
+            &self,
+            input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            if input == b"SLOW" {
+                self.gate.clone().wait_for(|open| *open).await?;
+            } else {
+                self.fast.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slow_worker_drops_its_own_packets() {
+        let server = ([10, 0, 0, 1], 5151);
+        let mut router = Router::new(2);
+        let now = Instant::now();
+        let slow = ([10, 0, 0, 2], 50000);
+        let slow_worker = router.route(&tcp_frame(slow, server, 0, 0, 0, &[]), now);
+        let fast = (50001..)
+            .map(|port| ([10, 0, 0, 2], port))
+            .find(|&fast| router.route(&tcp_frame(fast, server, 0, 0, 0, &[]), now) != slow_worker)
+            .unwrap();
+        const PSH_ACK: u8 = TcpFlags::PSH | TcpFlags::ACK;
+        // More than the slow worker can queue, then one for the other worker
+        let mut packets: Vec<_> = (0..16 + 8)
+            .map(|i| tcp_frame(slow, server, PSH_ACK, 1 + 4 * i, 1, b"SLOW"))
+            .collect();
+        packets.push(tcp_frame(fast, server, PSH_ACK, 1, 1, b"FAST"));
+        // The reader replays from the back
+        packets.reverse();
+        let (open, gate) = watch::channel(false);
+        let plugin = Arc::new(GatedPlugin {
+            gate,
+            fast: Arc::new(AtomicUsize::new(0)),
+        });
+        let obs = Arc::new(Observer::new(ObsConfig {
+            workers: 2,
+            queue_depth: 16,
+            ..Default::default()
+        }));
+        let dropped = PACKETS_DROPPED.with_label_values(&["5151"]);
+
+        // Paced, so only a worker's queue can fill up
+        let reader = PacedReader(MockPacketReader { packets });
+        let capture = tokio::spawn({
+            let (obs, plugin) = (obs.clone(), plugin.clone());
+            async move { obs.capture_packets(reader, plugin).await }
+        });
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while plugin.fast.load(Ordering::SeqCst) == 0 {
+            assert!(
+                Instant::now() < deadline,
+                "routing held up by the slow worker"
+            );
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert!(dropped.get() > 0);
+        open.send(true).unwrap();
+        capture.await.unwrap().unwrap();
+    }
+
+    /// Parses by blocking its thread, like a plugin busy with a large payload.
+    struct SlowPlugin;
+
+    impl Plugin<MockResult> for SlowPlugin {
+        async fn port(&self) -> u16 {
+            5050
+        }
+
+        async fn process(
+            &self,
+            _input: Vec<u8>,
+            _metrics: Option<Metrics>,
+        ) -> Result<Option<MockResult>> {
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(None)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_workers_parse_in_parallel() {
+        let server = ([10, 0, 0, 1], 5050);
+        let frame = |port| tcp_frame(([10, 0, 0, 2], port), server, TcpFlags::ACK, 0, 0, b"PING");
+        // Two connections that land on different workers
+        let first = frame(50000);
+        let mut router = Router::new(2);
+        let now = Instant::now();
+        let second = (50001..)
+            .map(frame)
+            .find(|f| router.route(f, now) != router.route(&first, now))
+            .unwrap();
+        let reader = MockPacketReader {
+            packets: vec![first, second],
+        };
+        let obs = Arc::new(Observer::new(ObsConfig {
+            workers: 2,
+            ..Default::default()
+        }));
+
+        let start = Instant::now();
+        obs.capture_packets(reader, Arc::new(SlowPlugin))
+            .await
+            .unwrap();
+        let elapsed = start.elapsed();
+
+        assert_eq!(PACKETS_PARSED.with_label_values(&["5050"]).get(), 2);
+        assert!(elapsed < Duration::from_millis(550), "took {:?}", elapsed);
     }
 }
@@ -0,0 +1,141 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+
+use crate::tun::{PacketReader, ReadResult};
+
+// _IOW('T', 202, int) from linux/if_tun.h
+#[cfg(target_os = "linux")]
+const TUNSETIFF: u64 = 0x4004_54ca;
+
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ETHERTYPE_IPV6: [u8; 2] = [0x86, 0xdd];
+const ETHERNET_HEADER_LEN: usize = 14;
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _pad: [u8; 22],
+}
+
+/// TunDeviceReader creates a TUN interface and reads the IP packets routed through it.
+///
+/// The interface exists for as long as the reader does: it is not persistent, so the
+/// kernel tears it down once the reader is dropped at the end of capture. It is
+/// created down and without an address; bring it up and route traffic to it with
+/// e.g. `ip addr add 10.9.0.1/24 dev <name> && ip link set <name> up`.
+pub struct TunDeviceReader {
+    file: File,
+    name: String,
+    buf: Vec<u8>,
+}
+
+impl TunDeviceReader {
+    pub fn create(name: &str) -> Result<Self> {
+        if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+            return Err(anyhow::anyhow!("Invalid TUN interface name {:?}", name));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+        let name = set_iff(&file, name)?;
+        Ok(TunDeviceReader {
+            file,
+            name,
+            buf: vec![0; 65535],
+        })
+    }
+
+    /// The name the kernel gave the interface.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_iff(file: &File, name: &str) -> Result<String> {
+    let mut req = IfReq {
+        name: [0; libc::IFNAMSIZ],
+        flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+        _pad: [0; 22],
+    };
+    req.name[..name.len()].copy_from_slice(name.as_bytes());
+    // SAFETY: req is a valid ifreq that outlives the call.
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut req) } < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let len = req
+        .name
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(req.name.len());
+    Ok(String::from_utf8_lossy(&req.name[..len]).to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_iff(_file: &File, _name: &str) -> Result<String> {
+    Err(anyhow::anyhow!(
+        "Creating TUN devices is only supported on Linux"
+    ))
+}
+
+impl PacketReader for TunDeviceReader {
+    /// TUN devices carry bare IP packets, so each one is given a blank Ethernet header
+    /// to look like the frames read from other interfaces.
+    fn read_packet(&mut self) -> ReadResult {
+        loop {
+            let n = match self.file.read(&mut self.buf) {
+                Ok(0) => return ReadResult::Eof,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return ReadResult::WouldBlock,
+                Err(_) => return ReadResult::Eof,
+            };
+            let ethertype = match self.buf[0] >> 4 {
+                4 => ETHERTYPE_IPV4,
+                6 => ETHERTYPE_IPV6,
+                _ => continue,
+            };
+            let mut frame = vec![0; ETHERNET_HEADER_LEN - 2];
+            frame.extend_from_slice(&ethertype);
+            frame.extend_from_slice(&self.buf[..n]);
+            return ReadResult::Packet(frame);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invalid_name() {
+        assert!(TunDeviceReader::create("").is_err());
+        assert!(TunDeviceReader::create("a-name-far-too-long").is_err());
+    }
+
+    #[test]
+    fn test_create() {
+        // Needs /dev/net/tun and CAP_NET_ADMIN, which not every host grants.
+        let reader = match TunDeviceReader::create("aragorn-test%d") {
+            Ok(reader) => reader,
+            Err(e) => {
+                eprintln!("Skipping TUN test: {}", e);
+                return;
+            }
+        };
+        assert!(reader.name().starts_with("aragorn-test"));
+        let name = reader.name().to_string();
+        assert!(pnet::datalink::interfaces()
+            .iter()
+            .any(|iface| iface.name == name));
+
+        drop(reader);
+        assert!(!pnet::datalink::interfaces()
+            .iter()
+            .any(|iface| iface.name == name));
+    }
+}
@@ -0,0 +1,148 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+
+use crate::tun::{Direction, Payload, PayloadReader};
+
+const QUEUE_SIZE: usize = 1024;
+const BUF_SIZE: usize = 16 * 1024;
+
+/// UnixSocketProxy observes traffic on a unix domain socket by sitting between clients
+/// and the real server. Clients connect to the listen path, every connection is
+/// forwarded to the upstream path and the bytes in both directions are surfaced as
+/// payloads. Observation never blocks forwarding: payloads are dropped if the
+/// consumer falls behind.
+pub struct UnixSocketProxy {
+    rx: mpsc::Receiver<Payload>,
+}
+
+impl UnixSocketProxy {
+    pub fn bind(listen: impl AsRef<Path>, upstream: impl Into<PathBuf>) -> Result<Self> {
+        let listener = UnixListener::bind(listen)?;
+        let upstream = upstream.into();
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let next_connection = Arc::new(AtomicU32::new(0));
+
+        tokio::spawn(async move {
+            loop {
+                let client = match listener.accept().await {
+                    Ok((client, _)) => client,
+                    Err(e) => {
+                        error!("Failed to accept unix connection: {:?}", e);
+                        continue;
+                    }
+                };
+                let server = match UnixStream::connect(&upstream).await {
+                    Ok(server) => server,
+                    Err(e) => {
+                        error!("Failed to connect to upstream {:?}: {:?}", upstream, e);
+                        continue;
+                    }
+                };
+                let connection = next_connection.fetch_add(1, Ordering::Relaxed);
+                let (client_rx, client_tx) = client.into_split();
+                let (server_rx, server_tx) = server.into_split();
+                tokio::spawn(forward(
+                    client_rx,
+                    server_tx,
+                    connection,
+                    Direction::Request,
+                    tx.clone(),
+                ));
+                tokio::spawn(forward(
+                    server_rx,
+                    client_tx,
+                    connection,
+                    Direction::Response,
+                    tx.clone(),
+                ));
+            }
+        });
+
+        Ok(UnixSocketProxy { rx })
+    }
+}
+
+async fn forward(
+    mut from: OwnedReadHalf,
+    mut to: OwnedWriteHalf,
+    connection: u32,
+    direction: Direction,
+    tx: mpsc::Sender<Payload>,
+) {
+    let mut buf = vec![0u8; BUF_SIZE];
+    loop {
+        let n = match from.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                debug!("Unix connection {} closed: {:?}", connection, e);
+                break;
+            }
+        };
+        if to.write_all(&buf[..n]).await.is_err() {
+            break;
+        }
+        let _ = tx.try_send(Payload {
+            connection,
+            direction,
+            data: buf[..n].to_vec(),
+        });
+    }
+    let _ = to.shutdown().await;
+}
+
+#[async_trait]
+impl PayloadReader for UnixSocketProxy {
+    async fn read_payload(&mut self) -> Option<Payload> {
+        self.rx.recv().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_proxy_forwards_and_observes() {
+        let dir = std::env::temp_dir().join(format!("aragorn-unix-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let listen = dir.join("proxy.sock");
+        let upstream = dir.join("server.sock");
+        let _ = std::fs::remove_file(&listen);
+        let _ = std::fs::remove_file(&upstream);
+
+        // A tiny server replying +PONG to whatever it receives
+        let server = UnixListener::bind(&upstream).unwrap();
+        tokio::spawn(async move {
+            let (mut conn, _) = server.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let _ = conn.read(&mut buf).await.unwrap();
+            conn.write_all(b"+PONG\r\n").await.unwrap();
+        });
+
+        let mut proxy = UnixSocketProxy::bind(&listen, &upstream).unwrap();
+        let mut client = UnixStream::connect(&listen).await.unwrap();
+        client.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        let mut reply = [0u8; 7];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        let request = proxy.read_payload().await.unwrap();
+        assert_eq!(request.direction, Direction::Request);
+        assert_eq!(request.data, b"*1\r\n$4\r\nPING\r\n");
+        let response = proxy.read_payload().await.unwrap();
+        assert_eq!(response.direction, Direction::Response);
+        assert_eq!(response.connection, request.connection);
+        assert_eq!(response.data, b"+PONG\r\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,26 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Pipe raw RESP bytes into `aragorn parse` and check what it prints.
+#[test]
+fn test_parse_stdin() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_aragorn"))
+        .args(["parse", "--format", "json"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"*3\r\n$3\r\nGET\r\n$1\r\nk\r\n$1\r\nv\r\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "{\"command\":\"GET\",\"key\":\"k\",\"field\":null,\"value\":\"v\"}\n"
+    );
+}